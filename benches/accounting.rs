@@ -0,0 +1,41 @@
+//! Reports the distribution of the time spent in the accounting performed by
+//! `PeakAlloc` upon each allocation.
+//!
+//! Run it with `cargo bench --features timed-accounting`.
+
+use std::hint::black_box;
+use peak_alloc::PeakAlloc;
+
+#[global_allocator]
+static PEAK_ALLOC: PeakAlloc = PeakAlloc;
+
+const ROUNDS: usize = 1_000_000;
+
+fn main() {
+    // single threaded: there is no contention on the counters
+    PEAK_ALLOC.reset_timed_accounting();
+    for i in 0..ROUNDS {
+        black_box(vec![0_u8; 1 + i % 512]);
+    }
+    println!("single thread\n{}", PEAK_ALLOC.timed_accounting());
+
+    // multi threaded: all threads hammer the same counters
+    PEAK_ALLOC.reset_timed_accounting();
+    let threads = std::thread::available_parallelism().map_or(4, |n| n.get());
+    let handles = (0..threads)
+        .map(|_| {
+            std::thread::spawn(move || {
+                for i in 0..ROUNDS / threads {
+                    black_box(vec![0_u8; 1 + i % 512]);
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    println!("{} threads\n{}", threads, PEAK_ALLOC.timed_accounting());
+    for q in [0.5, 0.9, 0.99, 0.999] {
+        println!("p{:<5} <= {:?}", q * 100.0, PEAK_ALLOC.timed_accounting().quantile(q).unwrap());
+    }
+}
@@ -0,0 +1,53 @@
+//! Measures the cost of allocating when the usage stays well below the peak
+//! (steady state), which is the case the peak fast-path is optimized for.
+//!
+//! Run it with `cargo bench --bench steady_state`.
+
+use std::hint::black_box;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use peak_alloc::PeakAlloc;
+
+#[global_allocator]
+static PEAK_ALLOC: PeakAlloc = PeakAlloc;
+
+const ROUNDS: usize = 10_000_000;
+
+/// Runs `f` from as many threads as there are cores and returns the mean
+/// number of nanoseconds per call.
+fn measure(f: fn(usize)) -> f64 {
+    let threads = std::thread::available_parallelism().map_or(4, |n| n.get());
+    let start = Instant::now();
+    let handles = (0..threads)
+        .map(|_| std::thread::spawn(move || (0..ROUNDS / threads).for_each(f)))
+        .collect::<Vec<_>>();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    start.elapsed().as_nanos() as f64 / (ROUNDS / threads) as f64
+}
+
+static MARK: AtomicUsize = AtomicUsize::new(usize::MAX / 2);
+
+fn unconditional(i: usize) {
+    MARK.fetch_max(black_box(i), Ordering::Relaxed);
+}
+fn fast_path(i: usize) {
+    let value = black_box(i);
+    if value > MARK.load(Ordering::Relaxed) {
+        MARK.fetch_max(value, Ordering::Relaxed);
+    }
+}
+fn allocate(i: usize) {
+    black_box(vec![0_u8; 1 + i % 256]);
+}
+
+fn main() {
+    // raise the peak well above the steady state usage
+    drop(black_box(vec![0_u8; 64 * 1024 * 1024]));
+
+    println!("unconditional fetch_max : {:6.2} ns/op", measure(unconditional));
+    println!("load then fetch_max     : {:6.2} ns/op", measure(fast_path));
+    println!("steady state alloc/free : {:6.2} ns/op", measure(allocate));
+}
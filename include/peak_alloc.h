@@ -0,0 +1,78 @@
+/* C accessors to the counters of peak_alloc (cargo feature `ffi`). */
+#ifndef PEAK_ALLOC_H
+#define PEAK_ALLOC_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define PEAK_ALLOC_STATS_VERSION 1
+/* The version of the schema of the exported statistics (JSON, influx). */
+#define PEAK_ALLOC_SCHEMA_VERSION 2
+
+#define PEAK_ALLOC_OK 0
+#define PEAK_ALLOC_ERR_NULL -1
+#define PEAK_ALLOC_ERR_SIZE -2
+
+/* Set `size` to sizeof(PeakAllocStatsC) before calling peak_alloc_stats. */
+typedef struct PeakAllocStatsC {
+    uint32_t version;
+    uint32_t size;
+    uint64_t current_usage;
+    uint64_t peak_usage;
+    uint64_t all_time_peak_usage;
+    uint64_t total_allocated;
+    uint64_t allocation_count;
+    uint64_t deallocation_count;
+    uint64_t largest_allocation;
+} PeakAllocStatsC;
+
+/* The entries of PeakAllocMetrics: values[i] is valid iff (valid >> i) & 1.
+ * Check that peak_alloc_schema_version() is PEAK_ALLOC_SCHEMA_VERSION first:
+ * the entries are only appended along with a bump of the schema. */
+#define PEAK_ALLOC_METRICS 23
+#define PEAK_ALLOC_METRIC_CURRENT_USAGE 0
+#define PEAK_ALLOC_METRIC_PEAK_USAGE 1
+#define PEAK_ALLOC_METRIC_ALL_TIME_PEAK_USAGE 2
+#define PEAK_ALLOC_METRIC_TOTAL_ALLOCATED 3
+#define PEAK_ALLOC_METRIC_ALLOCATION_COUNT 4
+#define PEAK_ALLOC_METRIC_DEALLOCATION_COUNT 5
+#define PEAK_ALLOC_METRIC_REALLOCATION_COUNT 6
+#define PEAK_ALLOC_METRIC_REALLOC_OVERHEAD_BYTES 7
+#define PEAK_ALLOC_METRIC_LARGEST_ALLOCATION 8
+#define PEAK_ALLOC_METRIC_FAILED_ALLOCATION_COUNT 9
+#define PEAK_ALLOC_METRIC_LARGE_CURRENT_USAGE 10
+#define PEAK_ALLOC_METRIC_LARGE_PEAK_USAGE 11
+#define PEAK_ALLOC_METRIC_LARGE_ALLOCATION_COUNT 12
+#define PEAK_ALLOC_METRIC_OVERALIGNED_COUNT 13
+#define PEAK_ALLOC_METRIC_OVERALIGNED_BYTES 14
+#define PEAK_ALLOC_METRIC_EXTERNAL_USAGE 15
+#define PEAK_ALLOC_METRIC_GROWTH_SLACK_BYTES 16
+#define PEAK_ALLOC_METRIC_SMOOTHED_PEAK_USAGE 17
+#define PEAK_ALLOC_METRIC_WIPED_BYTES 18
+#define PEAK_ALLOC_METRIC_INVALID_REQUEST_COUNT 19
+#define PEAK_ALLOC_METRIC_QUARANTINED_BYTES 20
+#define PEAK_ALLOC_METRIC_REDZONE_VIOLATIONS 21
+#define PEAK_ALLOC_METRIC_POINTER_MAP_OVERFLOWS 22
+
+typedef struct PeakAllocMetrics {
+    uint32_t schema;
+    uint32_t len;
+    uint64_t valid;
+    uint64_t values[PEAK_ALLOC_METRICS];
+} PeakAllocMetrics;
+
+uint32_t peak_alloc_schema_version(void);
+uint64_t peak_alloc_current_usage(void);
+uint64_t peak_alloc_peak_usage(void);
+void peak_alloc_reset_peak(void);
+int32_t peak_alloc_stats(PeakAllocStatsC *out);
+int32_t peak_alloc_read_metrics(PeakAllocMetrics *out);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* PEAK_ALLOC_H */
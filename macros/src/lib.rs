@@ -0,0 +1,179 @@
+//! The procedural macros of peak_alloc. They are re-exported by peak_alloc
+//! (with its `module-tags` feature), which is where they are documented.
+//!
+//! There is no dependency on `syn`: the items of a module are told apart
+//! token by token, which is all it takes to find the bodies of the functions
+//! and to recognize the impl blocks and the nested modules.
+
+use proc_macro::{Delimiter, Group, Ident, Punct, Spacing, Span, TokenStream, TokenTree};
+
+/// Attributes the memory allocated by the functions of a module to a tag:
+/// the path of the module by default, or the string given as argument
+/// (`#[instrument_module("parser")]`). See `peak_alloc::Tag`.
+///
+/// The public functions of the module (and of its nested modules) are
+/// instrumented, as well as the public methods of its impl blocks and all the
+/// methods of its trait impls. The `const fn`s are left alone.
+#[proc_macro_attribute]
+pub fn instrument_module(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let tag = match tag_expr(attr) {
+        Ok(tag) => tag,
+        Err(error) => return error,
+    };
+    let mut tokens: Vec<TokenTree> = item.into_iter().collect();
+    let inline_module = match tokens.split_last() {
+        Some((TokenTree::Group(body), head)) => body.delimiter() == Delimiter::Brace && is_keyword(head, "mod"),
+        _ => false,
+    };
+    if !inline_module {
+        return error("#[instrument_module] expects an inline module: `mod name { ... }`");
+    }
+    if let Some(TokenTree::Group(body)) = tokens.pop() {
+        tokens.push(TokenTree::Group(regroup(&body, instrument_items(body.stream(), &tag, false))));
+    }
+    tokens.into_iter().collect()
+}
+
+/// Returns the expression of the tag: the string literal given as argument,
+/// or the path of the module
+fn tag_expr(attr: TokenStream) -> Result<TokenStream, TokenStream> {
+    let tokens: Vec<TokenTree> = attr.into_iter().collect();
+    match tokens.as_slice() {
+        [] => Ok(parse("module_path!()")),
+        [TokenTree::Literal(literal)] if literal.to_string().starts_with('"') => Ok(tokens.into_iter().collect()),
+        _ => Err(error("#[instrument_module] expects no argument, or a string literal (the tag)")),
+    }
+}
+
+/// Instruments the items of a module (or of an impl block). `all` tells
+/// whether the private functions are instrumented as well.
+fn instrument_items(stream: TokenStream, tag: &TokenStream, all: bool) -> TokenStream {
+    let mut out = Vec::new();
+    let mut item = Vec::new();
+    for token in stream {
+        let last = match &token {
+            TokenTree::Punct(punct) => punct.as_char() == ';',
+            TokenTree::Group(group) => group.delimiter() == Delimiter::Brace,
+            _ => false,
+        };
+        item.push(token);
+        if last {
+            out.extend(instrument_item(std::mem::take(&mut item), tag, all));
+        }
+    }
+    out.extend(item);
+    out.into_iter().collect()
+}
+
+/// Instruments an item (ending with its block, if any)
+fn instrument_item(mut item: Vec<TokenTree>, tag: &TokenStream, all: bool) -> Vec<TokenTree> {
+    let mut i = 0;
+    // the attributes (inner ones included: `#![...]` has no terminator)
+    while is_punct(item.get(i), '#') {
+        i += 1;
+        if is_punct(item.get(i), '!') {
+            i += 1;
+        }
+        if matches!(item.get(i), Some(TokenTree::Group(_))) {
+            i += 1;
+        }
+    }
+    let public = is_ident(item.get(i), "pub");
+    if public {
+        i += 1;
+        if matches!(item.get(i), Some(TokenTree::Group(group)) if group.delimiter() == Delimiter::Parenthesis) {
+            i += 1;
+        }
+    }
+    let (mut asyncness, mut constness) = (false, false);
+    loop {
+        match item.get(i) {
+            Some(TokenTree::Ident(ident)) if ident.to_string() == "async" => asyncness = true,
+            Some(TokenTree::Ident(ident)) if ident.to_string() == "const" => constness = true,
+            Some(TokenTree::Ident(ident)) if ["default", "unsafe", "extern"].contains(&&*ident.to_string()) => {}
+            Some(TokenTree::Literal(_)) if is_ident(item.get(i.wrapping_sub(1)), "extern") => {}
+            _ => break,
+        }
+        i += 1;
+    }
+    let keyword = match item.get(i) {
+        Some(TokenTree::Ident(ident)) => ident.to_string(),
+        _ => return item,
+    };
+    let body = match item.pop() {
+        Some(TokenTree::Group(body)) if body.delimiter() == Delimiter::Brace => body,
+        other => {
+            item.extend(other);
+            return item;
+        }
+    };
+    let body = match keyword.as_str() {
+        "fn" if (public || all) && !constness => regroup(&body, wrap(body.clone(), tag, asyncness)),
+        "impl" => {
+            let trait_impl = item[i..].iter().enumerate().any(|(j, token)| {
+                is_ident(Some(token), "for") && !is_punct(item.get(i + j + 1), '<')
+            });
+            regroup(&body, instrument_items(body.stream(), tag, trait_impl))
+        }
+        "mod" => regroup(&body, instrument_items(body.stream(), tag, false)),
+        _ => body,
+    };
+    item.push(TokenTree::Group(body));
+    item
+}
+
+/// Returns the instrumented body of a function: the tag is entered for the
+/// duration of the call, or of each poll of the future of an async function
+fn wrap(body: Group, tag: &TokenStream, asyncness: bool) -> TokenStream {
+    let mut out = parse(&format!(
+        "static __PEAK_ALLOC_TAG: ::peak_alloc::Tag = ::peak_alloc::Tag::new({});",
+        tag
+    ));
+    if asyncness {
+        let future: TokenStream = vec![
+            TokenTree::Ident(Ident::new("async", Span::call_site())),
+            TokenTree::Ident(Ident::new("move", Span::call_site())),
+            TokenTree::Group(body),
+        ]
+        .into_iter()
+        .collect();
+        out.extend(parse("__PEAK_ALLOC_TAG.instrument"));
+        out.extend(Some(TokenTree::Group(Group::new(Delimiter::Parenthesis, future))));
+        out.extend(vec![
+            TokenTree::Punct(Punct::new('.', Spacing::Alone)),
+            TokenTree::Ident(Ident::new("await", Span::call_site())),
+        ]);
+    } else {
+        out.extend(parse("let __peak_alloc_tag = __PEAK_ALLOC_TAG.enter();"));
+        out.extend(Some(TokenTree::Group(body)));
+    }
+    out
+}
+
+/// Returns a brace group with the span of `group` and the given content
+fn regroup(group: &Group, stream: TokenStream) -> Group {
+    let mut regrouped = Group::new(Delimiter::Brace, stream);
+    regrouped.set_span(group.span());
+    regrouped
+}
+
+/// Returns true iff one of the given tokens is the given keyword
+fn is_keyword(tokens: &[TokenTree], keyword: &str) -> bool {
+    tokens.iter().any(|token| is_ident(Some(token), keyword))
+}
+
+fn is_ident(token: Option<&TokenTree>, name: &str) -> bool {
+    matches!(token, Some(TokenTree::Ident(ident)) if ident.to_string() == name)
+}
+
+fn is_punct(token: Option<&TokenTree>, ch: char) -> bool {
+    matches!(token, Some(TokenTree::Punct(punct)) if punct.as_char() == ch)
+}
+
+fn parse(code: &str) -> TokenStream {
+    code.parse().expect("the generated code is valid")
+}
+
+fn error(message: &str) -> TokenStream {
+    parse(&format!("compile_error!({:?});", message))
+}
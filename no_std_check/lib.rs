@@ -0,0 +1,63 @@
+//! A `no_std` crate using `peak_alloc` (without its `std` feature) on top
+//! of a toy bump allocator. It only exists to check that this configuration
+//! keeps building.
+#![no_std]
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use peak_alloc::{PeakAlloc, TrackingAlloc};
+
+const ARENA_SIZE: usize = 64 * 1024;
+
+/// A bump allocator which never reuses memory
+pub struct Bump {
+    arena: UnsafeCell<[u8; ARENA_SIZE]>,
+    next: AtomicUsize,
+}
+
+unsafe impl Sync for Bump {}
+
+impl Bump {
+    pub const fn new() -> Self {
+        Bump {
+            arena: UnsafeCell::new([0; ARENA_SIZE]),
+            next: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl Default for Bump {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl GlobalAlloc for Bump {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let base = self.arena.get() as usize;
+        let mut next = self.next.load(Ordering::Relaxed);
+        loop {
+            let start = (base + next + layout.align() - 1) & !(layout.align() - 1);
+            let end = start - base + layout.size();
+            if end > ARENA_SIZE {
+                return core::ptr::null_mut();
+            }
+            match self.next.compare_exchange_weak(next, end, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => return start as *mut u8,
+                Err(current) => next = current,
+            }
+        }
+    }
+
+    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {}
+}
+
+#[global_allocator]
+static ALLOC: TrackingAlloc<Bump> = TrackingAlloc::new(Bump::new());
+
+/// Returns the current and peak usage, without any floating point operation
+pub fn usage() -> (usize, usize) {
+    (PeakAlloc.current_usage(), PeakAlloc.peak_usage())
+}
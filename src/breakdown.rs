@@ -0,0 +1,303 @@
+//! Comparing the memory attributed along one dimension (the tags, or the
+//! threads) side by side, in a table (see `PeakAlloc::report_breakdown`).
+//!
+//! Each dimension is backed by a feature: when it is not compiled in, the
+//! report is empty and says which feature it takes, so that a program can
+//! print it regardless of its features.
+
+use core::fmt::{self, Write};
+
+use crate::delta::{spaces, Counter, Thousands};
+use crate::ByteSize;
+
+/// What the memory is broken down by
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Dimension {
+    /// The tags (see `Tag` and `#[instrument_module]`), with the
+    /// `module-tags` feature
+    Tags,
+    /// The live threads, with the `per-thread` feature
+    Threads,
+}
+
+impl Dimension {
+    /// The header of the first column
+    fn label(self) -> &'static str {
+        match self {
+            Dimension::Tags => "tag",
+            Dimension::Threads => "thread",
+        }
+    }
+}
+
+/// The order of the rows of a breakdown
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SortBy {
+    /// By name, in alphabetical order
+    Name,
+    /// The largest live (net) number of bytes first
+    LiveBytes,
+    /// The largest peak first
+    PeakBytes,
+    /// The largest number of allocations first
+    Allocations,
+}
+
+/// A row of a breakdown: a tag, a thread, or the aggregate of several
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BreakdownRow {
+    /// The name of the tag or of the thread
+    pub name: String,
+    /// The bytes allocated minus the bytes released (which is negative when
+    /// more memory was released than allocated)
+    pub live_bytes: isize,
+    /// The highest live number of bytes so far (for an aggregate, the sum of
+    /// the peaks, which need not have been reached at once)
+    pub peak_bytes: usize,
+    /// The number of allocations performed
+    pub allocations: usize,
+}
+
+impl BreakdownRow {
+    /// Creates a row
+    pub fn new(name: impl Into<String>, live_bytes: isize, peak_bytes: usize, allocations: usize) -> Self {
+        BreakdownRow { name: name.into(), live_bytes, peak_bytes, allocations }
+    }
+    /// Adds the figures of `other` to those of this row
+    fn add(&mut self, other: &BreakdownRow) {
+        self.live_bytes = self.live_bytes.saturating_add(other.live_bytes);
+        self.peak_bytes = self.peak_bytes.saturating_add(other.peak_bytes);
+        self.allocations = self.allocations.saturating_add(other.allocations);
+    }
+}
+
+/// The memory broken down along a dimension (see `PeakAlloc::report_breakdown`).
+/// Its `Display` implementation renders an aligned table, with a row for the
+/// entries beyond the limit (if any) and a row for the totals:
+///
+/// ```text
+/// tag            live       peak  allocs  % of total
+/// parser      1.5 MiB    2.0 MiB   1 234       75.0%
+/// lexer     512.0 KiB  512.0 KiB      17       25.0%
+/// (1 more)        0 B   64.0 KiB       3        0.0%
+/// total       2.0 MiB    2.6 MiB   1 254      100.0%
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BreakdownReport {
+    /// What the memory is broken down by
+    pub dimension: Dimension,
+    /// The rows, in the requested order, up to the limit
+    pub rows: Vec<BreakdownRow>,
+    /// The aggregate of the entries beyond the limit, if any
+    pub others: Option<BreakdownRow>,
+    /// The aggregate of all the entries
+    pub total: BreakdownRow,
+    /// Why the report is empty, when the dimension is not compiled in
+    pub note: Option<&'static str>,
+}
+
+impl BreakdownReport {
+    /// Sorts the given entries and keeps the first `limit` ones (the others
+    /// are aggregated)
+    pub fn new(dimension: Dimension, mut entries: Vec<BreakdownRow>, sort: SortBy, limit: usize) -> Self {
+        match sort {
+            SortBy::Name => entries.sort_by(|a, b| a.name.cmp(&b.name)),
+            SortBy::LiveBytes => entries.sort_by(|a, b| b.live_bytes.cmp(&a.live_bytes).then(a.name.cmp(&b.name))),
+            SortBy::PeakBytes => entries.sort_by(|a, b| b.peak_bytes.cmp(&a.peak_bytes).then(a.name.cmp(&b.name))),
+            SortBy::Allocations => {
+                entries.sort_by(|a, b| b.allocations.cmp(&a.allocations).then(a.name.cmp(&b.name)))
+            }
+        }
+        let mut total = BreakdownRow::new("total", 0, 0, 0);
+        entries.iter().for_each(|entry| total.add(entry));
+        let others = (entries.len() > limit).then(|| {
+            let omitted = &entries[limit..];
+            let mut others = BreakdownRow::new(format!("({} more)", omitted.len()), 0, 0, 0);
+            omitted.iter().for_each(|entry| others.add(entry));
+            others
+        });
+        entries.truncate(limit);
+        BreakdownReport { dimension, rows: entries, others, total, note: None }
+    }
+    /// Returns this report with a note saying why it is empty
+    #[cfg_attr(all(feature = "module-tags", feature = "per-thread"), allow(dead_code))]
+    pub(crate) fn with_note(mut self, note: &'static str) -> Self {
+        self.note = Some(note);
+        self
+    }
+    /// Returns the rows to render: the listed ones, the others and the total
+    fn lines(&self) -> impl Iterator<Item = &BreakdownRow> {
+        self.rows.iter().chain(self.others.as_ref()).chain(Some(&self.total))
+    }
+    /// Writes the cell of `row` in the given column
+    fn cell<W: Write + ?Sized>(&self, out: &mut W, row: &BreakdownRow, column: usize) -> fmt::Result {
+        match column {
+            0 => write_name(out, &row.name),
+            1 => write_live(out, row.live_bytes),
+            2 => write!(out, "{:.1}", ByteSize(row.peak_bytes)),
+            3 => write!(out, "{}", Thousands(row.allocations)),
+            _ if self.total.live_bytes > 0 => {
+                write!(out, "{:.1}%", 100.0 * row.live_bytes as f64 / self.total.live_bytes as f64)
+            }
+            _ => out.write_char('-'),
+        }
+    }
+}
+
+/// The names longer than this (in characters) are truncated
+pub(crate) const MAX_NAME_WIDTH: usize = 32;
+
+/// The headers of the columns, but the first (the dimension)
+const HEADERS: [&str; 4] = ["live", "peak", "allocs", "% of total"];
+
+impl fmt::Display for BreakdownReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let header = |column: usize| if column == 0 { self.dimension.label() } else { HEADERS[column - 1] };
+        let mut widths = [0; HEADERS.len() + 1];
+        for (column, width) in widths.iter_mut().enumerate() {
+            *width = header(column).chars().count();
+            for row in self.lines() {
+                let mut counter = Counter(0);
+                self.cell(&mut counter, row, column)?;
+                *width = (*width).max(counter.0);
+            }
+        }
+        for (column, width) in widths.iter().enumerate() {
+            let header = header(column);
+            align(f, column, width - header.chars().count(), |f| f.write_str(header))?;
+        }
+        f.write_char('\n')?;
+        if let Some(note) = self.note {
+            return writeln!(f, "({})", note);
+        }
+        for row in self.lines() {
+            for (column, width) in widths.iter().enumerate() {
+                let mut counter = Counter(0);
+                self.cell(&mut counter, row, column)?;
+                align(f, column, width - counter.0, |f| self.cell(f, row, column))?;
+            }
+            f.write_char('\n')?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes a cell padded with `padding` spaces, after the space between two
+/// columns: the names are left aligned, the figures are right aligned.
+fn align<W, F>(out: &mut W, column: usize, padding: usize, write: F) -> fmt::Result
+where
+    W: Write + ?Sized,
+    F: FnOnce(&mut W) -> fmt::Result,
+{
+    if column == 0 {
+        write(out)?;
+        spaces(out, padding)
+    } else {
+        spaces(out, 2 + padding)?;
+        write(out)
+    }
+}
+
+/// Writes a name, truncated with an ellipsis beyond `MAX_NAME_WIDTH` characters
+fn write_name<W: Write + ?Sized>(out: &mut W, name: &str) -> fmt::Result {
+    match name.char_indices().nth(MAX_NAME_WIDTH) {
+        None => out.write_str(name),
+        Some(_) => {
+            let (cut, _) = name.char_indices().nth(MAX_NAME_WIDTH - 1).unwrap_or((name.len(), ' '));
+            out.write_str(&name[..cut])?;
+            out.write_char('…')
+        }
+    }
+}
+
+/// Writes a live number of bytes (with a sign only when it is negative)
+fn write_live<W: Write + ?Sized>(out: &mut W, bytes: isize) -> fmt::Result {
+    if bytes < 0 {
+        out.write_char('-')?;
+    }
+    write!(out, "{:.1}", ByteSize(bytes.unsigned_abs()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BreakdownReport, BreakdownRow, Dimension, SortBy};
+
+    const KIB: usize = 1024;
+
+    fn tags() -> Vec<BreakdownRow> {
+        vec![
+            BreakdownRow::new("lexer", 512 * KIB as isize, 512 * KIB, 17),
+            BreakdownRow::new("parser", 1536 * KIB as isize, 2048 * KIB, 1234),
+            BreakdownRow::new("codegen", -(2 * KIB as isize), 64 * KIB, 3),
+        ]
+    }
+
+    #[test]
+    fn tags_are_compared_side_by_side() {
+        let report = BreakdownReport::new(Dimension::Tags, tags(), SortBy::LiveBytes, 10);
+        assert_eq!(
+            "tag           live       peak  allocs  % of total\n\
+             parser     1.5 MiB    2.0 MiB   1 234       75.1%\n\
+             lexer    512.0 KiB  512.0 KiB      17       25.0%\n\
+             codegen   -2.0 KiB   64.0 KiB       3       -0.1%\n\
+             total      2.0 MiB    2.6 MiB   1 254      100.0%\n",
+            report.to_string()
+        );
+        let names = |sort| {
+            let report = BreakdownReport::new(Dimension::Tags, tags(), sort, 10);
+            report.rows.into_iter().map(|row| row.name).collect::<Vec<_>>()
+        };
+        assert_eq!(vec!["codegen", "lexer", "parser"], names(SortBy::Name));
+        assert_eq!(vec!["parser", "lexer", "codegen"], names(SortBy::PeakBytes));
+        assert_eq!(vec!["parser", "lexer", "codegen"], names(SortBy::Allocations));
+    }
+
+    #[test]
+    fn threads_beyond_the_limit_are_aggregated() {
+        let threads = (1..=5).map(|i| BreakdownRow::new(format!("ThreadId({})", i), i * 100, i as usize * 200, 1));
+        let report = BreakdownReport::new(Dimension::Threads, threads.collect(), SortBy::LiveBytes, 2);
+        assert_eq!(
+            "thread          live     peak  allocs  % of total\n\
+             ThreadId(5)    500 B   1000 B       1       33.3%\n\
+             ThreadId(4)    400 B    800 B       1       26.7%\n\
+             (3 more)       600 B  1.2 KiB       3       40.0%\n\
+             total        1.5 KiB  2.9 KiB       5      100.0%\n",
+            report.to_string()
+        );
+        assert_eq!(2, report.rows.len());
+    }
+
+    #[test]
+    fn long_names_are_truncated() {
+        let name = "peak_alloc::tests::instrumented::parser::recursive_descent";
+        let report = BreakdownReport::new(Dimension::Tags, vec![BreakdownRow::new(name, 0, 0, 0)], SortBy::Name, 1);
+        assert_eq!(
+            "tag                               live  peak  allocs  % of total\n\
+             peak_alloc::tests::instrumented…   0 B   0 B       0           -\n\
+             total                              0 B   0 B       0           -\n",
+            report.to_string()
+        );
+        let exact = "x".repeat(super::MAX_NAME_WIDTH);
+        let report = BreakdownReport::new(Dimension::Tags, vec![BreakdownRow::new(&*exact, 0, 0, 0)], SortBy::Name, 1);
+        assert!(report.to_string().contains(&format!("\n{}  ", exact)));
+    }
+
+    #[test]
+    fn missing_dimensions_are_explained() {
+        let report = BreakdownReport::new(Dimension::Threads, Vec::new(), SortBy::Name, 10)
+            .with_note("needs the `per-thread` feature");
+        assert_eq!(
+            "thread  live  peak  allocs  % of total\n\
+             (needs the `per-thread` feature)\n",
+            report.to_string()
+        );
+        assert!(report.rows.is_empty());
+        let empty = BreakdownReport::new(Dimension::Tags, Vec::new(), SortBy::Name, 10);
+        assert_eq!(
+            "tag    live  peak  allocs  % of total\n\
+             total   0 B   0 B       0           -\n",
+            empty.to_string()
+        );
+    }
+}
@@ -0,0 +1,235 @@
+//! Application level quotas: a `MemoryBudget` caps the memory of logical
+//! objects (e.g. the buffers of a connection) which the application measures
+//! by itself. Budgets can be nested, a child charging against its parent,
+//! and their charges can be linked to the global picture (see
+//! `PeakAlloc::external_usage`, which the application can also feed
+//! directly with `PeakAlloc::record_external_alloc`).
+
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// The memory charged to all the linked budgets
+static EXTERNAL: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the memory charged to all the linked budgets
+pub(crate) fn external_usage() -> usize {
+    EXTERNAL.load(Ordering::Relaxed)
+}
+
+/// Accounts for `bytes` more external memory, outside of any budget
+pub(crate) fn add_external(bytes: usize) {
+    EXTERNAL.fetch_add(bytes, Ordering::Relaxed);
+}
+
+/// Accounts for `bytes` less external memory, outside of any budget
+pub(crate) fn sub_external(bytes: usize) {
+    EXTERNAL.fetch_sub(bytes, Ordering::Relaxed);
+}
+
+/// Sets the external memory (see `PeakAlloc::import_state`)
+pub(crate) fn set_external(bytes: usize) {
+    EXTERNAL.store(bytes, Ordering::Relaxed);
+}
+
+/// A quota of memory. Charging it is a single compare-and-swap (per level
+/// of the hierarchy), and it can be shared among threads.
+///
+/// ```
+/// use peak_alloc::MemoryBudget;
+///
+/// let server = MemoryBudget::new(1024);
+/// let connection = MemoryBudget::with_parent(512, &server);
+///
+/// let buffer = connection.try_charge(400).unwrap();
+/// assert_eq!(624, server.remaining());
+/// // the connection has 112 bytes left
+/// assert!(connection.try_charge(200).is_err());
+/// drop(buffer);
+/// assert_eq!(0, server.used());
+/// ```
+#[derive(Debug)]
+pub struct MemoryBudget<'p> {
+    /// The maximum number of bytes which can be charged at once
+    limit: usize,
+    /// The number of bytes currently charged
+    used: AtomicUsize,
+    /// The budget which is charged as well
+    parent: Option<&'p MemoryBudget<'p>>,
+    /// Whether the charges count in `PeakAlloc::external_usage`
+    linked: bool,
+}
+
+impl MemoryBudget<'static> {
+    /// Creates a budget of `limit` bytes
+    pub const fn new(limit: usize) -> Self {
+        MemoryBudget { limit, used: AtomicUsize::new(0), parent: None, linked: false }
+    }
+}
+
+impl<'p> MemoryBudget<'p> {
+    /// Creates a budget of `limit` bytes whose charges are also charged to
+    /// `parent` (they fail if either budget is exceeded)
+    pub const fn with_parent(limit: usize, parent: &'p MemoryBudget<'p>) -> Self {
+        MemoryBudget { limit, used: AtomicUsize::new(0), parent: Some(parent), linked: false }
+    }
+    /// Makes the charges of this budget count in `PeakAlloc::external_usage`
+    /// (along with its parent's, if that one is linked too)
+    pub const fn linked(mut self) -> Self {
+        self.linked = true;
+        self
+    }
+    /// Charges `bytes` to this budget (and to its ancestors). The charge is
+    /// released when the returned `BudgetCharge` is dropped.
+    pub fn try_charge(&self, bytes: usize) -> Result<BudgetCharge<'_>, BudgetExceeded> {
+        self.charge(bytes)?;
+        Ok(BudgetCharge { budget: self, bytes })
+    }
+    /// Returns the limit of this budget
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+    /// Returns the number of bytes currently charged to this budget
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+    /// Returns the number of bytes which can still be charged to this budget
+    /// (its ancestors may have less)
+    pub fn remaining(&self) -> usize {
+        self.limit.saturating_sub(self.used())
+    }
+
+    /// Charges this budget then its ancestors, undoing it all if any of them
+    /// is exceeded
+    fn charge(&self, bytes: usize) -> Result<(), BudgetExceeded> {
+        self.used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                used.checked_add(bytes).filter(|&total| total <= self.limit)
+            })
+            .map_err(|used| BudgetExceeded { requested: bytes, remaining: self.limit.saturating_sub(used) })?;
+        if let Some(parent) = self.parent {
+            if let Err(exceeded) = parent.charge(bytes) {
+                self.used.fetch_sub(bytes, Ordering::Relaxed);
+                return Err(exceeded);
+            }
+        }
+        if self.linked {
+            EXTERNAL.fetch_add(bytes, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+    /// Releases a charge of this budget and of its ancestors
+    fn uncharge(&self, bytes: usize) {
+        if self.linked {
+            EXTERNAL.fetch_sub(bytes, Ordering::Relaxed);
+        }
+        self.used.fetch_sub(bytes, Ordering::Relaxed);
+        if let Some(parent) = self.parent {
+            parent.uncharge(bytes);
+        }
+    }
+}
+
+/// A charge of a `MemoryBudget`, released when dropped
+#[derive(Debug)]
+#[must_use = "the charge is released as soon as it is dropped"]
+pub struct BudgetCharge<'a> {
+    budget: &'a MemoryBudget<'a>,
+    bytes: usize,
+}
+
+impl BudgetCharge<'_> {
+    /// Returns the number of bytes of this charge
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+}
+
+impl Drop for BudgetCharge<'_> {
+    fn drop(&mut self) {
+        self.budget.uncharge(self.bytes);
+    }
+}
+
+/// The error returned when a charge would exceed a budget (or one of its
+/// ancestors)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BudgetExceeded {
+    /// The number of bytes which could not be charged
+    pub requested: usize,
+    /// The number of bytes which remained in the exceeded budget
+    pub remaining: usize,
+}
+
+impl fmt::Display for BudgetExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "memory budget exceeded: {} bytes requested, {} remaining", self.requested, self.remaining)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for BudgetExceeded {}
+
+#[cfg(test)]
+mod tests {
+    use super::{BudgetExceeded, MemoryBudget};
+
+    #[test]
+    fn charges_are_released_when_dropped() {
+        let budget = MemoryBudget::new(100);
+        let first = budget.try_charge(60).unwrap();
+        assert_eq!(60, budget.used());
+        assert_eq!(40, budget.remaining());
+        assert_eq!(Err(BudgetExceeded { requested: 50, remaining: 40 }), budget.try_charge(50).map(|_| ()));
+        let second = budget.try_charge(40).unwrap();
+        assert_eq!(0, budget.remaining());
+        drop(first);
+        drop(second);
+        assert_eq!(0, budget.used());
+        assert!(budget.try_charge(usize::MAX).is_err());
+        assert_eq!(
+            "memory budget exceeded: 50 bytes requested, 40 remaining",
+            BudgetExceeded { requested: 50, remaining: 40 }.to_string()
+        );
+    }
+
+    #[test]
+    fn children_charge_their_parent() {
+        let parent = MemoryBudget::new(100);
+        let left = MemoryBudget::with_parent(80, &parent);
+        let right = MemoryBudget::with_parent(80, &parent);
+        let charge = left.try_charge(70).unwrap();
+        assert_eq!(70, parent.used());
+        // right has room, but the parent has not
+        assert_eq!(Err(BudgetExceeded { requested: 40, remaining: 30 }), right.try_charge(40).map(|_| ()));
+        assert_eq!(0, right.used());
+        let small = right.try_charge(30).unwrap();
+        assert_eq!(0, parent.remaining());
+        drop(charge);
+        drop(small);
+        assert_eq!((0, 0, 0), (parent.used(), left.used(), right.used()));
+    }
+
+    #[test]
+    fn concurrent_charges_never_exceed_the_limit() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        let parent = MemoryBudget::new(1000);
+        let child = MemoryBudget::with_parent(10_000, &parent);
+        // at most 3 charges of 300 bytes fit in the parent at once
+        let (held, max_held) = (AtomicUsize::new(0), AtomicUsize::new(0));
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    for _ in 0..10_000 {
+                        if let Ok(charge) = child.try_charge(300) {
+                            max_held.fetch_max(held.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+                            held.fetch_sub(1, Ordering::SeqCst);
+                            drop(charge);
+                        }
+                    }
+                });
+            }
+        });
+        assert!(max_held.load(Ordering::SeqCst) <= 3);
+        assert_eq!((0, 0), (parent.used(), child.used()));
+    }
+}
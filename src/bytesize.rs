@@ -0,0 +1,78 @@
+//! A number of bytes, with a human-readable `Display`.
+
+use core::fmt;
+
+use crate::units;
+
+/// A number of bytes. It is mostly a convenience to express configuration
+/// values (`ByteSize::mib(64)`) and to display byte counts in a
+/// human-readable way (`"64.00 MiB"`).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ByteSize(pub usize);
+
+impl ByteSize {
+    /// A size of `n` bytes
+    pub const fn b(n: usize) -> Self {
+        ByteSize(n)
+    }
+    /// A size of `n` kibibytes (1024 bytes)
+    pub const fn kib(n: usize) -> Self {
+        ByteSize(units::kib(n))
+    }
+    /// A size of `n` mebibytes (1024 kibibytes)
+    pub const fn mib(n: usize) -> Self {
+        ByteSize(units::mib(n))
+    }
+    /// A size of `n` gibibytes (1024 mebibytes)
+    pub const fn gib(n: usize) -> Self {
+        ByteSize(units::gib(n))
+    }
+    /// Returns the number of bytes
+    pub const fn bytes(self) -> usize {
+        self.0
+    }
+}
+
+impl From<usize> for ByteSize {
+    fn from(bytes: usize) -> Self {
+        ByteSize(bytes)
+    }
+}
+impl From<ByteSize> for usize {
+    fn from(size: ByteSize) -> Self {
+        size.0
+    }
+}
+
+impl fmt::Display for ByteSize {
+    /// Displays the size using the largest binary unit in which it is at
+    /// least one (e.g. `"1.50 KiB"`) with two decimals, unless a precision
+    /// is given (`{:.1}`). Plain bytes are displayed as integers.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        crate::fmt::write_bytes_with_precision(f, self.0, f.precision().unwrap_or(2))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ByteSize;
+
+    #[test]
+    fn bytesize_is_displayed_in_the_largest_unit() {
+        assert_eq!("0 B", ByteSize(0).to_string());
+        assert_eq!("1023 B", ByteSize(1023).to_string());
+        assert_eq!("1.00 KiB", ByteSize::kib(1).to_string());
+        assert_eq!("1.50 MiB", ByteSize(3 * 512 * 1024).to_string());
+        assert_eq!("2.00 GiB", ByteSize::gib(2).to_string());
+        if cfg!(target_pointer_width = "64") {
+            assert_eq!("16.00 EiB", ByteSize(usize::MAX).to_string());
+        }
+    }
+
+    #[test]
+    fn bytesize_honours_the_precision() {
+        assert_eq!("1.5 MiB", format!("{:.1}", ByteSize(3 * 512 * 1024)));
+        assert_eq!("2 KiB", format!("{:.0}", ByteSize::kib(2)));
+        assert_eq!("100 B", format!("{:.1}", ByteSize(100)));
+    }
+}
@@ -0,0 +1,119 @@
+//! Text rendering of the usage history: single-line sparklines and
+//! multi-line block charts.
+
+use std::fmt::Write;
+use std::time::Duration;
+
+use crate::history::downsample;
+use crate::ByteSize;
+
+/// The eighth blocks, from the lowest to the full one
+const BLOCKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// Scales the usages between 1 and `levels` (inclusive), the minimum usage
+/// mapping to 1 and the maximum one to `levels`. A flat line is drawn at
+/// mid-height.
+fn scale(points: &[(Duration, usize)], levels: usize) -> Vec<usize> {
+    let min = points.iter().map(|(_, u)| *u).min().unwrap_or(0);
+    let max = points.iter().map(|(_, u)| *u).max().unwrap_or(0);
+    points
+        .iter()
+        .map(|(_, usage)| {
+            if max == min {
+                levels.div_ceil(2)
+            } else {
+                1 + ((usage - min) as u128 * (levels - 1) as u128 / (max - min) as u128) as usize
+            }
+        })
+        .collect()
+}
+
+/// Renders the samples as a sparkline of (at most) `width` characters
+pub(crate) fn sparkline(samples: &[(Duration, usize)], width: usize) -> String {
+    let points = downsample(samples, width);
+    scale(&points, BLOCKS.len())
+        .into_iter()
+        .map(|level| BLOCKS[level - 1])
+        .collect()
+}
+
+/// Renders the samples as a block chart of (at most) `width` columns and
+/// `height` rows, with the maximum and minimum usages labelled on the left.
+pub(crate) fn chart(samples: &[(Duration, usize)], width: usize, height: usize) -> String {
+    let points = downsample(samples, width);
+    if points.is_empty() || height == 0 {
+        return String::new();
+    }
+    let levels = scale(&points, height * BLOCKS.len());
+    let max = ByteSize(points.iter().map(|(_, u)| *u).max().unwrap_or(0)).to_string();
+    let min = ByteSize(points.iter().map(|(_, u)| *u).min().unwrap_or(0)).to_string();
+    let label_width = max.len().max(min.len());
+
+    let mut out = String::new();
+    for row in 0..height {
+        let (label, axis) = match row {
+            0 => (max.as_str(), '┤'),
+            r if r == height - 1 => (min.as_str(), '┤'),
+            _ => ("", '│'),
+        };
+        let _ = write!(out, "{:>width$} {}", label, axis, width = label_width);
+        // the number of eighths below the bottom of this row
+        let floor = (height - 1 - row) * BLOCKS.len();
+        for level in levels.iter() {
+            let cell = if *level >= floor + BLOCKS.len() {
+                '█'
+            } else if *level > floor {
+                BLOCKS[level - floor - 1]
+            } else {
+                ' '
+            };
+            out.push(cell);
+        }
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{chart, sparkline};
+    use std::time::Duration;
+
+    fn samples(usages: &[usize]) -> Vec<(Duration, usize)> {
+        usages
+            .iter()
+            .enumerate()
+            .map(|(i, u)| (Duration::from_secs(i as u64), *u))
+            .collect()
+    }
+
+    #[test]
+    fn sparkline_snapshots() {
+        assert_eq!("▁▂▃▄▅▆▇█", sparkline(&samples(&[0, 1, 2, 3, 4, 5, 6, 7]), 8));
+        assert_eq!("▁█▁", sparkline(&samples(&[10, 20, 10]), 60));
+        // downsampling keeps the max of each bucket
+        assert_eq!("▁█", sparkline(&samples(&[0, 1, 2, 9, 3, 4]), 2));
+        // degenerate cases
+        assert_eq!("▄▄▄", sparkline(&samples(&[5, 5, 5]), 10));
+        assert_eq!("▄", sparkline(&samples(&[42]), 10));
+        assert_eq!("", sparkline(&[], 10));
+        assert_eq!("", sparkline(&samples(&[1, 2]), 0));
+    }
+
+    #[test]
+    fn chart_snapshots() {
+        let expected = "\
+2.00 KiB ┤   █
+         │  ██
+   512 B ┤▁███
+";
+        assert_eq!(expected, chart(&samples(&[512, 1024, 1536, 2048]), 4, 3));
+
+        let expected = "\
+10 B ┤▄▄
+";
+        assert_eq!(expected, chart(&samples(&[10, 10]), 10, 1));
+        assert_eq!("", chart(&[], 10, 3));
+        assert_eq!("", chart(&samples(&[1]), 10, 0));
+    }
+}
@@ -0,0 +1,50 @@
+//! A cheap (and coarse) monotonic clock which is suitable for use on the
+//! allocation path.
+//!
+//! On Linux, this clock reads `CLOCK_MONOTONIC_COARSE` which costs a few
+//! nanoseconds but only has the resolution of a scheduler tick (typically a
+//! few milliseconds). Elsewhere, it falls back to `std::time::Instant`, except
+//! on wasm32-unknown-unknown where there is no clock at all (hence all the
+//! ages are reported as zero).
+
+/// Returns the number of nanoseconds elapsed since an arbitrary (but fixed)
+/// point in the past.
+#[cfg(target_os = "linux")]
+#[inline]
+pub(crate) fn now() -> u64 {
+    use std::os::raw::{c_int, c_long};
+
+    #[repr(C)]
+    struct Timespec {
+        tv_sec: c_long,
+        tv_nsec: c_long,
+    }
+    extern "C" {
+        fn clock_gettime(clock: c_int, tp: *mut Timespec) -> c_int;
+    }
+    const CLOCK_MONOTONIC_COARSE: c_int = 6;
+
+    let mut ts = Timespec { tv_sec: 0, tv_nsec: 0 };
+    // this cannot fail: the clock id and the pointer are both valid
+    unsafe { clock_gettime(CLOCK_MONOTONIC_COARSE, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+/// Returns the number of nanoseconds elapsed since an arbitrary (but fixed)
+/// point in the past.
+#[cfg(not(any(target_os = "linux", all(target_arch = "wasm32", target_os = "unknown"))))]
+#[inline]
+pub(crate) fn now() -> u64 {
+    use std::sync::OnceLock;
+    use std::time::Instant;
+
+    static START: OnceLock<Instant> = OnceLock::new();
+    START.get_or_init(Instant::now).elapsed().as_nanos() as u64
+}
+
+/// There is no clock on wasm32-unknown-unknown (`Instant::now` panics there)
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+#[inline]
+pub(crate) fn now() -> u64 {
+    0
+}
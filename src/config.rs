@@ -0,0 +1,390 @@
+//! The settings of the allocator as a whole (see `PeakAlloc::configure`).
+//!
+//! Each setting still has its own setter: a `Config` is a way to validate
+//! them together (e.g. the watch threshold, a soft limit, must not be above
+//! the memory limit) and to apply them in one go, rather than through a
+//! sequence of calls whose order matters.
+//!
+//! The sizes read by the allocation path (the memory limit, the minimum
+//! tracked size, the large and the watch thresholds) are published along
+//! with a sequence number, which is odd while they are being written: each
+//! allocation reads them once, as a `Limits` snapshot, and hence decides on
+//! all of them with the same configuration.
+
+use core::fmt;
+use core::sync::atomic::{fence, AtomicBool, AtomicUsize, Ordering};
+
+use crate::entry_points::{self, EntryPoint};
+use crate::{large, overalign, sampling, ResetPolicy, MEMORY_LIMIT, MIN_TRACKED_SIZE, RESET_TO_ZERO};
+
+/// Held while a configuration (or a single setting) is applied or read, so
+/// that none is ever seen half applied (see `PeakAlloc::configure`)
+static BUSY: AtomicBool = AtomicBool::new(false);
+/// The sequence number of the `Limits`: odd while they are being written
+static SEQUENCE: AtomicUsize = AtomicUsize::new(0);
+
+/// The settings read by the allocation path, as they were at one instant
+/// (see `limits`). The sizes are 0 when the setting is disabled.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Limits {
+    /// The memory limit (see `PeakAlloc::set_memory_limit`)
+    pub(crate) memory_limit: usize,
+    /// The size below which the allocations are not accounted for
+    pub(crate) min_tracked_size: usize,
+    /// The size from which an allocation is large
+    pub(crate) large_threshold: usize,
+    /// The usage above which an `Event::ThresholdCrossed` is emitted
+    #[cfg(feature = "std")]
+    pub(crate) watch_threshold: usize,
+}
+
+/// The settings of the allocator. The default is the configuration of a
+/// fresh allocator:
+///
+/// ```
+/// use peak_alloc::{Config, PeakAlloc};
+///
+/// #[global_allocator]
+/// static PEAK_ALLOC: PeakAlloc = PeakAlloc;
+///
+/// let config = Config::default().memory_limit(Some(1 << 30)).large_threshold(Some(1 << 20));
+/// PEAK_ALLOC.configure(config).unwrap();
+/// assert_eq!(Some(1 << 30), PEAK_ALLOC.memory_limit());
+/// assert_eq!(config, PEAK_ALLOC.current_config());
+/// # PEAK_ALLOC.configure(Config::default()).unwrap();
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    /// The memory limit, in bytes (none by default, see
+    /// `PeakAlloc::set_memory_limit`)
+    pub memory_limit: Option<usize>,
+    /// The soft limit: the usage above which an `Event::ThresholdCrossed` is
+    /// emitted (none by default, see `PeakAlloc::set_watch_threshold`)
+    #[cfg(feature = "std")]
+    pub watch_threshold: Option<usize>,
+    /// The size from which an allocation is large (none by default, see
+    /// `PeakAlloc::set_large_threshold`)
+    pub large_threshold: Option<usize>,
+    /// The alignment above which an allocation is over-aligned (16 by
+    /// default, see `PeakAlloc::set_overalign_threshold`)
+    pub overalign_threshold: usize,
+    /// The size below which the allocations are not accounted for (0 by
+    /// default, see `PeakAlloc::set_min_tracked_size`)
+    pub min_tracked_size: usize,
+    /// The sample rate of the costly diagnostics (1 by default, see
+    /// `PeakAlloc::set_sample_rate`)
+    pub sample_rate: usize,
+    /// What `reset_peak_usage` resets the peak usage to (see
+    /// `PeakAlloc::set_reset_policy`)
+    pub reset_policy: ResetPolicy,
+    /// Whether the usage is charged with the usable sizes of the blocks
+    /// (false by default, see `PeakAlloc::set_usable_size_accounting`)
+    #[cfg(feature = "actual-size")]
+    pub usable_size_accounting: bool,
+    /// The maximum number of bytes held in quarantine (1 MB by default, see
+    /// `PeakAlloc::set_quarantine_capacity`)
+    #[cfg(feature = "quarantine")]
+    pub quarantine_capacity: usize,
+    /// The bits of the tracked entry points (see `entry_point_tracked`)
+    entry_points: u8,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            memory_limit: None,
+            #[cfg(feature = "std")]
+            watch_threshold: None,
+            large_threshold: None,
+            overalign_threshold: 16,
+            min_tracked_size: 0,
+            sample_rate: 1,
+            reset_policy: ResetPolicy::ToCurrent,
+            #[cfg(feature = "actual-size")]
+            usable_size_accounting: false,
+            #[cfg(feature = "quarantine")]
+            quarantine_capacity: crate::quarantine::DEFAULT_CAPACITY,
+            entry_points: u8::MAX,
+        }
+    }
+}
+
+impl Config {
+    /// Sets the memory limit (None removes it)
+    pub fn memory_limit(mut self, bytes: Option<usize>) -> Self {
+        self.memory_limit = bytes;
+        self
+    }
+    /// Sets the soft limit (None stops watching the usage)
+    #[cfg(feature = "std")]
+    pub fn watch_threshold(mut self, bytes: Option<usize>) -> Self {
+        self.watch_threshold = bytes;
+        self
+    }
+    /// Sets the size from which an allocation is large (None stops counting
+    /// the large allocations)
+    pub fn large_threshold(mut self, bytes: Option<usize>) -> Self {
+        self.large_threshold = bytes;
+        self
+    }
+    /// Sets the alignment above which an allocation is over-aligned
+    pub fn overalign_threshold(mut self, align: usize) -> Self {
+        self.overalign_threshold = align;
+        self
+    }
+    /// Sets the size below which the allocations are not accounted for
+    pub fn min_tracked_size(mut self, bytes: usize) -> Self {
+        self.min_tracked_size = bytes;
+        self
+    }
+    /// Sets the sample rate of the costly diagnostics
+    pub fn sample_rate(mut self, n: usize) -> Self {
+        self.sample_rate = n;
+        self
+    }
+    /// Sets what `reset_peak_usage` resets the peak usage to
+    pub fn reset_policy(mut self, policy: ResetPolicy) -> Self {
+        self.reset_policy = policy;
+        self
+    }
+    /// Sets whether the usage is charged with the usable sizes of the blocks
+    #[cfg(feature = "actual-size")]
+    pub fn usable_size_accounting(mut self, enabled: bool) -> Self {
+        self.usable_size_accounting = enabled;
+        self
+    }
+    /// Sets the maximum number of bytes held in quarantine
+    #[cfg(feature = "quarantine")]
+    pub fn quarantine_capacity(mut self, bytes: usize) -> Self {
+        self.quarantine_capacity = bytes;
+        self
+    }
+    /// Sets whether the calls to the given entry point are accounted for
+    /// (all of them are by default, see `PeakAlloc::set_entry_point_tracked`)
+    pub fn entry_point_tracked(mut self, entry: EntryPoint, tracked: bool) -> Self {
+        if tracked {
+            self.entry_points |= entry.bit();
+        } else {
+            self.entry_points &= !entry.bit();
+        }
+        self
+    }
+    /// Returns true iff the calls to the given entry point are accounted for
+    pub fn is_entry_point_tracked(&self, entry: EntryPoint) -> bool {
+        self.entry_points & entry.bit() != 0
+    }
+
+    /// Checks that the settings make sense together
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.memory_limit == Some(0) {
+            return Err(ConfigError::ZeroMemoryLimit);
+        }
+        #[cfg(feature = "std")]
+        if let (Some(soft_limit), Some(limit)) = (self.watch_threshold, self.memory_limit) {
+            if soft_limit > limit {
+                return Err(ConfigError::SoftLimitAboveLimit { soft_limit, limit });
+            }
+        }
+        if self.sample_rate == 0 {
+            return Err(ConfigError::ZeroSampleRate);
+        }
+        if self.large_threshold == Some(0) {
+            return Err(ConfigError::ZeroLargeThreshold);
+        }
+        if !self.overalign_threshold.is_power_of_two() {
+            return Err(ConfigError::OveralignThresholdNotPowerOfTwo(self.overalign_threshold));
+        }
+        Ok(())
+    }
+}
+
+/// The reason why a configuration was refused (see `PeakAlloc::configure`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ConfigError {
+    /// The watch threshold (the soft limit) is above the memory limit: it
+    /// would never be crossed
+    SoftLimitAboveLimit {
+        /// The watch threshold, in bytes
+        soft_limit: usize,
+        /// The memory limit, in bytes
+        limit: usize,
+    },
+    /// The memory limit is `Some(0)` (`None` is the absence of limit)
+    ZeroMemoryLimit,
+    /// The large threshold is `Some(0)` (`None` stops counting the large
+    /// allocations)
+    ZeroLargeThreshold,
+    /// The sample rate is 0 (1 samples every allocation)
+    ZeroSampleRate,
+    /// The over-alignment threshold is not a power of two
+    OveralignThresholdNotPowerOfTwo(usize),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::SoftLimitAboveLimit { soft_limit, limit } => {
+                write!(f, "the soft limit ({} bytes) is above the memory limit ({} bytes)", soft_limit, limit)
+            }
+            ConfigError::ZeroMemoryLimit => f.write_str("the memory limit is zero (use None to remove it)"),
+            ConfigError::ZeroLargeThreshold => f.write_str("the large threshold is zero (use None to disable it)"),
+            ConfigError::ZeroSampleRate => f.write_str("the sample rate is zero"),
+            ConfigError::OveralignThresholdNotPowerOfTwo(align) => {
+                write!(f, "the over-alignment threshold ({}) is not a power of two", align)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ConfigError {}
+
+/// Waits until no other configuration is being applied or read, and returns
+/// the guard of `BUSY`
+fn busy() -> Busy {
+    while BUSY.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+        core::hint::spin_loop();
+    }
+    Busy
+}
+
+struct Busy;
+
+impl Drop for Busy {
+    fn drop(&mut self) {
+        BUSY.store(false, Ordering::Release);
+    }
+}
+
+/// Returns the `Limits` in effect. This is a seqlock: the reader retries
+/// until it has loaded them all without a write in between.
+#[inline]
+pub(crate) fn limits() -> Limits {
+    loop {
+        let sequence = SEQUENCE.load(Ordering::Acquire);
+        if sequence & 1 == 0 {
+            let limits = Limits {
+                memory_limit: MEMORY_LIMIT.load(Ordering::Relaxed),
+                min_tracked_size: MIN_TRACKED_SIZE.load(Ordering::Relaxed),
+                large_threshold: large::threshold(),
+                #[cfg(feature = "std")]
+                watch_threshold: crate::WATCH_THRESHOLD.load(Ordering::Relaxed),
+            };
+            fence(Ordering::Acquire);
+            if SEQUENCE.load(Ordering::Relaxed) == sequence {
+                return limits;
+            }
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Writes some of the `Limits` with `write`, which must neither allocate
+/// nor release memory: the allocation path waits until it returns. `BUSY`
+/// must be held.
+fn write_limits(write: impl FnOnce()) {
+    let sequence = SEQUENCE.load(Ordering::Relaxed);
+    SEQUENCE.store(sequence.wrapping_add(1), Ordering::Relaxed);
+    fence(Ordering::Release);
+    write();
+    SEQUENCE.store(sequence.wrapping_add(2), Ordering::Release);
+}
+
+/// Applies a single setting with `set`, once no configuration is being
+/// applied or read (see the individual setters of `PeakAlloc`)
+pub(crate) fn exclusive<T>(set: impl FnOnce() -> T) -> T {
+    let _busy = busy();
+    set()
+}
+
+/// Writes some of the `Limits` with `write`, like `exclusive` (see
+/// `write_limits`)
+pub(crate) fn publish(write: impl FnOnce()) {
+    let _busy = busy();
+    write_limits(write)
+}
+
+/// Validates and applies a configuration (see `PeakAlloc::configure`)
+pub(crate) fn apply(config: &Config) -> Result<(), ConfigError> {
+    config.validate()?;
+    let _busy = busy();
+    write_limits(|| {
+        MEMORY_LIMIT.store(config.memory_limit.unwrap_or(0), Ordering::Relaxed);
+        #[cfg(feature = "std")]
+        crate::WATCH_THRESHOLD.store(config.watch_threshold.unwrap_or(0), Ordering::Relaxed);
+        large::set_threshold(config.large_threshold.unwrap_or(0));
+        MIN_TRACKED_SIZE.store(config.min_tracked_size, Ordering::Relaxed);
+    });
+    overalign::set_threshold(config.overalign_threshold);
+    sampling::set_rate(config.sample_rate);
+    RESET_TO_ZERO.store(config.reset_policy == ResetPolicy::ToZero, Ordering::Relaxed);
+    #[cfg(feature = "actual-size")]
+    crate::usable::set_charge_usable(config.usable_size_accounting);
+    #[cfg(feature = "quarantine")]
+    crate::quarantine::set_capacity(config.quarantine_capacity);
+    entry_points::set_tracked_bits(config.entry_points);
+    Ok(())
+}
+
+/// Returns the settings in effect (see `PeakAlloc::current_config`)
+pub(crate) fn current() -> Config {
+    let _busy = busy();
+    let some = |value: usize| if value == 0 { None } else { Some(value) };
+    Config {
+        memory_limit: some(MEMORY_LIMIT.load(Ordering::Relaxed)),
+        #[cfg(feature = "std")]
+        watch_threshold: some(crate::WATCH_THRESHOLD.load(Ordering::Relaxed)),
+        large_threshold: some(large::threshold()),
+        overalign_threshold: overalign::threshold(),
+        min_tracked_size: MIN_TRACKED_SIZE.load(Ordering::Relaxed),
+        sample_rate: sampling::rate(),
+        reset_policy: crate::PeakAlloc.reset_policy(),
+        #[cfg(feature = "actual-size")]
+        usable_size_accounting: crate::usable::charge_usable(),
+        #[cfg(feature = "quarantine")]
+        quarantine_capacity: crate::quarantine::capacity(),
+        entry_points: entry_points::tracked_bits(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Config, ConfigError};
+    use crate::EntryPoint;
+
+    #[test]
+    fn inconsistent_settings_are_refused() {
+        let limited = Config::default().memory_limit(Some(1000));
+        assert_eq!(Ok(()), limited.validate());
+        #[cfg(feature = "std")]
+        {
+            assert_eq!(Ok(()), limited.watch_threshold(Some(1000)).validate());
+            assert_eq!(
+                Err(ConfigError::SoftLimitAboveLimit { soft_limit: 1001, limit: 1000 }),
+                limited.watch_threshold(Some(1001)).validate()
+            );
+            assert_eq!(Ok(()), Config::default().watch_threshold(Some(1001)).validate());
+        }
+        assert_eq!(Err(ConfigError::ZeroMemoryLimit), Config::default().memory_limit(Some(0)).validate());
+        assert_eq!(Err(ConfigError::ZeroLargeThreshold), Config::default().large_threshold(Some(0)).validate());
+        assert_eq!(Err(ConfigError::ZeroSampleRate), Config::default().sample_rate(0).validate());
+        assert_eq!(
+            Err(ConfigError::OveralignThresholdNotPowerOfTwo(24)),
+            Config::default().overalign_threshold(24).validate()
+        );
+        assert_eq!(
+            "the soft limit (2 bytes) is above the memory limit (1 bytes)",
+            ConfigError::SoftLimitAboveLimit { soft_limit: 2, limit: 1 }.to_string()
+        );
+    }
+
+    #[test]
+    fn entry_points_are_toggled_one_by_one() {
+        let config = Config::default().entry_point_tracked(EntryPoint::Realloc, false);
+        assert!(!config.is_entry_point_tracked(EntryPoint::Realloc));
+        assert!(config.is_entry_point_tracked(EntryPoint::Alloc));
+        let config = config.entry_point_tracked(EntryPoint::Realloc, true);
+        assert_eq!(Config::default(), config);
+    }
+}
@@ -0,0 +1,151 @@
+//! Exponentially decaying averages of the memory usage, in the spirit of the
+//! Unix load averages (1, 5 and 15 minutes by default).
+//!
+//! The averages are updated whenever they are queried, and optionally by a
+//! sampler thread (see `PeakAlloc::start_load_average_sampler`). Since the
+//! samples may be irregularly spaced, the decay is computed from the time
+//! elapsed since the previous sample: `alpha = 1 - exp(-dt / tau)`.
+
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::periodic::Periodic;
+use crate::PeakAlloc;
+
+/// Three exponentially weighted moving averages of the usage
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct LoadAverages {
+    /// The time constants of the three averages (in seconds)
+    taus: [f64; 3],
+    /// The current value of the three averages
+    values: [f64; 3],
+    /// The time of the last sample (None until the first sample)
+    last: Option<Duration>,
+}
+
+impl LoadAverages {
+    pub(crate) const fn new(taus: [Duration; 3]) -> Self {
+        LoadAverages {
+            taus: [taus[0].as_secs_f64(), taus[1].as_secs_f64(), taus[2].as_secs_f64()],
+            values: [0.0; 3],
+            last: None,
+        }
+    }
+    /// Accounts for a sample of the usage taken at time `now`. The first
+    /// sample initializes all averages to its value.
+    pub(crate) fn update(&mut self, now: Duration, usage: usize) {
+        let usage = usage as f64;
+        match self.last {
+            None => self.values = [usage; 3],
+            Some(last) => {
+                let dt = now.saturating_sub(last).as_secs_f64();
+                for (value, tau) in self.values.iter_mut().zip(self.taus.iter()) {
+                    let alpha = if *tau > 0.0 { 1.0 - (-dt / tau).exp() } else { 1.0 };
+                    *value += alpha * (usage - *value);
+                }
+            }
+        }
+        self.last = Some(now);
+    }
+    pub(crate) fn values(&self) -> (f64, f64, f64) {
+        (self.values[0], self.values[1], self.values[2])
+    }
+}
+
+/// The default time constants: 1, 5 and 15 minutes
+const DEFAULT_TAUS: [Duration; 3] = [
+    Duration::from_secs(60),
+    Duration::from_secs(300),
+    Duration::from_secs(900),
+];
+
+static AVERAGES: Mutex<LoadAverages> = Mutex::new(LoadAverages::new(DEFAULT_TAUS));
+static START: Mutex<Option<Instant>> = Mutex::new(None);
+
+fn averages() -> MutexGuard<'static, LoadAverages> {
+    AVERAGES.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Returns the time elapsed since the first sample
+fn elapsed() -> Duration {
+    START
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get_or_insert_with(Instant::now)
+        .elapsed()
+}
+
+/// Samples the current usage and returns the updated averages
+pub(crate) fn sample(alloc: &PeakAlloc) -> (f64, f64, f64) {
+    let now = elapsed();
+    let mut averages = averages();
+    averages.update(now, alloc.current_usage());
+    averages.values()
+}
+
+/// Changes the time constants of the averages (this restarts them)
+pub(crate) fn set_time_constants(taus: [Duration; 3]) {
+    *averages() = LoadAverages::new(taus);
+}
+
+/// The handle to the load average sampler thread. The sampler is stopped
+/// when the handle is dropped.
+pub struct LoadAverageSampler {
+    _thread: Periodic,
+}
+
+/// Spawns the load average sampler thread
+pub(crate) fn start_sampler(alloc: PeakAlloc, interval: Duration) -> LoadAverageSampler {
+    let thread = Periodic::spawn("peak_alloc-loadavg", interval, move || {
+        sample(&alloc);
+    });
+    LoadAverageSampler { _thread: thread }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LoadAverages;
+    use std::time::Duration;
+
+    fn secs(s: u64) -> Duration {
+        Duration::from_secs(s)
+    }
+
+    #[test]
+    fn load_averages_converge_and_decay() {
+        let mut avg = LoadAverages::new([secs(1), secs(10), secs(100)]);
+        avg.update(secs(0), 1000);
+        assert_eq!((1000.0, 1000.0, 1000.0), avg.values());
+
+        // after one time constant, 1 - 1/e of the step has been absorbed
+        avg.update(secs(1), 2000);
+        let (a, b, c) = avg.values();
+        assert!((a - (2000.0 - 1000.0 / std::f64::consts::E)).abs() < 1e-6);
+        assert!(b < a && c < b && c > 1000.0);
+
+        // a long steady plateau makes all averages converge
+        avg.update(secs(10_000), 2000);
+        let (a, b, c) = avg.values();
+        assert!((a - 2000.0).abs() < 1e-6 && (b - 2000.0).abs() < 1e-6 && (c - 2000.0).abs() < 1e-6);
+
+        // then the usage drops: the short average follows faster
+        avg.update(secs(10_005), 0);
+        let (a, b, c) = avg.values();
+        assert!(a < b && b < c && c < 2000.0);
+    }
+
+    #[test]
+    fn decay_depends_on_elapsed_time_not_on_sample_count() {
+        let mut once = LoadAverages::new([secs(10); 3]);
+        once.update(secs(0), 0);
+        once.update(secs(10), 100);
+
+        let mut often = LoadAverages::new([secs(10); 3]);
+        often.update(secs(0), 0);
+        for t in 1..=10 {
+            often.update(secs(t), 100);
+        }
+        // sampling a constant input often or rarely yields the same average
+        assert!((once.values().0 - often.values().0).abs() < 1e-9);
+    }
+}
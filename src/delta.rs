@@ -0,0 +1,277 @@
+//! The difference between two `Stats` (taken at the beginning and at the end
+//! of a phase of the program), and its human-readable rendering. All the
+//! rendering is writer based: nothing is allocated.
+
+use core::fmt::{self, Write};
+
+use crate::{ByteSize, Stats};
+
+/// What happened between two `Stats` (see `Stats::since`)
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct StatsDelta {
+    /// The growth (or shrinkage, if negative) of the usage
+    pub net_bytes: isize,
+    /// The number of bytes allocated (the growth of the reallocated blocks
+    /// included)
+    pub allocated_bytes: usize,
+    /// The number of bytes released (the shrinkage of the reallocated blocks
+    /// included)
+    pub freed_bytes: usize,
+    /// The number of allocations
+    pub allocations: usize,
+    /// The number of deallocations
+    pub deallocations: usize,
+    /// The peak usage at the end of the phase. It is the peak within the
+    /// phase when the peak was reset at its beginning (see
+    /// `PeakAlloc::checkpoint`).
+    pub peak_in_phase: usize,
+}
+
+impl Stats {
+    /// Returns what happened between `start` and these (later) stats
+    pub fn since(&self, start: &Stats) -> StatsDelta {
+        let allocated = self.total_allocated.wrapping_sub(start.total_allocated);
+        StatsDelta {
+            net_bytes: self.current_usage.wrapping_sub(start.current_usage) as isize,
+            allocated_bytes: allocated,
+            freed_bytes: allocated.wrapping_add(start.current_usage).wrapping_sub(self.current_usage),
+            allocations: self.allocation_count.wrapping_sub(start.allocation_count),
+            deallocations: self.deallocation_count.wrapping_sub(start.deallocation_count),
+            peak_in_phase: self.peak_usage,
+        }
+    }
+}
+
+impl StatsDelta {
+    /// Writes a one line summary of the phase, e.g. `net +12.4 MiB (alloc
+    /// 96.1 MiB, freed 83.7 MiB, 41 203 allocs, peak-in-phase 57.2 MiB)`
+    pub fn render<W: Write + ?Sized>(&self, out: &mut W) -> fmt::Result {
+        write!(
+            out,
+            "net {} (alloc {:.1}, freed {:.1}, {} allocs, peak-in-phase {:.1})",
+            Net(self.net_bytes),
+            ByteSize(self.allocated_bytes),
+            ByteSize(self.freed_bytes),
+            Thousands(self.allocations),
+            ByteSize(self.peak_in_phase)
+        )
+    }
+    /// Writes the given phases as a table, one line per phase and one column
+    /// per figure, all aligned:
+    ///
+    /// ```text
+    /// phase         net     alloc     freed  allocs  peak-in-phase
+    /// parse   +12.4 MiB  96.1 MiB  83.7 MiB  41 203       57.2 MiB
+    /// ```
+    pub fn render_table<W: Write + ?Sized>(out: &mut W, phases: &[(&str, Self)]) -> fmt::Result {
+        let mut widths = [0; HEADERS.len()];
+        for (column, width) in widths.iter_mut().enumerate() {
+            *width = HEADERS[column].chars().count();
+            for (name, delta) in phases {
+                let mut counter = Counter(0);
+                cell(&mut counter, name, delta, column)?;
+                *width = (*width).max(counter.0);
+            }
+        }
+        for (column, header) in HEADERS.iter().enumerate() {
+            let len = header.chars().count();
+            separate(out, column)?;
+            align(out, column, widths[column] - len, |out| out.write_str(header))?;
+        }
+        out.write_char('\n')?;
+        for (name, delta) in phases {
+            for (column, width) in widths.iter().enumerate() {
+                let mut counter = Counter(0);
+                cell(&mut counter, name, delta, column)?;
+                separate(out, column)?;
+                align(out, column, width - counter.0, |out| cell(out, name, delta, column))?;
+            }
+            out.write_char('\n')?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for StatsDelta {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.render(f)
+    }
+}
+
+/// The columns of `render_table`
+const HEADERS: [&str; 6] = ["phase", "net", "alloc", "freed", "allocs", "peak-in-phase"];
+
+/// Writes the cell of a row of `render_table` in the given column
+fn cell<W: Write + ?Sized>(out: &mut W, name: &str, delta: &StatsDelta, column: usize) -> fmt::Result {
+    match column {
+        0 => out.write_str(name),
+        1 => write!(out, "{}", Net(delta.net_bytes)),
+        2 => write!(out, "{:.1}", ByteSize(delta.allocated_bytes)),
+        3 => write!(out, "{:.1}", ByteSize(delta.freed_bytes)),
+        4 => write!(out, "{}", Thousands(delta.allocations)),
+        _ => write!(out, "{:.1}", ByteSize(delta.peak_in_phase)),
+    }
+}
+
+/// Writes the space between two columns
+fn separate<W: Write + ?Sized>(out: &mut W, column: usize) -> fmt::Result {
+    if column > 0 {
+        out.write_str("  ")
+    } else {
+        Ok(())
+    }
+}
+
+/// Writes a cell padded with `padding` spaces: the first column (the names)
+/// is left aligned, the figures are right aligned.
+fn align<W, F>(out: &mut W, column: usize, padding: usize, write: F) -> fmt::Result
+where
+    W: Write + ?Sized,
+    F: FnOnce(&mut W) -> fmt::Result,
+{
+    if column == 0 {
+        write(out)?;
+        spaces(out, padding)
+    } else {
+        spaces(out, padding)?;
+        write(out)
+    }
+}
+
+pub(crate) fn spaces<W: Write + ?Sized>(out: &mut W, n: usize) -> fmt::Result {
+    (0..n).try_for_each(|_| out.write_char(' '))
+}
+
+/// A writer which merely counts the characters written to it
+pub(crate) struct Counter(pub(crate) usize);
+
+impl Write for Counter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0 += s.chars().count();
+        Ok(())
+    }
+}
+
+/// A signed number of bytes, always displayed with its sign
+struct Net(isize);
+
+impl fmt::Display for Net {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.0 < 0 { '-' } else { '+' };
+        write!(f, "{}{:.1}", sign, ByteSize(self.0.unsigned_abs()))
+    }
+}
+
+/// An integer displayed with its digits grouped by three (`41 203`)
+pub(crate) struct Thousands(pub(crate) usize);
+
+impl fmt::Display for Thousands {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut divisor = 1;
+        while self.0 / divisor >= 1000 {
+            divisor *= 1000;
+        }
+        write!(f, "{}", self.0 / divisor)?;
+        while divisor > 1 {
+            divisor /= 1000;
+            write!(f, " {:03}", self.0 / divisor % 1000)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{StatsDelta, Thousands};
+    use crate::Stats;
+
+    const MIB: usize = 1024 * 1024;
+
+    fn phase() -> StatsDelta {
+        StatsDelta {
+            net_bytes: 13_002_342,
+            allocated_bytes: 100_768_153,
+            freed_bytes: 87_765_811,
+            allocations: 41_203,
+            deallocations: 40_000,
+            peak_in_phase: 59_978_547,
+        }
+    }
+
+    #[test]
+    fn delta_is_rendered_on_one_line() {
+        assert_eq!(
+            "net +12.4 MiB (alloc 96.1 MiB, freed 83.7 MiB, 41 203 allocs, peak-in-phase 57.2 MiB)",
+            phase().to_string()
+        );
+        let shrink = StatsDelta { net_bytes: -(3 * MIB as isize), freed_bytes: 3 * MIB, ..Default::default() };
+        assert_eq!(
+            "net -3.0 MiB (alloc 0 B, freed 3.0 MiB, 0 allocs, peak-in-phase 0 B)",
+            shrink.to_string()
+        );
+    }
+
+    #[test]
+    fn very_large_values_are_rendered() {
+        let huge = StatsDelta {
+            net_bytes: isize::MIN,
+            allocated_bytes: usize::MAX,
+            freed_bytes: usize::MAX,
+            allocations: usize::MAX,
+            deallocations: usize::MAX,
+            peak_in_phase: usize::MAX,
+        };
+        if cfg!(target_pointer_width = "64") {
+            assert_eq!(
+                "net -8.0 EiB (alloc 16.0 EiB, freed 16.0 EiB, 18 446 744 073 709 551 615 allocs, \
+                 peak-in-phase 16.0 EiB)",
+                huge.to_string()
+            );
+        }
+    }
+
+    #[test]
+    fn thousands_are_separated() {
+        assert_eq!("0", Thousands(0).to_string());
+        assert_eq!("999", Thousands(999).to_string());
+        assert_eq!("1 000", Thousands(1000).to_string());
+        assert_eq!("1 000 001", Thousands(1_000_001).to_string());
+    }
+
+    #[test]
+    fn phases_are_aligned_in_a_table() {
+        let mut out = String::new();
+        let empty = StatsDelta::default();
+        StatsDelta::render_table(&mut out, &[("parse", phase()), ("typeck", empty)]).unwrap();
+        assert_eq!(
+            "phase         net     alloc     freed  allocs  peak-in-phase\n\
+             parse   +12.4 MiB  96.1 MiB  83.7 MiB  41 203       57.2 MiB\n\
+             typeck       +0 B       0 B       0 B       0            0 B\n",
+            out
+        );
+
+        let mut out = String::new();
+        StatsDelta::render_table(&mut out, &[]).unwrap();
+        assert_eq!("phase  net  alloc  freed  allocs  peak-in-phase\n", out);
+    }
+
+    #[test]
+    fn delta_is_computed_from_two_stats() {
+        let start = Stats { current_usage: 1000, total_allocated: 5000, allocation_count: 10, ..Default::default() };
+        let end = Stats {
+            current_usage: 400,
+            peak_usage: 1200,
+            total_allocated: 5300,
+            allocation_count: 13,
+            deallocation_count: 4,
+            ..Default::default()
+        };
+        let delta = end.since(&start);
+        assert_eq!(-600, delta.net_bytes);
+        assert_eq!(300, delta.allocated_bytes);
+        assert_eq!(900, delta.freed_bytes);
+        assert_eq!(3, delta.allocations);
+        assert_eq!(4, delta.deallocations);
+        assert_eq!(1200, delta.peak_in_phase);
+    }
+}
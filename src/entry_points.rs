@@ -0,0 +1,68 @@
+//! Which entry points of `GlobalAlloc` are accounted for. This is finer than
+//! the minimum tracked size: e.g. a workload which reallocates constantly
+//! may only care about the fresh allocations.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+/// An entry point of `GlobalAlloc`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EntryPoint {
+    /// `alloc`
+    Alloc,
+    /// `alloc_zeroed`
+    AllocZeroed,
+    /// `realloc`
+    Realloc,
+    /// `dealloc`
+    Dealloc,
+}
+
+impl EntryPoint {
+    /// The bit of this entry point in `TRACKED`
+    pub(crate) const fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+/// One bit per tracked entry point (all of them by default)
+static TRACKED: AtomicU8 = AtomicU8::new(u8::MAX);
+
+/// Returns true iff the calls to the given entry point are accounted for
+#[inline]
+pub(crate) fn is_tracked(entry: EntryPoint) -> bool {
+    TRACKED.load(Ordering::Relaxed) & entry.bit() != 0
+}
+
+/// Starts or stops accounting for the calls to the given entry point
+pub(crate) fn set_tracked(entry: EntryPoint, tracked: bool) {
+    if tracked {
+        TRACKED.fetch_or(entry.bit(), Ordering::Relaxed);
+    } else {
+        TRACKED.fetch_and(!entry.bit(), Ordering::Relaxed);
+    }
+}
+
+/// Returns the bits of the tracked entry points
+pub(crate) fn tracked_bits() -> u8 {
+    TRACKED.load(Ordering::Relaxed)
+}
+
+/// Tracks exactly the entry points whose bits are set
+pub(crate) fn set_tracked_bits(bits: u8) {
+    TRACKED.store(bits, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EntryPoint;
+
+    #[test]
+    fn entry_points_have_distinct_bits() {
+        let all = [EntryPoint::Alloc, EntryPoint::AllocZeroed, EntryPoint::Realloc, EntryPoint::Dealloc];
+        let mask = all.iter().fold(0, |mask, entry| {
+            assert_eq!(0, mask & entry.bit());
+            mask | entry.bit()
+        });
+        assert_eq!(0b1111, mask);
+    }
+}
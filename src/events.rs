@@ -0,0 +1,201 @@
+//! Deferred event dispatch. Running arbitrary code from within the allocator
+//! is a recipe for deadlocks (the code may well allocate itself). Hence, the
+//! allocation path merely pushes the events it detects into a preallocated
+//! lock-free queue. These events are dispatched to the registered handlers
+//! when `PeakAlloc::drain_events` is called, outside of the allocator. The
+//! handlers are thus free to allocate.
+//!
+//! The hooks run upon each reset of the peak live here as well. Resetting is
+//! never done from within the allocator: they are called right away.
+
+use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use crate::Stats;
+
+/// An event detected by the allocator
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Event {
+    /// The memory usage has risen above the watch threshold
+    ThresholdCrossed {
+        /// The memory usage (in bytes) right after the crossing
+        usage: usize,
+        /// The watch threshold (in bytes) which has been crossed
+        threshold: usize,
+        /// The id of the thread whose allocation crossed it (see
+        /// `PeakAlloc::current_thread_id`)
+        thread: u32,
+    },
+    /// A single allocation was much larger than the average one (see
+    /// `PeakAlloc::on_outlier_allocation`)
+    OutlierAllocation {
+        /// The size (in bytes) of the allocation
+        size: usize,
+        /// The average size (in bytes) of the allocations before it
+        mean: usize,
+        /// The id of the allocating thread (see `PeakAlloc::current_thread_id`)
+        thread: u32,
+    },
+    /// The allocations have come in a burst (see
+    /// `PeakAlloc::on_allocation_storm`)
+    AllocationStorm {
+        /// The estimated number of allocations within the window
+        count: usize,
+        /// The length of the window
+        window: core::time::Duration,
+    },
+}
+
+/// The type of the functions handling the events
+type Handler = Box<dyn Fn(Event) + Send + Sync>;
+/// The type of the functions called before each reset
+type ResetHook = Box<dyn Fn(Stats) + Send + Sync>;
+
+/// The number of slots of the event queue (must be a power of two)
+const CAPACITY: usize = 256;
+
+/// One slot of the event queue. The sequence number tells whether the slot
+/// is ready to be written (seq == pos) or read (seq == pos + 1).
+struct Slot {
+    seq: AtomicUsize,
+    event: UnsafeCell<Option<Event>>,
+}
+
+/// A bounded lock-free MPMC queue (after Dmitry Vyukov's design) which lives
+/// in a static and never allocates.
+struct Queue {
+    slots: [Slot; CAPACITY],
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+// The access to the slot contents is arbitrated by the sequence numbers
+unsafe impl Sync for Queue {}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY: Slot = Slot {
+    seq: AtomicUsize::new(0),
+    event: UnsafeCell::new(None),
+};
+
+impl Queue {
+    const fn new() -> Self {
+        // the sequence number of slot i must initially be i
+        let mut slots = [EMPTY; CAPACITY];
+        let mut i = 0;
+        while i < CAPACITY {
+            slots[i].seq = AtomicUsize::new(i);
+            i += 1;
+        }
+        Queue {
+            slots,
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+    /// Enqueues an event; returns false if the queue is full
+    fn push(&self, event: Event) -> bool {
+        let mut pos = self.tail.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[pos & (CAPACITY - 1)];
+            let seq = slot.seq.load(Ordering::Acquire);
+            let diff = seq as isize - pos as isize;
+            if diff == 0 {
+                match self.tail.compare_exchange_weak(pos, pos + 1, Ordering::Relaxed, Ordering::Relaxed) {
+                    Ok(_) => {
+                        unsafe { *slot.event.get() = Some(event) };
+                        slot.seq.store(pos + 1, Ordering::Release);
+                        return true;
+                    }
+                    Err(actual) => pos = actual,
+                }
+            } else if diff < 0 {
+                return false;
+            } else {
+                pos = self.tail.load(Ordering::Relaxed);
+            }
+        }
+    }
+    /// Dequeues the oldest event (if any)
+    fn pop(&self) -> Option<Event> {
+        let mut pos = self.head.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[pos & (CAPACITY - 1)];
+            let seq = slot.seq.load(Ordering::Acquire);
+            let diff = seq as isize - (pos + 1) as isize;
+            if diff == 0 {
+                match self.head.compare_exchange_weak(pos, pos + 1, Ordering::Relaxed, Ordering::Relaxed) {
+                    Ok(_) => {
+                        let event = unsafe { (*slot.event.get()).take() };
+                        slot.seq.store(pos + CAPACITY, Ordering::Release);
+                        return event;
+                    }
+                    Err(actual) => pos = actual,
+                }
+            } else if diff < 0 {
+                return None;
+            } else {
+                pos = self.head.load(Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+static QUEUE: Queue = Queue::new();
+/// The number of events which were lost because the queue was full
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+/// The registered event handlers
+static HANDLERS: Mutex<Vec<Handler>> = Mutex::new(Vec::new());
+
+/// Enqueues an event for later dispatch. This is safe to call from within
+/// the allocator: it neither locks nor allocates.
+#[cold]
+pub(crate) fn emit(event: Event) {
+    if !QUEUE.push(event) {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Registers a new event handler
+pub(crate) fn register(handler: Handler) {
+    HANDLERS.lock().unwrap_or_else(|e| e.into_inner()).push(handler);
+}
+
+/// Dispatches all the pending events to the registered handlers and returns
+/// the number of dispatched events.
+pub(crate) fn drain() -> usize {
+    let handlers = HANDLERS.lock().unwrap_or_else(|e| e.into_inner());
+    let mut count = 0;
+    while let Some(event) = QUEUE.pop() {
+        for handler in handlers.iter() {
+            handler(event);
+        }
+        count += 1;
+    }
+    count
+}
+
+/// The hooks called before each reset
+static RESET_HOOKS: Mutex<Vec<ResetHook>> = Mutex::new(Vec::new());
+
+/// Registers a new reset hook
+pub(crate) fn register_reset_hook(hook: ResetHook) {
+    RESET_HOOKS.lock().unwrap_or_else(|e| e.into_inner()).push(hook);
+}
+
+/// Calls the reset hooks with the statistics returned by `stats` (which is
+/// only called if there is a hook at all)
+pub(crate) fn before_reset(stats: impl FnOnce() -> Stats) {
+    let hooks = RESET_HOOKS.lock().unwrap_or_else(|e| e.into_inner());
+    if !hooks.is_empty() {
+        let stats = stats();
+        for hook in hooks.iter() {
+            hook(stats);
+        }
+    }
+}
+
+pub(crate) fn dropped() -> usize {
+    DROPPED.load(Ordering::Relaxed)
+}
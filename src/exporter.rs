@@ -0,0 +1,49 @@
+//! The plumbing shared by the exporters which periodically push the
+//! statistics of the allocator to a monitoring system (statsd, influx, ...).
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::periodic::Periodic;
+
+/// The handle to a running exporter. The exporter is stopped when the handle
+/// is dropped.
+pub struct ExporterHandle {
+    sent: Arc<AtomicUsize>,
+    errors: Arc<AtomicUsize>,
+    _thread: Periodic,
+}
+
+impl ExporterHandle {
+    /// Returns the number of exports (datagrams, requests) which have succeeded
+    /// so far
+    pub fn sent(&self) -> usize {
+        self.sent.load(Ordering::Relaxed)
+    }
+    /// Returns the number of exports which have failed. An exporter is
+    /// fire-and-forget: failures are counted but not fatal.
+    pub fn send_errors(&self) -> usize {
+        self.errors.load(Ordering::Relaxed)
+    }
+}
+
+/// Spawns a thread calling `export` once every `interval`. The task returns
+/// whether the export succeeded.
+pub(crate) fn spawn<F>(name: &str, interval: Duration, mut export: F) -> ExporterHandle
+where
+    F: FnMut() -> bool + Send + 'static,
+{
+    let sent = Arc::new(AtomicUsize::new(0));
+    let errors = Arc::new(AtomicUsize::new(0));
+    let (sent_count, error_count) = (Arc::clone(&sent), Arc::clone(&errors));
+    let thread = Periodic::spawn(name, interval, move || {
+        let counter = if export() { &sent_count } else { &error_count };
+        counter.fetch_add(1, Ordering::Relaxed);
+    });
+    ExporterHandle {
+        sent,
+        errors,
+        _thread: thread,
+    }
+}
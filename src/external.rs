@@ -0,0 +1,42 @@
+//! The call sites of the external memory records (see
+//! `PeakAlloc::record_external_alloc_located`): the last `RECENT_EXTERNAL`
+//! records are kept along with the location of the call, to audit which
+//! call site accounted for what.
+
+use core::panic::Location;
+use std::sync::Mutex;
+
+/// The number of records returned by `PeakAlloc::recent_external_records`
+pub const RECENT_EXTERNAL: usize = 32;
+
+/// An external memory record, and where it was made
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExternalRecord {
+    /// The number of bytes recorded
+    pub bytes: usize,
+    /// True iff the bytes were released (`record_external_dealloc_located`)
+    pub released: bool,
+    /// The call site of the record
+    pub location: &'static Location<'static>,
+}
+
+/// The last records, and the number of them ever made (the next one goes to
+/// `total % RECENT_EXTERNAL`)
+static RECENT: Mutex<([Option<ExternalRecord>; RECENT_EXTERNAL], usize)> =
+    Mutex::new(([None; RECENT_EXTERNAL], 0));
+
+/// Remembers a record
+pub(crate) fn record(bytes: usize, released: bool, location: &'static Location<'static>) {
+    let mut recent = RECENT.lock().unwrap_or_else(|e| e.into_inner());
+    let (records, total) = &mut *recent;
+    records[*total % RECENT_EXTERNAL] = Some(ExternalRecord { bytes, released, location });
+    *total += 1;
+}
+
+/// Returns the last records, the oldest first
+pub(crate) fn recent() -> Vec<ExternalRecord> {
+    // copied first: collecting allocates
+    let (records, total) = *RECENT.lock().unwrap_or_else(|e| e.into_inner());
+    let kept = total.min(RECENT_EXTERNAL);
+    (total - kept..total).filter_map(|i| records[i % RECENT_EXTERNAL]).collect()
+}
@@ -0,0 +1,256 @@
+//! C accessors to the counters of the allocator, for the applications where
+//! a Rust library (using `PeakAlloc` as its global allocator) is embedded in
+//! a C or C++ program. The declarations are in `include/peak_alloc.h`; they
+//! can also be generated with cbindgen.
+//!
+//! None of these functions panics: they merely load atomic counters.
+
+use core::mem::size_of;
+
+use crate::{MetricsBuffer, PeakAlloc};
+
+/// The version of the `PeakAllocStatsC` layout. It is bumped whenever fields
+/// are appended to the struct.
+pub const PEAK_ALLOC_STATS_VERSION: u32 = 1;
+
+/// `peak_alloc_stats` succeeded
+pub const PEAK_ALLOC_OK: i32 = 0;
+/// `peak_alloc_stats` was given a null pointer
+pub const PEAK_ALLOC_ERR_NULL: i32 = -1;
+/// `peak_alloc_stats` was given a struct too small to hold its header
+pub const PEAK_ALLOC_ERR_SIZE: i32 = -2;
+
+/// A snapshot of the counters of the allocator.
+///
+/// The caller sets `size` to `sizeof(PeakAllocStatsC)` before calling
+/// `peak_alloc_stats`. Only the fields which fit in that size are written,
+/// so that a program compiled against an older (smaller) version of the
+/// struct keeps working. Upon return, `version` holds the version of the
+/// library and `size` the number of bytes which were written.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PeakAllocStatsC {
+    /// The layout version of the struct (set by the library)
+    pub version: u32,
+    /// The size of the struct (set by the caller, updated by the library)
+    pub size: u32,
+    /// Same as `PeakAlloc::current_usage`
+    pub current_usage: u64,
+    /// Same as `PeakAlloc::peak_usage`
+    pub peak_usage: u64,
+    /// Same as `PeakAlloc::all_time_peak_usage`
+    pub all_time_peak_usage: u64,
+    /// Same as `PeakAlloc::total_allocated`
+    pub total_allocated: u64,
+    /// Same as `PeakAlloc::allocation_count`
+    pub allocation_count: u64,
+    /// Same as `PeakAlloc::deallocation_count`
+    pub deallocation_count: u64,
+    /// Same as `PeakAlloc::largest_allocation`
+    pub largest_allocation: u64,
+}
+
+/// Returns the version of the schema of the exported statistics (see
+/// `STATS_SCHEMA_VERSION`)
+#[no_mangle]
+pub extern "C" fn peak_alloc_schema_version() -> u32 {
+    crate::STATS_SCHEMA_VERSION
+}
+
+/// Returns the number of bytes currently in use
+#[no_mangle]
+pub extern "C" fn peak_alloc_current_usage() -> u64 {
+    PeakAlloc.current_usage() as u64
+}
+
+/// Returns the maximum number of bytes in use since the last reset
+#[no_mangle]
+pub extern "C" fn peak_alloc_peak_usage() -> u64 {
+    PeakAlloc.peak_usage() as u64
+}
+
+/// Resets the peak usage to the current usage
+#[no_mangle]
+pub extern "C" fn peak_alloc_reset_peak() {
+    PeakAlloc.reset_peak_usage()
+}
+
+/// Fills `out` with a snapshot of the counters (see `PeakAllocStatsC`) and
+/// returns `PEAK_ALLOC_OK`, or a negative error code.
+///
+/// # Safety
+/// `out` must either be null or point to a writable block of (at least)
+/// `out->size` bytes, suitably aligned for `PeakAllocStatsC`.
+#[no_mangle]
+pub unsafe extern "C" fn peak_alloc_stats(out: *mut PeakAllocStatsC) -> i32 {
+    if out.is_null() {
+        return PEAK_ALLOC_ERR_NULL;
+    }
+    let header = 2 * size_of::<u32>();
+    let size = (core::ptr::addr_of!((*out).size).read() as usize).min(size_of::<PeakAllocStatsC>());
+    if size < header {
+        return PEAK_ALLOC_ERR_SIZE;
+    }
+    let alloc = PeakAlloc;
+    let stats = PeakAllocStatsC {
+        version: PEAK_ALLOC_STATS_VERSION,
+        // the fields are all 8 bytes: only the whole ones are written
+        size: (header + (size - header) / 8 * 8) as u32,
+        current_usage: alloc.current_usage() as u64,
+        peak_usage: alloc.peak_usage() as u64,
+        all_time_peak_usage: alloc.all_time_peak_usage() as u64,
+        total_allocated: alloc.total_allocated() as u64,
+        allocation_count: alloc.allocation_count() as u64,
+        deallocation_count: alloc.deallocation_count() as u64,
+        largest_allocation: alloc.largest_allocation() as u64,
+    };
+    let bytes = &stats as *const PeakAllocStatsC as *const u8;
+    core::ptr::copy_nonoverlapping(bytes, out as *mut u8, stats.size as usize);
+    PEAK_ALLOC_OK
+}
+
+/// Fills `out` with all the counters of the build (see `MetricsBuffer`, or
+/// `PeakAllocMetrics` in C) and returns `PEAK_ALLOC_OK`, or
+/// `PEAK_ALLOC_ERR_NULL`.
+///
+/// # Safety
+/// `out` must either be null or point to a writable `MetricsBuffer`. Its
+/// size depends on the schema: a program checks that
+/// `peak_alloc_schema_version()` is the `PEAK_ALLOC_SCHEMA_VERSION` it was
+/// compiled against before the first call.
+#[no_mangle]
+pub unsafe extern "C" fn peak_alloc_read_metrics(out: *mut MetricsBuffer) -> i32 {
+    if out.is_null() {
+        return PEAK_ALLOC_ERR_NULL;
+    }
+    // filled aside: the buffer of the caller may well be uninitialized
+    let mut metrics = MetricsBuffer::new();
+    PeakAlloc.read_metrics(&mut metrics);
+    out.write(metrics);
+    PEAK_ALLOC_OK
+}
+
+#[cfg(test)]
+mod tests {
+    const HEADER: &str = include_str!("../include/peak_alloc.h");
+
+    #[test]
+    fn header_declares_all_the_functions() {
+        for declaration in [
+            "uint32_t peak_alloc_schema_version(void);",
+            "uint64_t peak_alloc_current_usage(void);",
+            "uint64_t peak_alloc_peak_usage(void);",
+            "void peak_alloc_reset_peak(void);",
+            "int32_t peak_alloc_stats(PeakAllocStatsC *out);",
+            "int32_t peak_alloc_read_metrics(PeakAllocMetrics *out);",
+        ] {
+            assert!(HEADER.contains(declaration), "{}", declaration);
+        }
+    }
+    #[test]
+    fn header_matches_the_struct_layout() {
+        let body = HEADER.split("typedef struct PeakAllocStatsC {").nth(1).unwrap();
+        let body = body.split('}').next().unwrap();
+        let fields = body
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with("//"))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                "uint32_t version;",
+                "uint32_t size;",
+                "uint64_t current_usage;",
+                "uint64_t peak_usage;",
+                "uint64_t all_time_peak_usage;",
+                "uint64_t total_allocated;",
+                "uint64_t allocation_count;",
+                "uint64_t deallocation_count;",
+                "uint64_t largest_allocation;",
+            ],
+            fields
+        );
+        assert_eq!(64, std::mem::size_of::<super::PeakAllocStatsC>());
+        assert!(HEADER.contains(&format!(
+            "#define PEAK_ALLOC_STATS_VERSION {}",
+            super::PEAK_ALLOC_STATS_VERSION
+        )));
+        assert!(HEADER.contains(&format!(
+            "#define PEAK_ALLOC_SCHEMA_VERSION {}",
+            crate::STATS_SCHEMA_VERSION
+        )));
+    }
+    #[test]
+    fn the_fields_of_the_struct_stay_in_place() {
+        use core::mem::{align_of, offset_of};
+        use super::PeakAllocStatsC as S;
+        // the fields may only be appended (along with a bump of the version)
+        assert_eq!(1, super::PEAK_ALLOC_STATS_VERSION);
+        assert_eq!(
+            [0, 4, 8, 16, 24, 32, 40, 48, 56],
+            [
+                offset_of!(S, version),
+                offset_of!(S, size),
+                offset_of!(S, current_usage),
+                offset_of!(S, peak_usage),
+                offset_of!(S, all_time_peak_usage),
+                offset_of!(S, total_allocated),
+                offset_of!(S, allocation_count),
+                offset_of!(S, deallocation_count),
+                offset_of!(S, largest_allocation),
+            ]
+        );
+        assert_eq!(8, align_of::<S>());
+    }
+    #[test]
+    fn header_matches_the_metrics_buffer() {
+        use crate::MetricsBuffer as M;
+        let body = HEADER.split("typedef struct PeakAllocMetrics {").nth(1).unwrap();
+        let body = body.split('}').next().unwrap();
+        let fields = body.lines().map(str::trim).filter(|line| !line.is_empty()).collect::<Vec<_>>();
+        assert_eq!(
+            vec!["uint32_t schema;", "uint32_t len;", "uint64_t valid;", "uint64_t values[PEAK_ALLOC_METRICS];"],
+            fields
+        );
+        assert_eq!(16 + 8 * crate::METRICS, std::mem::size_of::<M>());
+        assert!(HEADER.contains(&format!("#define PEAK_ALLOC_METRICS {}", crate::METRICS)));
+        for (name, index) in [
+            ("CURRENT_USAGE", M::CURRENT_USAGE),
+            ("PEAK_USAGE", M::PEAK_USAGE),
+            ("ALL_TIME_PEAK_USAGE", M::ALL_TIME_PEAK_USAGE),
+            ("TOTAL_ALLOCATED", M::TOTAL_ALLOCATED),
+            ("ALLOCATION_COUNT", M::ALLOCATION_COUNT),
+            ("DEALLOCATION_COUNT", M::DEALLOCATION_COUNT),
+            ("REALLOCATION_COUNT", M::REALLOCATION_COUNT),
+            ("REALLOC_OVERHEAD_BYTES", M::REALLOC_OVERHEAD_BYTES),
+            ("LARGEST_ALLOCATION", M::LARGEST_ALLOCATION),
+            ("FAILED_ALLOCATION_COUNT", M::FAILED_ALLOCATION_COUNT),
+            ("LARGE_CURRENT_USAGE", M::LARGE_CURRENT_USAGE),
+            ("LARGE_PEAK_USAGE", M::LARGE_PEAK_USAGE),
+            ("LARGE_ALLOCATION_COUNT", M::LARGE_ALLOCATION_COUNT),
+            ("OVERALIGNED_COUNT", M::OVERALIGNED_COUNT),
+            ("OVERALIGNED_BYTES", M::OVERALIGNED_BYTES),
+            ("EXTERNAL_USAGE", M::EXTERNAL_USAGE),
+            ("GROWTH_SLACK_BYTES", M::GROWTH_SLACK_BYTES),
+            ("SMOOTHED_PEAK_USAGE", M::SMOOTHED_PEAK_USAGE),
+            ("WIPED_BYTES", M::WIPED_BYTES),
+            ("INVALID_REQUEST_COUNT", M::INVALID_REQUEST_COUNT),
+            ("QUARANTINED_BYTES", M::QUARANTINED_BYTES),
+            ("REDZONE_VIOLATIONS", M::REDZONE_VIOLATIONS),
+            ("POINTER_MAP_OVERFLOWS", M::POINTER_MAP_OVERFLOWS),
+        ] {
+            let define = format!("#define PEAK_ALLOC_METRIC_{} {}\n", name, index);
+            assert!(HEADER.contains(&define), "{}", define);
+        }
+    }
+    #[test]
+    fn read_metrics_fills_the_buffer() {
+        let mut metrics = core::mem::MaybeUninit::<crate::MetricsBuffer>::uninit();
+        assert_eq!(super::PEAK_ALLOC_OK, unsafe { super::peak_alloc_read_metrics(metrics.as_mut_ptr()) });
+        let metrics = unsafe { metrics.assume_init() };
+        assert_eq!(crate::STATS_SCHEMA_VERSION, metrics.schema);
+        assert!(metrics.get(crate::MetricsBuffer::PEAK_USAGE).is_some());
+        let null = core::ptr::null_mut();
+        assert_eq!(super::PEAK_ALLOC_ERR_NULL, unsafe { super::peak_alloc_read_metrics(null) });
+    }
+}
@@ -0,0 +1,256 @@
+//! Formatting without allocating, for the output paths which must not touch
+//! the heap (e.g. the out of memory report, or code running while allocating
+//! is forbidden): a string over a fixed buffer, and the helpers writing the
+//! integers, the sizes and the durations into any `fmt::Write`.
+//!
+//! A `StackString` silently truncates what does not fit (on a character
+//! boundary) and remembers it did: a report cut short is more useful than
+//! none at all.
+
+use core::fmt::{self, Write};
+use core::time::Duration;
+
+/// A string over a buffer of `N` bytes, typically on the stack. Writing past
+/// its capacity truncates the output (without ever splitting a character)
+/// rather than failing:
+///
+/// ```
+/// use core::fmt::Write;
+/// use peak_alloc::fmt::StackString;
+///
+/// let mut line = StackString::<8>::new();
+/// write!(line, "usage: {}", 4096).unwrap();
+/// assert_eq!("usage: 4", line.as_str());
+/// assert!(line.is_truncated());
+/// ```
+#[derive(Clone, Copy)]
+pub struct StackString<const N: usize> {
+    bytes: [u8; N],
+    len: usize,
+    truncated: bool,
+}
+
+impl<const N: usize> StackString<N> {
+    /// Creates an empty string
+    pub const fn new() -> Self {
+        StackString { bytes: [0; N], len: 0, truncated: false }
+    }
+    /// Returns the text written so far
+    pub fn as_str(&self) -> &str {
+        // only whole characters are ever copied into the buffer
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap_or_default()
+    }
+    /// Returns the bytes written so far
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+    /// Returns the number of bytes written so far
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    /// Returns true iff nothing has been written (or kept)
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    /// Returns the number of bytes the buffer holds
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+    /// Returns true iff some output was cut off since the last `clear`
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+    /// Empties the string
+    pub fn clear(&mut self) {
+        self.len = 0;
+        self.truncated = false;
+    }
+}
+
+impl<const N: usize> Default for StackString<N> {
+    fn default() -> Self {
+        StackString::new()
+    }
+}
+
+impl<const N: usize> Write for StackString<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut n = s.len().min(N - self.len);
+        while !s.is_char_boundary(n) {
+            n -= 1;
+        }
+        self.bytes[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        self.truncated |= n < s.len();
+        Ok(())
+    }
+}
+
+impl<const N: usize> fmt::Display for StackString<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl<const N: usize> fmt::Debug for StackString<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+/// Writes an integer in decimal (`4096`)
+pub fn write_usize<W: Write + ?Sized>(out: &mut W, n: usize) -> fmt::Result {
+    let mut digits = [0_u8; 20];
+    let (mut i, mut n) = (digits.len(), n);
+    loop {
+        i -= 1;
+        digits[i] = b'0' + (n % 10) as u8;
+        n /= 10;
+        if n == 0 {
+            break;
+        }
+    }
+    out.write_str(core::str::from_utf8(&digits[i..]).map_err(|_| fmt::Error)?)
+}
+
+/// Writes a number of bytes in the largest binary unit in which it is at least
+/// one, with two decimals (`1.50 KiB`); plain bytes are written as integers
+/// (`1023 B`). This is the `Display` of `ByteSize`.
+pub fn write_bytes_human<W: Write + ?Sized>(out: &mut W, bytes: usize) -> fmt::Result {
+    write_bytes_with_precision(out, bytes, 2)
+}
+
+/// Writes a number of bytes as `write_bytes_human` does, with the given
+/// number of decimals
+pub(crate) fn write_bytes_with_precision<W: Write + ?Sized>(
+    out: &mut W,
+    bytes: usize,
+    decimals: usize,
+) -> fmt::Result {
+    if bytes < 1024 {
+        write_usize(out, bytes)?;
+        return out.write_str(" B");
+    }
+    let (value, unit) = crate::units::human(bytes);
+    write!(out, "{:.*} {}", decimals, value, unit)
+}
+
+/// Writes a duration in the unit which suits it: `850ns`, `12.5us`, `3.2ms`,
+/// `1.50s`, `2m05s` and `3h07m` (the minutes and the hours are truncated)
+pub fn write_duration<W: Write + ?Sized>(out: &mut W, duration: Duration) -> fmt::Result {
+    let nanos = duration.as_nanos();
+    let secs = duration.as_secs();
+    if nanos < 1_000 {
+        write_usize(out, nanos as usize)?;
+        out.write_str("ns")
+    } else if nanos < 1_000_000 {
+        write!(out, "{:.1}us", nanos as f64 / 1e3)
+    } else if nanos < 1_000_000_000 {
+        write!(out, "{:.1}ms", nanos as f64 / 1e6)
+    } else if secs < 60 {
+        write!(out, "{:.2}s", duration.as_secs_f64())
+    } else if secs < 3600 {
+        write!(out, "{}m{:02}s", secs / 60, secs % 60)
+    } else {
+        write!(out, "{}h{:02}m", secs / 3600, secs / 60 % 60)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{write_bytes_human, write_duration, write_usize, StackString};
+    use core::fmt::Write;
+    use core::time::Duration;
+
+    #[test]
+    fn stack_strings_truncate_what_overflows() {
+        let mut s = StackString::<4>::new();
+        assert!(s.is_empty() && !s.is_truncated());
+        s.write_str("abc").unwrap();
+        assert_eq!(("abc", false), (s.as_str(), s.is_truncated()));
+        s.write_str("de").unwrap();
+        assert_eq!(("abcd", true), (s.as_str(), s.is_truncated()));
+        s.write_str("f").unwrap();
+        assert_eq!("abcd", s.to_string());
+        s.clear();
+        assert!(s.is_empty() && !s.is_truncated());
+        assert_eq!(4, s.capacity());
+    }
+
+    #[test]
+    fn stack_strings_fit_exactly() {
+        let mut s = StackString::<5>::new();
+        write!(s, "{}", 12345).unwrap();
+        assert_eq!(("12345", false), (s.as_str(), s.is_truncated()));
+        s.write_str("").unwrap();
+        assert!(!s.is_truncated());
+        let mut empty = StackString::<0>::new();
+        empty.write_str("").unwrap();
+        assert!(!empty.is_truncated());
+        empty.write_str("x").unwrap();
+        assert_eq!(("", true), (empty.as_str(), empty.is_truncated()));
+    }
+
+    #[test]
+    fn stack_strings_never_split_a_character() {
+        let mut s = StackString::<4>::new();
+        s.write_str("ab…").unwrap();
+        assert_eq!(("ab", true, 2), (s.as_str(), s.is_truncated(), s.len()));
+        let mut s = StackString::<5>::new();
+        s.write_str("ab…").unwrap();
+        assert_eq!(("ab…", false), (s.as_str(), s.is_truncated()));
+        assert_eq!("\"ab…\"", format!("{:?}", s));
+    }
+
+    #[test]
+    fn integers_are_written_in_decimal() {
+        let written = |n| {
+            let mut s = StackString::<32>::new();
+            write_usize(&mut s, n).unwrap();
+            s
+        };
+        assert_eq!("0", written(0).as_str());
+        assert_eq!("7", written(7).as_str());
+        assert_eq!("1000", written(1000).as_str());
+        assert_eq!(usize::MAX.to_string(), written(usize::MAX).as_str());
+        let mut short = StackString::<3>::new();
+        write_usize(&mut short, 12345).unwrap();
+        assert_eq!(("123", true), (short.as_str(), short.is_truncated()));
+    }
+
+    #[test]
+    fn sizes_are_written_in_the_largest_unit() {
+        let written = |bytes| {
+            let mut s = StackString::<16>::new();
+            write_bytes_human(&mut s, bytes).unwrap();
+            s
+        };
+        assert_eq!("0 B", written(0).as_str());
+        assert_eq!("1023 B", written(1023).as_str());
+        assert_eq!("1.00 KiB", written(1024).as_str());
+        assert_eq!("1.50 MiB", written(3 * 512 * 1024).as_str());
+        if cfg!(target_pointer_width = "64") {
+            assert_eq!("16.00 EiB", written(usize::MAX).as_str());
+        }
+    }
+
+    #[test]
+    fn durations_are_written_in_a_suitable_unit() {
+        let written = |duration| {
+            let mut s = StackString::<32>::new();
+            write_duration(&mut s, duration).unwrap();
+            s
+        };
+        assert_eq!("0ns", written(Duration::ZERO).as_str());
+        assert_eq!("999ns", written(Duration::from_nanos(999)).as_str());
+        assert_eq!("12.5us", written(Duration::from_nanos(12_500)).as_str());
+        assert_eq!("3.2ms", written(Duration::from_micros(3_200)).as_str());
+        assert_eq!("1.50s", written(Duration::from_millis(1_500)).as_str());
+        assert_eq!("2m05s", written(Duration::from_secs(125)).as_str());
+        assert_eq!("3h07m", written(Duration::from_secs(3 * 3600 + 7 * 60 + 59)).as_str());
+        assert_eq!("5124095576030431h00m", written(Duration::MAX).as_str());
+        let mut short = StackString::<4>::new();
+        write_duration(&mut short, Duration::from_secs(125)).unwrap();
+        assert_eq!(("2m05", true), (short.as_str(), short.is_truncated()));
+    }
+}
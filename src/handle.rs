@@ -0,0 +1,193 @@
+//! A handle to read the statistics without naming the allocator static (see
+//! `PeakAlloc::handle`).
+
+use crate::PeakAlloc;
+
+/// A read-only handle to the statistics of `PeakAlloc`. It is zero-sized,
+/// `Copy`, `Send` and `Sync`: it can be handed to the threads and to the
+/// functions which only need to read the memory usage, without granting
+/// them the methods which change the configuration or reset the counters.
+///
+/// ```
+/// use peak_alloc::{PeakAlloc, StatsHandle};
+///
+/// #[global_allocator]
+/// static PEAK_ALLOC: PeakAlloc = PeakAlloc;
+///
+/// fn report(memory: StatsHandle) -> String {
+///     format!("peak: {} B", memory.peak_usage())
+/// }
+///
+/// let handle = PEAK_ALLOC.handle();
+/// let report = std::thread::spawn(move || report(handle)).join().unwrap();
+/// assert!(report.starts_with("peak: "));
+/// ```
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct StatsHandle;
+
+impl PeakAlloc {
+    /// Returns a read-only handle to the statistics (see `StatsHandle`)
+    pub fn handle(&self) -> StatsHandle {
+        StatsHandle
+    }
+}
+
+/// Declares the methods of `StatsHandle` which forward to `PeakAlloc`
+macro_rules! forward {
+    ($($(#[$attr:meta])* fn $name:ident(&self $(, $arg:ident: $ty:ty)*) -> $ret:ty;)*) => {
+        impl StatsHandle {
+            $(
+                $(#[$attr])*
+                #[doc = concat!("See `PeakAlloc::", stringify!($name), "`")]
+                #[inline]
+                pub fn $name(&self $(, $arg: $ty)*) -> $ret {
+                    PeakAlloc.$name($($arg),*)
+                }
+            )*
+        }
+    };
+}
+
+forward! {
+    fn current_usage(&self) -> usize;
+    fn peak_usage(&self) -> usize;
+    fn all_time_peak_usage(&self) -> usize;
+    fn total_allocated(&self) -> usize;
+    fn total_allocated_overflowed(&self) -> bool;
+    fn allocation_count(&self) -> usize;
+    fn deallocation_count(&self) -> usize;
+    fn reallocation_count(&self) -> usize;
+    fn growth_slack_bytes(&self) -> usize;
+    fn realloc_overhead_bytes(&self) -> usize;
+    fn largest_allocation(&self) -> usize;
+    fn top_allocations(&self) -> [usize; crate::TOP_ALLOCATIONS];
+    fn failed_allocation_count(&self) -> usize;
+    fn memory_limit(&self) -> Option<usize>;
+    fn poisoned(&self) -> bool;
+    fn current_config(&self) -> crate::Config;
+    fn external_usage(&self) -> usize;
+    fn is_entry_point_tracked(&self, entry: crate::EntryPoint) -> bool;
+    fn tracker(&self) -> &'static crate::AllocationTracker;
+    fn stats(&self) -> crate::Stats;
+    fn export_state(&self) -> crate::AllocatorState;
+    fn read_all(&self) -> (usize, usize, usize, usize);
+    fn final_report(&self) -> crate::Report;
+    #[allow(deprecated)]
+    #[deprecated(note = "use `current_usage_in_units(units::KIB)`, which is exact")]
+    fn current_usage_as_kb(&self) -> f32;
+    #[allow(deprecated)]
+    #[deprecated(note = "use `current_usage_in_units(units::MIB)`, which is exact")]
+    fn current_usage_as_mb(&self) -> f32;
+    fn current_usage_as_mb_with(&self, mode: crate::RoundMode) -> f64;
+    #[allow(deprecated)]
+    #[deprecated(note = "use `current_usage_in_units(units::GIB)`, which is exact")]
+    fn current_usage_as_gb(&self) -> f32;
+    #[allow(deprecated)]
+    #[deprecated(note = "use `peak_usage_in_units(units::KIB)`, which is exact")]
+    fn peak_usage_as_kb(&self) -> f32;
+    #[allow(deprecated)]
+    #[deprecated(note = "use `peak_usage_in_units(units::MIB)`, which is exact")]
+    fn peak_usage_as_mb(&self) -> f32;
+    fn peak_usage_as_mb_with(&self, mode: crate::RoundMode) -> f64;
+    #[allow(deprecated)]
+    #[deprecated(note = "use `peak_usage_in_units(units::GIB)`, which is exact")]
+    fn peak_usage_as_gb(&self) -> f32;
+    fn current_usage_in_units(&self, unit_bytes: usize) -> f64;
+    fn peak_usage_in_units(&self, unit_bytes: usize) -> f64;
+    fn current_usage_auto(&self) -> (f64, &'static str);
+    fn peak_usage_auto(&self) -> (f64, &'static str);
+    fn display_in(&self, unit: crate::units::Unit) -> crate::units::DisplayIn;
+    fn large_current_usage(&self) -> usize;
+    fn large_peak_usage(&self) -> usize;
+    fn large_allocation_count(&self) -> usize;
+    fn overalign_threshold(&self) -> usize;
+    fn overaligned_count(&self) -> usize;
+    fn overaligned_bytes(&self) -> usize;
+    fn process_baseline(&self) -> usize;
+    fn startup_usage(&self) -> Option<usize>;
+    fn startup_allocation_count(&self) -> Option<usize>;
+    fn post_startup_peak(&self) -> usize;
+    fn reset_policy(&self) -> crate::ResetPolicy;
+    fn sample_rate(&self) -> usize;
+    #[cfg(feature = "std")]
+    fn main_thread_allocations(&self) -> usize;
+    #[cfg(feature = "std")]
+    fn other_thread_allocations(&self) -> usize;
+    #[cfg(feature = "std")]
+    fn thread_allocation_count(&self) -> usize;
+    #[cfg(feature = "std")]
+    fn current_thread_id(&self) -> u32;
+    #[cfg(feature = "std")]
+    fn smoothed_peak_usage(&self) -> usize;
+    #[cfg(feature = "std")]
+    fn peak_since(&self, since: std::time::Instant) -> Option<usize>;
+    #[cfg(feature = "std")]
+    fn allocations_per_second(&self) -> f64;
+    #[cfg(feature = "windowed-peak")]
+    fn windowed_peak(&self, window: core::time::Duration) -> usize;
+    #[cfg(feature = "std")]
+    fn dropped_events(&self) -> usize;
+    #[cfg(feature = "std")]
+    fn footprint(&self) -> crate::FootprintReport;
+    #[cfg(feature = "std")]
+    fn stats_json(&self) -> String;
+    #[cfg(feature = "std")]
+    fn recent_overaligned(&self) -> Vec<crate::OveralignedRecord>;
+    #[cfg(feature = "std")]
+    fn report_breakdown(&self, dimension: crate::Dimension, sort: crate::SortBy, limit: usize)
+        -> crate::BreakdownReport;
+    #[cfg(feature = "histogram")]
+    fn size_histogram(&self) -> crate::SizeHistogram;
+    #[cfg(feature = "histogram")]
+    fn live_by_size_class(&self) -> crate::LiveSizeClasses;
+    #[cfg(feature = "histogram")]
+    fn estimated_internal_fragmentation(&self) -> usize;
+    #[cfg(feature = "histogram")]
+    fn padding_waste_bytes(&self) -> usize;
+    #[cfg(feature = "histogram")]
+    fn alignment_report(&self) -> crate::AlignmentReport;
+    #[cfg(feature = "actual-size")]
+    fn fragmentation(&self) -> Option<crate::FragmentationReport>;
+    #[cfg(feature = "actual-size")]
+    fn usable_size_accounting(&self) -> bool;
+    #[cfg(feature = "decayed-stats")]
+    fn usage_load_averages(&self) -> (f64, f64, f64);
+    #[cfg(feature = "module-tags")]
+    fn tag_usage(&self) -> Vec<crate::TagUsage>;
+    #[cfg(feature = "per-thread")]
+    fn peak_thread_breakdown(&self) -> Vec<(std::thread::ThreadId, isize)>;
+    #[cfg(feature = "per-thread")]
+    fn thread_name(&self, thread: u32) -> Option<String>;
+    #[cfg(feature = "pointer-map")]
+    fn pointer_map_overflows(&self) -> usize;
+    #[cfg(feature = "pointer-map")]
+    fn lifetime_histogram(&self) -> crate::LifetimeHistogram;
+    #[cfg(feature = "pointer-map")]
+    fn mean_allocation_lifetime(&self) -> Option<core::time::Duration>;
+    #[cfg(feature = "pointer-map")]
+    fn oldest_live_allocation_age(&self) -> Option<core::time::Duration>;
+    #[cfg(feature = "pointer-map")]
+    fn long_lived_bytes(&self, older_than: core::time::Duration) -> usize;
+    #[cfg(feature = "pointer-map")]
+    fn transient_usage(&self) -> usize;
+    #[cfg(feature = "quarantine")]
+    fn quarantined_bytes(&self) -> usize;
+    #[cfg(feature = "quarantine")]
+    fn quarantine_violations(&self) -> usize;
+    #[cfg(feature = "quarantine")]
+    fn last_quarantine_violation_size(&self) -> usize;
+    #[cfg(feature = "redzones")]
+    fn redzone_violations(&self) -> usize;
+    #[cfg(feature = "redzones")]
+    fn last_redzone_violation_size(&self) -> usize;
+    #[cfg(feature = "redzones")]
+    fn redzone_overhead_bytes(&self) -> usize;
+    #[cfg(feature = "zeroize-on-free")]
+    fn wiped_bytes(&self) -> usize;
+    #[cfg(feature = "checked-layout")]
+    fn invalid_request_count(&self) -> usize;
+    #[cfg(feature = "timed-accounting")]
+    fn timed_accounting(&self) -> crate::AccountingLatency;
+    #[cfg(feature = "external-locations")]
+    fn recent_external_records(&self) -> Vec<crate::ExternalRecord>;
+}
@@ -0,0 +1,325 @@
+//! The size histogram counts the allocations per size class. The size classes
+//! are powers of two: class `i` holds the allocations whose size is greater
+//! than `2^(i-1)` and no greater than `2^i` bytes (hence class 7 holds the
+//! allocations from 65 to 128 bytes).
+//!
+//! The very same machinery is used to count the live blocks and bytes per
+//! size class (to estimate the internal fragmentation, and to size per class
+//! pools with the peak of each class), to count the allocations per
+//! alignment and to track the bytes wasted in padding by over-aligned
+//! allocations.
+
+use std::alloc::Layout;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// The number of size classes of the histogram
+pub const SIZE_CLASSES: usize = usize::BITS as usize + 1;
+/// The number of alignment classes (alignments are powers of two)
+pub const ALIGN_CLASSES: usize = usize::BITS as usize;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicUsize = AtomicUsize::new(0);
+
+/// A fixed number of atomic counters, one per bucket
+struct Buckets<const N: usize>([AtomicUsize; N]);
+
+impl<const N: usize> Buckets<N> {
+    const fn new() -> Self {
+        Buckets([ZERO; N])
+    }
+    #[inline]
+    fn record(&self, bucket: usize) {
+        self.0[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+    #[inline]
+    fn release(&self, bucket: usize) {
+        self.0[bucket].fetch_sub(1, Ordering::Relaxed);
+    }
+    /// Adds `n` to a bucket and returns its new value
+    #[inline]
+    fn add(&self, bucket: usize, n: usize) -> usize {
+        self.0[bucket].fetch_add(n, Ordering::Relaxed).wrapping_add(n)
+    }
+    #[inline]
+    fn sub(&self, bucket: usize, n: usize) {
+        self.0[bucket].fetch_sub(n, Ordering::Relaxed);
+    }
+    fn snapshot(&self) -> [usize; N] {
+        let mut counts = [0; N];
+        for (count, bucket) in counts.iter_mut().zip(self.0.iter()) {
+            *count = bucket.load(Ordering::Relaxed);
+        }
+        counts
+    }
+}
+
+/// The number of allocations per size class
+static COUNTS: Buckets<SIZE_CLASSES> = Buckets::new();
+/// The number of live blocks per size class
+static LIVE: Buckets<SIZE_CLASSES> = Buckets::new();
+/// The number of bytes held by the live blocks per size class
+static LIVE_BYTES: Buckets<SIZE_CLASSES> = Buckets::new();
+/// The maximum number of bytes held at once by the live blocks of each size
+/// class (since the last reset)
+static PEAK_LIVE_BYTES: Buckets<SIZE_CLASSES> = Buckets::new();
+/// The number of allocations per alignment class
+static ALIGNMENTS: Buckets<ALIGN_CLASSES> = Buckets::new();
+/// The number of bytes wasted in padding by the live allocations
+static PADDING_WASTE: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the size class of an allocation of `size` bytes
+#[inline]
+pub(crate) fn class_of(size: usize) -> usize {
+    if size <= 1 {
+        0
+    } else {
+        (usize::BITS - (size - 1).leading_zeros()) as usize
+    }
+}
+
+/// Returns the number of bytes wasted in padding by a block of this layout
+#[inline]
+fn padding(layout: Layout) -> usize {
+    layout.pad_to_align().size() - layout.size()
+}
+
+/// Records one allocation of the given layout
+#[inline]
+pub(crate) fn record(layout: Layout) {
+    let class = class_of(layout.size());
+    COUNTS.record(class);
+    LIVE.record(class);
+    let live = LIVE_BYTES.add(class, layout.size());
+    crate::tracker::raise(&PEAK_LIVE_BYTES.0[class], live);
+    ALIGNMENTS.record(layout.align().trailing_zeros() as usize);
+    let waste = padding(layout);
+    if waste > 0 {
+        PADDING_WASTE.fetch_add(waste, Ordering::Relaxed);
+    }
+}
+
+/// Records the release of a block of the given layout
+#[inline]
+pub(crate) fn release(layout: Layout) {
+    let class = class_of(layout.size());
+    LIVE.release(class);
+    LIVE_BYTES.sub(class, layout.size());
+    let waste = padding(layout);
+    if waste > 0 {
+        PADDING_WASTE.fetch_sub(waste, Ordering::Relaxed);
+    }
+}
+
+/// Returns a copy of the size histogram
+pub(crate) fn snapshot() -> SizeHistogram {
+    SizeHistogram { counts: COUNTS.snapshot() }
+}
+
+/// Returns the number of bytes the live blocks would hold if each of them
+/// was rounded up to the upper bound of its size class
+pub(crate) fn live_class_bytes() -> usize {
+    LIVE.snapshot()
+        .iter()
+        .enumerate()
+        .map(|(class, n)| SizeHistogram::class_upper_bound(class).saturating_mul(*n))
+        .fold(0, usize::saturating_add)
+}
+
+/// Returns the live blocks and bytes of each size class, and their peaks
+pub(crate) fn live_by_size_class() -> LiveSizeClasses {
+    LiveSizeClasses {
+        live_blocks: LIVE.snapshot(),
+        live_bytes: LIVE_BYTES.snapshot(),
+        peak_live_bytes: PEAK_LIVE_BYTES.snapshot(),
+    }
+}
+
+/// Resets the peak of the given size class (or of all of them) to the bytes
+/// its live blocks currently hold
+pub(crate) fn reset_class_peaks(class: Option<usize>) {
+    let classes = match class {
+        Some(class) if class < SIZE_CLASSES => class..class + 1,
+        Some(_) => 0..0,
+        None => 0..SIZE_CLASSES,
+    };
+    for class in classes {
+        crate::tracker::reset_mark(&PEAK_LIVE_BYTES.0[class], &LIVE_BYTES.0[class]);
+    }
+}
+
+/// Writes the non empty size classes as a JSON array of objects
+pub(crate) fn write_json<W: fmt::Write + ?Sized>(out: &mut W) -> fmt::Result {
+    out.write_char('[')?;
+    let classes = live_by_size_class();
+    for (i, class) in classes.iter().enumerate() {
+        if i > 0 {
+            out.write_char(',')?;
+        }
+        write!(
+            out,
+            "{{\"class_bytes\":{},\"live_blocks\":{},\"live_bytes\":{},\"peak_live_bytes\":{}}}",
+            class.upper_bound, class.live_blocks, class.live_bytes, class.peak_live_bytes
+        )?;
+    }
+    out.write_char(']')
+}
+
+/// Returns the number of bytes wasted in padding by the live allocations
+pub(crate) fn padding_waste() -> usize {
+    PADDING_WASTE.load(Ordering::Relaxed)
+}
+
+/// Returns a copy of the alignment statistics
+pub(crate) fn alignment_report() -> AlignmentReport {
+    AlignmentReport {
+        counts: ALIGNMENTS.snapshot(),
+        padding_waste_bytes: padding_waste(),
+    }
+}
+
+/// A copy of the histogram of the allocation sizes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SizeHistogram {
+    /// The number of allocations which fell in each size class
+    pub counts: [usize; SIZE_CLASSES],
+}
+
+impl SizeHistogram {
+    /// Returns the largest size (in bytes) that belongs to the given class
+    pub fn class_upper_bound(class: usize) -> usize {
+        1_usize.checked_shl(class as u32).unwrap_or(usize::MAX)
+    }
+    /// Returns the total number of allocations in the histogram
+    pub fn count(&self) -> usize {
+        self.counts.iter().sum()
+    }
+    /// Iterates over the non empty classes, yielding the upper bound of each
+    /// class along with the number of allocations it holds.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.counts
+            .iter()
+            .enumerate()
+            .filter(|(_, n)| **n > 0)
+            .map(|(class, n)| (Self::class_upper_bound(class), *n))
+    }
+}
+
+impl fmt::Display for SizeHistogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let count = self.count();
+        writeln!(f, "size histogram ({} allocations)", count)?;
+        for (upper, n) in self.iter() {
+            writeln!(
+                f,
+                "  <= {:>20} B: {:>10} ({:5.1}%)",
+                upper,
+                n,
+                100.0 * n as f64 / count as f64
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// The live blocks per size class, along with the maximum number of bytes
+/// they held at once (see `PeakAlloc::live_by_size_class`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LiveSizeClasses {
+    /// The number of live blocks in each size class
+    pub live_blocks: [usize; SIZE_CLASSES],
+    /// The number of bytes held by the live blocks of each size class
+    pub live_bytes: [usize; SIZE_CLASSES],
+    /// The maximum number of bytes held at once by the live blocks of each
+    /// size class, since the last reset of its peak
+    pub peak_live_bytes: [usize; SIZE_CLASSES],
+}
+
+/// The usage of one size class (see `LiveSizeClasses::iter`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SizeClassUsage {
+    /// The largest size (in bytes) that belongs to the class
+    pub upper_bound: usize,
+    /// The number of live blocks in the class
+    pub live_blocks: usize,
+    /// The number of bytes held by the live blocks of the class
+    pub live_bytes: usize,
+    /// The maximum number of bytes held at once by the blocks of the class
+    pub peak_live_bytes: usize,
+}
+
+impl LiveSizeClasses {
+    /// Returns the usage of the given size class (see `SizeHistogram` for
+    /// the classes)
+    pub fn class(&self, class: usize) -> SizeClassUsage {
+        SizeClassUsage {
+            upper_bound: SizeHistogram::class_upper_bound(class),
+            live_blocks: self.live_blocks[class],
+            live_bytes: self.live_bytes[class],
+            peak_live_bytes: self.peak_live_bytes[class],
+        }
+    }
+    /// Iterates over the classes which ever held a block (since the last
+    /// reset of their peak), the smallest first
+    pub fn iter(&self) -> impl Iterator<Item = SizeClassUsage> + '_ {
+        (0..SIZE_CLASSES)
+            .filter(move |class| self.peak_live_bytes[*class] > 0 || self.live_blocks[*class] > 0)
+            .map(move |class| self.class(class))
+    }
+}
+
+impl fmt::Display for LiveSizeClasses {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "live blocks per size class")?;
+        for class in self.iter() {
+            writeln!(
+                f,
+                "  <= {:>20} B: {:>10} blocks, {:>14} B live, {:>14} B peak",
+                class.upper_bound, class.live_blocks, class.live_bytes, class.peak_live_bytes
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// The number of allocations per requested alignment, along with the number
+/// of bytes wasted in padding by the live allocations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AlignmentReport {
+    /// The number of allocations per alignment: `counts[i]` is the number of
+    /// allocations which requested an alignment of `2^i` bytes.
+    pub counts: [usize; ALIGN_CLASSES],
+    /// The number of bytes wasted in padding by the live allocations. That
+    /// is, the sum of `layout.pad_to_align().size() - layout.size()`.
+    pub padding_waste_bytes: usize,
+}
+
+impl AlignmentReport {
+    /// Returns the number of allocations which requested the given alignment
+    pub fn count(&self, align: usize) -> usize {
+        if align.is_power_of_two() {
+            self.counts[align.trailing_zeros() as usize]
+        } else {
+            0
+        }
+    }
+    /// Iterates over the requested alignments, yielding each alignment along
+    /// with the number of allocations which requested it.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.counts
+            .iter()
+            .enumerate()
+            .filter(|(_, n)| **n > 0)
+            .map(|(class, n)| (1_usize << class, *n))
+    }
+}
+
+impl fmt::Display for AlignmentReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "alignments")?;
+        for (align, n) in self.iter() {
+            writeln!(f, "  {:>10} B: {:>10}", align, n)?;
+        }
+        writeln!(f, "  padding waste: {} B", self.padding_waste_bytes)
+    }
+}
@@ -0,0 +1,291 @@
+//! The usage history keeps the most recent samples of the memory usage in a
+//! ring buffer, so that a program can display (or plot) them by itself. The
+//! ring is allocated once, upfront: the sampler thread never allocates.
+//!
+//! The most recently started history also answers `PeakAlloc::peak_since`,
+//! for as long as its handle is alive.
+//!
+//! The sampler also maintains the allocation rate gauge (see
+//! `PeakAlloc::allocations_per_second`): the number of allocations performed
+//! during its last interval, scaled to one second.
+//!
+//! # Note
+//! To keep the samples compact, their time is stored as a `u32` number of
+//! milliseconds since the start of the history. It hence wraps around to
+//! zero after about 49.7 days; the order of the samples is not affected.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::time::{Duration, Instant};
+
+use crate::chart;
+use crate::periodic::Periodic;
+use crate::PeakAlloc;
+
+/// The bits of the (f64) allocation rate measured by the last sampler tick
+static ALLOCATION_RATE: AtomicU64 = AtomicU64::new(0);
+
+/// The ring of the most recently started history, along with its start
+static LATEST: Mutex<Option<(Weak<Mutex<Ring>>, Instant)>> = Mutex::new(None);
+
+/// Returns the allocation rate (per second) measured during the last window
+pub(crate) fn allocation_rate() -> f64 {
+    f64::from_bits(ALLOCATION_RATE.load(Ordering::Relaxed))
+}
+
+/// Returns the number of allocations per second given the allocation counts
+/// at the beginning and at the end of a window of the given length (0 for an
+/// empty window)
+pub(crate) fn rate(before: usize, after: usize, window: Duration) -> f64 {
+    let seconds = window.as_secs_f64();
+    if seconds > 0.0 {
+        after.wrapping_sub(before) as f64 / seconds
+    } else {
+        0.0
+    }
+}
+
+/// One sample: the milliseconds elapsed since the start of the history (see
+/// `elapsed_ms`) along with the usage.
+pub(crate) type Sample = (u32, usize);
+
+/// Returns the (wrapping) number of milliseconds between `start` and `now`
+pub(crate) fn elapsed_ms(start: Instant, now: Instant) -> u32 {
+    now.saturating_duration_since(start).as_millis() as u32
+}
+
+/// Converts a compact sample to the (time, usage) pair of the public API
+fn expand((ms, usage): Sample) -> (Duration, usize) {
+    (Duration::from_millis(ms as u64), usage)
+}
+
+/// A fixed capacity ring buffer of samples
+#[derive(Debug)]
+pub(crate) struct Ring {
+    samples: Vec<Sample>,
+    capacity: usize,
+    /// The index where the next sample will be written
+    next: usize,
+}
+
+impl Ring {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Ring {
+            samples: Vec::with_capacity(capacity),
+            capacity,
+            next: 0,
+        }
+    }
+    /// Records a sample, overwriting the oldest one if the ring is full
+    pub(crate) fn push(&mut self, sample: Sample) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.samples.len() < self.capacity {
+            self.samples.push(sample);
+        } else {
+            self.samples[self.next] = sample;
+        }
+        self.next = (self.next + 1) % self.capacity;
+    }
+    /// Iterates over the samples, from the oldest to the most recent one
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &Sample> + '_ {
+        let (recent, old) = if self.samples.len() < self.capacity {
+            self.samples.split_at(self.samples.len())
+        } else {
+            self.samples.split_at(self.next)
+        };
+        old.iter().chain(recent.iter())
+    }
+    /// Returns the maximum usage among the samples taken `since` (or later),
+    /// given the start of the history
+    pub(crate) fn peak_since(&self, start: Instant, since: Instant) -> Option<usize> {
+        let since = elapsed_ms(start, since);
+        self.iter().filter(|(ms, _)| *ms >= since).map(|(_, usage)| *usage).max()
+    }
+}
+
+/// Returns the maximum usage sampled since `since` by the most recently
+/// started history (None if it is gone, or if it has no sample that recent)
+pub(crate) fn peak_since(since: Instant) -> Option<usize> {
+    let (ring, start) = LATEST.lock().unwrap_or_else(|e| e.into_inner()).clone()?;
+    let ring = ring.upgrade()?;
+    let peak = ring.lock().unwrap_or_else(|e| e.into_inner()).peak_since(start, since);
+    peak
+}
+
+/// Splits the samples in (at most) `n` buckets of consecutive samples and
+/// returns one point per bucket: the time of its last sample along with the
+/// maximum usage in the bucket (so that no peak disappears from the plot).
+pub(crate) fn downsample(samples: &[(Duration, usize)], n: usize) -> Vec<(Duration, usize)> {
+    if n == 0 || samples.is_empty() {
+        return Vec::new();
+    }
+    let n = n.min(samples.len());
+    (0..n)
+        .map(|i| {
+            let bucket = &samples[i * samples.len() / n..(i + 1) * samples.len() / n];
+            let time = bucket[bucket.len() - 1].0;
+            let max = bucket.iter().map(|(_, usage)| *usage).max().unwrap_or(0);
+            (time, max)
+        })
+        .collect()
+}
+
+/// The handle to a usage history (see `PeakAlloc::start_history`). The
+/// sampler thread is stopped when the handle is dropped, or explicitly with
+/// `stop` (in which case the samples remain available).
+pub struct HistoryHandle {
+    ring: Arc<Mutex<Ring>>,
+    start: Instant,
+    sampler: Periodic,
+}
+
+impl HistoryHandle {
+    fn ring(&self) -> MutexGuard<'_, Ring> {
+        self.ring.lock().unwrap_or_else(|e| e.into_inner())
+    }
+    /// Returns the recorded samples, from the oldest to the most recent one.
+    /// Each sample is a pair (time since the start of the history, usage);
+    /// the time has a millisecond resolution.
+    pub fn samples(&self) -> Vec<(Duration, usize)> {
+        self.ring().iter().copied().map(expand).collect()
+    }
+    /// Calls `f` with each recorded sample (time since the start of the
+    /// history, usage), from the oldest to the most recent one. Unlike
+    /// `samples`, this does not allocate.
+    pub fn for_each_sample<F: FnMut(Duration, usize)>(&self, mut f: F) {
+        for sample in self.ring().iter() {
+            let (time, usage) = expand(*sample);
+            f(time, usage);
+        }
+    }
+    /// Returns the maximum usage in the recorded samples
+    pub fn max(&self) -> Option<usize> {
+        self.ring().iter().map(|(_, usage)| *usage).max()
+    }
+    /// Returns the maximum usage among the samples taken at `since` or later
+    /// (None if there is none)
+    pub fn peak_since(&self, since: Instant) -> Option<usize> {
+        self.ring().peak_since(self.start, since)
+    }
+    /// Returns the minimum usage in the recorded samples
+    pub fn min(&self) -> Option<usize> {
+        self.ring().iter().map(|(_, usage)| *usage).min()
+    }
+    /// Returns the most recent sample
+    pub fn latest(&self) -> Option<(Duration, usize)> {
+        self.ring().iter().last().copied().map(expand)
+    }
+    /// Returns (at most) `n` points summarizing the recorded samples, which
+    /// is handy for plotting. Each point stands for a run of consecutive
+    /// samples: it holds the time of the last sample of the run along with
+    /// the maximum usage of the run.
+    pub fn downsample(&self, n: usize) -> Vec<(Duration, usize)> {
+        downsample(&self.samples(), n)
+    }
+    /// Renders the recorded samples as a sparkline of (at most) `width`
+    /// characters, scaled between the minimum and maximum usage. E.g.
+    /// `println!("{}", history.sparkline(60))` makes for a nice progress
+    /// indicator.
+    pub fn sparkline(&self, width: usize) -> String {
+        chart::sparkline(&self.samples(), width)
+    }
+    /// Renders the recorded samples as a block chart of (at most) `width`
+    /// columns and `height` lines, labelled with the maximum and minimum
+    /// usage.
+    pub fn chart(&self, width: usize, height: usize) -> String {
+        chart::chart(&self.samples(), width, height)
+    }
+    /// Returns true iff the history is still being sampled
+    pub fn is_running(&self) -> bool {
+        self.sampler.is_running()
+    }
+    /// Stops sampling. The samples recorded so far remain available. This
+    /// is idempotent.
+    pub fn stop(&mut self) {
+        self.sampler.stop();
+    }
+}
+
+/// Spawns the history sampler thread
+pub(crate) fn start(alloc: PeakAlloc, samples: usize, interval: Duration) -> HistoryHandle {
+    let ring = Arc::new(Mutex::new(Ring::new(samples)));
+    let writer = Arc::clone(&ring);
+    let start = Instant::now();
+    *LATEST.lock().unwrap_or_else(|e| e.into_inner()) = Some((Arc::downgrade(&ring), start));
+    let mut last = (start, alloc.allocation_count());
+    let sampler = Periodic::spawn("peak_alloc-history", interval, move || {
+        let now = Instant::now();
+        let count = alloc.allocation_count();
+        let per_second = rate(last.1, count, now - last.0);
+        ALLOCATION_RATE.store(per_second.to_bits(), Ordering::Relaxed);
+        last = (now, count);
+        let sample = (elapsed_ms(start, now), alloc.current_usage());
+        writer.lock().unwrap_or_else(|e| e.into_inner()).push(sample);
+    });
+    HistoryHandle { ring, start, sampler }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{downsample, elapsed_ms, rate, Ring};
+    use std::time::{Duration, Instant};
+
+    fn secs(s: u64) -> Duration {
+        Duration::from_secs(s)
+    }
+
+    #[test]
+    fn ring_wraps_around() {
+        let mut ring = Ring::new(3);
+        assert_eq!(0, ring.iter().count());
+        ring.push((1, 10));
+        ring.push((2, 20));
+        assert_eq!(vec![(1, 10), (2, 20)], ring.iter().copied().collect::<Vec<_>>());
+        ring.push((3, 30));
+        ring.push((4, 40));
+        ring.push((5, 50));
+        assert_eq!(vec![(3, 30), (4, 40), (5, 50)], ring.iter().copied().collect::<Vec<_>>());
+
+        let mut empty = Ring::new(0);
+        empty.push((1, 10));
+        assert_eq!(0, empty.iter().count());
+    }
+
+    #[test]
+    fn elapsed_time_is_in_wrapping_milliseconds() {
+        let start = Instant::now();
+        assert_eq!(0, elapsed_ms(start, start));
+        assert_eq!(1500, elapsed_ms(start, start + Duration::from_micros(1_500_999)));
+        // about 49.7 days later, the time wraps around
+        let wrap = Duration::from_millis(u32::MAX as u64 + 1);
+        assert_eq!(42, elapsed_ms(start, start + wrap + Duration::from_millis(42)));
+        // never negative
+        assert_eq!(0, elapsed_ms(start + secs(1), start));
+    }
+
+    #[test]
+    fn rate_is_scaled_to_one_second() {
+        assert_eq!(2000.0, rate(100, 200, Duration::from_millis(50)));
+        assert_eq!(0.0, rate(100, 100, secs(1)));
+        assert_eq!(0.0, rate(100, 200, Duration::ZERO));
+    }
+
+    #[test]
+    fn compact_samples_are_smaller() {
+        assert!(std::mem::size_of::<super::Sample>() < std::mem::size_of::<(Duration, usize)>());
+    }
+
+    #[test]
+    fn downsampling_keeps_the_max_of_each_bucket() {
+        let samples = (1..=10).map(|i| (secs(i), (i as usize * 7) % 10)).collect::<Vec<_>>();
+        // usages: 7 4 1 8 5 2 9 6 3 0
+        assert_eq!(vec![(secs(5), 8), (secs(10), 9)], downsample(&samples, 2));
+        assert_eq!(vec![(secs(3), 7), (secs(6), 8), (secs(10), 9)], downsample(&samples, 3));
+        assert_eq!(samples, downsample(&samples, 10));
+        assert_eq!(samples, downsample(&samples, 100));
+        assert!(downsample(&samples, 0).is_empty());
+        assert!(downsample(&[], 4).is_empty());
+    }
+}
@@ -0,0 +1,276 @@
+//! Renders the statistics of the allocator in the InfluxDB line protocol,
+//! and (with the `influx-http` feature) periodically posts them to a server.
+//!
+//! A record looks like this:
+//! ```text
+//! heap,host=server\ 01 current=1024i,peak=4096i,allocations=12i,deallocations=8i,schema=2i 1700000000000000000
+//! ```
+//! The fields are integers (hence the `i` suffix) and the timestamp is the
+//! number of nanoseconds since the unix epoch.
+
+use std::fmt::{self, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The characters which must be escaped in a measurement name
+const MEASUREMENT_SPECIALS: &[char] = &[',', ' ', '\\'];
+/// The characters which must be escaped in tag keys and values
+const TAG_SPECIALS: &[char] = &[',', '=', ' ', '\\'];
+
+/// The values of the fields of one record
+pub(crate) struct Fields {
+    pub(crate) current: usize,
+    pub(crate) peak: usize,
+    pub(crate) allocations: usize,
+    pub(crate) deallocations: usize,
+}
+
+/// Returns the current time in nanoseconds since the unix epoch
+pub(crate) fn timestamp() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|t| t.as_nanos())
+        .unwrap_or(0)
+}
+
+/// Writes `text` with a backslash in front of each of the special characters.
+/// A backslash is itself escaped so that a trailing one can never swallow the
+/// separator which follows it.
+fn escape<W: Write>(out: &mut W, text: &str, specials: &[char]) -> fmt::Result {
+    for c in text.chars() {
+        if specials.contains(&c) {
+            out.write_char('\\')?;
+        }
+        out.write_char(c)?;
+    }
+    Ok(())
+}
+
+/// Writes one line protocol record (without the trailing newline). The tags
+/// with an empty key or value are skipped since the protocol forbids them.
+pub(crate) fn write_line<W, I, K, V>(
+    out: &mut W,
+    measurement: &str,
+    tags: I,
+    fields: &Fields,
+    timestamp: u128,
+) -> fmt::Result
+where
+    W: Write,
+    I: IntoIterator<Item = (K, V)>,
+    K: AsRef<str>,
+    V: AsRef<str>,
+{
+    escape(out, measurement, MEASUREMENT_SPECIALS)?;
+    for (key, value) in tags {
+        let (key, value) = (key.as_ref(), value.as_ref());
+        if key.is_empty() || value.is_empty() {
+            continue;
+        }
+        out.write_char(',')?;
+        escape(out, key, TAG_SPECIALS)?;
+        out.write_char('=')?;
+        escape(out, value, TAG_SPECIALS)?;
+    }
+    write!(
+        out,
+        " current={}i,peak={}i,allocations={}i,deallocations={}i,schema={}i {}",
+        fields.current,
+        fields.peak,
+        fields.allocations,
+        fields.deallocations,
+        crate::STATS_SCHEMA_VERSION,
+        timestamp
+    )
+}
+
+#[cfg(feature = "influx-http")]
+pub(crate) use self::http::start;
+#[cfg(feature = "influx-http")]
+pub use self::http::InfluxConfig;
+
+#[cfg(feature = "influx-http")]
+mod http {
+    use std::fmt::Write as _;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use std::time::Duration;
+
+    use crate::exporter::{self, ExporterHandle};
+    use crate::periodic::Scratch;
+    use crate::PeakAlloc;
+
+    /// The configuration of the influx poster (see
+    /// `PeakAlloc::start_influx_poster`)
+    pub struct InfluxConfig {
+        /// The address of the InfluxDB server
+        pub addr: SocketAddr,
+        /// The path (and query) of the write endpoint, e.g.
+        /// `/write?db=metrics` (v1) or
+        /// `/api/v2/write?org=acme&bucket=metrics&precision=ns` (v2)
+        pub path: String,
+        /// The name of the measurement ("peak_alloc" by default)
+        pub measurement: String,
+        /// The tags attached to each record
+        pub tags: Vec<(String, String)>,
+        /// The API token sent in the `Authorization` header, if any
+        pub token: Option<String>,
+        /// The delay between two records (10s by default)
+        pub interval: Duration,
+        /// The connect, read and write timeout of the requests (1s by default)
+        pub timeout: Duration,
+    }
+
+    impl InfluxConfig {
+        /// Creates a poster configuration for the given server and endpoint
+        /// with the default measurement, interval and timeout, and no tags.
+        pub fn new(addr: SocketAddr, path: impl Into<String>) -> Self {
+            InfluxConfig {
+                addr,
+                path: path.into(),
+                measurement: "peak_alloc".to_string(),
+                tags: Vec::new(),
+                token: None,
+                interval: Duration::from_secs(10),
+                timeout: Duration::from_secs(1),
+            }
+        }
+        /// Sets the name of the measurement
+        pub fn measurement(mut self, measurement: impl Into<String>) -> Self {
+            self.measurement = measurement.into();
+            self
+        }
+        /// Adds a tag to each record
+        pub fn tag(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+            self.tags.push((key.into(), value.into()));
+            self
+        }
+        /// Sets the API token sent in the `Authorization` header
+        pub fn token(mut self, token: impl Into<String>) -> Self {
+            self.token = Some(token.into());
+            self
+        }
+        /// Sets the delay between two records
+        pub fn interval(mut self, interval: Duration) -> Self {
+            self.interval = interval;
+            self
+        }
+        /// Sets the timeout of the requests
+        pub fn timeout(mut self, timeout: Duration) -> Self {
+            self.timeout = timeout;
+            self
+        }
+    }
+
+    /// Sends `request` and returns true iff the server answered with a 2xx
+    fn post(config: &InfluxConfig, request: &[u8]) -> std::io::Result<bool> {
+        let mut stream = TcpStream::connect_timeout(&config.addr, config.timeout)?;
+        stream.set_read_timeout(Some(config.timeout))?;
+        stream.set_write_timeout(Some(config.timeout))?;
+        stream.write_all(request)?;
+        // "HTTP/1.1 204": the status code is all we care about
+        let mut status = [0_u8; 12];
+        stream.read_exact(&mut status)?;
+        Ok(status.starts_with(b"HTTP/") && status[9] == b'2')
+    }
+
+    /// Spawns the poster thread
+    pub(crate) fn start(alloc: PeakAlloc, config: InfluxConfig) -> ExporterHandle {
+        // allocated once and reused for all the requests
+        let mut buffers = Scratch::new();
+        exporter::spawn("peak_alloc-influx", config.interval, move || {
+            let (body, request) = buffers.get_or_init(|| (String::with_capacity(256), String::with_capacity(512)));
+            body.clear();
+            let _ = super::write_line(
+                &mut *body,
+                &config.measurement,
+                config.tags.iter().map(|(k, v)| (k, v)),
+                &super::Fields {
+                    current: alloc.current_usage(),
+                    peak: alloc.peak_usage(),
+                    allocations: alloc.allocation_count(),
+                    deallocations: alloc.deallocation_count(),
+                },
+                super::timestamp(),
+            );
+            body.push('\n');
+
+            request.clear();
+            let _ = write!(
+                request,
+                "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: text/plain; charset=utf-8\r\n",
+                config.path, config.addr
+            );
+            if let Some(token) = config.token.as_ref() {
+                let _ = write!(request, "Authorization: Token {}\r\n", token);
+            }
+            let _ = write!(
+                request,
+                "Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            post(&config, request.as_bytes()).unwrap_or(false)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{write_line, Fields};
+
+    fn line(measurement: &str, tags: &[(&str, &str)]) -> String {
+        let fields = Fields {
+            current: 1024,
+            peak: 4096,
+            allocations: 12,
+            deallocations: 8,
+        };
+        let mut out = String::new();
+        write_line(&mut out, measurement, tags.iter().copied(), &fields, 1_700_000_000_123_456_789).unwrap();
+        out
+    }
+
+    #[test]
+    fn fields_are_integers_and_timestamp_is_in_nanoseconds() {
+        assert_eq!(
+            "heap current=1024i,peak=4096i,allocations=12i,deallocations=8i,schema=2i 1700000000123456789",
+            line("heap", &[])
+        );
+    }
+    #[test]
+    fn tags_follow_the_measurement() {
+        assert_eq!(
+            "heap,host=a,region=eu current=1024i,peak=4096i,allocations=12i,deallocations=8i,schema=2i 1700000000123456789",
+            line("heap", &[("host", "a"), ("region", "eu")])
+        );
+    }
+    #[test]
+    fn measurement_escapes_commas_and_spaces_only() {
+        let out = line("my heap,v=2", &[]);
+        assert!(out.starts_with(r"my\ heap\,v=2 current="), "{}", out);
+    }
+    #[test]
+    fn tags_escape_commas_equals_and_spaces() {
+        let out = line("heap", &[("host name", "server 01"), ("k=v", "a,b=c")]);
+        assert!(
+            out.starts_with(r"heap,host\ name=server\ 01,k\=v=a\,b\=c current="),
+            "{}",
+            out
+        );
+    }
+    #[test]
+    fn backslashes_are_escaped() {
+        let out = line(r"heap\", &[("path", r"C:\temp\")]);
+        assert!(out.starts_with(r"heap\\,path=C:\\temp\\ current="), "{}", out);
+    }
+    #[test]
+    fn empty_tags_are_skipped() {
+        let out = line("heap", &[("", "x"), ("y", ""), ("host", "a")]);
+        assert!(out.starts_with("heap,host=a current="), "{}", out);
+    }
+    #[test]
+    fn unicode_is_kept_verbatim() {
+        let out = line("tas", &[("région", "île de france")]);
+        assert!(out.starts_with(r"tas,région=île\ de\ france current="), "{}", out);
+    }
+}
@@ -0,0 +1,111 @@
+//! A parallel set of counters for the "large" allocations, those at or above
+//! a configurable threshold. Such blocks are typically mmap-backed and handed
+//! back to the OS on free: they behave quite differently from the churn of
+//! small objects.
+//!
+//! # Note
+//! Whether a block is large is decided by its size and the *current*
+//! threshold. The threshold should hence be set early, before any large
+//! block is allocated.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// The size from which an allocation is large (0 means no tracking)
+static THRESHOLD: AtomicUsize = AtomicUsize::new(0);
+/// The number of bytes currently held in large blocks
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+/// The maximum number of bytes held in large blocks at once
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+/// The number of large allocations
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// Sets the size from which an allocation is large (0 to stop tracking)
+pub(crate) fn set_threshold(bytes: usize) {
+    THRESHOLD.store(bytes, Ordering::Relaxed);
+}
+
+/// Returns the size from which an allocation is large (0 if none is)
+pub(crate) fn threshold() -> usize {
+    THRESHOLD.load(Ordering::Relaxed)
+}
+
+/// Returns true iff a block of `size` bytes is large, for the given
+/// `threshold` (that of the `Limits` of the allocation)
+#[inline]
+fn is_large(size: usize, threshold: usize) -> bool {
+    threshold > 0 && size >= threshold
+}
+
+/// Accounts for `delta` more bytes held in large blocks
+fn grow(delta: usize) {
+    let usage = CURRENT.fetch_add(delta, Ordering::Relaxed).wrapping_add(delta);
+    PEAK.fetch_max(usage, Ordering::Relaxed);
+}
+
+/// Accounts for `delta` less bytes held in large blocks
+fn shrink(delta: usize) {
+    CURRENT.fetch_sub(delta, Ordering::Relaxed);
+}
+
+/// Accounts for the allocation of a block of `size` bytes
+#[inline]
+pub(crate) fn on_alloc(size: usize, threshold: usize) {
+    if is_large(size, threshold) {
+        grow(size);
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Accounts for the deallocation of a block of `size` bytes
+#[inline]
+pub(crate) fn on_dealloc(size: usize, threshold: usize) {
+    if is_large(size, threshold) {
+        shrink(size);
+    }
+}
+
+/// Accounts for the resizing of a block. A block which crosses the threshold
+/// moves between the two sets of counters: growing past it counts as a
+/// large allocation.
+#[inline]
+pub(crate) fn on_realloc(old_size: usize, new_size: usize, threshold: usize) {
+    match (is_large(old_size, threshold), is_large(new_size, threshold)) {
+        (false, false) => {}
+        (false, true) => on_alloc(new_size, threshold),
+        (true, false) => shrink(old_size),
+        (true, true) if new_size >= old_size => grow(new_size - old_size),
+        (true, true) => shrink(old_size - new_size),
+    }
+}
+
+/// Returns the number of bytes currently held in large blocks
+pub(crate) fn current() -> usize {
+    CURRENT.load(Ordering::Relaxed)
+}
+
+/// Returns the maximum number of bytes held in large blocks at once
+pub(crate) fn peak() -> usize {
+    PEAK.load(Ordering::Relaxed)
+}
+
+/// Returns the number of large allocations
+pub(crate) fn allocations() -> usize {
+    ALLOCATIONS.load(Ordering::Relaxed)
+}
+
+/// Returns the current usage, the peak and the number of allocations
+pub(crate) fn counters() -> [usize; 3] {
+    [CURRENT.load(Ordering::Relaxed), PEAK.load(Ordering::Relaxed), ALLOCATIONS.load(Ordering::Relaxed)]
+}
+
+/// Sets the current usage, the peak and the number of allocations
+pub(crate) fn set_counters([current, peak, allocations]: [usize; 3]) {
+    CURRENT.store(current, Ordering::Relaxed);
+    PEAK.store(peak, Ordering::Relaxed);
+    ALLOCATIONS.store(allocations, Ordering::Relaxed);
+}
+
+/// Resets the peak to the current usage (see `reset_mark`)
+pub(crate) fn reset_peak() {
+    crate::tracker::reset_mark(&PEAK, &CURRENT);
+}
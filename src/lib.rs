@@ -20,16 +20,447 @@
 //! This module provides a dead simple low-overhead wrapper around the system
 //! allocator which lets a program know its own memory consumption and peak
 //! memory consumption at runtime.
+//!
+//! # `no_std`
+//! Without the (default) `std` feature, the crate is `no_std`: `PeakAlloc`
+//! then only gives access to the counters, which are maintained by a
+//! `TrackingAlloc` wrapped around an allocator of your own. All the other
+//! features require `std`.
+//!
+//! # `unsafe` code
+//! The statistics API is compiled under `#![deny(unsafe_code)]`: the
+//! `unsafe` code is confined to the modules which implement the allocators
+//! (and to the debugging features which write into the blocks). These are
+//! the only modules marked `#[allow(unsafe_code)]` below. Reading the
+//! statistics is hence possible from a crate which forbids `unsafe`:
+//!
+//! ```
+//! #![forbid(unsafe_code)]
+//! use peak_alloc::PeakAlloc;
+//!
+//! #[global_allocator]
+//! static PEAK_ALLOC: PeakAlloc = PeakAlloc;
+//!
+//! fn main() {
+//!     let data = vec![0_u8; 1024];
+//!     let stats = PEAK_ALLOC.stats();
+//!     assert!(stats.peak_usage >= stats.current_usage);
+//!     drop(data);
+//! }
+//! ```
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+#![cfg_attr(all(peak_alloc_nightly, feature = "oom-report"), feature(alloc_error_hook))]
+#![deny(unsafe_code)]
 
-use std::alloc::{GlobalAlloc, Layout, System};
-use std::sync::atomic::{AtomicUsize, Ordering};
+// the code generated by `#[instrument_module]` refers to `::peak_alloc`
+#[cfg(all(test, feature = "module-tags"))]
+extern crate self as peak_alloc;
 
-/// This atomic counter monitors the amount of memory (in bytes) that is
-/// currently allocated for this process.
-static CURRENT: AtomicUsize = AtomicUsize::new(0);
-/// This atomic counter monitors the maximum amount of memory (in bytes) that
-/// has been allocated for this process over the course of its life.
-static PEAK: AtomicUsize = AtomicUsize::new(0);
+use core::alloc::Layout;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+#[cfg(feature = "std")]
+use core::{sync::atomic::AtomicU32, time::Duration};
+#[cfg(feature = "std")]
+use std::io::Write;
+#[cfg(feature = "std")]
+use std::time::Instant;
+
+use config::Limits;
+use tracker::clamp;
+
+mod budget;
+mod bytesize;
+#[cfg(feature = "std")]
+mod breakdown;
+#[cfg(feature = "std")]
+mod chart;
+mod config;
+pub mod fmt;
+#[cfg(feature = "std")]
+#[allow(unsafe_code)]
+mod clock;
+#[cfg(feature = "decayed-stats")]
+mod decay;
+mod delta;
+mod entry_points;
+mod handle;
+#[cfg(feature = "std")]
+#[allow(unsafe_code)]
+mod events;
+#[cfg(feature = "external-locations")]
+mod external;
+#[cfg(any(feature = "statsd", feature = "influx-http"))]
+mod exporter;
+#[cfg(feature = "ffi")]
+#[allow(unsafe_code)]
+pub mod ffi;
+#[cfg(feature = "histogram")]
+mod histogram;
+#[cfg(feature = "std")]
+mod history;
+#[cfg(feature = "std")]
+mod influx;
+mod large;
+mod overalign;
+#[cfg(feature = "std")]
+mod measure;
+mod macros;
+mod metrics;
+#[allow(unsafe_code)]
+mod mirror;
+#[cfg(feature = "pointer-map")]
+mod lifetime;
+#[cfg(feature = "oom-report")]
+#[allow(unsafe_code)]
+mod oom;
+#[cfg(feature = "poison")]
+#[allow(unsafe_code)]
+mod poison;
+#[cfg(feature = "std")]
+mod periodic;
+#[cfg(feature = "per-thread")]
+mod per_thread;
+#[cfg(all(feature = "psi", target_os = "linux"))]
+mod psi;
+#[cfg(feature = "pointer-map")]
+mod ptrmap;
+#[cfg(feature = "quarantine")]
+#[allow(unsafe_code)]
+mod quarantine;
+#[cfg(feature = "redzones")]
+#[allow(unsafe_code)]
+mod redzones;
+mod report;
+mod rounding;
+#[cfg(feature = "std")]
+#[allow(unsafe_code)]
+mod rss;
+mod sampling;
+#[cfg(feature = "std")]
+mod smoothing;
+#[cfg(feature = "tracing-attribution")]
+mod spans;
+mod state;
+mod stats;
+#[cfg(feature = "std")]
+#[allow(unsafe_code)]
+mod system;
+#[cfg(feature = "module-tags")]
+#[allow(unsafe_code)]
+mod tags;
+#[cfg(feature = "statsd")]
+mod statsd;
+#[cfg(feature = "std")]
+#[allow(unsafe_code)]
+mod sync;
+#[cfg(feature = "timed-accounting")]
+mod timing;
+mod tracker;
+#[cfg(feature = "std")]
+mod threads;
+mod top;
+#[allow(unsafe_code)]
+mod tracking;
+pub mod units;
+#[cfg(feature = "actual-size")]
+#[allow(unsafe_code)]
+mod usable;
+#[cfg(feature = "usdt")]
+#[allow(unsafe_code)]
+mod usdt;
+#[cfg(all(feature = "socket", unix))]
+mod socket;
+#[cfg(feature = "std")]
+mod scope;
+mod slack;
+#[cfg(feature = "std")]
+mod spikes;
+#[cfg(feature = "std")]
+mod storm;
+#[cfg(feature = "std")]
+mod watchdog;
+#[cfg(feature = "windowed-peak")]
+mod windowed;
+#[cfg(feature = "zeroize-on-free")]
+#[allow(unsafe_code)]
+mod zeroize;
+
+#[cfg(feature = "histogram")]
+pub use histogram::{
+    AlignmentReport, LiveSizeClasses, SizeClassUsage, SizeHistogram, ALIGN_CLASSES, SIZE_CLASSES,
+};
+#[cfg(feature = "std")]
+pub use history::HistoryHandle;
+#[cfg(feature = "influx-http")]
+pub use influx::InfluxConfig;
+#[cfg(feature = "pointer-map")]
+pub use lifetime::LifetimeHistogram;
+pub use budget::{BudgetCharge, BudgetExceeded, MemoryBudget};
+pub use bytesize::ByteSize;
+pub use config::{Config, ConfigError};
+#[cfg(feature = "decayed-stats")]
+pub use decay::LoadAverageSampler;
+pub use delta::StatsDelta;
+pub use entry_points::EntryPoint;
+#[cfg(feature = "std")]
+pub use measure::{measure_construction, measure_drop};
+#[cfg(feature = "std")]
+pub use events::Event;
+#[cfg(any(feature = "statsd", feature = "influx-http"))]
+pub use exporter::ExporterHandle;
+#[cfg(feature = "external-locations")]
+pub use external::{ExternalRecord, RECENT_EXTERNAL};
+#[cfg(feature = "oom-report")]
+pub use oom::{alloc_or_report, report_out_of_memory, reserve_or_report, EMERGENCY_RESERVE};
+#[cfg(all(feature = "psi", target_os = "linux"))]
+pub use psi::{Pressure, PressureConfig, PressureEvent, PressureHandle, PressureKind, PsiAverages};
+pub use handle::StatsHandle;
+pub use metrics::{MetricsBuffer, METRICS};
+pub use overalign::{OveralignedRecord, RECENT_OVERALIGNED};
+pub use report::Report;
+#[cfg(feature = "std")]
+pub use breakdown::{BreakdownReport, BreakdownRow, Dimension, SortBy};
+#[cfg(feature = "per-thread")]
+pub use per_thread::PEAK_BREAKDOWN_THREADS;
+pub use rounding::{RoundMode, ROUND_DECIMALS};
+pub use top::TOP_ALLOCATIONS;
+pub use tracker::{AllocationTracker, ResetPolicy};
+pub use tracking::{DynAlloc, TrackingAlloc};
+#[cfg(feature = "std")]
+pub use tracking::DynTrackingAlloc;
+#[cfg(feature = "actual-size")]
+pub use usable::FragmentationReport;
+#[cfg(feature = "std")]
+pub use rss::{process_rss, FootprintReport};
+pub use sampling::SampleRateGuard;
+#[cfg(feature = "std")]
+pub use scope::TestScope;
+#[cfg(feature = "std")]
+pub use smoothing::SmoothingHandle;
+#[cfg(feature = "tracing-attribution")]
+pub use spans::SpanMemory;
+#[cfg(all(feature = "socket", unix))]
+pub use socket::StatsServerHandle;
+#[cfg(feature = "module-tags")]
+pub use peak_alloc_macros::instrument_module;
+#[cfg(feature = "module-tags")]
+pub use tags::{Tag, TagGuard, TagUsage, Tagged, TAG_SLOTS};
+#[cfg(feature = "std")]
+pub use spikes::{SpikeConfig, SpikeReport, SpikeWatchHandle};
+pub use state::AllocatorState;
+pub use stats::{Stats, STATS_SCHEMA_VERSION};
+#[cfg(feature = "std")]
+pub use threads::ForbidAllocGuard;
+#[cfg(feature = "timed-accounting")]
+pub use timing::{AccountingLatency, LATENCY_BUCKETS};
+#[cfg(feature = "std")]
+pub use watchdog::{WatchdogAlert, WatchdogConfig, WatchdogHandle};
+
+/// The counters of the memory (in bytes) that is allocated for this process
+static TRACKER: AllocationTracker = AllocationTracker::new();
+/// This atomic counter holds the number of bytes which are subtracted from
+/// the values reported by `current_usage` and `peak_usage`. It is a mere
+/// presentation offset: the true counters are never affected by it.
+static BASELINE: AtomicUsize = AtomicUsize::new(0);
+/// This atomic counter holds the usage recorded upon the very first
+/// allocation of the process. It is set once (0 means not captured yet).
+static PROCESS_BASELINE: AtomicUsize = AtomicUsize::new(0);
+/// Whether the startup of the program has been marked as complete (see
+/// `mark_startup_complete`)
+static STARTUP_MARKED: AtomicBool = AtomicBool::new(false);
+/// The usage and the allocation count when the startup was complete
+static STARTUP: [AtomicUsize; 2] = [AtomicUsize::new(0), AtomicUsize::new(0)];
+/// The values of the cumulative counters (total allocated, allocations and
+/// deallocations) when the current reporting window started (see
+/// `take_stats`)
+static WINDOW_START: [AtomicUsize; 3] = [AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0)];
+/// The allocations which would take the (raw) usage above this number of
+/// bytes are refused (0 means unlimited)
+static MEMORY_LIMIT: AtomicUsize = AtomicUsize::new(0);
+/// The usage (net of the baseline) above which the process is poisoned, see
+/// `assert_peak_under` (usize::MAX means that no ceiling is declared)
+static PEAK_CEILING: AtomicUsize = AtomicUsize::new(usize::MAX);
+/// Whether the usage has risen above the declared ceiling (see `poisoned`)
+static POISONED: AtomicBool = AtomicBool::new(false);
+/// Whether `reset_peak_usage` resets the peak to zero (see `ResetPolicy`)
+static RESET_TO_ZERO: AtomicBool = AtomicBool::new(false);
+/// The number of allocations (and reallocations) which failed, either because
+/// the system allocator returned null or because of the memory limit
+static FAILED_ALLOCS: AtomicUsize = AtomicUsize::new(0);
+/// The allocations smaller than this number of bytes are not accounted for
+static MIN_TRACKED_SIZE: AtomicUsize = AtomicUsize::new(0);
+/// An event is emitted whenever the memory usage rises above this number of
+/// bytes (0 means that no threshold is being watched)
+#[cfg(feature = "std")]
+static WATCH_THRESHOLD: AtomicUsize = AtomicUsize::new(0);
+/// The bits of the (f32) factor above which an allocation is an outlier: it
+/// is larger than that many times the average allocation (0 means that the
+/// outliers are not looked for)
+#[cfg(feature = "std")]
+static OUTLIER_FACTOR: AtomicU32 = AtomicU32::new(0);
+/// This flag remembers whether the accounting underflow warning has already
+/// been emitted (so that it is only ever emitted once).
+#[cfg(feature = "std")]
+static UNDERFLOW_WARNED: AtomicBool = AtomicBool::new(false);
+/// When this flag is set, any allocation aborts the process (see `freeze`)
+#[cfg(feature = "std")]
+static FROZEN: AtomicBool = AtomicBool::new(false);
+
+/// Accounts for the allocation of `size` bytes.
+#[inline]
+fn add_memory(size: usize, limits: &Limits) {
+    #[cfg(feature = "timed-accounting")]
+    let start = timing::TICKER.sampled().then(std::time::Instant::now);
+    #[cfg(feature = "std")]
+    check_outlier(size);
+    grow_memory(size, limits);
+    TRACKER.count_allocation(size);
+    top::on_alloc(size);
+    #[cfg(feature = "module-tags")]
+    tags::on_alloc();
+    #[cfg(feature = "per-thread")]
+    per_thread::on_alloc();
+    #[cfg(feature = "std")]
+    threads::on_alloc();
+    #[cfg(feature = "std")]
+    storm::on_alloc();
+    #[cfg(feature = "timed-accounting")]
+    if let Some(start) = start {
+        timing::record(start.elapsed());
+    }
+}
+/// Accounts for `delta` more bytes being in use, be it because a block was
+/// allocated or because it was grown. Everything downstream sees the clamped
+/// usage: a counter which has wrapped around stands for no usage at all.
+#[inline]
+#[allow(unused_variables)]
+fn grow_memory(delta: usize, limits: &Limits) {
+    let raw = TRACKER.grow(delta);
+    let (prev, usage) = (clamp(raw), clamp(raw.wrapping_add(delta)));
+    mirror(usage);
+    #[cfg(feature = "usdt")]
+    usdt::on_grow(usage);
+    #[cfg(feature = "std")]
+    smoothing::on_grow(usage);
+    #[cfg(feature = "module-tags")]
+    tags::on_grow(delta);
+    #[cfg(feature = "std")]
+    measure::on_grow(delta);
+    #[cfg(feature = "per-thread")]
+    per_thread::on_grow(delta, usage);
+    #[cfg(feature = "windowed-peak")]
+    windowed::on_usage(usage);
+    if PROCESS_BASELINE.load(Ordering::Relaxed) == 0 {
+        capture_process_baseline(usage);
+    }
+    // the raw usage is never below the net one
+    if usage > PEAK_CEILING.load(Ordering::Relaxed) {
+        check_ceiling(usage);
+    }
+    #[cfg(feature = "std")]
+    {
+        let threshold = limits.watch_threshold;
+        if threshold > 0 && prev < threshold && usage >= threshold {
+            #[cfg(feature = "usdt")]
+            usdt::on_threshold_crossed(usage, threshold);
+            events::emit(Event::ThresholdCrossed { usage, threshold, thread: threads::fast_thread_id() });
+        }
+    }
+}
+/// Poisons the process if the (clamped) `usage` is above the declared
+/// ceiling, net of the baseline (see `assert_peak_under`)
+#[cold]
+fn check_ceiling(usage: usize) {
+    if usage.saturating_sub(BASELINE.load(Ordering::Relaxed)) > PEAK_CEILING.load(Ordering::Relaxed) {
+        POISONED.store(true, Ordering::SeqCst);
+    }
+}
+/// Emits an event if the allocation of `size` bytes is an outlier. The mean
+/// is derived from the counters: it is the total allocated divided by the
+/// number of allocations (the growth of the reallocated blocks included).
+#[cfg(feature = "std")]
+#[inline]
+fn check_outlier(size: usize) {
+    let factor = f32::from_bits(OUTLIER_FACTOR.load(Ordering::Relaxed));
+    if factor > 0.0 {
+        if let Some(mean) = TRACKER.total_allocated().checked_div(TRACKER.allocation_count()) {
+            if size as f32 > factor * mean as f32 {
+                let thread = threads::fast_thread_id();
+                events::emit(Event::OutlierAllocation { size, mean, thread });
+            }
+        }
+    }
+}
+/// Writes the new (raw) usage to the user supplied mirror, if any
+#[inline]
+fn mirror(usage: usize) {
+    if let Some(mirror) = mirror::target() {
+        mirror.store(usage.saturating_sub(BASELINE.load(Ordering::Relaxed)), Ordering::Relaxed);
+    }
+}
+/// Records the usage upon the first allocation. Only the first caller wins.
+#[cold]
+fn capture_process_baseline(usage: usize) {
+    let _ = PROCESS_BASELINE.compare_exchange(0, usage, Ordering::Relaxed, Ordering::Relaxed);
+}
+/// Accounts for the deallocation of `size` bytes. In debug builds, this
+/// also checks that no more bytes are released than what is currently
+/// accounted for (which would mean that some `Layout` was inconsistent).
+#[inline]
+fn sub_memory(size: usize) {
+    shrink_memory(size);
+    TRACKER.count_deallocation();
+}
+/// Accounts for `delta` less bytes being in use, be it because a block was
+/// released or because it was shrunk.
+#[inline]
+fn shrink_memory(delta: usize) {
+    let prev = TRACKER.shrink(delta);
+    mirror(prev.wrapping_sub(delta));
+    #[cfg(feature = "windowed-peak")]
+    windowed::on_usage(prev);
+    #[cfg(feature = "module-tags")]
+    tags::on_shrink(delta);
+    #[cfg(feature = "std")]
+    measure::on_shrink(delta);
+    #[cfg(feature = "per-thread")]
+    per_thread::on_shrink(delta);
+    if cfg!(debug_assertions) && prev < delta {
+        #[cfg(feature = "std")]
+        warn_underflow_once();
+    }
+}
+/// Emits a warning on stderr to signal that the accounting has underflowed.
+/// The warning is emitted at most once per process; this function returns
+/// true iff it is the one call that emitted the warning.
+#[cfg(feature = "std")]
+#[cold]
+fn warn_underflow_once() -> bool {
+    if UNDERFLOW_WARNED.swap(true, Ordering::Relaxed) {
+        return false;
+    }
+    // writing a static message to the (unbuffered) stderr does not allocate
+    let _ = std::io::stderr().write_all(
+        b"peak_alloc: warning: dealloc released more bytes than currently \
+          allocated; memory accounting is corrupt (inconsistent Layout?)\n",
+    );
+    true
+}
+/// Aborts the process if the allocator is frozen. Panicking is not an option
+/// here: the panic machinery itself allocates.
+#[cfg(feature = "std")]
+#[inline]
+fn check_frozen() {
+    if FROZEN.load(Ordering::Relaxed) {
+        abort_frozen();
+    }
+}
+#[cfg(feature = "std")]
+#[cold]
+fn abort_frozen() -> ! {
+    let _ = std::io::stderr().write_all(b"peak_alloc: allocation while the allocator is frozen, aborting\n");
+    std::process::abort()
+}
 
 /// This structure implements a dead simple low-overhead wrapper around the
 /// system allocator. It lets a program know its own memory and peak memory
@@ -55,118 +486,1733 @@ static PEAK: AtomicUsize = AtomicUsize::new(0);
 /// fn main() {
 ///     // Do your funky stuff...
 ///
-///     let current_mem = PEAK_ALLOC.current_usage_as_mb();
+///     let current_mem = PEAK_ALLOC.current_usage_in_units(peak_alloc::units::MIB);
 ///     println!("This program currently uses {} MB of RAM.", current_mem);
-///     let peak_mem = PEAK_ALLOC.peak_usage_as_gb();
+///     let peak_mem = PEAK_ALLOC.peak_usage_in_units(peak_alloc::units::GIB);
 ///     println!("The max amount that was used {}", peak_mem);
 /// }
 /// ```
-#[derive(Debug, Default, Copy, Clone)]
+#[derive(Default, Copy, Clone)]
 pub struct PeakAlloc;
 
+/// Rather than the (useless) name of the unit struct, the debug output shows
+/// the live statistics of the allocator.
+impl core::fmt::Debug for PeakAlloc {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("PeakAlloc")
+            .field("current", &self.current_usage())
+            .field("peak", &self.peak_usage())
+            .field("all_time_peak", &self.all_time_peak_usage())
+            .field("total_allocated", &self.total_allocated())
+            .field("allocations", &self.allocation_count())
+            .field("deallocations", &self.deallocation_count())
+            .finish()
+    }
+}
+
+/// Writes the current and the peak usage in bytes (`current: 1572864 B, peak:
+/// 3145728 B`), or in the largest suitable unit with the alternate flag
+/// (`{:#}` gives `current: 1.50 MiB, peak: 3.00 MiB`). The precision, if
+/// any, is the number of decimals. See `display_in` for the other units.
+impl core::fmt::Display for PeakAlloc {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let unit = if f.alternate() { units::Unit::Auto } else { units::Unit::Bytes };
+        core::fmt::Display::fmt(&self.display_in(unit), f)
+    }
+}
+
 impl PeakAlloc {
     /// Returns the number of bytes that are currently allocated to the process
+    /// (net of the reported baseline, if any).
     pub fn current_usage(&self) -> usize {
-        CURRENT.load(Ordering::Relaxed)
+        TRACKER.current_usage().saturating_sub(BASELINE.load(Ordering::Relaxed))
     }
     /// Returns the maximum number of bytes that have been allocated to the
-    /// process over the course of its life.
+    /// process over the course of its life (net of the reported baseline, if
+    /// any).
     pub fn peak_usage(&self) -> usize {
-        PEAK.load(Ordering::Relaxed)
+        TRACKER.peak_usage().saturating_sub(BASELINE.load(Ordering::Relaxed))
+    }
+    /// Sets the size (in bytes) below which the allocations are not accounted
+    /// for at all. This trades completeness for a reduced overhead on the
+    /// workloads dominated by tiny allocations.
+    ///
+    /// # Note
+    /// Since the small allocations are ignored, `current_usage`, `peak_usage`
+    /// and the other statistics undercount the memory actually in use. This
+    /// threshold should be set once, early: a block allocated before the
+    /// threshold is changed may be released after, hence skewing the
+    /// counters.
+    pub fn set_min_tracked_size(&self, bytes: usize) {
+        config::publish(|| MIN_TRACKED_SIZE.store(bytes, Ordering::Relaxed))
+    }
+    /// Sets the memory limit: the allocations (and reallocations) which would
+    /// take the memory usage above that many bytes fail, returning a null
+    /// pointer. 0 removes the limit, which is the default.
+    ///
+    /// # Note
+    /// The limit applies to the memory actually accounted for, regardless of
+    /// the baseline. It is best effort: concurrent allocations are checked
+    /// against the same usage, and may exceed the limit together.
+    pub fn set_memory_limit(&self, bytes: usize) {
+        config::publish(|| MEMORY_LIMIT.store(bytes, Ordering::Relaxed))
+    }
+    /// Returns the number of allocations and reallocations which failed
+    /// (returned a null pointer), whether the allocator was out of memory,
+    /// the memory limit refused them or they were rejected as invalid (see
+    /// `invalid_request_count`). Collections abort the program upon such
+    /// a failure, but the fallible APIs (e.g. `Vec::try_reserve`) fail
+    /// silently.
+    pub fn failed_allocation_count(&self) -> usize {
+        FAILED_ALLOCS.load(Ordering::Relaxed)
+    }
+    /// Prepares the out of memory report: sets an emergency reserve of
+    /// `EMERGENCY_RESERVE` bytes aside, which is released when the report is
+    /// written. The report lists the usage, the peak, the memory limit, the
+    /// failing layout (and the busiest size classes, with `histogram`).
+    ///
+    /// # Note
+    /// On stable Rust, the report is only written when an allocation made
+    /// through `alloc_or_report` or `reserve_or_report` fails (or when the
+    /// program calls `report_out_of_memory`). Built with
+    /// `--cfg peak_alloc_nightly` on nightly, the reporter is also installed as
+    /// the alloc error hook, which covers all the allocations.
+    #[cfg(feature = "oom-report")]
+    pub fn install_oom_reporter(&self) {
+        oom::install()
+    }
+    /// Returns the memory limit (see `set_memory_limit`), or None if the
+    /// allocations are not limited
+    pub fn memory_limit(&self) -> Option<usize> {
+        match MEMORY_LIMIT.load(Ordering::Relaxed) {
+            0 => None,
+            bytes => Some(bytes),
+        }
+    }
+    /// Declares that the memory usage must stay under `ceiling` bytes from
+    /// now on (net of the baseline): the process is poisoned the instant the
+    /// usage rises above it, be it for a single allocation. Panicking within
+    /// the allocator is not an option, hence the test must check `poisoned()`
+    /// afterward: the regression is caught even if the memory was released
+    /// in between.
+    ///
+    /// The process is poisoned right away if the usage is already above the
+    /// ceiling. Declaring another ceiling clears the poison.
+    ///
+    /// ```
+    /// # use peak_alloc::PeakAlloc;
+    /// # #[global_allocator]
+    /// # static PEAK_ALLOC: PeakAlloc = PeakAlloc;
+    /// PEAK_ALLOC.assert_peak_under(PEAK_ALLOC.current_usage() + (64 << 20));
+    /// let buffer = vec![0_u8; 1 << 20];
+    /// drop(buffer);
+    /// assert!(!PEAK_ALLOC.poisoned());
+    /// PEAK_ALLOC.clear_peak_ceiling();
+    /// ```
+    pub fn assert_peak_under(&self, ceiling: usize) {
+        PEAK_CEILING.store(usize::MAX, Ordering::SeqCst);
+        POISONED.store(self.current_usage() > ceiling, Ordering::SeqCst);
+        PEAK_CEILING.store(ceiling, Ordering::SeqCst);
+    }
+    /// Removes the ceiling declared with `assert_peak_under`, and clears the
+    /// poison
+    pub fn clear_peak_ceiling(&self) {
+        PEAK_CEILING.store(usize::MAX, Ordering::SeqCst);
+        POISONED.store(false, Ordering::SeqCst);
+    }
+    /// Returns true iff the memory usage has risen above the ceiling declared
+    /// with `assert_peak_under`
+    pub fn poisoned(&self) -> bool {
+        POISONED.load(Ordering::SeqCst)
+    }
+    /// Validates a configuration and applies all its settings at once (see
+    /// `Config`). Nothing is applied if it is refused. The configurations
+    /// (and the individual settings) applied concurrently do not mix up, and
+    /// `current_config` never sees one half applied. An allocation sees the
+    /// memory limit, the minimum tracked size, the large and the watch
+    /// thresholds of a single configuration; the other settings are each
+    /// read on their own, and may change while it is accounted for.
+    pub fn configure(&self, config: Config) -> Result<(), ConfigError> {
+        config::apply(&config)
+    }
+    /// Returns the settings in effect, whether they were applied by
+    /// `configure` or by the individual setters
+    pub fn current_config(&self) -> Config {
+        config::current()
+    }
+    /// Starts or stops accounting for the calls to one entry point of
+    /// `GlobalAlloc` (all of them are accounted for by default). E.g. with
+    /// `EntryPoint::Realloc` disabled, only the fresh allocations move the
+    /// counters.
+    ///
+    /// # Note
+    /// The counters then no longer balance out: a block grown by an
+    /// untracked reallocation is released with its new size, and the blocks
+    /// released by an untracked `dealloc` remain in use as far as the
+    /// statistics are concerned. The reallocations by copy (see `realloc`)
+    /// are governed by the `Alloc` and `Dealloc` entry points.
+    pub fn set_entry_point_tracked(&self, entry: EntryPoint, tracked: bool) {
+        config::exclusive(|| entry_points::set_tracked(entry, tracked))
+    }
+    /// Returns true iff the calls to the given entry point are accounted for
+    pub fn is_entry_point_tracked(&self, entry: EntryPoint) -> bool {
+        entry_points::is_tracked(entry)
+    }
+    /// Returns the maximum number of bytes that have been allocated to the
+    /// process over the course of its life (net of the reported baseline, if
+    /// any). Unlike `peak_usage`, this value is not affected by
+    /// `reset_peak_usage`.
+    pub fn all_time_peak_usage(&self) -> usize {
+        TRACKER.all_time_peak_usage().saturating_sub(BASELINE.load(Ordering::Relaxed))
+    }
+    /// Returns the cumulative number of bytes that have been allocated to the
+    /// process over the course of its life (regardless of their release).
+    pub fn total_allocated(&self) -> usize {
+        TRACKER.total_allocated()
+    }
+    /// Returns true iff the cumulative number of bytes allocated has ever
+    /// overflowed, in which case `total_allocated` saturates at `usize::MAX`.
+    /// This can hardly happen on a 64-bit target, but it can on a 32-bit one
+    /// (after 4 GiB of allocations).
+    pub fn total_allocated_overflowed(&self) -> bool {
+        TRACKER.total_allocated_overflowed()
+    }
+    /// Returns the number of allocations performed by the process
+    pub fn allocation_count(&self) -> usize {
+        TRACKER.allocation_count()
+    }
+    /// Returns the number of deallocations performed by the process
+    pub fn deallocation_count(&self) -> usize {
+        TRACKER.deallocation_count()
+    }
+    /// Returns the number of bytes currently charged to the linked memory
+    /// budgets (see `MemoryBudget::linked`): the memory the application
+    /// accounts for by itself, which may or may not have been allocated
+    /// through this allocator.
+    pub fn external_usage(&self) -> usize {
+        budget::external_usage()
+    }
+    /// Accounts for `bytes` of memory which the application manages by
+    /// itself, outside of any budget (e.g. a mapped file or a GPU buffer):
+    /// they count in `external_usage` until `record_external_dealloc`.
+    pub fn record_external_alloc(&self, bytes: usize) {
+        budget::add_external(bytes)
+    }
+    /// Accounts for the release of `bytes` of memory recorded with
+    /// `record_external_alloc`
+    pub fn record_external_dealloc(&self, bytes: usize) {
+        budget::sub_external(bytes)
+    }
+    /// Does what `record_external_alloc` does and, with the
+    /// `external-locations` feature, remembers the call site of the record
+    /// (see `recent_external_records`), to audit the manual accounting.
+    #[track_caller]
+    pub fn record_external_alloc_located(&self, bytes: usize) {
+        #[cfg(feature = "external-locations")]
+        external::record(bytes, false, core::panic::Location::caller());
+        self.record_external_alloc(bytes)
+    }
+    /// Does what `record_external_dealloc` does and, with the
+    /// `external-locations` feature, remembers the call site of the record
+    #[track_caller]
+    pub fn record_external_dealloc_located(&self, bytes: usize) {
+        #[cfg(feature = "external-locations")]
+        external::record(bytes, true, core::panic::Location::caller());
+        self.record_external_dealloc(bytes)
+    }
+    /// Returns the last `RECENT_EXTERNAL` records made with
+    /// `record_external_alloc_located` and `record_external_dealloc_located`,
+    /// the oldest first, along with their call sites.
+    ///
+    /// ```
+    /// use peak_alloc::PeakAlloc;
+    ///
+    /// #[global_allocator]
+    /// static PEAK_ALLOC: PeakAlloc = PeakAlloc;
+    ///
+    /// PEAK_ALLOC.record_external_alloc_located(4096);
+    /// let record = *PEAK_ALLOC.recent_external_records().last().unwrap();
+    /// assert_eq!((4096, false), (record.bytes, record.released));
+    /// assert!(record.location.file().ends_with(".rs"));
+    /// ```
+    #[cfg(feature = "external-locations")]
+    pub fn recent_external_records(&self) -> Vec<ExternalRecord> {
+        external::recent()
+    }
+    /// Returns the number of reallocations performed by the process (a block
+    /// resized in place or moved). The reallocations by copy (see `realloc`)
+    /// are counted as allocations and deallocations instead.
+    pub fn reallocation_count(&self) -> usize {
+        TRACKER.reallocation_count()
+    }
+    /// Returns the growth slack: an estimate of the bytes of the usage which
+    /// come from the geometric growth of the collections (say, the unused
+    /// capacity of a `Vec`). The bytes added by the reallocations count as
+    /// slack until a reallocation shrinks the block (which gives back its
+    /// delta) or until it is released (which gives back a share of the
+    /// slack, in proportion of its size to the usage).
+    ///
+    /// # Note
+    /// This is a heuristic: the allocator sees the capacities, not the
+    /// lengths, and the slack is not tracked per block. There is no slack at
+    /// all with the reallocations by copy (see `realloc`).
+    pub fn growth_slack_bytes(&self) -> usize {
+        slack::bytes()
+    }
+    /// Returns the number of bytes copied because a reallocation could not
+    /// resize its block in place (and moved it): the overhead which reserving
+    /// the capacity upfront (e.g. `Vec::with_capacity`) would have saved.
+    /// With the reallocations by copy (see `realloc`), all the copies count.
+    pub fn realloc_overhead_bytes(&self) -> usize {
+        TRACKER.realloc_copied_bytes()
+    }
+    /// Returns the number of bytes overwritten with zeros upon their release
+    /// (see the `zeroize-on-free` feature)
+    #[cfg(feature = "zeroize-on-free")]
+    pub fn wiped_bytes(&self) -> usize {
+        zeroize::wiped()
+    }
+    /// Returns the number of invalid requests the allocator rejected (by
+    /// returning null): the zero-sized allocations, and the reallocations to
+    /// a size which does not make a valid `Layout` (that is, a size of zero
+    /// or above `isize::MAX` once rounded up to the alignment). They are
+    /// counted by `failed_allocation_count` as well.
+    #[cfg(feature = "checked-layout")]
+    pub fn invalid_request_count(&self) -> usize {
+        system::invalid_requests()
+    }
+    /// Returns the size (in bytes) of the largest allocation performed by the
+    /// process over the course of its life.
+    pub fn largest_allocation(&self) -> usize {
+        TRACKER.largest_allocation()
+    }
+    /// Returns the sizes (in bytes) of the `TOP_ALLOCATIONS` largest
+    /// allocations performed by the process over the course of its life, in
+    /// decreasing order (the free slots are 0). The same size appears as many
+    /// times as it was allocated, and the growth of the reallocated blocks is
+    /// not taken into account.
+    pub fn top_allocations(&self) -> [usize; TOP_ALLOCATIONS] {
+        top::sizes()
+    }
+    /// Marks the calling thread as the main one (which is typically done early
+    /// in `main`). From then on, the allocations performed by that thread and
+    /// by all the others are counted separately (see
+    /// `main_thread_allocations` and `other_thread_allocations`). This is
+    /// useful for UI programs, where the main thread is latency sensitive.
+    ///
+    /// # Note
+    /// The threads are identified by the address of a thread local. Should
+    /// the marked thread exit, its id could hence be reused by a new thread.
+    #[cfg(feature = "std")]
+    pub fn mark_main_thread(&self) {
+        threads::mark_main_thread()
+    }
+    /// Returns the number of allocations performed by the main thread since
+    /// it was marked (see `mark_main_thread`).
+    #[cfg(feature = "std")]
+    pub fn main_thread_allocations(&self) -> usize {
+        threads::main_thread_allocations()
+    }
+    /// Returns the number of allocations performed by the threads other than
+    /// the main one since the latter was marked (see `mark_main_thread`).
+    #[cfg(feature = "std")]
+    pub fn other_thread_allocations(&self) -> usize {
+        threads::other_thread_allocations()
+    }
+    /// Returns the number of allocations performed by the calling thread
+    /// (since it started). The counter is thread local: reading it is cheap
+    /// and never contends with the other threads, which makes the delta
+    /// between two readings an exact answer to "did this thread allocate?",
+    /// even while other threads allocate. `forbid_alloc` is the guard flavor
+    /// of the same check.
+    #[cfg(feature = "std")]
+    pub fn thread_allocation_count(&self) -> usize {
+        threads::thread_allocations()
+    }
+    /// Returns the id of the calling thread, as found in the events and in
+    /// the records of the allocator: a small number (from 1 on), stable
+    /// within the thread and never reused by another one. Unlike
+    /// `std::thread::current().id()`, getting it never allocates.
+    #[cfg(feature = "std")]
+    pub fn current_thread_id(&self) -> u32 {
+        threads::fast_thread_id()
+    }
+    /// Returns the name of the live thread whose id (see `current_thread_id`)
+    /// is `thread`, for the reports. Only the threads which have allocated
+    /// (and which are among the 64 first ones alive at once) are known, and
+    /// they are forgotten when they exit.
+    #[cfg(feature = "per-thread")]
+    pub fn thread_name(&self, thread: u32) -> Option<String> {
+        per_thread::thread_name(thread)
+    }
+    /// Returns the breakdown of the memory per thread when the peak was last
+    /// reached: the (at most `PEAK_BREAKDOWN_THREADS`) threads holding the
+    /// most memory, along with their net number of bytes (what they
+    /// allocated minus what they released), the largest first.
+    ///
+    /// # Note
+    /// This is a best-effort approximation. The breakdown is only captured
+    /// when the peak rises by at least the step set by
+    /// `set_peak_breakdown_step` (1 MiB by default), and the nets are read
+    /// while the other threads keep allocating. A thread which releases the
+    /// memory allocated by another one has its own net decrease. At most 64
+    /// threads alive at once are accounted for.
+    #[cfg(feature = "per-thread")]
+    pub fn peak_thread_breakdown(&self) -> Vec<(std::thread::ThreadId, isize)> {
+        per_thread::breakdown()
+    }
+    /// Sets the minimum rise of the peak usage (in bytes) between two
+    /// captures of the breakdown per thread (see `peak_thread_breakdown`)
+    #[cfg(feature = "per-thread")]
+    pub fn set_peak_breakdown_step(&self, bytes: usize) {
+        per_thread::set_step(bytes)
+    }
+    /// Returns the memory attributed to each tag seen so far (see `Tag` and
+    /// `#[instrument_module]`), sorted by tag. Each of them has the number of
+    /// allocations performed and bytes allocated within the tag, and the net
+    /// number of bytes it holds.
+    #[cfg(feature = "module-tags")]
+    pub fn tag_usage(&self) -> Vec<TagUsage> {
+        tags::usage()
+    }
+    /// Returns the memory broken down along the given dimension: one row per
+    /// tag or per live thread, with its live bytes, peak, allocations and
+    /// share of the live bytes, sorted as requested. The first `limit` rows
+    /// are listed, the remaining ones are aggregated in a single row. Its
+    /// `Display` implementation renders an aligned table:
+    ///
+    /// ```
+    /// use peak_alloc::{Dimension, PeakAlloc, SortBy};
+    ///
+    /// #[global_allocator]
+    /// static PEAK_ALLOC: PeakAlloc = PeakAlloc;
+    ///
+    /// println!("{}", PEAK_ALLOC.report_breakdown(Dimension::Tags, SortBy::LiveBytes, 10));
+    /// ```
+    ///
+    /// A dimension needs a feature (`module-tags` for the tags, `per-thread`
+    /// for the threads): without it, the report is empty and its `note` says
+    /// which feature is missing.
+    #[cfg(feature = "std")]
+    pub fn report_breakdown(&self, dimension: Dimension, sort: SortBy, limit: usize) -> BreakdownReport {
+        match dimension {
+            #[cfg(feature = "module-tags")]
+            Dimension::Tags => {
+                let tags = tags::usage().into_iter().map(|usage| {
+                    BreakdownRow::new(usage.tag, usage.net_bytes, usage.peak_bytes, usage.allocations)
+                });
+                BreakdownReport::new(dimension, tags.collect(), sort, limit)
+            }
+            #[cfg(not(feature = "module-tags"))]
+            Dimension::Tags => BreakdownReport::new(dimension, Vec::new(), sort, limit)
+                .with_note("the tags need the `module-tags` feature"),
+            #[cfg(feature = "per-thread")]
+            Dimension::Threads => {
+                let threads = per_thread::usage().into_iter().map(|usage| {
+                    BreakdownRow::new(format!("{:?}", usage.id), usage.net, usage.peak, usage.allocations)
+                });
+                BreakdownReport::new(dimension, threads.collect(), sort, limit)
+            }
+            #[cfg(not(feature = "per-thread"))]
+            Dimension::Threads => BreakdownReport::new(dimension, Vec::new(), sort, limit)
+                .with_note("the threads need the `per-thread` feature"),
+        }
+    }
+    /// Forbids the calling thread to allocate until the returned guard is
+    /// dropped, at which point it panics if the thread has allocated anyway.
+    /// This is meant for tests making sure that some code never allocates:
+    ///
+    /// ```
+    /// # use peak_alloc::PeakAlloc;
+    /// # #[global_allocator]
+    /// # static PEAK_ALLOC: PeakAlloc = PeakAlloc;
+    /// let guard = PEAK_ALLOC.forbid_alloc();
+    /// let usage = PEAK_ALLOC.current_usage();
+    /// assert_eq!(0, guard.violations());
+    /// ```
+    ///
+    /// The other threads are free to allocate. The allocations are noticed
+    /// (rather than prevented) so as to not fail inside of the allocator.
+    #[cfg(feature = "std")]
+    pub fn forbid_alloc(&self) -> ForbidAllocGuard {
+        ForbidAllocGuard::new()
+    }
+    /// Isolates a test which measures the memory from the other ones: the
+    /// counters of the scope only count what happened since it was created,
+    /// and the counters of the allocator (the allocations, deallocations and
+    /// reallocations, the total, the largest allocation, the failures and
+    /// the peak usage) are put back as they were when it is dropped. The
+    /// current usage is not: the blocks allocated within the scope are live
+    /// until they are released.
+    ///
+    /// ```
+    /// use peak_alloc::PeakAlloc;
+    ///
+    /// #[global_allocator]
+    /// static PEAK_ALLOC: PeakAlloc = PeakAlloc;
+    ///
+    /// let allocations = PEAK_ALLOC.allocation_count();
+    /// {
+    ///     let scope = PEAK_ALLOC.test_scope();
+    ///     drop(vec![0_u8; 4096]);
+    ///     assert!(scope.peak_bytes() >= 4096);
+    ///     assert_eq!(0, scope.net_bytes());
+    /// }
+    /// assert_eq!(allocations, PEAK_ALLOC.allocation_count());
+    /// ```
+    ///
+    /// The scopes of the different threads are serialized (creating one
+    /// waits until the scopes of the other threads are dropped), and the
+    /// scopes of a thread can be nested. What the threads which are not
+    /// within a scope do is still counted.
+    #[cfg(feature = "std")]
+    pub fn test_scope(&self) -> TestScope {
+        TestScope::new()
+    }
+    /// Captures all the counters of the allocator (see `AllocatorState`, which
+    /// can be serialized with `to_bytes`), to put them back later with
+    /// `import_state`: the startup milestone (see `mark_startup_complete`)
+    /// and the start of the reporting window (see `take_stats`) included.
+    /// The counters are read one after the other.
+    pub fn export_state(&self) -> AllocatorState {
+        AllocatorState::capture()
+    }
+    /// Overwrites all the counters of the allocator with those of `state`,
+    /// e.g. to replay a test from a known state.
+    ///
+    /// # Note
+    /// The current usage is overwritten too: the counters no longer match
+    /// the blocks which are actually live, and releasing the blocks
+    /// allocated after the export may well underflow the usage. This is
+    /// only sound for a state exported when the very same blocks were live.
+    /// The allocations made meanwhile by the other threads are lost.
+    pub fn import_state(&self, state: AllocatorState) {
+        state.apply()
+    }
+    /// Returns the tracker which maintains the counters of this allocator.
+    /// The values it reports are the raw ones (the reported baseline is not
+    /// subtracted from them).
+    pub fn tracker(&self) -> &'static AllocationTracker {
+        &TRACKER
+    }
+    /// Returns a copy of all the counters. This never allocates.
+    pub fn stats(&self) -> Stats {
+        Stats {
+            current_usage: self.current_usage(),
+            peak_usage: self.peak_usage(),
+            all_time_peak_usage: self.all_time_peak_usage(),
+            total_allocated: self.total_allocated(),
+            allocation_count: self.allocation_count(),
+            deallocation_count: self.deallocation_count(),
+            largest_allocation: self.largest_allocation(),
+            large_current_usage: self.large_current_usage(),
+            large_peak_usage: self.large_peak_usage(),
+            large_allocation_count: self.large_allocation_count(),
+            external_usage: self.external_usage(),
+            startup_usage: self.startup_usage(),
+            post_startup_peak: self.post_startup_peak(),
+        }
+    }
+    /// Returns the stats of the current reporting window and starts a new one,
+    /// for periodic reporting. In the returned stats, `total_allocated`,
+    /// `allocation_count` and `deallocation_count` only account for the
+    /// window (since the previous call, or since the process started) and
+    /// `peak_usage` is the peak within the window. The peak is then reset
+    /// (which calls the `on_reset` hooks); the other fields are as usual.
+    ///
+    /// No allocation is ever lost between two windows: each one is counted
+    /// in exactly one window, even when several threads take the stats.
+    ///
+    /// # Note
+    /// The peak cannot be read and reset at once: an allocation landing in
+    /// between raises the peak of the old window but is not reported in it.
+    /// The peak of the new window nonetheless starts from the current usage,
+    /// that allocation included.
+    pub fn take_stats(&self) -> Stats {
+        let mut stats = self.stats();
+        let cumulative = [stats.total_allocated, stats.allocation_count, stats.deallocation_count];
+        let mut window = [0; 3];
+        for (i, start) in WINDOW_START.iter().enumerate() {
+            window[i] = cumulative[i].wrapping_sub(start.swap(cumulative[i], Ordering::Relaxed));
+        }
+        self.reset_peak_usage();
+        stats.total_allocated = window[0];
+        stats.allocation_count = window[1];
+        stats.deallocation_count = window[2];
+        stats
+    }
+    /// Marks the beginning of a phase of the program: resets the peak usage
+    /// and returns the stats at that point. At the end of the phase,
+    /// `self.stats().since(&checkpoint)` tells what happened in between
+    /// (the peak usage included):
+    ///
+    /// ```
+    /// # use peak_alloc::PeakAlloc;
+    /// # #[global_allocator]
+    /// # static PEAK_ALLOC: PeakAlloc = PeakAlloc;
+    /// let start = PEAK_ALLOC.checkpoint();
+    /// let data = vec![0_u8; 1024];
+    /// let phase = PEAK_ALLOC.stats().since(&start);
+    /// assert!(phase.allocations >= 1);
+    /// drop(data);
+    /// ```
+    pub fn checkpoint(&self) -> Stats {
+        self.reset_peak_usage();
+        self.stats()
+    }
+    /// Returns the current usage, the peak usage, the total allocated bytes
+    /// and the allocation count, in that order, in one pass.
+    ///
+    /// # Note
+    /// This is not an atomic snapshot: these are plain relaxed loads
+    /// performed in that very order (current, then peak, then total, then
+    /// count). Concurrent allocations may hence be reflected in the latter
+    /// values but not in the former ones. Reading the current usage first
+    /// makes it unlikely (but not impossible) to observe it above the peak.
+    pub fn read_all(&self) -> (usize, usize, usize, usize) {
+        let current = self.current_usage();
+        let peak = self.peak_usage();
+        let total = self.total_allocated();
+        let count = self.allocation_count();
+        (current, peak, total, count)
+    }
+    /// Fills `buffer` with all the counters available in this build, in one
+    /// pass (see `MetricsBuffer`): a poller reads them all with a single
+    /// call, without allocating, and `MetricsBuffer::diff` gives their rates.
+    ///
+    /// # Example
+    /// ```
+    /// use peak_alloc::{MetricsBuffer, PeakAlloc};
+    ///
+    /// let mut metrics = MetricsBuffer::new();
+    /// PeakAlloc.read_metrics(&mut metrics);
+    /// assert!(metrics.get(MetricsBuffer::CURRENT_USAGE).is_some());
+    /// ```
+    pub fn read_metrics(&self, buffer: &mut MetricsBuffer) {
+        buffer.fill(self)
+    }
+    /// Returns the histogram of the allocation sizes
+    #[cfg(feature = "histogram")]
+    pub fn size_histogram(&self) -> SizeHistogram {
+        histogram::snapshot()
+    }
+    /// Returns the number of live blocks and bytes per size class, along
+    /// with the maximum number of bytes each class held at once: the peak of
+    /// a class need not coincide with the peak usage, and it is what a pool
+    /// dedicated to that class would have to hold. A reallocated block moves
+    /// from the class of its old size to the class of its new one.
+    #[cfg(feature = "histogram")]
+    pub fn live_by_size_class(&self) -> LiveSizeClasses {
+        histogram::live_by_size_class()
+    }
+    /// Resets the peak of the given size class to the bytes its live blocks
+    /// currently hold (see `live_by_size_class`). `reset_peak_usage` resets
+    /// the peaks of all the classes.
+    #[cfg(feature = "histogram")]
+    pub fn reset_size_class_peak(&self, class: usize) {
+        histogram::reset_class_peaks(Some(class))
+    }
+    /// Estimates the internal fragmentation: the number of bytes which would
+    /// be lost if each live block was rounded up to the upper bound of its
+    /// (power of two) size class. That is, `sum(class_upper_bound *
+    /// live_count_in_class) - live_bytes`.
+    ///
+    /// This is a heuristic: the actual size classes of the system allocator
+    /// are finer grained than powers of two. It is, however, valuable to
+    /// compare workloads or to tune the sizes of the hot allocations.
+    #[cfg(feature = "histogram")]
+    pub fn estimated_internal_fragmentation(&self) -> usize {
+        histogram::live_class_bytes().saturating_sub(TRACKER.raw_current())
+    }
+    /// Returns the number of bytes which are wasted in padding by the live
+    /// allocations whose size is not a multiple of their alignment.
+    #[cfg(feature = "histogram")]
+    pub fn padding_waste_bytes(&self) -> usize {
+        histogram::padding_waste()
+    }
+    /// Returns the number of allocations per requested alignment along with
+    /// the padding waste.
+    #[cfg(feature = "histogram")]
+    pub fn alignment_report(&self) -> AlignmentReport {
+        histogram::alignment_report()
+    }
+    /// Returns a report bundling all the statistics gathered so far. This is
+    /// the "print everything" entry point for end-of-run diagnostics:
+    /// ```
+    /// # use peak_alloc::PeakAlloc;
+    /// # #[global_allocator]
+    /// # static PEAK_ALLOC: PeakAlloc = PeakAlloc;
+    /// println!("{}", PEAK_ALLOC.final_report());
+    /// ```
+    pub fn final_report(&self) -> Report {
+        Report {
+            current_usage: self.current_usage(),
+            peak_usage: self.peak_usage(),
+            total_allocated: self.total_allocated(),
+            allocation_count: self.allocation_count(),
+            deallocation_count: self.deallocation_count(),
+            largest_allocation: self.largest_allocation(),
+            startup_usage: self.startup_usage(),
+            post_startup_peak: self.post_startup_peak(),
+            #[cfg(feature = "histogram")]
+            size_histogram: self.size_histogram(),
+        }
+    }
+    /// Writes the final report (see `final_report`) to `out`. Unlike
+    /// `final_report().to_string()`, this does not allocate by itself.
+    pub fn write_report<W: core::fmt::Write>(&self, out: &mut W) -> core::fmt::Result {
+        write!(out, "{}", self.final_report())
+    }
+    /// Compares the heap usage tracked by this allocator to the resident set
+    /// size of the process (as reported by the operating system).
+    #[cfg(feature = "std")]
+    pub fn footprint(&self) -> FootprintReport {
+        FootprintReport::new(self.current_usage(), process_rss())
+    }
+    /// Estimates the fragmentation of the heap by comparing the bytes the
+    /// live blocks were requested with, the bytes the system allocator
+    /// reserved for them (their usable sizes) and the resident set size of
+    /// the process. All of this is computed upon request, nothing is added
+    /// to the allocation path but the usable size lookup.
+    ///
+    /// Returns None when the usable sizes or the rss cannot be queried on
+    /// this platform.
+    #[cfg(feature = "actual-size")]
+    pub fn fragmentation(&self) -> Option<FragmentationReport> {
+        if !usable::SUPPORTED {
+            return None;
+        }
+        Some(FragmentationReport {
+            requested_bytes: TRACKER.raw_current().saturating_sub(usable::slack()),
+            usable_bytes: usable::current(),
+            rss_bytes: process_rss()?,
+        })
+    }
+    /// Sets whether the usage (hence the peak, the memory limit and all the
+    /// figures derived from them) is charged with the usable sizes of the
+    /// blocks, as reported by the system allocator, rather than with the sizes
+    /// they were requested with (the default). This counts the slack of the
+    /// size classes, which the requested sizes miss.
+    ///
+    /// # Note
+    /// Like the minimum tracked size, this should be set once, early: a block
+    /// allocated before the setting is changed may be released after, hence
+    /// skewing the counters by its slack. Where the usable sizes cannot be
+    /// queried (see `fragmentation`), the blocks count for their requested
+    /// size either way.
+    #[cfg(feature = "actual-size")]
+    pub fn set_usable_size_accounting(&self, enabled: bool) {
+        config::exclusive(|| usable::set_charge_usable(enabled))
+    }
+    /// Returns true iff the usage is charged with the usable sizes of the
+    /// blocks (see `set_usable_size_accounting`)
+    #[cfg(feature = "actual-size")]
+    pub fn usable_size_accounting(&self) -> bool {
+        usable::charge_usable()
+    }
+    /// Returns the overhead of the process heap: the sum, over the live
+    /// blocks, of their usable size (`HeapSize`) minus their requested size
+    #[cfg(all(feature = "actual-size", windows))]
+    pub fn heap_overhead_bytes(&self) -> usize {
+        usable::overhead()
+    }
+    /// Returns the number of blocks whose usable size could not be queried
+    /// (`HeapSize` failed, or the block was not handed out by the process
+    /// heap as is because of its alignment): their requested size stood for
+    /// their usable size.
+    #[cfg(all(feature = "actual-size", windows))]
+    pub fn heap_size_fallback_count(&self) -> usize {
+        usable::fallbacks()
+    }
+    /// Sets the watch threshold: an `Event::ThresholdCrossed` is emitted
+    /// whenever the memory usage rises above that many bytes (0 disables the
+    /// watch, which is the default).
+    #[cfg(feature = "std")]
+    pub fn set_watch_threshold(&self, bytes: usize) {
+        config::publish(|| WATCH_THRESHOLD.store(bytes, Ordering::Relaxed))
+    }
+    /// Registers a handler which is called for each event detected by the
+    /// allocator, when the events get dispatched by `drain_events`.
+    ///
+    /// Unlike the code running inside of the allocator, the handlers are free
+    /// to allocate memory. They must however neither register new handlers
+    /// nor drain the events themselves (that would deadlock).
+    #[cfg(feature = "std")]
+    pub fn on_event<F>(&self, handler: F)
+    where
+        F: Fn(Event) + Send + Sync + 'static,
+    {
+        // registered for good: neither the box nor the registry is counted
+        let _internal = threads::internal();
+        events::register(Box::new(handler))
+    }
+    /// Registers a hook which is called with the statistics right before
+    /// each reset of the peak usage (`reset_peak_usage`, `checkpoint`), e.g.
+    /// to flush the figures of a window before they are lost.
+    ///
+    /// The hooks are called synchronously by the thread which resets, in the
+    /// order they were registered. They are free to allocate but must neither
+    /// reset the peak nor register new hooks themselves (that would
+    /// deadlock).
+    #[cfg(feature = "std")]
+    pub fn on_reset<F>(&self, hook: F)
+    where
+        F: Fn(Stats) + Send + Sync + 'static,
+    {
+        let _internal = threads::internal();
+        events::register_reset_hook(Box::new(hook))
+    }
+    /// Looks for the outlier allocations: those which are larger than
+    /// `factor` times the average allocation size. `callback` is given the
+    /// size of each of them when the events get dispatched by `drain_events`
+    /// (the allocator only emits an `Event::OutlierAllocation`), hence it is
+    /// free to allocate. A factor of 0 stops looking for outliers.
+    ///
+    /// The average is the total allocated divided by the allocation count:
+    /// it costs nothing to maintain and is read without locking. There is
+    /// only one factor: registering a second callback also changes the
+    /// factor of the first one.
+    #[cfg(feature = "std")]
+    pub fn on_outlier_allocation(&self, factor: f32, callback: fn(usize)) {
+        OUTLIER_FACTOR.store(factor.max(0.0).to_bits(), Ordering::Relaxed);
+        self.on_event(move |event| {
+            if let Event::OutlierAllocation { size, .. } = event {
+                callback(size);
+            }
+        });
+    }
+    /// Calls `callback` when the allocations come in a storm: more than
+    /// `count_threshold` of them within a sliding `window` (e.g. 10,000 within
+    /// 100ms), which often betrays a bug. Like the outliers, the storms are
+    /// only detected by the allocator (which emits an
+    /// `Event::AllocationStorm`): `callback` is called when the events get
+    /// dispatched by `drain_events`. A storm is reported once, however long
+    /// it lasts. A threshold of 0 stops the detection.
+    ///
+    /// # Note
+    /// The count within the window is an estimate, derived from the counts
+    /// of two consecutive fixed windows, and each thread only checks it once
+    /// every 64 allocations. There is only one threshold and window:
+    /// registering a second callback also changes those of the first one.
+    #[cfg(feature = "std")]
+    pub fn on_allocation_storm(&self, count_threshold: usize, window: Duration, callback: fn()) {
+        storm::configure(count_threshold, window);
+        self.on_event(move |event| {
+            if let Event::AllocationStorm { .. } = event {
+                callback();
+            }
+        });
+    }
+    /// Dispatches all the pending events to the registered handlers and
+    /// returns the number of events that were dispatched.
+    ///
+    /// # Note
+    /// The allocator only ever enqueues the events it detects (in a bounded
+    /// lock-free queue): it is up to the program to call this method, e.g.
+    /// periodically from a thread of its own.
+    #[cfg(feature = "std")]
+    pub fn drain_events(&self) -> usize {
+        events::drain()
+    }
+    /// Returns the number of events which have been lost because the event
+    /// queue was full (i.e. the events were not drained often enough).
+    #[cfg(feature = "std")]
+    pub fn dropped_events(&self) -> usize {
+        events::dropped()
+    }
+    /// Starts a watchdog thread which samples the memory usage periodically
+    /// and calls back when the usage has remained above the configured
+    /// threshold for longer than the grace period (at most once per
+    /// cooldown period). The watchdog stops when the handle is dropped.
+    ///
+    /// # Note
+    /// Sampling does not allocate: the watchdog only perturbs the
+    /// measurements with the allocations performed by its callback.
+    ///
+    /// ```
+    /// # use peak_alloc::{ByteSize, PeakAlloc, WatchdogConfig};
+    /// # use std::time::Duration;
+    /// # #[global_allocator]
+    /// # static PEAK_ALLOC: PeakAlloc = PeakAlloc;
+    /// let _watchdog = PEAK_ALLOC.start_watchdog(
+    ///     WatchdogConfig::new(ByteSize::gib(1)).grace(Duration::from_secs(5)),
+    /// );
+    /// ```
+    #[cfg(feature = "std")]
+    pub fn start_watchdog(&self, config: WatchdogConfig) -> WatchdogHandle {
+        watchdog::start(*self, config)
+    }
+    /// Starts detecting the spikes of the memory usage: `callback` is called
+    /// from a background thread, once per spike, when the usage has risen
+    /// more than `config.rise_percent` above a trailing reference then fallen
+    /// back within `config.settle_percent` of it. A spike which is still open
+    /// when the handle is stopped (or dropped) is reported as incomplete.
+    ///
+    /// Sampling does not allocate: only the callback perturbs the
+    /// measurements.
+    ///
+    /// ```
+    /// # use peak_alloc::{PeakAlloc, SpikeConfig};
+    /// # #[global_allocator]
+    /// # static PEAK_ALLOC: PeakAlloc = PeakAlloc;
+    /// let _spikes = PEAK_ALLOC.on_spike(SpikeConfig::default().rise_percent(100.0), |spike| {
+    ///     eprintln!("spike from {} to {} bytes", spike.start_usage, spike.peak_usage);
+    /// });
+    /// ```
+    #[cfg(feature = "std")]
+    pub fn on_spike<F>(&self, config: SpikeConfig, callback: F) -> SpikeWatchHandle
+    where
+        F: FnMut(SpikeReport) + Send + 'static,
+    {
+        spikes::start(*self, config, callback)
+    }
+    /// Starts smoothing the peak usage: from then on, and until the handle is
+    /// dropped, `smoothed_peak_usage` only accounts for the usage levels
+    /// which persist until the next tick of a sampler thread (run once every
+    /// `interval`). A transient spike, freed before the next tick, does not
+    /// register. The raw `peak_usage` is left unaffected.
+    ///
+    /// The smoothed peak lags the usage by up to one interval, and a spike
+    /// which happens to be alive upon a tick is accounted for in full. Only
+    /// one smoothing sampler should run at a time.
+    #[cfg(feature = "std")]
+    pub fn start_peak_smoothing(&self, interval: Duration) -> SmoothingHandle {
+        smoothing::start(interval)
+    }
+    /// Returns the smoothed peak usage (see `start_peak_smoothing`), net of
+    /// the reported baseline. It is 0 if smoothing was never started.
+    #[cfg(feature = "std")]
+    pub fn smoothed_peak_usage(&self) -> usize {
+        smoothing::peak().saturating_sub(BASELINE.load(Ordering::Relaxed))
+    }
+    /// Starts recording the history of the memory usage: a sampler thread
+    /// records the usage once every `interval` in a ring buffer holding the
+    /// `samples` most recent samples. The buffer is allocated upfront, so
+    /// the sampler does not perturb the measurements it takes.
+    #[cfg(feature = "std")]
+    pub fn start_history(&self, samples: usize, interval: Duration) -> HistoryHandle {
+        history::start(*self, samples, interval)
+    }
+    /// Returns the peak usage since `since`, as sampled by the most recently
+    /// started history (see `start_history`): the maximum of its samples
+    /// taken at `since` or later. This is handy for incident analysis ("what
+    /// was the peak since 10:00?").
+    ///
+    /// Returns None when no history is alive (its handle was dropped), or
+    /// when it has no sample in the window. The samples are taken once every
+    /// interval: a spike in between two of them is missed, and those older
+    /// than the capacity of the history are gone.
+    #[cfg(feature = "std")]
+    pub fn peak_since(&self, since: Instant) -> Option<usize> {
+        history::peak_since(since)
+    }
+    /// Returns the number of allocations per second, measured by the history
+    /// sampler (see `start_history`) over its last interval. It is 0 until
+    /// the sampler has completed a first interval.
+    #[cfg(feature = "std")]
+    pub fn allocations_per_second(&self) -> f64 {
+        history::allocation_rate()
+    }
+    /// Returns the peak usage over the last `window` (rounded up to whole
+    /// seconds, and at most one minute): unlike `peak_usage`, it decays as
+    /// time goes by, which suits the autoscaling decisions. It is never
+    /// below the current usage.
+    ///
+    /// The maximum usage of each second is recorded on the allocation path
+    /// (at the cost of a read of the coarse clock), hence no spike is missed;
+    /// `reset_peak_usage` leaves it alone.
+    #[cfg(feature = "windowed-peak")]
+    pub fn windowed_peak(&self, window: Duration) -> usize {
+        let peak = windowed::peak(window).saturating_sub(BASELINE.load(Ordering::Relaxed));
+        peak.max(self.current_usage())
+    }
+    /// Watches the memory pressure of the system (Linux PSI): `callback` is
+    /// called from a background thread whenever the tasks have been stalled
+    /// waiting for memory for more than `config.stall` within a window. It
+    /// is given the PSI averages and the current tracked usage, so that the
+    /// application can decide how much of its caches to drop.
+    ///
+    /// The watcher does not allocate: only the callback perturbs the
+    /// measurements. It is stopped when the handle is dropped.
+    ///
+    /// ```no_run
+    /// # use peak_alloc::{PeakAlloc, PressureConfig};
+    /// # #[global_allocator]
+    /// # static PEAK_ALLOC: PeakAlloc = PeakAlloc;
+    /// let _watcher = PEAK_ALLOC.watch_memory_pressure(PressureConfig::default(), |event| {
+    ///     eprintln!("memory pressure ({:?} stalled), shedding caches", event.stalled);
+    /// });
+    /// ```
+    #[cfg(all(feature = "psi", target_os = "linux"))]
+    pub fn watch_memory_pressure<F>(&self, config: PressureConfig, callback: F) -> PressureHandle
+    where
+        F: FnMut(PressureEvent) + Send + 'static,
+    {
+        psi::start(*self, config, callback)
+    }
+    /// Returns three exponentially decaying averages of the memory usage (in
+    /// bytes), like the Unix load averages. By default, their time constants
+    /// are 1, 5 and 15 minutes.
+    ///
+    /// # Note
+    /// The averages are updated with the current usage whenever they are
+    /// queried. For them to be meaningful, they should be queried regularly
+    /// or sampled by a background thread (`start_load_average_sampler`).
+    #[cfg(feature = "decayed-stats")]
+    pub fn usage_load_averages(&self) -> (f64, f64, f64) {
+        decay::sample(self)
+    }
+    /// Changes the time constants of the load averages (which restarts them)
+    #[cfg(feature = "decayed-stats")]
+    pub fn set_load_average_time_constants(&self, short: Duration, medium: Duration, long: Duration) {
+        decay::set_time_constants([short, medium, long])
+    }
+    /// Starts a thread which samples the usage once every `interval` to keep
+    /// the load averages up to date. It stops when the handle is dropped.
+    #[cfg(feature = "decayed-stats")]
+    pub fn start_load_average_sampler(&self, interval: Duration) -> LoadAverageSampler {
+        decay::start_sampler(*self, interval)
+    }
+    /// Returns the current statistics as an InfluxDB line protocol record
+    /// (without trailing newline) with the given measurement name and tags,
+    /// timestamped in nanoseconds. The measurement name and the tags are
+    /// escaped as required by the protocol.
+    ///
+    /// ```
+    /// # use peak_alloc::PeakAlloc;
+    /// let line = PeakAlloc.influx_line("heap", &[("host", "server 01")]);
+    /// assert!(line.starts_with("heap,host=server\\ 01 current="));
+    /// ```
+    #[cfg(feature = "std")]
+    pub fn influx_line(&self, measurement: &str, tags: &[(&str, &str)]) -> String {
+        let mut line = String::with_capacity(128);
+        // writing to a string cannot fail
+        let _ = self.write_influx(&mut line, measurement, tags);
+        line
+    }
+    /// Writes the current statistics as an InfluxDB line protocol record
+    /// (see `influx_line`). This does not allocate by itself, which makes it
+    /// suitable to format into a preallocated buffer.
+    #[cfg(feature = "std")]
+    pub fn write_influx<W: std::fmt::Write>(
+        &self,
+        out: &mut W,
+        measurement: &str,
+        tags: &[(&str, &str)],
+    ) -> std::fmt::Result {
+        let fields = influx::Fields {
+            current: self.current_usage(),
+            peak: self.peak_usage(),
+            allocations: self.allocation_count(),
+            deallocations: self.deallocation_count(),
+        };
+        influx::write_line(out, measurement, tags.iter().copied(), &fields, influx::timestamp())
+    }
+    /// Starts posting the statistics to an InfluxDB server over HTTP, once
+    /// every `config.interval`. The failed requests are counted (see
+    /// `ExporterHandle::send_errors`) but not fatal. The poster stops when the
+    /// handle is dropped.
+    #[cfg(feature = "influx-http")]
+    pub fn start_influx_poster(&self, config: InfluxConfig) -> ExporterHandle {
+        influx::start(*self, config)
+    }
+    /// Starts exporting the statistics to a statsd server (DogStatsD flavor)
+    /// listening at `addr`. Once every `interval`, one datagram is sent with
+    /// the current and peak usage as gauges (`<prefix>.current`,
+    /// `<prefix>.peak`) and the number of allocations and deallocations since
+    /// the previous datagram as counters (`<prefix>.allocations`,
+    /// `<prefix>.deallocations`), all of them carrying the given tags.
+    ///
+    /// The exporter uses a non-blocking socket: the failed sends are counted
+    /// (see `ExporterHandle::send_errors`) but not fatal. It stops when the
+    /// handle is dropped. An error is only returned if the socket cannot be
+    /// set up.
+    #[cfg(feature = "statsd")]
+    pub fn start_statsd_exporter(
+        &self,
+        addr: std::net::SocketAddr,
+        prefix: &str,
+        interval: Duration,
+        tags: &[(&str, &str)],
+    ) -> std::io::Result<ExporterHandle> {
+        statsd::start(*self, addr, prefix, interval, tags)
+    }
+    /// Serves the statistics over a Unix domain socket bound at `path`: a
+    /// background thread answers each connection with the JSON object of
+    /// `stats_json`, then closes it. The server stops, and the socket file is
+    /// removed, when the handle is dropped. An error is returned if the
+    /// socket cannot be bound (e.g. if `path` exists already).
+    ///
+    /// The server allocates its buffer upfront: answering a connection only
+    /// perturbs the measurements with what the system needs for it.
+    #[cfg(all(feature = "socket", unix))]
+    pub fn serve_stats(&self, path: &std::path::Path) -> std::io::Result<StatsServerHandle> {
+        socket::serve(*self, path)
+    }
+    /// Blocks the calling thread until `current_usage` drops below the given
+    /// number of bytes, or until the timeout elapses. Returns true iff the
+    /// usage dropped below the threshold in time.
+    ///
+    /// # Note
+    /// This polls the counter with an exponential backoff (up to 10ms between
+    /// two polls): waking the waiting threads from within the allocator would
+    /// require locking there, at the risk of reentrancy.
+    #[cfg(feature = "std")]
+    pub fn wait_until_below(&self, bytes: usize, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut backoff = Duration::from_micros(10);
+        loop {
+            if self.current_usage() < bytes {
+                return true;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            std::thread::sleep(backoff.min(deadline - now));
+            backoff = (backoff * 2).min(Duration::from_millis(10));
+        }
+    }
+    /// Sets a number of bytes that is to be subtracted from all the values
+    /// reported by `current_usage` and `peak_usage` (and their unit converted
+    /// variants). The reported values are floored at zero.
+    ///
+    /// This is typically useful to exclude the memory that has been allocated
+    /// by the runtime before `main` even started. Note that this is only a
+    /// presentation offset: the true counters are left untouched.
+    pub fn set_reported_baseline(&self, bytes: usize) {
+        BASELINE.store(bytes, Ordering::Relaxed);
+    }
+    /// Removes the reported baseline so that `current_usage` and `peak_usage`
+    /// report the true counter values again.
+    pub fn clear_reported_baseline(&self) {
+        BASELINE.store(0, Ordering::Relaxed);
+    }
+    /// Makes the allocator write the current usage (see `current_usage`) into
+    /// the given atomic each time it changes, with a relaxed store. This
+    /// spares polling the allocator in addition to an atomic of your own.
+    /// The atomic is only updated upon the next change of the usage.
+    pub fn mirror_current_into(&self, mirror: &'static AtomicUsize) {
+        mirror::set(mirror);
+    }
+    /// Stops writing the current usage into the atomic given to
+    /// `mirror_current_into`
+    pub fn stop_mirroring(&self) {
+        mirror::clear();
+    }
+    /// Returns the usage which was recorded upon the very first allocation
+    /// of the process, typically performed by the runtime before `main`
+    /// runs (or 0 if nothing has been allocated yet). Subtracting it from
+    /// `current_usage` gives an estimate of the usage of "your own" code.
+    ///
+    /// Unlike `set_reported_baseline`, this is captured once and for all:
+    /// it is neither configurable nor applied to the reported values.
+    pub fn process_baseline(&self) -> usize {
+        PROCESS_BASELINE.load(Ordering::Relaxed)
+    }
+    /// Marks the startup of the program as complete: the usage and the
+    /// allocation count at this point are recorded as the startup milestone,
+    /// which tells the memory of the framework apart from the memory of the
+    /// data (see `startup_usage` and `post_startup_peak`).
+    ///
+    /// The milestone is recorded once and for all: only the first call
+    /// records it and returns true, the later ones leave it untouched and
+    /// return false.
+    pub fn mark_startup_complete(&self) -> bool {
+        if STARTUP_MARKED.swap(true, Ordering::Relaxed) {
+            return false;
+        }
+        STARTUP[0].store(self.current_usage(), Ordering::Relaxed);
+        STARTUP[1].store(self.allocation_count(), Ordering::Relaxed);
+        true
+    }
+    /// Returns the usage when the startup was marked as complete (see
+    /// `mark_startup_complete`), or None if it has not been marked yet
+    pub fn startup_usage(&self) -> Option<usize> {
+        STARTUP_MARKED.load(Ordering::Relaxed).then(|| STARTUP[0].load(Ordering::Relaxed))
+    }
+    /// Returns the number of allocations performed during the startup (see
+    /// `mark_startup_complete`), or None if it has not been marked yet
+    pub fn startup_allocation_count(&self) -> Option<usize> {
+        STARTUP_MARKED.load(Ordering::Relaxed).then(|| STARTUP[1].load(Ordering::Relaxed))
+    }
+    /// Returns how far the peak usage rose above the usage of the startup:
+    /// the memory needed by the data rather than by the framework (floored
+    /// at 0). This is the peak usage itself until the startup is marked as
+    /// complete.
+    pub fn post_startup_peak(&self) -> usize {
+        self.peak_usage().saturating_sub(self.startup_usage().unwrap_or(0))
+    }
+    /// Returns the counters of the allocator as a (single line) JSON object,
+    /// e.g. to be polled by a JavaScript overlay when compiled to wasm. Its
+    /// first field is the version of its schema (`STATS_SCHEMA_VERSION`).
+    ///
+    /// ```
+    /// # use peak_alloc::PeakAlloc;
+    /// let json = PeakAlloc.stats_json();
+    /// assert!(json.starts_with("{\"schema\":2,\"current\":"));
+    /// ```
+    #[cfg(feature = "std")]
+    pub fn stats_json(&self) -> String {
+        let mut json = String::with_capacity(256);
+        // writing to a string cannot fail
+        let _ = self.write_json(&mut json);
+        json
+    }
+    /// Writes the counters of the allocator as a JSON object (see
+    /// `stats_json`) to `out`. This does not allocate by itself.
+    pub fn write_json<W: core::fmt::Write>(&self, out: &mut W) -> core::fmt::Result {
+        let stats = self.stats();
+        write!(
+            out,
+            "{{\"schema\":{},\"current\":{},\"peak\":{},\"all_time_peak\":{},\
+             \"total_allocated\":{},\"allocations\":{},\"deallocations\":{},\"largest\":{},\
+             \"large_current\":{},\"large_peak\":{},\"large_allocations\":{},\"startup\":",
+            STATS_SCHEMA_VERSION,
+            stats.current_usage,
+            stats.peak_usage,
+            stats.all_time_peak_usage,
+            stats.total_allocated,
+            stats.allocation_count,
+            stats.deallocation_count,
+            stats.largest_allocation,
+            stats.large_current_usage,
+            stats.large_peak_usage,
+            stats.large_allocation_count
+        )?;
+        match stats.startup_usage {
+            Some(usage) => write!(out, "{}", usage)?,
+            None => out.write_str("null")?,
+        }
+        write!(out, ",\"post_startup_peak\":{}", stats.post_startup_peak)?;
+        #[cfg(feature = "histogram")]
+        {
+            out.write_str(",\"size_classes\":")?;
+            histogram::write_json(out)?;
+        }
+        out.write_char('}')
     }
     /// Returns the amount of memory (in kb) that is currently allocated
     /// to the process.
+    #[deprecated(note = "use `current_usage_in_units(units::KIB)`, which is exact")]
     pub fn current_usage_as_kb(&self) -> f32 {
         Self::kb(self.current_usage())
     }
     /// Returns the amount of memory (in mb) that is currently allocated
     /// to the process.
+    #[deprecated(note = "use `current_usage_in_units(units::MIB)`, which is exact")]
     pub fn current_usage_as_mb(&self) -> f32 {
         Self::mb(self.current_usage())
     }
+    /// Returns the amount of memory (in mb) that is currently allocated
+    /// to the process, rounded to `ROUND_DECIMALS` decimals with the given
+    /// mode. Using the same mode throughout a report keeps its sections
+    /// consistent.
+    pub fn current_usage_as_mb_with(&self, mode: RoundMode) -> f64 {
+        mode.round_bytes(self.current_usage(), units::MIB)
+    }
     /// Returns the amount of memory (in gb) that is currently allocated
     /// to the process.
+    #[deprecated(note = "use `current_usage_in_units(units::GIB)`, which is exact")]
     pub fn current_usage_as_gb(&self) -> f32 {
         Self::gb(self.current_usage())
     }
     /// Returns the maximum quantity of memory (in kb) that have been allocated
     /// to the process over the course of its life.
+    #[deprecated(note = "use `peak_usage_in_units(units::KIB)`, which is exact")]
     pub fn peak_usage_as_kb(&self) -> f32 {
         Self::kb(self.peak_usage())
     }
     /// Returns the maximum quantity of memory (in mb) that have been allocated
     /// to the process over the course of its life.
+    #[deprecated(note = "use `peak_usage_in_units(units::MIB)`, which is exact")]
     pub fn peak_usage_as_mb(&self) -> f32 {
         Self::mb(self.peak_usage())
     }
+    /// Returns the maximum quantity of memory (in mb) that have been allocated
+    /// to the process over the course of its life, rounded to
+    /// `ROUND_DECIMALS` decimals with the given mode.
+    pub fn peak_usage_as_mb_with(&self, mode: RoundMode) -> f64 {
+        mode.round_bytes(self.peak_usage(), units::MIB)
+    }
     /// Returns the maximum quantity of memory (in gb) that have been allocated
     /// to the process over the course of its life.
+    #[deprecated(note = "use `peak_usage_in_units(units::GIB)`, which is exact")]
     pub fn peak_usage_as_gb(&self) -> f32 {
         Self::gb(self.peak_usage())
     }
-    /// Resets the peak usage to the value currently in memory
+    /// Returns the amount of memory that is currently allocated to the
+    /// process, expressed in units of `unit_bytes` bytes (e.g. 4096 to get a
+    /// number of pages, or one of the `units`). This returns `NaN` when
+    /// `unit_bytes` is zero.
+    pub fn current_usage_in_units(&self, unit_bytes: usize) -> f64 {
+        Self::units(self.current_usage(), unit_bytes)
+    }
+    /// Returns the maximum quantity of memory that has been allocated to the
+    /// process over the course of its life, expressed in units of
+    /// `unit_bytes` bytes. This returns `NaN` when `unit_bytes` is zero.
+    pub fn peak_usage_in_units(&self, unit_bytes: usize) -> f64 {
+        Self::units(self.peak_usage(), unit_bytes)
+    }
+    /// Returns the current usage in the largest binary unit in which it is
+    /// at least one, and the symbol of that unit (e.g. `(3.42, "MiB")`), to
+    /// format it with one's own rules (see `units::human`)
+    pub fn current_usage_auto(&self) -> (f64, &'static str) {
+        units::human(self.current_usage())
+    }
+    /// Returns the peak usage in the largest binary unit in which it is at
+    /// least one, and the symbol of that unit (see `current_usage_auto`)
+    pub fn peak_usage_auto(&self) -> (f64, &'static str) {
+        units::human(self.peak_usage())
+    }
+    /// Returns a value whose `Display` writes the current and the peak usage
+    /// in the given unit, e.g. to fix the unit of a log format:
+    ///
+    /// ```
+    /// use peak_alloc::{units::Unit, PeakAlloc};
+    ///
+    /// #[global_allocator]
+    /// static PEAK_ALLOC: PeakAlloc = PeakAlloc;
+    ///
+    /// let line = format!("{:.1}", PEAK_ALLOC.display_in(Unit::Mb));
+    /// assert!(line.starts_with("current: ") && line.ends_with(" MB"));
+    /// ```
+    pub fn display_in(&self, unit: units::Unit) -> units::DisplayIn {
+        units::DisplayIn { unit }
+    }
+    /// Resets the peak usage (and the peaks of the large blocks and of the
+    /// size classes, and the smoothed peaks) to the value currently in memory
+    /// (the all time peak usage is left untouched). The hooks registered
+    /// with `on_reset` are called first.
+    ///
+    /// With `ResetPolicy::ToZero` (see `set_reset_policy`), the peak usage is
+    /// reset to zero instead; the other peaks are still reset to their
+    /// current values.
     pub fn reset_peak_usage(&self) {
-        PEAK.store(CURRENT.load(Ordering::Relaxed), Ordering::Relaxed);
+        #[cfg(feature = "std")]
+        events::before_reset(|| self.stats());
+        match self.reset_policy() {
+            ResetPolicy::ToCurrent => TRACKER.reset_peak_usage(),
+            ResetPolicy::ToZero => TRACKER.clear_peak_usage(),
+        }
+        large::reset_peak();
+        #[cfg(feature = "histogram")]
+        histogram::reset_class_peaks(None);
+        #[cfg(feature = "std")]
+        smoothing::reset();
+        #[cfg(feature = "per-thread")]
+        per_thread::reset();
+    }
+    /// Sets what `reset_peak_usage` resets the peak usage to (the current
+    /// usage by default)
+    pub fn set_reset_policy(&self, policy: ResetPolicy) {
+        config::exclusive(|| RESET_TO_ZERO.store(policy == ResetPolicy::ToZero, Ordering::Relaxed))
+    }
+    /// Returns what `reset_peak_usage` resets the peak usage to
+    pub fn reset_policy(&self) -> ResetPolicy {
+        if RESET_TO_ZERO.load(Ordering::Relaxed) {
+            ResetPolicy::ToZero
+        } else {
+            ResetPolicy::ToCurrent
+        }
+    }
+    /// Sets the size (in bytes) from which an allocation is considered large.
+    /// The large allocations are counted apart (in addition to the regular
+    /// counters), see `large_current_usage`. A threshold of 0 (the default)
+    /// stops counting them. It should be set early, before any large block
+    /// is allocated.
+    pub fn set_large_threshold(&self, bytes: usize) {
+        config::publish(|| large::set_threshold(bytes))
+    }
+    /// Returns the number of bytes currently held in large blocks (see
+    /// `set_large_threshold`)
+    pub fn large_current_usage(&self) -> usize {
+        large::current()
+    }
+    /// Returns the maximum number of bytes held in large blocks at once since
+    /// the last reset (see `set_large_threshold`)
+    pub fn large_peak_usage(&self) -> usize {
+        large::peak()
+    }
+    /// Returns the number of large allocations (see `set_large_threshold`).
+    /// A block which grows past the threshold counts as a large allocation.
+    pub fn large_allocation_count(&self) -> usize {
+        large::allocations()
+    }
+    /// Sets the alignment (in bytes) above which an allocation is considered
+    /// over-aligned (16 by default). The over-aligned allocations are counted
+    /// apart (in addition to the regular counters), see `overaligned_count`:
+    /// this catches the dependencies which over-align their small objects
+    /// (e.g. on a cache line), whose overhead is much higher than their size.
+    pub fn set_overalign_threshold(&self, align: usize) {
+        config::exclusive(|| overalign::set_threshold(align))
+    }
+    /// Returns the alignment above which an allocation is over-aligned (see
+    /// `set_overalign_threshold`)
+    pub fn overalign_threshold(&self) -> usize {
+        overalign::threshold()
+    }
+    /// Returns the number of over-aligned allocations (see
+    /// `set_overalign_threshold`)
+    pub fn overaligned_count(&self) -> usize {
+        overalign::count()
+    }
+    /// Returns the number of bytes requested by the over-aligned allocations
+    /// (see `set_overalign_threshold`)
+    pub fn overaligned_bytes(&self) -> usize {
+        overalign::bytes()
+    }
+    /// Returns the last `RECENT_OVERALIGNED` over-aligned allocations, the
+    /// oldest first, along with the tag they were attributed to (with the
+    /// `module-tags` feature), to find out where they come from.
+    ///
+    /// ```
+    /// use peak_alloc::PeakAlloc;
+    ///
+    /// #[global_allocator]
+    /// static PEAK_ALLOC: PeakAlloc = PeakAlloc;
+    ///
+    /// #[repr(align(128))]
+    /// struct Padded([u8; 8]);
+    ///
+    /// let padded = Box::new(Padded([0; 8]));
+    /// let last = *PEAK_ALLOC.recent_overaligned().last().unwrap();
+    /// assert_eq!((128, 128), (last.size, last.align));
+    /// # drop(padded);
+    /// ```
+    #[cfg(feature = "std")]
+    pub fn recent_overaligned(&self) -> Vec<OveralignedRecord> {
+        overalign::recent()
+    }
+    /// Freezes the allocator: from then on (and until `unfreeze`), any
+    /// allocation or reallocation on any thread aborts the process with a
+    /// message on stderr. This proves that a steady state is allocation-free;
+    /// running it under a debugger gives the culprit's backtrace.
+    ///
+    /// Releasing memory remains allowed. To check a single thread without
+    /// killing the process, see `forbid_alloc`.
+    #[cfg(feature = "std")]
+    pub fn freeze(&self) {
+        FROZEN.store(true, Ordering::Relaxed);
+    }
+    /// Lifts the effect of `freeze`
+    #[cfg(feature = "std")]
+    pub fn unfreeze(&self) {
+        FROZEN.store(false, Ordering::Relaxed);
+    }
+    /// Returns the number of bytes which have been freed by the program but
+    /// are still held in quarantine (these are *not* included in
+    /// `current_usage`).
+    #[cfg(feature = "quarantine")]
+    pub fn quarantined_bytes(&self) -> usize {
+        quarantine::quarantined_bytes()
+    }
+    /// Returns the number of quarantined blocks that have been found written
+    /// to after they were freed (use-after-free).
+    #[cfg(feature = "quarantine")]
+    pub fn quarantine_violations(&self) -> usize {
+        quarantine::violations()
+    }
+    /// Returns the size of the last quarantined block that has been found
+    /// written to after it was freed (0 if no violation was ever detected).
+    #[cfg(feature = "quarantine")]
+    pub fn last_quarantine_violation_size(&self) -> usize {
+        quarantine::last_violation_size()
+    }
+    /// Checks the poison pattern of all the blocks that are currently held in
+    /// quarantine (without waiting for their eviction) and returns the total
+    /// number of violations detected so far.
+    #[cfg(feature = "quarantine")]
+    pub fn verify_quarantine(&self) -> usize {
+        quarantine::verify()
+    }
+    /// Sets the maximum number of bytes that can be held in quarantine at
+    /// once (1 MB by default). The oldest blocks get evicted when that bound
+    /// is exceeded.
+    #[cfg(feature = "quarantine")]
+    pub fn set_quarantine_capacity(&self, bytes: usize) {
+        config::exclusive(|| quarantine::set_capacity(bytes))
+    }
+    /// Returns the number of blocks whose redzones have been found altered
+    /// upon release (buffer overflows or underflows).
+    #[cfg(feature = "redzones")]
+    pub fn redzone_violations(&self) -> usize {
+        redzones::violations()
+    }
+    /// Returns the size of the last block whose redzones have been found
+    /// altered (0 if no violation was ever detected).
+    #[cfg(feature = "redzones")]
+    pub fn last_redzone_violation_size(&self) -> usize {
+        redzones::last_violation_size()
+    }
+    /// Returns the number of bytes which are currently spent on redzones
+    /// (these are *not* included in `current_usage`).
+    #[cfg(feature = "redzones")]
+    pub fn redzone_overhead_bytes(&self) -> usize {
+        redzones::overhead_bytes()
+    }
+    /// Changes the patterns which are used to poison the freshly allocated
+    /// (non-zeroed) blocks and the freed blocks (0xAA and 0xDD by default).
+    #[cfg(feature = "poison")]
+    pub fn set_poison_patterns(&self, alloc_byte: u8, free_byte: u8) {
+        poison::set_patterns(alloc_byte, free_byte)
+    }
+    /// Returns the number of allocations which could not be recorded in the
+    /// pointer map because it was full. These allocations are still
+    /// accounted for in the usage counters, but they are invisible to the
+    /// features relying on the pointer map (e.g. the lifetime statistics).
+    #[cfg(feature = "pointer-map")]
+    pub fn pointer_map_overflows(&self) -> usize {
+        ptrmap::overflows()
+    }
+    /// Returns the histogram of the ages at which the blocks have been
+    /// released (only the blocks recorded in the pointer map are counted).
+    #[cfg(feature = "pointer-map")]
+    pub fn lifetime_histogram(&self) -> LifetimeHistogram {
+        lifetime::histogram()
+    }
+    /// Returns the mean age at which the blocks have been released, or None
+    /// if no block has been released yet.
+    #[cfg(feature = "pointer-map")]
+    pub fn mean_allocation_lifetime(&self) -> Option<Duration> {
+        lifetime::mean()
+    }
+    /// Returns the age of the oldest block which is still alive, or None if
+    /// there is no such block. A steadily growing value is a good leak smell.
+    ///
+    /// # Note
+    /// This is computed on demand by scanning the whole pointer map.
+    #[cfg(feature = "pointer-map")]
+    pub fn oldest_live_allocation_age(&self) -> Option<Duration> {
+        lifetime::oldest_live()
+    }
+    /// Returns the number of live bytes which were allocated more than
+    /// `older_than` ago: the interners, configurations and lazy statics which
+    /// inflate the usage forever (or the leaks).
+    ///
+    /// # Note
+    /// This is computed on demand by scanning the whole pointer map (a few
+    /// hundred thousand slots, under the locks of the allocation path), and
+    /// only knows about the blocks recorded in it. With a sample rate above 1
+    /// (see `set_sample_rate`), the sampled bytes are scaled up by the rate.
+    #[cfg(feature = "pointer-map")]
+    pub fn long_lived_bytes(&self, older_than: Duration) -> usize {
+        lifetime::long_lived(older_than).saturating_mul(sampling::rate())
+    }
+    /// Returns the part of the usage which actually fluctuates: the current
+    /// usage minus the bytes allocated more than a minute ago (see
+    /// `long_lived_bytes`, whose cost it shares).
+    #[cfg(feature = "pointer-map")]
+    pub fn transient_usage(&self) -> usize {
+        self.current_usage().saturating_sub(self.long_lived_bytes(Duration::from_secs(60)))
+    }
+    /// Returns the current sample rate of the costly diagnostics: only one
+    /// allocation out of that many is measured by the timed accounting and
+    /// recorded in the pointer map. It is 1 (every allocation) by default.
+    pub fn sample_rate(&self) -> usize {
+        sampling::rate()
+    }
+    /// Sets the sample rate of the costly diagnostics (a rate of 0 is
+    /// treated as 1). The plain counters are never sampled.
+    pub fn set_sample_rate(&self, n: usize) {
+        config::exclusive(|| sampling::set_rate(n));
+    }
+    /// Sets the sample rate of the costly diagnostics to `n` until the
+    /// returned guard is dropped, at which point the previous rate is
+    /// restored. This is meant to crank sampling down in a known allocation
+    /// heavy section.
+    ///
+    /// ```
+    /// # use peak_alloc::PeakAlloc;
+    /// let alloc = PeakAlloc;
+    /// {
+    ///     let _guard = alloc.with_sample_rate(100);
+    ///     assert_eq!(100, alloc.sample_rate());
+    ///     // allocation heavy loop
+    /// }
+    /// assert_eq!(1, alloc.sample_rate());
+    /// ```
+    pub fn with_sample_rate(&self, n: usize) -> SampleRateGuard {
+        SampleRateGuard::new(n)
+    }
+    /// Returns the latency histogram of the accounting performed upon each
+    /// allocation. This is a diagnostic for the maintainers of this crate;
+    /// it is not meant to be enabled in production.
+    #[cfg(feature = "timed-accounting")]
+    pub fn timed_accounting(&self) -> AccountingLatency {
+        timing::snapshot()
+    }
+    /// Clears the latency histogram of the accounting
+    #[cfg(feature = "timed-accounting")]
+    pub fn reset_timed_accounting(&self) {
+        timing::reset()
     }
     /// Performs the bytes to kilobytes conversion
     fn kb(x: usize) -> f32 {
-        x as f32 / 1024.0
+        x as f32 / units::KIB as f32
     }
     /// Performs the bytes to megabytes conversion
     fn mb(x: usize) -> f32 {
-        x as f32 / (1024.0 * 1024.0)
+        x as f32 / units::MIB as f32
     }
     /// Performs the bytes to gigabytes conversion
     fn gb(x: usize) -> f32 {
-        x as f32 / (1024.0 * 1024.0 * 1024.0)
+        x as f32 / units::GIB as f32
+    }
+    /// Performs the bytes to arbitrary units conversion
+    fn units(x: usize, unit_bytes: usize) -> f64 {
+        if unit_bytes == 0 {
+            f64::NAN
+        } else {
+            x as f64 / unit_bytes as f64
+        }
     }
 }
 
-/// PeakAlloc only implements the minimum required set of methods to make it
-/// useable as a global allocator (with `#[global_allocator]` attribute).
-///
-/// No funky stuff is done below.
-unsafe impl GlobalAlloc for PeakAlloc {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let ret = System.alloc(layout);
-        if !ret.is_null() {
-            // as pointed out by @luxalpa, fetch_add returns the PREVIOUS value.
-            let prev = CURRENT.fetch_add(layout.size(), Ordering::Relaxed);
-            PEAK.fetch_max(prev + layout.size(), Ordering::Relaxed);
-        }
-        ret
+/// Returns true iff `delta` more bytes can be allocated without exceeding
+/// the memory limit. The usage is clamped: a counter which has wrapped
+/// around (more bytes were released than allocated) refuses nothing.
+#[inline]
+fn within_limit(limits: &Limits, delta: usize) -> bool {
+    let limit = limits.memory_limit;
+    limit == 0 || TRACKER.current_usage().saturating_add(delta) <= limit
+}
+
+/// Returns by how many bytes the usage grows when a block of `old_size` bytes
+/// is resized to `new_size` bytes: the difference, or the whole new size when
+/// the old block was not tracked (and nothing when the new one is not)
+#[inline]
+fn growth(limits: &Limits, old_size: usize, new_size: usize) -> usize {
+    match (is_tracked(limits, old_size), is_tracked(limits, new_size)) {
+        (_, false) => 0,
+        (false, true) => new_size,
+        (true, true) => new_size.saturating_sub(old_size),
+    }
+}
+
+/// Counts an allocation which failed and returns its (null) result
+#[cold]
+fn failed() -> *mut u8 {
+    FAILED_ALLOCS.fetch_add(1, Ordering::Relaxed);
+    core::ptr::null_mut()
+}
+
+/// Returns true iff the allocations of the given size are to be accounted for
+#[inline]
+fn is_tracked(limits: &Limits, size: usize) -> bool {
+    #[cfg(feature = "std")]
+    if threads::is_internal() {
+        return false;
+    }
+    size >= limits.min_tracked_size
+}
+
+/// Performs all the accounting related to the allocation of a block
+#[inline]
+#[allow(unused_variables)]
+fn track_alloc(ptr: *mut u8, layout: Layout, limits: &Limits) {
+    #[cfg(feature = "pointer-map")]
+    if lifetime::TICKER.sampled() {
+        ptrmap::insert(ptr, layout.size(), clock::now());
+    }
+    #[cfg(feature = "histogram")]
+    histogram::record(layout);
+    large::on_alloc(layout.size(), limits.large_threshold);
+    overalign::on_alloc(layout);
+    add_memory(layout.size(), limits);
+}
+
+/// Performs all the accounting related to the deallocation of a block
+#[inline]
+#[allow(unused_variables)]
+fn track_dealloc(ptr: *mut u8, layout: Layout, limits: &Limits) {
+    #[cfg(feature = "histogram")]
+    histogram::release(layout);
+    #[cfg(feature = "pointer-map")]
+    if let Some(entry) = ptrmap::remove(ptr) {
+        lifetime::record(clock::now().saturating_sub(entry.born));
     }
+    large::on_dealloc(layout.size(), limits.large_threshold);
+    slack::on_dealloc(layout.size(), TRACKER.raw_current());
+    sub_memory(layout.size());
+}
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        System.dealloc(ptr, layout);
-        CURRENT.fetch_sub(layout.size(), Ordering::Relaxed);
+/// Performs all the accounting related to the resizing of a block (which
+/// may have moved from `old_ptr` to `new_ptr`). A resize is neither an
+/// allocation nor a deallocation: only the usage is adjusted by the size
+/// difference, unless the block crosses the minimum tracked size.
+#[inline]
+#[allow(unused_variables)]
+fn track_realloc(old_ptr: *mut u8, old_layout: Layout, new_ptr: *mut u8, new_layout: Layout, limits: &Limits) {
+    let (old_size, new_size) = (old_layout.size(), new_layout.size());
+    match (is_tracked(limits, old_size), is_tracked(limits, new_size)) {
+        (false, false) => return,
+        (true, false) => return track_dealloc(old_ptr, old_layout, limits),
+        (false, true) => return track_alloc(new_ptr, new_layout, limits),
+        (true, true) => {}
+    }
+    TRACKER.count_reallocation();
+    if new_ptr != old_ptr {
+        TRACKER.count_realloc_copy(old_size.min(new_size));
+    }
+    #[cfg(feature = "std")]
+    threads::check_forbidden();
+    #[cfg(feature = "histogram")]
+    {
+        histogram::release(old_layout);
+        histogram::record(new_layout);
+    }
+    #[cfg(feature = "pointer-map")]
+    if let Some(entry) = ptrmap::remove(old_ptr) {
+        ptrmap::insert(new_ptr, new_size, entry.born);
+    }
+    large::on_realloc(old_size, new_size, limits.large_threshold);
+    slack::on_realloc(old_size, new_size);
+    // only the difference is accounted for: the usage never counts both
+    // blocks (the copy made without `realloc` goes through alloc and dealloc)
+    if new_size >= old_size {
+        grow_memory(new_size - old_size, limits);
+        TRACKER.raise_largest(new_size);
+    } else {
+        shrink_memory(old_size - new_size);
     }
 }
 
 
-#[cfg(test)]
+
+#[cfg(all(test, feature = "std"))]
+#[allow(unsafe_code)]
 mod tests {
-    use crate::{CURRENT, PEAK};
+    use std::sync::{Mutex, MutexGuard};
 
     #[global_allocator]
     static PEAK_ALLOC: crate::PeakAlloc = crate::PeakAlloc;
 
+    /// All tests share the same (global) counters. This lock makes sure they
+    /// do not step on each other's toes.
+    static SERIAL: Mutex<()> = Mutex::new(());
+
+    fn serial() -> MutexGuard<'static, ()> {
+        SERIAL.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
     #[test]
     fn test_issue_4() {
+        let _guard = serial();
         // neutralize process allocated memory etc.. (makes it easier to reason about)
-        CURRENT.store(0, std::sync::atomic::Ordering::Relaxed);
-        PEAK.store   (0, std::sync::atomic::Ordering::Relaxed);
+        PEAK_ALLOC.reset_peak_usage();
+        let base = PEAK_ALLOC.current_usage();
 
         // initially both 
-        assert_eq!(0, PEAK_ALLOC.current_usage());
-        assert_eq!(0, PEAK_ALLOC.peak_usage());
+        assert_eq!(base, PEAK_ALLOC.current_usage());
+        assert_eq!(base, PEAK_ALLOC.peak_usage());
 
         // make one allocation:
         {
             let mut data = vec![0_u32; 1000];
 
-            assert_eq!(4000, PEAK_ALLOC.current_usage());
-            assert_eq!(4000, PEAK_ALLOC.peak_usage());     // before the fix, this would fail
+            assert_eq!(base + 4000, PEAK_ALLOC.current_usage());
+            assert_eq!(base + 4000, PEAK_ALLOC.peak_usage());     // before the fix, this would fail
 
             let mut tot = 0;
             for (i, x) in data.iter_mut().enumerate() {
@@ -178,7 +2224,3005 @@ mod tests {
             // drop the allocated data
         }
 
-        assert_eq!(0,    PEAK_ALLOC.current_usage());
-        assert_eq!(4000, PEAK_ALLOC.peak_usage());
+        assert_eq!(base,        PEAK_ALLOC.current_usage());
+        assert_eq!(base + 4000, PEAK_ALLOC.peak_usage());
+    }
+
+    #[test]
+    fn reported_baseline_is_subtracted_and_floored() {
+        let _guard = serial();
+        let data = vec![0_u8; 4096];
+        let raw  = PEAK_ALLOC.current_usage();
+
+        PEAK_ALLOC.set_reported_baseline(1024);
+        assert_eq!(raw - 1024, PEAK_ALLOC.current_usage());
+        assert!(PEAK_ALLOC.peak_usage() >= raw - 1024);
+
+        PEAK_ALLOC.set_reported_baseline(usize::MAX);
+        assert_eq!(0, PEAK_ALLOC.current_usage());
+        assert_eq!(0, PEAK_ALLOC.peak_usage());
+
+        PEAK_ALLOC.clear_reported_baseline();
+        assert_eq!(raw, PEAK_ALLOC.current_usage());
+        drop(data);
+    }
+
+    /// Returns the size of the block actually parked in quarantine when a
+    /// block of the given layout is freed.
+    #[cfg(feature = "quarantine")]
+    fn parked_size(layout: std::alloc::Layout) -> usize {
+        #[cfg(feature = "redzones")]
+        return crate::redzones::outer_layout(layout).unwrap().size();
+        #[cfg(not(feature = "redzones"))]
+        return layout.size();
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    fn underflow_warning_is_emitted_once() {
+        use std::sync::atomic::Ordering;
+        let _guard = serial();
+        crate::UNDERFLOW_WARNED.store(false, Ordering::Relaxed);
+
+        // releasing usize::MAX bytes necessarily underflows. Since it wraps
+        // around, this amounts to adding one byte: releasing one more byte
+        // afterwards restores the counter.
+        crate::sub_memory(usize::MAX);
+        crate::shrink_memory(1);
+        assert!(crate::UNDERFLOW_WARNED.load(Ordering::Relaxed));
+
+        crate::sub_memory(usize::MAX);
+        crate::shrink_memory(1);
+        // the warning has already been issued: it is not issued again
+        assert!(!crate::warn_underflow_once());
+    }
+
+    #[cfg(feature = "quarantine")]
+    #[test]
+    #[cfg_attr(miri, ignore)] // writes through a dangling pointer on purpose
+    fn quarantine_detects_use_after_free() {
+        use std::alloc::{GlobalAlloc, Layout};
+        let _guard = serial();
+        let layout = Layout::from_size_align(128, 8).unwrap();
+        let before = PEAK_ALLOC.quarantine_violations();
+        unsafe {
+            let ptr = PEAK_ALLOC.alloc(layout);
+            PEAK_ALLOC.dealloc(ptr, layout);
+            // the block is still parked in quarantine, hence this write lands
+            // in memory that is still owned by the allocator.
+            ptr.add(64).write(0);
+        }
+        assert_eq!(before + 1, PEAK_ALLOC.verify_quarantine());
+        assert_eq!(parked_size(layout), PEAK_ALLOC.last_quarantine_violation_size());
+        // the same violation is not reported twice
+        assert_eq!(before + 1, PEAK_ALLOC.verify_quarantine());
+    }
+
+    #[cfg(feature = "quarantine")]
+    #[test]
+    #[cfg_attr(miri, ignore)] // writes through a dangling pointer on purpose
+    fn quarantine_is_bounded_by_bytes() {
+        use std::alloc::{GlobalAlloc, Layout};
+        let _guard = serial();
+        let layout = Layout::from_size_align(128, 8).unwrap();
+        PEAK_ALLOC.set_quarantine_capacity(2 * parked_size(layout));
+        let before = PEAK_ALLOC.quarantine_violations();
+        unsafe {
+            let a = PEAK_ALLOC.alloc(layout);
+            let b = PEAK_ALLOC.alloc(layout);
+            let c = PEAK_ALLOC.alloc(layout);
+            let base = PEAK_ALLOC.current_usage();
+
+            PEAK_ALLOC.dealloc(a, layout);
+            a.add(64).write(0);
+            PEAK_ALLOC.dealloc(b, layout);
+            assert_eq!(before, PEAK_ALLOC.quarantine_violations());
+            // parking c evicts a, whose pattern gets checked on the way out
+            PEAK_ALLOC.dealloc(c, layout);
+            assert_eq!(before + 1, PEAK_ALLOC.quarantine_violations());
+
+            assert!(PEAK_ALLOC.quarantined_bytes() <= 2 * parked_size(layout));
+            assert_eq!(base - 3 * 128, PEAK_ALLOC.current_usage());
+        }
+        PEAK_ALLOC.set_quarantine_capacity(1024 * 1024);
     }
-}
\ No newline at end of file
+
+    #[cfg(feature = "redzones")]
+    #[test]
+    #[cfg_attr(miri, ignore)] // overflows a buffer on purpose
+    fn redzones_detect_one_byte_overflow() {
+        let _guard = serial();
+        let before = PEAK_ALLOC.redzone_violations();
+
+        let mut data: Vec<u8> = Vec::with_capacity(32);
+        unsafe { data.as_mut_ptr().add(32).write(0xAB) };
+        drop(data);
+
+        assert_eq!(before + 1, PEAK_ALLOC.redzone_violations());
+        assert_eq!(32, PEAK_ALLOC.last_redzone_violation_size());
+    }
+
+    #[cfg(feature = "redzones")]
+    #[test]
+    fn redzones_are_transparent_to_well_behaved_programs() {
+        let _guard = serial();
+        let before   = PEAK_ALLOC.redzone_violations();
+        let base     = PEAK_ALLOC.current_usage();
+        let overhead = PEAK_ALLOC.redzone_overhead_bytes();
+        {
+            #[repr(align(64))]
+            struct Aligned(#[allow(dead_code)] [u8; 64]);
+            let mut data = vec![0_u64; 100];
+            data.extend(0..100);
+            let aligned = Box::new(Aligned([1; 64]));
+            assert_eq!(0, &*aligned as *const Aligned as usize % 64);
+            assert_eq!(base + data.capacity() * 8 + 64, PEAK_ALLOC.current_usage());
+            assert!(PEAK_ALLOC.redzone_overhead_bytes() > overhead);
+        }
+        assert_eq!(base, PEAK_ALLOC.current_usage());
+        assert_eq!(overhead, PEAK_ALLOC.redzone_overhead_bytes());
+        assert_eq!(before, PEAK_ALLOC.redzone_violations());
+    }
+
+    #[cfg(feature = "timed-accounting")]
+    #[test]
+    fn accounting_latency_is_measured() {
+        use crate::AccountingLatency;
+        let _guard = serial();
+        PEAK_ALLOC.reset_timed_accounting();
+        let data = vec![0_u8; 128];
+        let latency = PEAK_ALLOC.timed_accounting();
+        assert!(latency.count() >= 1);
+        assert!(latency.quantile(0.5).is_some());
+        assert!(latency.to_string().contains("measurements"));
+        drop(data);
+
+        let mut latency = AccountingLatency { buckets: [0; crate::LATENCY_BUCKETS] };
+        assert_eq!(None, latency.quantile(0.5));
+        latency.buckets[3] = 9;
+        latency.buckets[5] = 1;
+        assert_eq!(Some(std::time::Duration::from_nanos(8)),  latency.quantile(0.9));
+        assert_eq!(Some(std::time::Duration::from_nanos(32)), latency.quantile(1.0));
+    }
+
+    #[cfg(feature = "poison")]
+    #[test]
+    fn poison_fills_allocated_but_not_zeroed_blocks() {
+        use std::alloc::{GlobalAlloc, Layout};
+        let _guard = serial();
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        unsafe {
+            let ptr = PEAK_ALLOC.alloc(layout);
+            let bytes = std::slice::from_raw_parts(ptr, 64);
+            assert!(bytes.iter().all(|b| *b == 0xAA));
+            // this is the block that is about to be freed
+            crate::poison::on_free(ptr, 64);
+            assert!(bytes.iter().all(|b| *b == 0xDD));
+            PEAK_ALLOC.dealloc(ptr, layout);
+
+            let ptr = PEAK_ALLOC.alloc_zeroed(layout);
+            let bytes = std::slice::from_raw_parts(ptr, 64);
+            assert!(bytes.iter().all(|b| *b == 0));
+            PEAK_ALLOC.dealloc(ptr, layout);
+
+            PEAK_ALLOC.set_poison_patterns(0x42, 0x24);
+            let ptr = PEAK_ALLOC.alloc(layout);
+            let bytes = std::slice::from_raw_parts(ptr, 64);
+            assert!(bytes.iter().all(|b| *b == 0x42));
+            crate::poison::on_free(ptr, 64);
+            assert!(bytes.iter().all(|b| *b == 0x24));
+            PEAK_ALLOC.dealloc(ptr, layout);
+
+            // zero sized blocks are left alone
+            crate::poison::on_alloc(ptr, 0);
+            crate::poison::on_free(ptr, 0);
+        }
+        PEAK_ALLOC.set_poison_patterns(crate::poison::DEFAULT_ALLOC_BYTE, crate::poison::DEFAULT_FREE_BYTE);
+    }
+
+    #[test]
+    fn wait_until_below_returns_once_memory_is_released() {
+        use std::time::Duration;
+        let _guard = serial();
+        let data = vec![0_u8; 1024 * 1024];
+        let threshold = PEAK_ALLOC.current_usage() - 512 * 1024;
+
+        assert!(!PEAK_ALLOC.wait_until_below(threshold, Duration::from_millis(20)));
+
+        let cleanup = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            drop(data);
+        });
+        assert!(PEAK_ALLOC.wait_until_below(threshold, Duration::from_secs(10)));
+        cleanup.join().unwrap();
+    }
+
+    #[cfg(feature = "pointer-map")]
+    #[test]
+    fn lifetimes_land_in_the_expected_buckets() {
+        use std::time::Duration;
+        let _guard = serial();
+
+        let before = PEAK_ALLOC.lifetime_histogram();
+        let data = vec![0_u8; 64];
+        assert!(PEAK_ALLOC.oldest_live_allocation_age().is_some());
+        std::thread::sleep(Duration::from_millis(50));
+        drop(data);
+        let after = PEAK_ALLOC.lifetime_histogram();
+        assert_eq!(before.under_1s + 1, after.under_1s);
+
+        let data = vec![0_u8; 64];
+        std::thread::sleep(Duration::from_millis(1100));
+        drop(data);
+        let after = PEAK_ALLOC.lifetime_histogram();
+        assert_eq!(before.longer + 1, after.longer);
+
+        assert!(PEAK_ALLOC.mean_allocation_lifetime().is_some());
+        assert!(PEAK_ALLOC.oldest_live_allocation_age().unwrap() >= Duration::from_millis(1100));
+    }
+
+    #[cfg(feature = "pointer-map")]
+    #[test]
+    fn long_lived_bytes_separate_old_and_young_blocks() {
+        use std::time::Duration;
+        let _guard = serial();
+        const MB: usize = 1024 * 1024;
+        let age = Duration::from_millis(200);
+
+        let old = std::hint::black_box(vec![1_u8; 4 * MB]);
+        std::thread::sleep(2 * age);
+        let with_old = PEAK_ALLOC.long_lived_bytes(age);
+        assert!(with_old >= 4 * MB);
+        let mut young = std::hint::black_box(vec![1_u8; 8 * MB]);
+        let with_young = PEAK_ALLOC.long_lived_bytes(age);
+        assert!(with_young < with_old + MB, "{} vs {}", with_young, with_old);
+        std::thread::sleep(2 * age);
+        // growing a block does not make it any younger (unless it is copied)
+        young.reserve_exact(8 * MB);
+        let moved = crate::system::REALLOC_BY_COPY;
+        if !moved {
+            assert!(PEAK_ALLOC.long_lived_bytes(age) >= with_old + 16 * MB);
+        }
+        std::thread::sleep(2 * age);
+        drop(old);
+        assert!(PEAK_ALLOC.long_lived_bytes(age) >= 16 * MB);
+        assert!(PEAK_ALLOC.long_lived_bytes(Duration::from_secs(3600)) < MB);
+        assert!(PEAK_ALLOC.transient_usage() <= PEAK_ALLOC.current_usage());
+        drop(young);
+    }
+
+    #[cfg(feature = "pointer-map")]
+    #[test]
+    fn pointer_map_forgets_released_blocks() {
+        let _guard = serial();
+        let data = (0..10_000).map(Box::new).collect::<Vec<_>>();
+        let contains = |ptr: usize| {
+            let mut found = false;
+            crate::ptrmap::for_each(|e| found |= e.ptr == ptr);
+            found
+        };
+        let first = &*data[0] as *const i32 as usize;
+        assert!(contains(first));
+        drop(data);
+        assert!(!contains(first));
+    }
+
+    #[test]
+    fn final_report_covers_all_sections() {
+        let _guard = serial();
+        let before = PEAK_ALLOC.final_report();
+        let small = vec![0_u8; 10];
+        let large = vec![0_u8; 100_000];
+        drop(small);
+        let report = PEAK_ALLOC.final_report();
+        drop(large);
+
+        assert!(report.total_allocated >= before.total_allocated + 100_010);
+        assert!(report.allocation_count >= before.allocation_count + 2);
+        assert!(report.deallocation_count > before.deallocation_count);
+        assert!(report.largest_allocation >= 100_000);
+        assert!(report.peak_usage >= report.current_usage);
+
+        let text = report.to_string();
+        assert!(text.contains("memory usage"));
+        assert!(text.contains("peak"));
+        assert!(text.contains("total allocated"));
+        assert!(text.contains("allocations"));
+        assert!(text.contains("largest allocation"));
+        #[cfg(feature = "histogram")]
+        assert!(text.contains("size histogram"));
+    }
+
+    #[cfg(feature = "histogram")]
+    #[test]
+    fn size_histogram_uses_power_of_two_classes() {
+        use crate::histogram::class_of;
+        let _guard = serial();
+        assert_eq!(0, class_of(0));
+        assert_eq!(0, class_of(1));
+        assert_eq!(1, class_of(2));
+        assert_eq!(7, class_of(65));
+        assert_eq!(7, class_of(128));
+        assert_eq!(8, class_of(129));
+        assert_eq!(64, class_of(usize::MAX));
+
+        let before = PEAK_ALLOC.size_histogram();
+        let data = vec![0_u8; 100];
+        let after = PEAK_ALLOC.size_histogram();
+        assert!(after.counts[7] > before.counts[7]);
+        drop(data);
+    }
+
+    #[cfg(feature = "histogram")]
+    #[test]
+    fn each_size_class_has_a_peak_of_its_own() {
+        let _guard = serial();
+        // the 64 bytes blocks are in class 6 (33 to 64 bytes)
+        PEAK_ALLOC.reset_size_class_peak(6);
+        let before = PEAK_ALLOC.live_by_size_class().class(6);
+        assert_eq!(64, before.upper_bound);
+        assert_eq!(before.live_bytes, before.peak_live_bytes);
+
+        let small: Vec<Box<[u8; 64]>> = (0..1000).map(|_| Box::new([0; 64])).collect();
+        assert!(PEAK_ALLOC.live_by_size_class().class(6).live_bytes >= before.live_bytes + 64_000);
+        drop(small);
+        let big = std::hint::black_box(vec![0_u8; 8 << 20]);
+        let at_peak = PEAK_ALLOC.live_by_size_class();
+        assert!(PEAK_ALLOC.peak_usage() >= 8 << 20);
+        drop(big);
+
+        // the class peaked before the global peak, with less than it held
+        let after = PEAK_ALLOC.live_by_size_class();
+        assert!(at_peak.class(6).live_bytes < before.live_bytes + 64_000);
+        assert!(after.class(6).peak_live_bytes >= before.live_bytes + 64_000);
+        assert!(after.class(23).peak_live_bytes >= 8 << 20);
+        assert!(after.iter().any(|class| class.upper_bound == 64));
+
+        // a reallocated block moves from one class to the other
+        let mut grown: Vec<u8> = Vec::with_capacity(100);
+        let moved = PEAK_ALLOC.live_by_size_class();
+        grown.reserve_exact(1000);
+        let resized = PEAK_ALLOC.live_by_size_class();
+        assert!(resized.class(10).live_bytes >= moved.class(10).live_bytes + 1000);
+        assert!(resized.class(10).peak_live_bytes >= moved.class(10).live_bytes + 1000);
+        assert!(resized.class(7).live_bytes < moved.class(7).live_bytes);
+        assert!(resized.class(7).peak_live_bytes >= moved.class(7).live_bytes);
+        drop(grown);
+        PEAK_ALLOC.reset_peak_usage();
+        let reset = PEAK_ALLOC.live_by_size_class();
+        assert!(reset.class(23).peak_live_bytes < 8 << 20);
+    }
+
+    #[cfg(feature = "histogram")]
+    #[test]
+    fn internal_fragmentation_is_estimated_from_the_size_classes() {
+        let _guard = serial();
+        // 65 bytes blocks fall in the 128 bytes class: 63 bytes are lost
+        let blocks: Vec<Vec<u8>> = (0..100).map(|_| Vec::with_capacity(65)).collect();
+        assert!(PEAK_ALLOC.estimated_internal_fragmentation() >= 100 * 63);
+        drop(blocks);
+    }
+
+    #[cfg(feature = "histogram")]
+    #[test]
+    fn alignment_report_tracks_overaligned_allocations() {
+        use std::alloc::{GlobalAlloc, Layout};
+        #[repr(align(64))]
+        struct Aligned(#[allow(dead_code)] u8);
+        let _guard = serial();
+
+        let before = PEAK_ALLOC.alignment_report();
+        let boxed = Box::new(Aligned(1));
+        let after = PEAK_ALLOC.alignment_report();
+        assert_eq!(before.count(64) + 1, after.count(64));
+        // the size of a rust type is always a multiple of its alignment
+        assert_eq!(before.padding_waste_bytes, after.padding_waste_bytes);
+        drop(boxed);
+
+        let layout = Layout::from_size_align(65, 64).unwrap();
+        let waste = PEAK_ALLOC.padding_waste_bytes();
+        unsafe {
+            let ptr = PEAK_ALLOC.alloc(layout);
+            assert_eq!(waste + 63, PEAK_ALLOC.padding_waste_bytes());
+            // a reallocation keeps the alignment but changes the padding
+            let ptr = PEAK_ALLOC.realloc(ptr, layout, 100);
+            assert_eq!(waste + 28, PEAK_ALLOC.padding_waste_bytes());
+            PEAK_ALLOC.dealloc(ptr, Layout::from_size_align(100, 64).unwrap());
+        }
+        assert_eq!(waste, PEAK_ALLOC.padding_waste_bytes());
+        assert!(PEAK_ALLOC.alignment_report().to_string().contains("padding waste"));
+    }
+
+    #[test]
+    fn allocations_below_min_tracked_size_are_ignored() {
+        use std::alloc::{GlobalAlloc, Layout};
+        let _guard = serial();
+        let small = Layout::from_size_align(100, 8).unwrap();
+        let large = Layout::from_size_align(2048, 8).unwrap();
+
+        PEAK_ALLOC.set_min_tracked_size(1024);
+        let base = PEAK_ALLOC.current_usage();
+        unsafe {
+            let a = PEAK_ALLOC.alloc(small);
+            assert_eq!(base, PEAK_ALLOC.current_usage());
+            let b = PEAK_ALLOC.alloc(large);
+            assert_eq!(base + 2048, PEAK_ALLOC.current_usage());
+            PEAK_ALLOC.dealloc(a, small);
+            PEAK_ALLOC.dealloc(b, large);
+        }
+        assert_eq!(base, PEAK_ALLOC.current_usage());
+        PEAK_ALLOC.set_min_tracked_size(0);
+    }
+
+    #[test]
+    fn allocations_beyond_the_memory_limit_fail() {
+        use std::alloc::{GlobalAlloc, Layout};
+        let _guard = serial();
+        assert_eq!(None, PEAK_ALLOC.memory_limit());
+        // leave enough room for the tests running concurrently
+        let limit = crate::TRACKER.raw_current() + 64 * 1024 * 1024;
+        PEAK_ALLOC.set_memory_limit(limit);
+        assert_eq!(Some(limit), PEAK_ALLOC.memory_limit());
+        let huge = Layout::from_size_align(128 * 1024 * 1024, 8).unwrap();
+        let small = Layout::from_size_align(1024, 8).unwrap();
+        unsafe {
+            assert!(PEAK_ALLOC.alloc(huge).is_null());
+            let ptr = PEAK_ALLOC.alloc(small);
+            assert!(!ptr.is_null());
+            assert!(PEAK_ALLOC.realloc(ptr, small, huge.size()).is_null());
+            PEAK_ALLOC.dealloc(ptr, small);
+        }
+        PEAK_ALLOC.set_memory_limit(0);
+        assert_eq!(None, PEAK_ALLOC.memory_limit());
+        unsafe {
+            let ptr = PEAK_ALLOC.alloc(huge);
+            assert!(!ptr.is_null());
+            PEAK_ALLOC.dealloc(ptr, huge);
+        }
+    }
+
+    #[test]
+    fn the_limit_still_applies_after_an_over_release() {
+        let _guard = serial();
+        // releasing more than what is in use wraps the counter around
+        let over = crate::TRACKER.raw_current() + 1000;
+        crate::TRACKER.shrink(over);
+        PEAK_ALLOC.set_memory_limit(1 << 30);
+        let mut data: Vec<u8> = Vec::new();
+        let reserved = data.try_reserve(100);
+        let refused = data.try_reserve_exact(2 << 30);
+        drop(data);
+        PEAK_ALLOC.set_memory_limit(0);
+        // releasing the wrapped around amount adds it back
+        crate::TRACKER.shrink(over.wrapping_neg());
+        assert!(reserved.is_ok());
+        assert!(refused.is_err());
+    }
+
+    #[test]
+    fn an_over_release_does_not_poison_the_process() {
+        let _guard = serial();
+        let over = crate::TRACKER.raw_current() + 1000;
+        crate::TRACKER.shrink(over);
+        PEAK_ALLOC.assert_peak_under(64 << 20);
+        drop(std::hint::black_box(vec![0_u8; 64]));
+        let poisoned = PEAK_ALLOC.poisoned();
+        PEAK_ALLOC.clear_peak_ceiling();
+        crate::TRACKER.shrink(over.wrapping_neg());
+        assert!(!poisoned);
+    }
+
+    #[test]
+    fn refused_allocations_are_counted_as_failed() {
+        use std::alloc::{GlobalAlloc, Layout};
+        let _guard = serial();
+        let failures = PEAK_ALLOC.failed_allocation_count();
+        PEAK_ALLOC.set_memory_limit(crate::TRACKER.raw_current() + 64 * 1024 * 1024);
+        let mut data: Vec<u8> = Vec::new();
+        assert!(data.try_reserve_exact(128 * 1024 * 1024).is_err());
+        let huge = Layout::from_size_align(128 * 1024 * 1024, 8).unwrap();
+        assert!(unsafe { PEAK_ALLOC.alloc_zeroed(huge) }.is_null());
+        PEAK_ALLOC.set_memory_limit(0);
+        assert_eq!(failures + 2, PEAK_ALLOC.failed_allocation_count());
+        // so is a request the system cannot serve
+        assert!(data.try_reserve_exact(isize::MAX as usize / 2).is_err());
+        assert_eq!(failures + 3, PEAK_ALLOC.failed_allocation_count());
+    }
+
+    #[test]
+    fn limits_locks_and_callbacks_do_not_deadlock_under_load() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        let _guard = serial();
+        // allocating callbacks, which run while the other threads allocate
+        PEAK_ALLOC.on_event(|event| drop(format!("{:?}", event)));
+        PEAK_ALLOC.on_reset(|stats| drop(vec![stats.peak_usage; 16]));
+        PEAK_ALLOC.set_memory_limit(crate::TRACKER.raw_current() + 256 * 1024 * 1024);
+        let done = AtomicBool::new(false);
+        std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..8)
+                .map(|t| {
+                    scope.spawn(move || {
+                        let mut held: Vec<Vec<u8>> = Vec::new();
+                        for i in 0..500 {
+                            let mut data = vec![t as u8; 1 + (i * 37 + t * 101) % 4096];
+                            data.extend_from_slice(&[0; 300]);
+                            data.truncate(10);
+                            data.shrink_to_fit();
+                            held.push(data);
+                            if held.len() > 32 {
+                                held.swap_remove(i % 32);
+                            }
+                            if i % 100 == 0 {
+                                // beyond the limit
+                                assert!(Vec::<u8>::new().try_reserve_exact(512 * 1024 * 1024).is_err());
+                            }
+                        }
+                    })
+                })
+                .collect();
+            scope.spawn(|| {
+                while !done.load(Ordering::Relaxed) {
+                    PEAK_ALLOC.drain_events();
+                    PEAK_ALLOC.reset_peak_usage();
+                    #[cfg(feature = "quarantine")]
+                    PEAK_ALLOC.verify_quarantine();
+                    #[cfg(feature = "pointer-map")]
+                    PEAK_ALLOC.long_lived_bytes(std::time::Duration::from_millis(1));
+                    std::thread::yield_now();
+                }
+            });
+            for worker in workers {
+                worker.join().unwrap();
+            }
+            done.store(true, Ordering::Relaxed);
+        });
+        PEAK_ALLOC.set_memory_limit(0);
+        PEAK_ALLOC.drain_events();
+    }
+
+    #[test]
+    fn footprint_compares_tracked_heap_and_rss() {
+        let _guard = serial();
+        let data = vec![1_u8; 1024 * 1024];
+        let footprint = PEAK_ALLOC.footprint();
+        if cfg!(any(target_os = "linux", target_os = "macos", windows)) {
+            let rss = footprint.rss.expect("rss should be available");
+            assert!(rss >= footprint.tracked_heap);
+            assert_eq!(Some(rss as isize - footprint.tracked_heap as isize), footprint.difference);
+        }
+        assert!(footprint.to_string().contains("tracked heap"));
+        drop(data);
+    }
+
+    #[cfg(all(feature = "actual-size", windows))]
+    #[test]
+    fn heap_overhead_is_the_slack_of_the_process_heap() {
+        use std::alloc::{GlobalAlloc, Layout};
+        let _guard = serial();
+        let sizes = [1, 7, 24, 100, 1000, 4097, 65_536, 1 << 20];
+        let layouts: Vec<_> = sizes.iter().map(|&size| Layout::from_size_align(size, 8).unwrap()).collect();
+        let overhead = PEAK_ALLOC.heap_overhead_bytes();
+        let fallbacks = PEAK_ALLOC.heap_size_fallback_count();
+        unsafe {
+            let blocks: Vec<_> = layouts.iter().map(|&layout| PEAK_ALLOC.alloc(layout)).collect();
+            let extra = PEAK_ALLOC.heap_overhead_bytes().wrapping_sub(overhead);
+            // at most a granule (and a page for the largest blocks) per block
+            assert!(extra <= sizes.len() * 4096, "{}", extra);
+            // the over-aligned blocks are not queried
+            let aligned = Layout::from_size_align(64, 4096).unwrap();
+            let block = PEAK_ALLOC.alloc(aligned);
+            assert!(PEAK_ALLOC.heap_size_fallback_count() > fallbacks);
+            PEAK_ALLOC.dealloc(block, aligned);
+            for (block, layout) in blocks.into_iter().zip(layouts.iter()) {
+                PEAK_ALLOC.dealloc(block, *layout);
+            }
+        }
+        assert_eq!(overhead, PEAK_ALLOC.heap_overhead_bytes());
+    }
+
+    #[cfg(feature = "actual-size")]
+    #[test]
+    fn fragmentation_compares_requested_usable_and_rss() {
+        let _guard = serial();
+        if !cfg!(any(target_os = "linux", target_os = "macos")) {
+            assert_eq!(None, PEAK_ALLOC.fragmentation());
+            return;
+        }
+        let before = PEAK_ALLOC.fragmentation().expect("fragmentation should be available");
+        // odd sizes do not match the size classes of the allocator: each
+        // block comes with some slack
+        let mut blocks: Vec<Vec<u8>> = (0..1_000).map(|i| vec![1_u8; 17 + 2 * i]).collect();
+        let mut i = 0;
+        blocks.retain(|_| {
+            i += 1;
+            i % 2 == 0
+        });
+        let report = PEAK_ALLOC.fragmentation().expect("fragmentation should be available");
+        assert!(report.usable_bytes >= report.requested_bytes, "{:?}", report);
+        assert!(report.usable_bytes - before.usable_bytes > report.requested_bytes - before.requested_bytes);
+        assert!(report.rss_bytes >= report.usable_bytes, "{:?}", report);
+        assert!(report.slack_ratio() > 0.0 && report.slack_ratio() < 0.5, "{:?}", report);
+        assert!(report.amplification() >= 1.0);
+        assert!(report.to_string().contains("% slack"));
+        drop(blocks);
+    }
+
+    #[cfg(feature = "actual-size")]
+    #[test]
+    fn usable_size_accounting_counts_the_slack() {
+        use std::alloc::{GlobalAlloc, Layout};
+        let _guard = serial();
+        assert!(!PEAK_ALLOC.usable_size_accounting());
+        PEAK_ALLOC.set_usable_size_accounting(true);
+        let base = PEAK_ALLOC.current_usage();
+        // odd sizes do not match the size classes of the allocator
+        let layouts: Vec<Layout> = (0..100).map(|i| Layout::from_size_align(17 + 2 * i, 1).unwrap()).collect();
+        let requested: usize = layouts.iter().map(|layout| layout.size()).sum();
+        let blocks: Vec<*mut u8> = layouts.iter().map(|&layout| unsafe { PEAK_ALLOC.alloc(layout) }).collect();
+        let charged = PEAK_ALLOC.current_usage() - base;
+        if cfg!(any(target_os = "linux", target_os = "macos")) {
+            assert!(charged > requested, "{} <= {}", charged, requested);
+            let report = PEAK_ALLOC.fragmentation().expect("fragmentation should be available");
+            assert!(report.usable_bytes >= report.requested_bytes, "{:?}", report);
+        } else {
+            assert!(charged >= requested);
+        }
+        for (ptr, layout) in blocks.into_iter().zip(layouts) {
+            unsafe { PEAK_ALLOC.dealloc(ptr, layout) };
+        }
+        // the very same slack was released
+        assert_eq!(base, PEAK_ALLOC.current_usage());
+        PEAK_ALLOC.set_usable_size_accounting(false);
+    }
+
+    #[cfg(all(feature = "psi", target_os = "linux"))]
+    #[test]
+    fn memory_pressure_is_reported_when_the_stall_exceeds_the_threshold() {
+        use crate::{PressureConfig, PressureKind};
+        use std::sync::mpsc;
+        use std::time::Duration;
+
+        let dir = std::env::temp_dir().join(format!("peak_alloc_psi_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("memory");
+        let write = |some: u64, full: u64| {
+            let content = format!(
+                "some avg10=12.50 avg60=3.00 avg300=1.00 total={}\n\
+                 full avg10=2.00 avg60=1.00 avg300=0.50 total={}\n",
+                some, full
+            );
+            // renaming is atomic: the watcher never reads a partial file
+            std::fs::write(dir.join("memory.tmp"), content).unwrap();
+            std::fs::rename(dir.join("memory.tmp"), &path).unwrap();
+        };
+        write(1_000, 10);
+
+        let (sender, receiver) = mpsc::channel();
+        let config = PressureConfig::default()
+            .path(&path)
+            .kind(PressureKind::Full)
+            .stall(Duration::from_millis(100))
+            .window(Duration::from_millis(10));
+        let mut watcher = PEAK_ALLOC.watch_memory_pressure(config, move |event| {
+            let _ = sender.send(event);
+        });
+        // only the `some` line stalls: nothing is reported
+        std::thread::sleep(Duration::from_millis(50));
+        write(900_000, 20);
+        assert!(receiver.recv_timeout(Duration::from_millis(100)).is_err());
+
+        write(900_000, 200_020);
+        let event = receiver.recv_timeout(Duration::from_secs(5)).expect("no pressure event");
+        assert_eq!(Duration::from_millis(200), event.stalled);
+        assert_eq!(12.5, event.pressure.some.avg10);
+        assert_eq!(200_020, event.pressure.full.total);
+        assert!(event.tracked_usage > 0);
+
+        watcher.stop();
+        assert!(!watcher.is_running());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "usdt")]
+    #[test]
+    fn usdt_probes_only_fire_when_a_tracer_is_attached() {
+        use crate::usdt;
+        use std::sync::atomic::Ordering;
+        let _guard = serial();
+
+        let (peaks, crossings) = usdt::fired();
+        let threshold = PEAK_ALLOC.current_usage() + 1024 * 1024;
+        PEAK_ALLOC.set_watch_threshold(threshold);
+        drop(vec![0_u8; 2 * 1024 * 1024]);
+        // nobody is attached: the probes are skipped
+        assert_eq!((peaks, crossings), usdt::fired());
+
+        // this is what the tracer does when it attaches
+        usdt::NEW_PEAK.fetch_add(1, Ordering::Relaxed);
+        usdt::THRESHOLD_CROSSED.fetch_add(1, Ordering::Relaxed);
+        PEAK_ALLOC.reset_peak_usage();
+        drop(vec![0_u8; 2 * 1024 * 1024]);
+        usdt::NEW_PEAK.fetch_sub(1, Ordering::Relaxed);
+        usdt::THRESHOLD_CROSSED.fetch_sub(1, Ordering::Relaxed);
+        PEAK_ALLOC.set_watch_threshold(0);
+
+        let (new_peaks, new_crossings) = usdt::fired();
+        assert!(new_peaks > peaks);
+        assert_eq!(crossings + 1, new_crossings);
+    }
+
+    #[test]
+    fn event_handlers_may_allocate() {
+        use crate::Event;
+        use std::sync::{Arc, Mutex};
+        let _guard = serial();
+
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&log);
+        PEAK_ALLOC.on_event(move |event: Event| {
+            // formatting allocates: this is fine outside of the allocator
+            let line = format!("{:?}", event);
+            sink.lock().unwrap().push(line);
+        });
+        PEAK_ALLOC.drain_events();
+
+        let threshold = PEAK_ALLOC.current_usage() + 1024 * 1024;
+        PEAK_ALLOC.set_watch_threshold(threshold);
+        let data = vec![0_u8; 2 * 1024 * 1024];
+        PEAK_ALLOC.set_watch_threshold(0);
+        drop(data);
+
+        assert!(PEAK_ALLOC.drain_events() >= 1);
+        let log = log.lock().unwrap();
+        assert!(log.iter().any(|line| line.contains(&format!("threshold: {}", threshold))));
+    }
+
+    #[cfg(feature = "checked-layout")]
+    #[test]
+    fn invalid_layouts_are_rejected() {
+        use std::alloc::{GlobalAlloc, Layout};
+        let _guard = serial();
+        let invalid = PEAK_ALLOC.invalid_request_count();
+        let failures = PEAK_ALLOC.failed_allocation_count();
+        let usage = PEAK_ALLOC.current_usage();
+
+        let empty = Layout::from_size_align(0, 8).unwrap();
+        assert!(unsafe { PEAK_ALLOC.alloc(empty) }.is_null());
+        assert!(unsafe { PEAK_ALLOC.alloc_zeroed(empty) }.is_null());
+        assert_eq!(invalid + 2, PEAK_ALLOC.invalid_request_count());
+
+        let layout = Layout::from_size_align(64, 16).unwrap();
+        let ptr = unsafe { PEAK_ALLOC.alloc(layout) };
+        assert!(!ptr.is_null());
+        for new_size in [0, isize::MAX as usize - 14, isize::MAX as usize, usize::MAX] {
+            assert!(unsafe { PEAK_ALLOC.realloc(ptr, layout, new_size) }.is_null());
+        }
+        assert_eq!(invalid + 6, PEAK_ALLOC.invalid_request_count());
+        // every rejected request is a failed one too
+        assert_eq!(failures + 6, PEAK_ALLOC.failed_allocation_count());
+        // the largest valid size is forwarded (and fails on its own)
+        assert!(unsafe { PEAK_ALLOC.realloc(ptr, layout, isize::MAX as usize - 15) }.is_null());
+        assert_eq!(invalid + 6, PEAK_ALLOC.invalid_request_count());
+        assert_eq!(failures + 7, PEAK_ALLOC.failed_allocation_count());
+
+        // the original block is left untouched, and the accounting too
+        assert_eq!(usage + 64, PEAK_ALLOC.current_usage());
+        unsafe { PEAK_ALLOC.dealloc(ptr, layout) };
+    }
+
+    #[test]
+    fn an_imported_state_restores_all_the_counters() {
+        let _guard = serial();
+        let state = PEAK_ALLOC.export_state();
+        let stats = PEAK_ALLOC.stats();
+        let kept = std::hint::black_box(vec![0_u8; 1 << 20]);
+        let mut grown = std::hint::black_box(Vec::<u8>::with_capacity(8));
+        grown.reserve(4096);
+        PEAK_ALLOC.record_external_alloc(64);
+        let _ = std::hint::black_box(Vec::<u8>::new().try_reserve(usize::MAX / 2));
+        // a new reporting window starts
+        let _ = PEAK_ALLOC.take_stats();
+        assert_ne!(state, PEAK_ALLOC.export_state());
+        // exported without allocating: the blocks are released afterwards
+        let mutated = PEAK_ALLOC.export_state();
+        PEAK_ALLOC.import_state(state);
+        assert_eq!(state, PEAK_ALLOC.export_state());
+        assert_eq!(stats, PEAK_ALLOC.stats());
+        let bytes = mutated.to_bytes();
+        PEAK_ALLOC.import_state(crate::AllocatorState::from_bytes(&bytes).unwrap());
+        assert_eq!(mutated, PEAK_ALLOC.export_state());
+        PEAK_ALLOC.record_external_dealloc(64);
+        drop((kept, grown));
+    }
+
+    #[test]
+    fn the_internal_allocations_are_not_accounted_for() {
+        let _guard = serial();
+        PEAK_ALLOC.reset_peak_usage();
+        let (usage, allocations) = (PEAK_ALLOC.current_usage(), PEAK_ALLOC.allocation_count());
+        {
+            let _internal = crate::threads::internal();
+            let mut report = String::with_capacity(1 << 20);
+            report.push_str("internal");
+            report.reserve(4 << 20);
+            drop(std::hint::black_box(report));
+        }
+        assert_eq!((usage, allocations), (PEAK_ALLOC.current_usage(), PEAK_ALLOC.allocation_count()));
+        assert!(PEAK_ALLOC.peak_usage() < usage + (1 << 20));
+        // the handlers are registered for good
+        let large = [7_u8; 64 << 10];
+        PEAK_ALLOC.on_event(move |_| {
+            std::hint::black_box(&large);
+        });
+        assert_eq!(usage, PEAK_ALLOC.current_usage());
+        assert!(PEAK_ALLOC.peak_usage() < usage + (64 << 10));
+        // and the accounting resumes
+        let block = std::hint::black_box(vec![0_u8; 1 << 20]);
+        assert_eq!(usage + (1 << 20), PEAK_ALLOC.current_usage());
+        drop(block);
+    }
+
+    #[test]
+    fn the_background_threads_allocate_as_internal() {
+        use crate::periodic::{Periodic, Scratch};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+        let _guard = serial();
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let kept = Arc::new(Mutex::new(Vec::<u64>::new()));
+        let (ticked, pushed) = (Arc::clone(&ticks), Arc::clone(&kept));
+        PEAK_ALLOC.reset_peak_usage();
+        let before = PEAK_ALLOC.current_usage();
+        let mut buffer = Scratch::new();
+        let mut periodic = Periodic::spawn("peak_alloc-test", Duration::from_millis(1), move || {
+            // a buffer grown from one run to the next, and a large temporary
+            buffer.get_or_init(Vec::new).extend_from_slice(&[0_u8; 64 << 10]);
+            drop(std::hint::black_box(vec![0_u8; 16 << 20]));
+            // what the callbacks of the application allocate is theirs
+            crate::threads::accounted(|| pushed.lock().unwrap().push(1));
+            ticked.fetch_add(1, Ordering::SeqCst);
+        });
+        while ticks.load(Ordering::SeqCst) < 8 {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        periodic.stop();
+        let usage = PEAK_ALLOC.current_usage() - before;
+        assert!(PEAK_ALLOC.peak_usage() < before + (16 << 20));
+        assert!(usage >= 8 * kept.lock().unwrap().capacity() && usage < 64 << 10, "{}", usage);
+    }
+
+    #[test]
+    fn thread_ids_are_unique_and_stable() {
+        let main = PEAK_ALLOC.current_thread_id();
+        assert_eq!(main, PEAK_ALLOC.current_thread_id());
+        let threads = (0..32)
+            .map(|_| {
+                std::thread::spawn(|| {
+                    let id = PEAK_ALLOC.current_thread_id();
+                    drop(std::hint::black_box(vec![0_u8; 64]));
+                    assert_eq!(id, PEAK_ALLOC.current_thread_id());
+                    id
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut ids = threads.into_iter().map(|thread| thread.join().unwrap()).collect::<Vec<_>>();
+        ids.push(main);
+        ids.sort_unstable();
+        ids.dedup();
+        assert_eq!(33, ids.len());
+        assert!(!ids.contains(&0));
+    }
+
+    #[cfg(feature = "per-thread")]
+    #[test]
+    fn the_names_of_the_threads_are_known_by_their_ids() {
+        use std::sync::mpsc;
+        let (send_id, ids) = mpsc::channel();
+        let (done, wait) = mpsc::channel::<()>();
+        let worker = std::thread::Builder::new()
+            .name("worker-7".into())
+            .spawn(move || {
+                drop(std::hint::black_box(vec![0_u8; 64]));
+                send_id.send(PEAK_ALLOC.current_thread_id()).unwrap();
+                wait.recv().unwrap();
+            })
+            .unwrap();
+        let id = ids.recv().unwrap();
+        assert_eq!(Some("worker-7".to_string()), PEAK_ALLOC.thread_name(id));
+        assert_eq!(None, PEAK_ALLOC.thread_name(0));
+        done.send(()).unwrap();
+        worker.join().unwrap();
+        // the exited threads are forgotten
+        assert_eq!(None, PEAK_ALLOC.thread_name(id));
+    }
+
+    #[cfg(feature = "per-thread")]
+    #[test]
+    fn peak_is_broken_down_per_thread() {
+        use std::sync::{Arc, Barrier};
+        const MB: usize = 1024 * 1024;
+        let _guard = serial();
+        PEAK_ALLOC.set_peak_breakdown_step(MB / 2);
+        PEAK_ALLOC.reset_peak_usage();
+
+        // each thread holds its block until both have allocated theirs
+        let (allocated, release) = (Arc::new(Barrier::new(3)), Arc::new(Barrier::new(3)));
+        let spawn = |size: usize| {
+            let (allocated, release) = (Arc::clone(&allocated), Arc::clone(&release));
+            std::thread::spawn(move || {
+                let block = std::hint::black_box(vec![1_u8; size]);
+                allocated.wait();
+                release.wait();
+                drop(block);
+            })
+        };
+        let greedy = spawn(10 * MB);
+        let moderate = spawn(MB);
+        allocated.wait();
+        let breakdown = PEAK_ALLOC.peak_thread_breakdown();
+        release.wait();
+        let (greedy_id, moderate_id) = (greedy.thread().id(), moderate.thread().id());
+        greedy.join().unwrap();
+        moderate.join().unwrap();
+        PEAK_ALLOC.set_peak_breakdown_step(MB);
+
+        let net = |id| breakdown.iter().find(|(thread, _)| *thread == id).map(|(_, net)| *net);
+        assert_eq!(Some(greedy_id), breakdown.first().map(|(thread, _)| *thread));
+        // a spawned thread releases a few bytes allocated by its parent
+        assert!(net(greedy_id).unwrap() > 9 * MB as isize);
+        assert!(net(moderate_id).unwrap() > MB as isize / 2);
+        assert!(net(moderate_id).unwrap() < 2 * MB as isize);
+        assert!(breakdown.windows(2).all(|w| w[0].1 >= w[1].1));
+    }
+
+    #[test]
+    fn memory_is_broken_down_along_a_dimension() {
+        use crate::{Dimension, SortBy};
+        let _guard = serial();
+        #[cfg(feature = "module-tags")]
+        {
+            static BREAKDOWN: crate::Tag = crate::Tag::new("breakdown");
+            let block = {
+                let _tag = BREAKDOWN.enter();
+                drop(std::hint::black_box(vec![0_u8; 8192]));
+                std::hint::black_box(vec![0_u8; 4096])
+            };
+            let report = PEAK_ALLOC.report_breakdown(Dimension::Tags, SortBy::Name, crate::TAG_SLOTS);
+            let row = report.rows.iter().find(|row| row.name == "breakdown").unwrap();
+            assert_eq!((4096, 8192, 2), (row.live_bytes, row.peak_bytes, row.allocations));
+            assert!(report.to_string().lines().any(|line| line.starts_with("breakdown ")));
+            drop(block);
+        }
+        #[cfg(feature = "per-thread")]
+        {
+            let snapshot = std::thread::spawn(|| {
+                let block = std::hint::black_box(vec![0_u8; 1 << 20]);
+                let report = PEAK_ALLOC.report_breakdown(Dimension::Threads, SortBy::LiveBytes, 64);
+                drop(block);
+                (report, std::thread::current().id())
+            });
+            let (report, id) = snapshot.join().unwrap();
+            let row = report.rows.iter().find(|row| row.name == format!("{:?}", id)).unwrap();
+            assert!(row.live_bytes >= 1 << 20);
+            assert!(row.peak_bytes >= 1 << 20 && row.allocations >= 1);
+        }
+        let notes = [Dimension::Tags, Dimension::Threads]
+            .map(|dimension| PEAK_ALLOC.report_breakdown(dimension, SortBy::LiveBytes, 10).note);
+        assert_eq!([cfg!(feature = "module-tags"), cfg!(feature = "per-thread")], notes.map(|note| note.is_none()));
+    }
+
+    #[test]
+    fn taken_stats_only_reflect_their_window() {
+        let _guard = serial();
+        PEAK_ALLOC.take_stats();
+        let first = std::hint::black_box(vec![0_u8; 4 * 1024 * 1024]);
+        let window = PEAK_ALLOC.take_stats();
+        assert!(window.total_allocated >= 4 * 1024 * 1024);
+        assert!(window.allocation_count >= 1);
+        assert!(window.peak_usage >= window.current_usage);
+
+        let second = std::hint::black_box(vec![0_u8; 1024]);
+        let next = PEAK_ALLOC.take_stats();
+        // the 4 MiB block was accounted for in the previous window only
+        assert!(next.total_allocated >= 1024);
+        assert!(next.total_allocated < 1024 * 1024);
+        assert!(next.allocation_count >= 1);
+        assert!(next.peak_usage < window.peak_usage + 1024 * 1024);
+        drop((first, second));
+        assert!(PEAK_ALLOC.take_stats().deallocation_count >= 2);
+    }
+
+    #[test]
+    fn linked_budgets_appear_in_the_stats() {
+        use crate::MemoryBudget;
+        let _guard = serial();
+        static SERVER: MemoryBudget = MemoryBudget::new(1 << 20).linked();
+        let connection = MemoryBudget::with_parent(4096, &SERVER).linked();
+        let unlinked = MemoryBudget::with_parent(4096, &SERVER);
+
+        let external = PEAK_ALLOC.external_usage();
+        let buffer = connection.try_charge(1000).unwrap();
+        // both the connection and the server are linked
+        assert_eq!(external + 2000, PEAK_ALLOC.stats().external_usage);
+        let other = unlinked.try_charge(500).unwrap();
+        assert_eq!(external + 2500, PEAK_ALLOC.external_usage());
+        drop(buffer);
+        drop(other);
+        assert_eq!(external, PEAK_ALLOC.external_usage());
+    }
+
+    #[test]
+    fn external_records_count_in_the_external_usage() {
+        let _guard = serial();
+        let external = PEAK_ALLOC.external_usage();
+        PEAK_ALLOC.record_external_alloc(1000);
+        PEAK_ALLOC.record_external_alloc_located(500);
+        assert_eq!(external + 1500, PEAK_ALLOC.external_usage());
+        assert_eq!(external + 1500, PEAK_ALLOC.stats().external_usage);
+        PEAK_ALLOC.record_external_dealloc_located(500);
+        PEAK_ALLOC.record_external_dealloc(1000);
+        assert_eq!(external, PEAK_ALLOC.external_usage());
+    }
+
+    #[cfg(feature = "external-locations")]
+    #[test]
+    fn external_records_remember_their_call_sites() {
+        let _guard = serial();
+        fn map_file(bytes: usize) {
+            PEAK_ALLOC.record_external_alloc_located(bytes);
+        }
+        PEAK_ALLOC.record_external_alloc_located(100);
+        let line = line!() - 1;
+        map_file(200);
+        PEAK_ALLOC.record_external_dealloc_located(300);
+        let records = PEAK_ALLOC.recent_external_records();
+        let last: Vec<_> = records[records.len() - 3..].iter().map(|r| (r.bytes, r.released)).collect();
+        assert_eq!(vec![(100, false), (200, false), (300, true)], last);
+        let (direct, nested) = (records[records.len() - 3].location, records[records.len() - 2].location);
+        assert_ne!(direct, nested);
+        assert_eq!((file!(), line), (direct.file(), direct.line()));
+        assert_eq!(file!(), nested.file());
+        assert!(nested.line() < line);
+
+        for _ in 0..2 * crate::RECENT_EXTERNAL {
+            PEAK_ALLOC.record_external_alloc_located(1);
+        }
+        assert_eq!(crate::RECENT_EXTERNAL, PEAK_ALLOC.recent_external_records().len());
+        PEAK_ALLOC.record_external_dealloc(2 * crate::RECENT_EXTERNAL);
+    }
+
+    #[cfg(feature = "zeroize-on-free")]
+    #[test]
+    fn freed_blocks_are_wiped() {
+        use std::alloc::{GlobalAlloc, Layout};
+        let _guard = serial();
+        // the quarantined blocks are only wiped once they are truly released
+        let parked = cfg!(feature = "quarantine");
+        let wiped = PEAK_ALLOC.wiped_bytes();
+        let layout = Layout::from_size_align(4096, 8).unwrap();
+        unsafe {
+            let ptr = PEAK_ALLOC.alloc(layout);
+            ptr.write_bytes(0x5A, 4096);
+            // a reallocation wipes the block it discards
+            let ptr = PEAK_ALLOC.realloc(ptr, layout, 8192);
+            assert!(parked || PEAK_ALLOC.wiped_bytes() >= wiped + 4096);
+            assert!((0..4096).all(|i| *ptr.add(i) == 0x5A));
+            PEAK_ALLOC.dealloc(ptr, Layout::from_size_align(8192, 8).unwrap());
+        }
+        assert!(parked || PEAK_ALLOC.wiped_bytes() >= wiped + 4096 + 8192);
+    }
+
+    #[test]
+    fn outlier_allocations_are_reported() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        let _guard = serial();
+        static OUTLIER: AtomicUsize = AtomicUsize::new(0);
+        fn record(size: usize) {
+            OUTLIER.fetch_max(size, Ordering::Relaxed);
+        }
+        PEAK_ALLOC.drain_events();
+        // warm-up: plenty of small allocations bring the mean down
+        for i in 0..10_000_u32 {
+            drop(std::hint::black_box(Box::new(i)));
+        }
+        let mean = PEAK_ALLOC.total_allocated() / PEAK_ALLOC.allocation_count();
+        let huge = 10_000 * mean.max(64);
+        PEAK_ALLOC.on_outlier_allocation(100.0, record);
+        drop(std::hint::black_box(vec![0_u8; huge]));
+        PEAK_ALLOC.on_outlier_allocation(0.0, record);
+        PEAK_ALLOC.drain_events();
+        assert_eq!(huge, OUTLIER.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn allocation_storms_are_reported() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        let _guard = serial();
+        static STORM: AtomicBool = AtomicBool::new(false);
+        fn record() {
+            STORM.store(true, Ordering::Relaxed);
+        }
+        PEAK_ALLOC.drain_events();
+        // a slow trickle: about 50 allocations per window
+        PEAK_ALLOC.on_allocation_storm(5_000, std::time::Duration::from_millis(50), record);
+        for _ in 0..100 {
+            for i in 0..10_u32 {
+                drop(std::hint::black_box(Box::new(i)));
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        PEAK_ALLOC.drain_events();
+        assert!(!STORM.load(Ordering::Relaxed));
+        // a burst
+        for i in 0..100_000_u32 {
+            drop(std::hint::black_box(Box::new(i)));
+        }
+        PEAK_ALLOC.on_allocation_storm(0, std::time::Duration::from_millis(50), record);
+        PEAK_ALLOC.drain_events();
+        assert!(STORM.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn startup_is_marked_only_once() {
+        let _guard = serial();
+        let first = PEAK_ALLOC.mark_startup_complete();
+        let startup = PEAK_ALLOC.startup_usage().unwrap();
+        let allocations = PEAK_ALLOC.startup_allocation_count().unwrap();
+        // the other tests may have marked it already: the first call wins
+        assert!(first || startup <= PEAK_ALLOC.all_time_peak_usage());
+        assert!(!PEAK_ALLOC.mark_startup_complete());
+        assert_eq!(Some(startup), PEAK_ALLOC.startup_usage());
+        assert_eq!(Some(allocations), PEAK_ALLOC.startup_allocation_count());
+
+        let data = std::hint::black_box(vec![0_u8; 1024 * 1024]);
+        let current = PEAK_ALLOC.current_usage();
+        assert!(PEAK_ALLOC.post_startup_peak() >= current.saturating_sub(startup));
+        assert_eq!(PEAK_ALLOC.peak_usage().saturating_sub(startup), PEAK_ALLOC.post_startup_peak());
+
+        let stats = PEAK_ALLOC.stats();
+        assert_eq!(Some(startup), stats.startup_usage);
+        let report = PEAK_ALLOC.final_report();
+        assert_eq!(Some(startup), report.startup_usage);
+        assert!(report.to_string().contains(&format!("  startup            : {} B", startup)));
+        assert!(report.to_string().contains("post-startup peak"));
+        assert!(PEAK_ALLOC.stats_json().contains(&format!("\"startup\":{},", startup)));
+        drop(data);
+    }
+
+    #[test]
+    fn reset_hooks_see_the_stats_before_the_reset() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+        let _guard = serial();
+
+        let seen = Arc::new(AtomicUsize::new(0));
+        let cell = Arc::clone(&seen);
+        PEAK_ALLOC.on_reset(move |stats| {
+            cell.fetch_max(stats.peak_usage, Ordering::Relaxed);
+        });
+        let spike = PEAK_ALLOC.current_usage() + 4 * 1024 * 1024;
+        drop(std::hint::black_box(vec![0_u8; 4 * 1024 * 1024]));
+        assert!(PEAK_ALLOC.peak_usage() >= spike);
+        PEAK_ALLOC.reset_peak_usage();
+
+        assert!(seen.load(Ordering::Relaxed) >= spike);
+        assert!(PEAK_ALLOC.peak_usage() < spike);
+    }
+
+    #[test]
+    fn watchdog_fires_once_for_sustained_usage() {
+        use crate::WatchdogConfig;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+        use std::time::{Duration, Instant};
+        let _guard = serial();
+
+        let fired = Arc::new(AtomicUsize::new(0));
+        let count = Arc::clone(&fired);
+        let threshold = PEAK_ALLOC.current_usage() + 1024 * 1024;
+        let data = vec![1_u8; 2 * 1024 * 1024];
+        let watchdog = PEAK_ALLOC.start_watchdog(
+            WatchdogConfig::new(threshold)
+                .interval(Duration::from_millis(5))
+                .grace(Duration::from_millis(50))
+                .cooldown(Duration::from_secs(3600))
+                .callback(move |_| { count.fetch_add(1, Ordering::Relaxed); }),
+        );
+        std::thread::sleep(Duration::from_millis(300));
+        drop(data);
+        drop(watchdog);
+        assert_eq!(1, fired.load(Ordering::Relaxed));
+
+        // dropping the handle does not wait for the end of the interval
+        let watchdog = PEAK_ALLOC.start_watchdog(
+            WatchdogConfig::new(usize::MAX).interval(Duration::from_secs(3600)),
+        );
+        let start = Instant::now();
+        drop(watchdog);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn spikes_are_reported_once_closed() {
+        use crate::{SpikeConfig, SpikeReport};
+        use std::sync::mpsc;
+        use std::time::Duration;
+        let _guard = serial();
+
+        let (sender, receiver) = mpsc::channel::<SpikeReport>();
+        let spike = 64 * 1024 * 1024;
+        let mut spikes = PEAK_ALLOC.on_spike(SpikeConfig::default().interval(Duration::from_millis(5)), move |r| {
+            let _ = sender.send(r);
+        });
+        std::thread::sleep(Duration::from_millis(50));
+        let data = vec![1_u8; spike];
+        std::thread::sleep(Duration::from_millis(50));
+        drop(data);
+        std::thread::sleep(Duration::from_millis(50));
+        spikes.stop();
+        assert!(!spikes.is_running());
+        let reports: Vec<_> = receiver.try_iter().collect();
+        let report = reports.iter().find(|r| r.peak_usage >= r.start_usage + spike).unwrap();
+        assert!(report.complete);
+        assert!(report.bytes_allocated_during >= spike);
+        assert!(report.duration >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn all_time_peak_survives_resets() {
+        let _guard = serial();
+        PEAK_ALLOC.reset_peak_usage();
+        let base = PEAK_ALLOC.current_usage();
+
+        drop(vec![0_u8; 1024 * 1024]);
+        let high = PEAK_ALLOC.all_time_peak_usage();
+        assert!(high >= base + 1024 * 1024);
+
+        PEAK_ALLOC.reset_peak_usage();
+        drop(vec![0_u8; 1024]);
+        assert!(PEAK_ALLOC.peak_usage() < base + 1024 * 1024);
+        assert!(PEAK_ALLOC.peak_usage() >= base + 1024);
+        assert!(PEAK_ALLOC.all_time_peak_usage() >= high);
+    }
+
+    #[test]
+    fn reset_policy_selects_what_the_peak_is_reset_to() {
+        use crate::ResetPolicy;
+        let _guard = serial();
+        assert_eq!(ResetPolicy::ToCurrent, PEAK_ALLOC.reset_policy());
+        let data = vec![1_u8; 1024 * 1024];
+        drop(vec![0_u8; 1024 * 1024]);
+        PEAK_ALLOC.reset_peak_usage();
+        assert!(PEAK_ALLOC.peak_usage() >= PEAK_ALLOC.current_usage());
+        assert!(PEAK_ALLOC.peak_usage() >= 1024 * 1024);
+
+        PEAK_ALLOC.set_reset_policy(ResetPolicy::ToZero);
+        assert_eq!(ResetPolicy::ToZero, PEAK_ALLOC.reset_policy());
+        let high = PEAK_ALLOC.all_time_peak_usage();
+        PEAK_ALLOC.reset_peak_usage();
+        let peak = PEAK_ALLOC.peak_usage();
+        // the next allocations raise the peak from 0 up to the usage
+        assert!(peak < 1024 * 1024, "{}", peak);
+        let block = vec![0_u8; 4096];
+        assert!(PEAK_ALLOC.peak_usage() >= PEAK_ALLOC.current_usage());
+        assert!(PEAK_ALLOC.all_time_peak_usage() >= high);
+        PEAK_ALLOC.set_reset_policy(ResetPolicy::ToCurrent);
+        drop(block);
+        drop(data);
+    }
+
+    #[test]
+    fn history_records_samples_until_stopped() {
+        use std::time::Duration;
+        let _guard = serial();
+        let mut history = PEAK_ALLOC.start_history(4, Duration::from_millis(5));
+        std::thread::sleep(Duration::from_millis(100));
+        history.stop();
+        assert!(!history.is_running());
+
+        let samples = history.samples();
+        assert_eq!(4, samples.len());
+        assert!(samples.windows(2).all(|w| w[0].0 < w[1].0));
+        assert_eq!(samples.last().copied(), history.latest());
+        assert!(history.min() <= history.max());
+        // the samples do not change anymore once stopped
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(samples, history.samples());
+        history.stop();
+    }
+
+    #[test]
+    #[cfg(feature = "windowed-peak")]
+    fn the_windowed_peak_covers_the_recent_spikes() {
+        use std::time::Duration;
+        let _guard = serial();
+        let before = PEAK_ALLOC.current_usage();
+        let buffer = vec![1_u8; 16 * 1024 * 1024];
+        drop(buffer);
+        let peak = PEAK_ALLOC.windowed_peak(Duration::from_secs(60));
+        assert!(peak >= before + 16 * 1024 * 1024, "{} < {}", peak, before);
+        // it is not reset with the peak usage
+        PEAK_ALLOC.reset_peak_usage();
+        assert!(PEAK_ALLOC.windowed_peak(Duration::from_secs(60)) >= peak);
+        assert!(PEAK_ALLOC.windowed_peak(Duration::ZERO) >= PEAK_ALLOC.current_usage());
+    }
+
+    #[test]
+    fn peak_since_is_the_maximum_of_the_recent_samples() {
+        use std::time::{Duration, Instant};
+        let _guard = serial();
+        let history = PEAK_ALLOC.start_history(1024, Duration::from_millis(2));
+        std::thread::sleep(Duration::from_millis(20));
+        let since = Instant::now();
+        let before = PEAK_ALLOC.current_usage();
+        let buffer = vec![1_u8; 16 * 1024 * 1024];
+        std::thread::sleep(Duration::from_millis(20));
+        drop(buffer);
+        std::thread::sleep(Duration::from_millis(20));
+        let peak = PEAK_ALLOC.peak_since(since).expect("there should be samples since");
+        assert!(peak >= before + 16 * 1024 * 1024, "{} < {}", peak, before);
+        assert_eq!(Some(peak), history.peak_since(since));
+        // no sample was taken in the future, and none once the history is gone
+        assert_eq!(None, PEAK_ALLOC.peak_since(Instant::now() + Duration::from_secs(60)));
+        drop(history);
+        assert_eq!(None, PEAK_ALLOC.peak_since(since));
+    }
+
+    #[test]
+    fn allocation_rate_reflects_a_burst() {
+        use std::time::Duration;
+        let _guard = serial();
+        let mut history = PEAK_ALLOC.start_history(4, Duration::from_millis(500));
+        std::thread::sleep(Duration::from_millis(20));
+        // a burst of 20k small allocations, all within the first window
+        for i in 0..20_000_u32 {
+            drop(std::hint::black_box(Box::new(i)));
+        }
+        std::thread::sleep(Duration::from_millis(600));
+        let rate = PEAK_ALLOC.allocations_per_second();
+        history.stop();
+        // 20k allocations in (about) 500ms: about 40k per second
+        assert!(rate >= 30_000.0, "{}", rate);
+    }
+
+    #[test]
+    fn peak_never_falls_below_the_usage_after_a_reset() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+        use std::time::{Duration, Instant};
+        let _guard = serial();
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                let stop = Arc::clone(&stop);
+                std::thread::spawn(move || {
+                    // the blocks live long enough to be seen by the resets
+                    let mut held = Vec::with_capacity(16);
+                    while !stop.load(Ordering::Relaxed) {
+                        if held.len() == held.capacity() {
+                            held.clear();
+                        }
+                        held.push(vec![1_u8; 4096]);
+                    }
+                })
+            })
+            .collect();
+        let mut violations = 0;
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_millis(200) {
+            PEAK_ALLOC.reset_peak_usage();
+            // the peak is read last: it may only have risen in between
+            let current = PEAK_ALLOC.current_usage();
+            if PEAK_ALLOC.peak_usage() < current {
+                violations += 1;
+            }
+        }
+        stop.store(true, Ordering::Relaxed);
+        workers.into_iter().for_each(|w| w.join().unwrap());
+        assert_eq!(0, violations);
+    }
+
+    #[test]
+    fn smoothed_peak_ignores_transient_spikes() {
+        use std::time::Duration;
+        const MB: usize = 1024 * 1024;
+        let _guard = serial();
+
+        let interval = Duration::from_millis(50);
+        let mut smoothing = PEAK_ALLOC.start_peak_smoothing(interval);
+        // let the first tick happen
+        std::thread::sleep(interval / 2);
+        let base = PEAK_ALLOC.smoothed_peak_usage();
+        // a spike which is freed right away
+        drop(std::hint::black_box(Vec::<u8>::with_capacity(16 * MB)));
+        std::thread::sleep(3 * interval);
+        assert!(PEAK_ALLOC.smoothed_peak_usage() < base + 16 * MB);
+        assert!(PEAK_ALLOC.peak_usage() >= PEAK_ALLOC.current_usage() + 16 * MB);
+
+        // a plateau which spans several ticks
+        let plateau = vec![1_u8; 8 * MB];
+        std::thread::sleep(3 * interval);
+        assert!(PEAK_ALLOC.smoothed_peak_usage() >= base + 8 * MB);
+        drop(plateau);
+        smoothing.stop();
+    }
+
+    #[test]
+    fn usage_can_be_expressed_in_custom_units() {
+        use crate::PeakAlloc;
+        assert_eq!(3.0, PeakAlloc::units(3 * 4096, 4096));
+        assert_eq!(0.5, PeakAlloc::units(2048, 4096));
+        assert!(PeakAlloc::units(4096, 0).is_nan());
+        assert!(PEAK_ALLOC.current_usage_in_units(0).is_nan());
+        assert!(PEAK_ALLOC.peak_usage_in_units(4096) > 0.0);
+    }
+
+    #[test]
+    fn conversions_use_the_binary_units() {
+        use crate::units::{GB, GIB, KB, KIB, MB, MIB};
+        use crate::PeakAlloc;
+        let table = [(PeakAlloc::kb as fn(usize) -> f32, KIB), (PeakAlloc::mb, MIB), (PeakAlloc::gb, GIB)];
+        for (convert, unit) in table.iter() {
+            assert_eq!(0.0, convert(0));
+            assert_eq!(1.0, convert(*unit));
+            assert_eq!(2.5, convert(5 * *unit / 2));
+            assert_eq!(convert(*unit) as f64, PeakAlloc::units(*unit, *unit));
+        }
+        for unit in [KIB, MIB, GIB, KB, MB, GB] {
+            assert_eq!(1.5, PeakAlloc::units(3 * unit / 2, unit));
+        }
+        assert_eq!(1.024, PeakAlloc::units(KIB, KB));
+    }
+
+    #[test]
+    fn usage_in_mb_is_rounded_with_the_given_mode() {
+        use crate::RoundMode;
+        const MB: usize = 1024 * 1024;
+        // 1.5 MB and a little: 1.50286 MB
+        let bytes = MB + MB / 2 + 3_000;
+        assert_eq!(1.50, RoundMode::Floor.round_bytes(bytes, MB));
+        assert_eq!(1.51, RoundMode::Ceil.round_bytes(bytes, MB));
+        assert_eq!(1.50, RoundMode::Nearest.round_bytes(bytes, MB));
+        assert_eq!(1.50, RoundMode::Truncate.round_bytes(bytes, MB));
+        // 1.5 MB and a bit more: 1.50763 MB
+        assert_eq!(1.51, RoundMode::Nearest.round_bytes(bytes + 5_000, MB));
+        // 1.5 MB exactly
+        for mode in [RoundMode::Floor, RoundMode::Ceil, RoundMode::Nearest, RoundMode::Truncate] {
+            assert_eq!(1.5, mode.round_bytes(MB + MB / 2, MB));
+        }
+
+        let _guard = serial();
+        let data = vec![1_u8; 2 * MB];
+        assert!(PEAK_ALLOC.current_usage_as_mb_with(RoundMode::Floor) >= 2.0);
+        assert!(PEAK_ALLOC.peak_usage_as_mb_with(RoundMode::Nearest) >= 2.0);
+        drop(data);
+    }
+
+    #[test]
+    fn peak_is_accurate_with_the_fast_path() {
+        let _guard = serial();
+        PEAK_ALLOC.reset_peak_usage();
+        let base = PEAK_ALLOC.current_usage();
+        {
+            let _big = vec![0_u8; 1 << 20];
+            assert_eq!(base + (1 << 20), PEAK_ALLOC.peak_usage());
+        }
+        // steady state: the usage remains below the peak
+        for _ in 0..100 {
+            drop(vec![0_u8; 1 << 10]);
+        }
+        assert_eq!(base + (1 << 20), PEAK_ALLOC.peak_usage());
+        {
+            let _bigger = vec![0_u8; 1 << 21];
+            assert_eq!(base + (1 << 21), PEAK_ALLOC.peak_usage());
+        }
+    }
+
+    #[cfg(feature = "decayed-stats")]
+    #[test]
+    fn load_averages_follow_the_usage() {
+        use std::time::Duration;
+        let _guard = serial();
+        let tau = Duration::from_millis(10);
+        PEAK_ALLOC.set_load_average_time_constants(tau, tau * 10, tau * 100);
+        let (a, b, c) = PEAK_ALLOC.usage_load_averages();
+        assert!(a > 0.0 && a == b && b == c);
+
+        let data = vec![1_u8; 4 * 1024 * 1024];
+        let sampler = PEAK_ALLOC.start_load_average_sampler(Duration::from_millis(1));
+        std::thread::sleep(Duration::from_millis(100));
+        drop(sampler);
+        let (a, b, c) = PEAK_ALLOC.usage_load_averages();
+        drop(data);
+        assert!(a > b && b > c);
+    }
+
+    #[test]
+    fn debug_shows_live_statistics() {
+        let text = format!("{:?}", crate::PeakAlloc);
+        assert!(text.starts_with("PeakAlloc {"));
+        assert!(text.contains("current: "));
+        assert!(text.contains("peak: "));
+        assert!(text.contains("allocations: "));
+    }
+
+    #[cfg(feature = "statsd")]
+    #[test]
+    fn statsd_exporter_sends_datagrams_periodically() {
+        use std::net::UdpSocket;
+        use std::time::Duration;
+        let _guard = serial();
+
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let addr = server.local_addr().unwrap();
+        let interval = Duration::from_millis(10);
+        let exporter = PEAK_ALLOC
+            .start_statsd_exporter(addr, "test.heap", interval, &[("env", "ci")])
+            .unwrap();
+
+        let mut buffer = [0_u8; 1024];
+        for _ in 0..3 {
+            let len = server.recv(&mut buffer).unwrap();
+            let text = std::str::from_utf8(&buffer[..len]).unwrap();
+            let lines = text.lines().collect::<Vec<_>>();
+            assert_eq!(4, lines.len());
+            let expected = [
+                ("test.heap.current:", "|g|#env:ci"),
+                ("test.heap.peak:", "|g|#env:ci"),
+                ("test.heap.allocations:", "|c|#env:ci"),
+                ("test.heap.deallocations:", "|c|#env:ci"),
+            ];
+            for (line, (name, suffix)) in lines.iter().zip(expected.iter()) {
+                assert!(line.starts_with(name) && line.ends_with(suffix), "{}", line);
+            }
+        }
+        assert!(exporter.sent() >= 3);
+        assert_eq!(0, exporter.send_errors());
+    }
+
+    #[test]
+    fn sample_rate_guard_restores_the_previous_rate() {
+        let _guard = serial();
+        assert_eq!(1, PEAK_ALLOC.sample_rate());
+        {
+            let _outer = PEAK_ALLOC.with_sample_rate(10);
+            assert_eq!(10, PEAK_ALLOC.sample_rate());
+            {
+                let _inner = PEAK_ALLOC.with_sample_rate(0);
+                assert_eq!(1, PEAK_ALLOC.sample_rate());
+            }
+            assert_eq!(10, PEAK_ALLOC.sample_rate());
+        }
+        assert_eq!(1, PEAK_ALLOC.sample_rate());
+    }
+    #[cfg(feature = "timed-accounting")]
+    #[test]
+    fn timed_accounting_is_sampled() {
+        let _guard = serial();
+        let sampled = {
+            let _rate = PEAK_ALLOC.with_sample_rate(4);
+            PEAK_ALLOC.reset_timed_accounting();
+            for _ in 0..400 {
+                drop(std::hint::black_box(vec![0_u8; 8]));
+            }
+            PEAK_ALLOC.timed_accounting().count()
+        };
+        // other threads may allocate concurrently, hence the slack
+        assert!((100..200).contains(&sampled), "{}", sampled);
+    }
+
+    #[test]
+    fn influx_line_is_timestamped_in_nanoseconds() {
+        use std::time::{SystemTime, UNIX_EPOCH};
+        let _guard = serial();
+        let now = || SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
+
+        let before = now();
+        let line = PEAK_ALLOC.influx_line("heap", &[("env", "ci")]);
+        let after = now();
+
+        let parts = line.split(' ').collect::<Vec<_>>();
+        assert_eq!(3, parts.len(), "{}", line);
+        assert_eq!("heap,env=ci", parts[0]);
+        for (field, name) in parts[1].split(',').zip(["current", "peak", "allocations", "deallocations"]) {
+            let (key, value) = field.split_once('=').unwrap();
+            assert_eq!(name, key);
+            assert!(value.ends_with('i') && value[..value.len() - 1].parse::<usize>().is_ok(), "{}", field);
+        }
+        let timestamp = parts[2].parse::<u128>().unwrap();
+        assert!(before <= timestamp && timestamp <= after);
+    }
+    #[cfg(feature = "influx-http")]
+    #[test]
+    fn influx_poster_posts_line_protocol() {
+        use std::io::{Read, Write};
+        use std::net::TcpListener;
+        use std::time::{Duration, Instant};
+        let _guard = serial();
+
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = crate::InfluxConfig::new(server.local_addr().unwrap(), "/write?db=test")
+            .measurement("heap")
+            .tag("env", "ci")
+            .token("secret")
+            .interval(Duration::from_millis(10));
+        let poster = PEAK_ALLOC.start_influx_poster(config);
+
+        let (mut stream, _) = server.accept().unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut request = Vec::new();
+        let mut buffer = [0_u8; 1024];
+        while !request.ends_with(b"\n") {
+            let len = stream.read(&mut buffer).unwrap();
+            assert!(len > 0);
+            request.extend_from_slice(&buffer[..len]);
+        }
+        stream.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").unwrap();
+        drop(stream);
+
+        let request = String::from_utf8(request).unwrap();
+        assert!(request.starts_with("POST /write?db=test HTTP/1.1\r\n"), "{}", request);
+        assert!(request.contains("\r\nAuthorization: Token secret\r\n"), "{}", request);
+        let (_, body) = request.split_once("\r\n\r\n").unwrap();
+        assert!(body.starts_with("heap,env=ci current="), "{}", body);
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while poster.sent() == 0 && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(1, poster.sent());
+    }
+
+    #[test]
+    fn realloc_accounts_for_the_size_difference() {
+        use std::alloc::{GlobalAlloc, Layout};
+        let _guard = serial();
+        PEAK_ALLOC.reset_peak_usage();
+        let base = PEAK_ALLOC.current_usage();
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        unsafe {
+            let ptr = PEAK_ALLOC.alloc(layout);
+            assert_eq!(base + 100, PEAK_ALLOC.current_usage());
+
+            let ptr = PEAK_ALLOC.realloc(ptr, layout, 300);
+            assert!(!ptr.is_null());
+            assert_eq!(base + 300, PEAK_ALLOC.current_usage());
+            assert!(PEAK_ALLOC.peak_usage() >= base + 300);
+
+            let grown = Layout::from_size_align(300, 8).unwrap();
+            let ptr = PEAK_ALLOC.realloc(ptr, grown, 50);
+            assert!(!ptr.is_null());
+            assert_eq!(base + 50, PEAK_ALLOC.current_usage());
+
+            PEAK_ALLOC.dealloc(ptr, Layout::from_size_align(50, 8).unwrap());
+        }
+        assert_eq!(base, PEAK_ALLOC.current_usage());
+    }
+
+    #[test]
+    fn every_realloc_outcome_keeps_the_counters_in_line_with_the_blocks() {
+        use std::alloc::{GlobalAlloc, Layout};
+        let _guard = serial();
+        let layout = |size| Layout::from_size_align(size, 8).unwrap();
+        let moved = crate::system::REALLOC_BY_COPY;
+        let base = PEAK_ALLOC.current_usage();
+        let (reallocs, failures) = (PEAK_ALLOC.reallocation_count(), PEAK_ALLOC.failed_allocation_count());
+        unsafe {
+            let ptr = PEAK_ALLOC.alloc(layout(256));
+            ptr.write_bytes(0x5A, 256);
+            let ptr = PEAK_ALLOC.realloc(ptr, layout(256), 256);
+            assert_eq!(base + 256, PEAK_ALLOC.current_usage());
+            let ptr = PEAK_ALLOC.realloc(ptr, layout(256), 4096);
+            assert_eq!(base + 4096, PEAK_ALLOC.current_usage());
+            let ptr = PEAK_ALLOC.realloc(ptr, layout(4096), 128);
+            assert_eq!(base + 128, PEAK_ALLOC.current_usage());
+
+            // the system allocator fails: the block is left as it was
+            assert!(PEAK_ALLOC.realloc(ptr, layout(128), 1 << 60).is_null());
+            assert_eq!(base + 128, PEAK_ALLOC.current_usage());
+            assert_eq!(failures + 1, PEAK_ALLOC.failed_allocation_count());
+            assert!(std::slice::from_raw_parts(ptr, 128).iter().all(|&byte| byte == 0x5A));
+
+            // the limit refuses the growth, never the shrinkage (unless the
+            // shrunk block is a new one)
+            PEAK_ALLOC.set_memory_limit(crate::TRACKER.raw_current() + 1024);
+            assert!(PEAK_ALLOC.realloc(ptr, layout(128), 4096).is_null());
+            assert_eq!(base + 128, PEAK_ALLOC.current_usage());
+            if !moved {
+                PEAK_ALLOC.set_memory_limit(crate::TRACKER.raw_current() + 16);
+            }
+            let ptr = PEAK_ALLOC.realloc(ptr, layout(128), 64);
+            PEAK_ALLOC.set_memory_limit(0);
+            assert!(!ptr.is_null());
+            assert_eq!(base + 64, PEAK_ALLOC.current_usage());
+            assert_eq!(failures + 2, PEAK_ALLOC.failed_allocation_count());
+            if !moved {
+                assert_eq!(reallocs + 4, PEAK_ALLOC.reallocation_count());
+            }
+
+            // an untracked block grown beyond the minimum tracked size is
+            // charged (and limited) with its whole new size
+            PEAK_ALLOC.set_min_tracked_size(1024);
+            let small = PEAK_ALLOC.alloc(layout(100));
+            PEAK_ALLOC.set_memory_limit(crate::TRACKER.raw_current() + 2000);
+            assert!(PEAK_ALLOC.realloc(small, layout(100), 2048).is_null());
+            assert_eq!(failures + 3, PEAK_ALLOC.failed_allocation_count());
+            PEAK_ALLOC.set_memory_limit(0);
+            let large = PEAK_ALLOC.realloc(small, layout(100), 2048);
+            assert_eq!(base + 64 + 2048, PEAK_ALLOC.current_usage());
+            let small = PEAK_ALLOC.realloc(large, layout(2048), 100);
+            assert_eq!(base + 64, PEAK_ALLOC.current_usage());
+            PEAK_ALLOC.dealloc(small, layout(100));
+            PEAK_ALLOC.set_min_tracked_size(0);
+
+            PEAK_ALLOC.dealloc(ptr, layout(64));
+        }
+        assert_eq!(base, PEAK_ALLOC.current_usage());
+    }
+
+    #[test]
+    fn a_failing_inner_allocator_leaves_the_block_and_the_counters_alone() {
+        use crate::{DynAlloc, TrackingAlloc};
+        use std::alloc::{GlobalAlloc, Layout, System};
+        use std::sync::atomic::{AtomicBool, Ordering};
+        let _guard = serial();
+
+        /// A backend whose allocations fail on demand
+        struct Failing(AtomicBool);
+        unsafe impl GlobalAlloc for Failing {
+            unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+                if self.0.load(Ordering::Relaxed) {
+                    std::ptr::null_mut()
+                } else {
+                    System.alloc(layout)
+                }
+            }
+            unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+                System.dealloc(ptr, layout)
+            }
+        }
+        static FAILING: Failing = Failing(AtomicBool::new(false));
+        let tracking = TrackingAlloc::new(DynAlloc::new(&FAILING));
+        let layout = |size| Layout::from_size_align(size, 8).unwrap();
+
+        let base = PEAK_ALLOC.current_usage();
+        let (reallocs, failures) = (PEAK_ALLOC.reallocation_count(), PEAK_ALLOC.failed_allocation_count());
+        unsafe {
+            let ptr = tracking.alloc(layout(256));
+            ptr.write_bytes(0x5A, 256);
+            FAILING.0.store(true, Ordering::Relaxed);
+            for size in [16, 256, 4096] {
+                assert!(tracking.realloc(ptr, layout(256), size).is_null());
+            }
+            assert!(tracking.alloc(layout(256)).is_null());
+            FAILING.0.store(false, Ordering::Relaxed);
+            assert_eq!(base + 256, PEAK_ALLOC.current_usage());
+            assert_eq!(reallocs, PEAK_ALLOC.reallocation_count());
+            assert_eq!(failures + 4, PEAK_ALLOC.failed_allocation_count());
+            assert!(std::slice::from_raw_parts(ptr, 256).iter().all(|&byte| byte == 0x5A));
+
+            let ptr = tracking.realloc(ptr, layout(256), 4096);
+            assert_eq!(base + 4096, PEAK_ALLOC.current_usage());
+            assert_eq!(reallocs + 1, PEAK_ALLOC.reallocation_count());
+            assert!(std::slice::from_raw_parts(ptr, 256).iter().all(|&byte| byte == 0x5A));
+            tracking.dealloc(ptr, layout(4096));
+        }
+        assert_eq!(base, PEAK_ALLOC.current_usage());
+    }
+
+    #[test]
+    fn realloc_overhead_counts_the_bytes_copied_by_the_growth() {
+        let _guard = serial();
+        let base = PEAK_ALLOC.realloc_overhead_bytes();
+        let mut pushed = Vec::new();
+        for i in 0..1_000_000_u32 {
+            pushed.push(std::hint::black_box(i));
+        }
+        let overhead = PEAK_ALLOC.realloc_overhead_bytes() - base;
+        assert!(overhead > 0);
+        // each copy only moves what the block held before it grew
+        assert!(overhead < 2 * std::mem::size_of_val(&pushed[..]));
+
+        let base = PEAK_ALLOC.realloc_overhead_bytes();
+        let mut reserved = Vec::with_capacity(pushed.len());
+        for i in 0..1_000_000_u32 {
+            reserved.push(std::hint::black_box(i));
+        }
+        assert_eq!(base, PEAK_ALLOC.realloc_overhead_bytes());
+        drop((pushed, reserved));
+    }
+
+    #[test]
+    fn the_growth_slack_comes_from_the_reallocations() {
+        let _guard = serial();
+        let moved = crate::system::REALLOC_BY_COPY;
+        // grown, then shrunk
+        let base = PEAK_ALLOC.growth_slack_bytes();
+        let mut buffer = Vec::<u8>::with_capacity(1_000);
+        buffer.reserve_exact(5_000);
+        let grown = PEAK_ALLOC.growth_slack_bytes();
+        buffer.shrink_to(2_000);
+        let shrunk = PEAK_ALLOC.growth_slack_bytes();
+        if !moved {
+            assert_eq!((base + 4_000, base + 1_000), (grown, shrunk));
+        }
+        // then released: a share of the slack goes along, in proportion of
+        // the size of the block to the usage
+        let usage = crate::TRACKER.raw_current();
+        drop(buffer);
+        let released = PEAK_ALLOC.growth_slack_bytes();
+        let share = (shrunk as u128 * 2_000 / usage as u128) as usize;
+        assert_eq!(shrunk - share, released);
+
+        // grown, then released
+        let base = PEAK_ALLOC.growth_slack_bytes();
+        let mut buffer = Vec::<u8>::with_capacity(64);
+        buffer.reserve_exact(64 << 10);
+        let grown = PEAK_ALLOC.growth_slack_bytes();
+        if !moved {
+            assert_eq!(base + (64 << 10) - 64, grown);
+        }
+        let usage = crate::TRACKER.raw_current();
+        drop(buffer);
+        let share = (grown as u128 * (64 << 10) / usage as u128) as usize;
+        assert_eq!(grown - share, PEAK_ALLOC.growth_slack_bytes());
+    }
+
+    #[test]
+    fn untracked_reallocations_leave_the_counters_alone() {
+        use crate::EntryPoint;
+        use std::alloc::{GlobalAlloc, Layout};
+        let _guard = serial();
+        let layout = |size| Layout::from_size_align(size, 8).unwrap();
+        let reallocs = PEAK_ALLOC.reallocation_count();
+        let allocs = PEAK_ALLOC.allocation_count();
+        let moved = crate::system::REALLOC_BY_COPY;
+
+        PEAK_ALLOC.set_entry_point_tracked(EntryPoint::Realloc, false);
+        assert!(!PEAK_ALLOC.is_entry_point_tracked(EntryPoint::Realloc));
+        let base = PEAK_ALLOC.current_usage();
+        unsafe {
+            let mut ptr = PEAK_ALLOC.alloc(layout(16));
+            let mut size = 16;
+            while size < 64 * 1024 {
+                ptr = PEAK_ALLOC.realloc(ptr, layout(size), size * 2);
+                size *= 2;
+            }
+            let tracked = if moved { size } else { 16 };
+            assert_eq!(base + tracked, PEAK_ALLOC.current_usage());
+            // shrinking back (untracked) keeps the counters balanced
+            ptr = PEAK_ALLOC.realloc(ptr, layout(size), 16);
+            PEAK_ALLOC.set_entry_point_tracked(EntryPoint::Realloc, true);
+            PEAK_ALLOC.dealloc(ptr, layout(16));
+        }
+        assert_eq!(base, PEAK_ALLOC.current_usage());
+        assert_eq!(reallocs, PEAK_ALLOC.reallocation_count());
+        assert!(PEAK_ALLOC.allocation_count() > allocs);
+
+        // once tracked again, the reallocations are counted
+        let mut data = Vec::<u8>::with_capacity(128);
+        data.resize(1024, 0);
+        if !moved {
+            assert!(PEAK_ALLOC.reallocation_count() > reallocs);
+        }
+    }
+
+    #[cfg(feature = "ffi")]
+    #[test]
+    fn ffi_matches_the_safe_api() {
+        use crate::ffi::*;
+        let _guard = serial();
+        assert_eq!(PEAK_ALLOC.current_usage() as u64, peak_alloc_current_usage());
+        assert_eq!(PEAK_ALLOC.peak_usage() as u64, peak_alloc_peak_usage());
+        let block = vec![0_u8; 4096];
+        peak_alloc_reset_peak();
+        drop(block);
+        assert_eq!(PEAK_ALLOC.peak_usage() as u64, peak_alloc_current_usage() + 4096);
+
+        let mut stats = PeakAllocStatsC {
+            size: std::mem::size_of::<PeakAllocStatsC>() as u32,
+            ..Default::default()
+        };
+        assert_eq!(PEAK_ALLOC_OK, unsafe { peak_alloc_stats(&mut stats) });
+        assert_eq!(PEAK_ALLOC_STATS_VERSION, stats.version);
+        assert_eq!(std::mem::size_of::<PeakAllocStatsC>() as u32, stats.size);
+        assert_eq!(PEAK_ALLOC.current_usage() as u64, stats.current_usage);
+        assert_eq!(PEAK_ALLOC.peak_usage() as u64, stats.peak_usage);
+        assert_eq!(PEAK_ALLOC.all_time_peak_usage() as u64, stats.all_time_peak_usage);
+        assert_eq!(PEAK_ALLOC.total_allocated() as u64, stats.total_allocated);
+        assert_eq!(PEAK_ALLOC.allocation_count() as u64, stats.allocation_count);
+        assert_eq!(PEAK_ALLOC.deallocation_count() as u64, stats.deallocation_count);
+        assert_eq!(PEAK_ALLOC.largest_allocation() as u64, stats.largest_allocation);
+    }
+    #[cfg(feature = "ffi")]
+    #[test]
+    fn ffi_stats_are_defensive() {
+        use crate::ffi::*;
+        assert_eq!(PEAK_ALLOC_ERR_NULL, unsafe { peak_alloc_stats(std::ptr::null_mut()) });
+
+        let mut stats = PeakAllocStatsC { size: 4, ..Default::default() };
+        assert_eq!(PEAK_ALLOC_ERR_SIZE, unsafe { peak_alloc_stats(&mut stats) });
+        assert_eq!(0, stats.version);
+
+        // a caller compiled against an older version only knows of the header
+        // and the first two counters: the other fields are left untouched
+        let mut stats = PeakAllocStatsC {
+            size: 24,
+            largest_allocation: 42,
+            ..Default::default()
+        };
+        assert_eq!(PEAK_ALLOC_OK, unsafe { peak_alloc_stats(&mut stats) });
+        assert_eq!(PEAK_ALLOC_STATS_VERSION, stats.version);
+        assert_eq!(24, stats.size);
+        assert_ne!(0, stats.current_usage);
+        assert_eq!(0, stats.all_time_peak_usage);
+        assert_eq!(42, stats.largest_allocation);
+
+        // a caller compiled against a newer version gets what we know of
+        let mut stats = PeakAllocStatsC { size: 1000, ..Default::default() };
+        assert_eq!(PEAK_ALLOC_OK, unsafe { peak_alloc_stats(&mut stats) });
+        assert_eq!(std::mem::size_of::<PeakAllocStatsC>() as u32, stats.size);
+    }
+
+    #[test]
+    fn process_baseline_is_captured_upon_the_first_allocation() {
+        let _guard = serial();
+        let _block = Box::new([0_u8; 16]);
+        let baseline = PEAK_ALLOC.process_baseline();
+        assert!(baseline > 0);
+        assert!(baseline <= PEAK_ALLOC.all_time_peak_usage());
+        // captured once and for all
+        let _more = std::hint::black_box(vec![0_u8; 1024 * 1024]);
+        assert_eq!(baseline, PEAK_ALLOC.process_baseline());
+    }
+
+    #[test]
+    fn stats_json_holds_all_the_counters() {
+        let _guard = serial();
+        let json = PEAK_ALLOC.stats_json();
+        assert!(json.starts_with('{') && json.ends_with('}'), "{}", json);
+        // the size classes are an array of objects, at the end
+        #[cfg(feature = "histogram")]
+        let json = {
+            let (counters, classes) = json.split_once(",\"size_classes\":").unwrap();
+            assert!(classes.starts_with("[{\"class_bytes\":") && classes.ends_with("}]}"), "{}", classes);
+            format!("{}}}", counters)
+        };
+        let fields = json[1..json.len() - 1].split(',').collect::<Vec<_>>();
+        let keys = [
+            "schema",
+            "current",
+            "peak",
+            "all_time_peak",
+            "total_allocated",
+            "allocations",
+            "deallocations",
+            "largest",
+            "large_current",
+            "large_peak",
+            "large_allocations",
+            "startup",
+            "post_startup_peak",
+        ];
+        assert_eq!(keys.len(), fields.len());
+        for (field, key) in fields.iter().zip(keys.iter()) {
+            let (name, value) = field.split_once(':').unwrap();
+            assert_eq!(format!("\"{}\"", key), name);
+            // the startup is null until it is marked as complete
+            let null = *key == "startup" && value == "null";
+            assert!(null || value.parse::<usize>().is_ok(), "{}", field);
+        }
+        assert!(json.contains(&format!("\"largest\":{}", PEAK_ALLOC.largest_allocation())));
+    }
+
+    #[test]
+    fn the_json_schema_matches_its_version() {
+        let _guard = serial();
+        // a change to the snapshot below must come with a bump of the version
+        assert_eq!(2, crate::STATS_SCHEMA_VERSION);
+        let json = PEAK_ALLOC.stats_json();
+        #[cfg(feature = "histogram")]
+        let json = {
+            let (counters, classes) = json.split_once(",\"size_classes\":").unwrap();
+            let class = schema_of(classes.split('}').next().unwrap());
+            assert_eq!("[{\"class_bytes\":0,\"live_blocks\":0,\"live_bytes\":0,\"peak_live_bytes\":0", class);
+            format!("{}}}", counters)
+        };
+        assert_eq!(
+            "{\"schema\":0,\"current\":0,\"peak\":0,\"all_time_peak\":0,\"total_allocated\":0,\
+             \"allocations\":0,\"deallocations\":0,\"largest\":0,\"large_current\":0,\"large_peak\":0,\
+             \"large_allocations\":0,\"startup\":0,\"post_startup_peak\":0}",
+            schema_of(&json)
+        );
+    }
+    /// Replaces the values of a JSON object (numbers or null) with 0
+    fn schema_of(json: &str) -> String {
+        let json = json.replace("null", "0");
+        let mut schema = String::with_capacity(json.len());
+        let mut in_number = false;
+        for c in json.chars() {
+            if !c.is_ascii_digit() {
+                schema.push(c);
+            } else if !in_number {
+                schema.push('0');
+            }
+            in_number = c.is_ascii_digit();
+        }
+        schema
+    }
+
+    #[test]
+    fn read_all_returns_current_peak_total_and_count() {
+        let _guard = serial();
+        let (current, _, total, count) = PEAK_ALLOC.read_all();
+        let block = std::hint::black_box(vec![0_u8; 1000]);
+        let after = PEAK_ALLOC.read_all();
+        assert_eq!(current + 1000, after.0);
+        assert!(after.1 >= after.0);
+        assert_eq!(total + 1000, after.2);
+        assert_eq!(count + 1, after.3);
+        let expected = (
+            PEAK_ALLOC.current_usage(),
+            PEAK_ALLOC.peak_usage(),
+            PEAK_ALLOC.total_allocated(),
+            PEAK_ALLOC.allocation_count(),
+        );
+        assert_eq!(expected, after);
+        drop(block);
+    }
+
+    #[test]
+    fn the_metrics_buffer_holds_the_counters_of_the_build() {
+        use crate::MetricsBuffer as M;
+        let _guard = serial();
+        let mut before = M::new();
+        PEAK_ALLOC.read_metrics(&mut before);
+        let block = std::hint::black_box(vec![0_u8; 1000]);
+        let mut after = M::new();
+        PEAK_ALLOC.read_metrics(&mut after);
+        assert_eq!((crate::STATS_SCHEMA_VERSION, crate::METRICS as u32), (after.schema, after.len));
+        assert_eq!(Some(PEAK_ALLOC.current_usage() as u64), after.get(M::CURRENT_USAGE));
+        assert_eq!(Some(PEAK_ALLOC.largest_allocation() as u64), after.get(M::LARGEST_ALLOCATION));
+        let rates = after.diff(&before);
+        assert_eq!(Some(1000), rates.get(M::CURRENT_USAGE));
+        assert_eq!(Some(1000), rates.get(M::TOTAL_ALLOCATED));
+        assert_eq!(Some(1), rates.get(M::ALLOCATION_COUNT));
+        assert_eq!(cfg!(feature = "pointer-map"), after.get(M::POINTER_MAP_OVERFLOWS).is_some());
+        assert_eq!(cfg!(feature = "quarantine"), after.get(M::QUARANTINED_BYTES).is_some());
+        drop(block);
+    }
+
+    #[test]
+    fn stats_handles_read_the_stats_from_other_threads() {
+        use crate::StatsHandle;
+        fn assert_send_sync<T: Send + Sync + Copy>() {}
+        assert_send_sync::<StatsHandle>();
+        assert_eq!(0, std::mem::size_of::<StatsHandle>());
+        let _guard = serial();
+
+        let block = std::hint::black_box(vec![0_u8; 1 << 20]);
+        let handle = PEAK_ALLOC.handle();
+        let read = std::thread::spawn(move || (handle.current_usage(), handle.peak_usage(), handle.stats()));
+        let (current, peak, stats) = read.join().unwrap();
+        assert!(current >= 1 << 20 && peak >= current);
+        assert!(stats.peak_usage >= 1 << 20);
+        assert_eq!(PEAK_ALLOC.all_time_peak_usage(), handle.all_time_peak_usage());
+        drop(block);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_scopes_restore_the_counters_when_dropped() {
+        let _guard = serial();
+        let before = (PEAK_ALLOC.allocation_count(), PEAK_ALLOC.total_allocated(), PEAK_ALLOC.peak_usage());
+        let kept;
+        {
+            let outer = PEAK_ALLOC.test_scope();
+            kept = std::hint::black_box(vec![0_u8; 4096]);
+            let (outer_allocations, outer_peak) = (PEAK_ALLOC.allocation_count(), PEAK_ALLOC.peak_usage());
+            {
+                let inner = PEAK_ALLOC.test_scope();
+                let boxes: Vec<Box<u64>> = (0..100).map(Box::new).collect();
+                drop(std::hint::black_box(vec![0_u8; 8 << 20]));
+                assert!(inner.allocation_count() >= 102);
+                assert!(inner.allocated_bytes() >= 8 << 20);
+                assert!(inner.peak_bytes() >= 8 << 20);
+                assert!(inner.net_bytes() >= 100 * 8);
+                drop(boxes);
+                assert!(outer.allocation_count() > inner.allocation_count());
+            }
+            // the inner scope is gone, as far as the outer one can tell
+            assert!(PEAK_ALLOC.allocation_count() < outer_allocations + 100);
+            assert!(PEAK_ALLOC.peak_usage() < outer_peak + (8 << 20));
+            assert!(outer.allocated_bytes() >= 4096 && outer.allocated_bytes() < 8 << 20);
+            assert!(outer.net_bytes() >= 4096);
+        }
+        assert!(PEAK_ALLOC.allocation_count() < before.0 + 100);
+        assert!(PEAK_ALLOC.total_allocated() < before.1 + (8 << 20));
+        assert!(PEAK_ALLOC.peak_usage() >= PEAK_ALLOC.current_usage());
+        assert!(PEAK_ALLOC.peak_usage() < before.2.max(PEAK_ALLOC.current_usage()) + (8 << 20));
+        assert!(PEAK_ALLOC.all_time_peak_usage() >= 8 << 20);
+        drop(kept);
+    }
+
+    #[test]
+    fn exceeding_the_ceiling_poisons_the_process() {
+        let _guard = serial();
+        let base = PEAK_ALLOC.current_usage();
+        PEAK_ALLOC.assert_peak_under(base + (1 << 20));
+        let small = vec![1_u8; 256 << 10];
+        drop(small);
+        assert!(!PEAK_ALLOC.poisoned());
+        let large = vec![1_u8; 2 << 20];
+        drop(large);
+        // the poison outlives the memory
+        assert!(PEAK_ALLOC.poisoned());
+        PEAK_ALLOC.assert_peak_under(base + (1 << 20));
+        assert!(!PEAK_ALLOC.poisoned());
+        PEAK_ALLOC.assert_peak_under(0);
+        assert!(PEAK_ALLOC.poisoned());
+        PEAK_ALLOC.clear_peak_ceiling();
+        assert!(!PEAK_ALLOC.poisoned());
+        let large = vec![1_u8; 2 << 20];
+        drop(large);
+        assert!(!PEAK_ALLOC.poisoned());
+    }
+
+    #[test]
+    fn a_configuration_behaves_like_the_individual_settings() {
+        use crate::{Config, ConfigError, EntryPoint, ResetPolicy};
+        let _guard = serial();
+        let previous = PEAK_ALLOC.current_config();
+
+        let limit = crate::TRACKER.raw_current() + 64 * 1024 * 1024;
+        let config = Config::default()
+            .memory_limit(Some(limit))
+            .large_threshold(Some(1 << 20))
+            .min_tracked_size(64)
+            .overalign_threshold(64)
+            .sample_rate(7)
+            .reset_policy(ResetPolicy::ToZero)
+            .entry_point_tracked(EntryPoint::AllocZeroed, false);
+        PEAK_ALLOC.configure(config).unwrap();
+        assert_eq!(config, PEAK_ALLOC.current_config());
+        assert_eq!(Some(limit), PEAK_ALLOC.memory_limit());
+        assert_eq!((7, 64), (PEAK_ALLOC.sample_rate(), PEAK_ALLOC.overalign_threshold()));
+        assert_eq!(ResetPolicy::ToZero, PEAK_ALLOC.reset_policy());
+        assert!(!PEAK_ALLOC.is_entry_point_tracked(EntryPoint::AllocZeroed));
+        // the limit refuses, the small blocks are skipped, the large counted
+        let behaves_as_configured = || {
+            let failed = PEAK_ALLOC.failed_allocation_count();
+            let mut refused: Vec<u8> = Vec::new();
+            assert!(refused.try_reserve_exact(128 * 1024 * 1024).is_err());
+            assert!(PEAK_ALLOC.failed_allocation_count() > failed);
+            let count = PEAK_ALLOC.thread_allocation_count();
+            drop(std::hint::black_box(Vec::<u8>::with_capacity(63)));
+            assert_eq!(count, PEAK_ALLOC.thread_allocation_count());
+            drop(std::hint::black_box(Vec::<u8>::with_capacity(64)));
+            assert_eq!(count + 1, PEAK_ALLOC.thread_allocation_count());
+            let large = PEAK_ALLOC.large_allocation_count();
+            drop(std::hint::black_box(vec![1_u8; 2 << 20]));
+            assert_eq!(large + 1, PEAK_ALLOC.large_allocation_count());
+        };
+        behaves_as_configured();
+
+        // a refused configuration changes nothing
+        #[cfg(feature = "std")]
+        assert_eq!(
+            Err(ConfigError::SoftLimitAboveLimit { soft_limit: limit + 1, limit }),
+            PEAK_ALLOC.configure(Config::default().memory_limit(Some(limit)).watch_threshold(Some(limit + 1)))
+        );
+        assert_eq!(Err(ConfigError::ZeroSampleRate), PEAK_ALLOC.configure(Config::default().sample_rate(0)));
+        assert_eq!(config, PEAK_ALLOC.current_config());
+
+        // the individual settings end up in the same state
+        PEAK_ALLOC.configure(previous).unwrap();
+        PEAK_ALLOC.set_memory_limit(limit);
+        PEAK_ALLOC.set_large_threshold(1 << 20);
+        PEAK_ALLOC.set_min_tracked_size(64);
+        PEAK_ALLOC.set_overalign_threshold(64);
+        PEAK_ALLOC.set_sample_rate(7);
+        PEAK_ALLOC.set_reset_policy(ResetPolicy::ToZero);
+        PEAK_ALLOC.set_entry_point_tracked(EntryPoint::AllocZeroed, false);
+        assert_eq!(config, PEAK_ALLOC.current_config());
+        behaves_as_configured();
+        PEAK_ALLOC.configure(previous).unwrap();
+        assert_eq!(previous, PEAK_ALLOC.current_config());
+    }
+
+    #[test]
+    fn the_allocations_never_see_a_configuration_half_applied() {
+        use crate::Config;
+        let _guard = serial();
+        let previous = PEAK_ALLOC.current_config();
+        // none of these sizes bites: no block is empty, none is that large
+        let configs = [
+            Config::default().memory_limit(Some(1 << 60)).large_threshold(Some(1 << 50)).min_tracked_size(0),
+            Config::default().memory_limit(Some(1 << 61)).large_threshold(Some(1 << 51)).min_tracked_size(1),
+        ];
+        PEAK_ALLOC.configure(configs[0]).unwrap();
+        let done = std::sync::atomic::AtomicBool::new(false);
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                for config in configs.iter().cycle().take(200_000) {
+                    PEAK_ALLOC.configure(*config).unwrap();
+                }
+                done.store(true, std::sync::atomic::Ordering::Relaxed);
+            });
+            while !done.load(std::sync::atomic::Ordering::Relaxed) {
+                let limits = crate::config::limits();
+                let seen = Config::default()
+                    .memory_limit(Some(limits.memory_limit))
+                    .large_threshold(Some(limits.large_threshold))
+                    .min_tracked_size(limits.min_tracked_size);
+                assert!(configs.contains(&seen), "{:?}", limits);
+            }
+        });
+        PEAK_ALLOC.configure(previous).unwrap();
+    }
+
+    #[test]
+    fn the_largest_allocation_sizes_are_retained_in_order() {
+        let _guard = serial();
+        let base = PEAK_ALLOC.top_allocations()[0];
+        let sizes = [3, 9, 1, 7, 10, 2, 8, 5, 4, 6].map(|k| base + k * 4096);
+        for size in sizes {
+            drop(std::hint::black_box(Vec::<u8>::with_capacity(size)));
+        }
+        let expected = [10, 9, 8, 7, 6, 5, 4, 3].map(|k| base + k * 4096);
+        assert_eq!(expected, PEAK_ALLOC.top_allocations());
+        assert_eq!(base + 10 * 4096, PEAK_ALLOC.largest_allocation());
+    }
+
+    #[test]
+    fn the_display_unit_can_be_chosen() {
+        use crate::units::Unit;
+        let _guard = serial();
+        let block = std::hint::black_box(vec![0_u8; 3 << 20]);
+        let raw = format!("{}", PEAK_ALLOC);
+        let human = format!("{:#}", PEAK_ALLOC);
+        assert!(raw.starts_with("current: ") && raw.contains(" B, peak: ") && raw.ends_with(" B"));
+        assert!(human.starts_with("current: ") && human.contains(" MiB, peak: "));
+        assert_ne!(raw, human);
+        let current: usize = raw["current: ".len()..raw.find(" B,").unwrap()].parse().unwrap();
+        assert!(current >= 3 << 20);
+
+        let mb = format!("{:.1}", PEAK_ALLOC.display_in(Unit::Mb));
+        assert!(mb.contains(" MB, peak: ") && mb.ends_with(" MB"));
+        let decimals = mb["current: ".len()..mb.find(" MB,").unwrap()].split('.').nth(1).unwrap();
+        assert_eq!(1, decimals.len());
+        assert!(format!("{}", PEAK_ALLOC.display_in(Unit::Kib)).ends_with(" KiB"));
+        assert!(format!("{:#}", PEAK_ALLOC.display_in(Unit::Bytes)).ends_with(" B"));
+        drop(block);
+    }
+
+    #[test]
+    fn overaligned_allocations_are_counted_apart() {
+        #[repr(align(128))]
+        struct Padded(#[allow(dead_code)] [u8; 8]);
+        let _guard = serial();
+        let (count, bytes) = (PEAK_ALLOC.overaligned_count(), PEAK_ALLOC.overaligned_bytes());
+        let small = std::hint::black_box(Box::new(0_u64));
+        assert_eq!(count, PEAK_ALLOC.overaligned_count());
+        let padded = std::hint::black_box(Box::new(Padded([0; 8])));
+        let many = std::hint::black_box(vec![Padded([0; 8]), Padded([0; 8]), Padded([0; 8])]);
+        assert_eq!(count + 2, PEAK_ALLOC.overaligned_count());
+        assert_eq!(bytes + 128 + 3 * 128, PEAK_ALLOC.overaligned_bytes());
+        #[cfg(feature = "std")]
+        {
+            let recent = PEAK_ALLOC.recent_overaligned();
+            let sizes: Vec<(usize, usize)> = recent.iter().rev().take(2).map(|r| (r.size, r.align)).collect();
+            assert_eq!(vec![(3 * 128, 128), (128, 128)], sizes);
+            assert!(recent.len() <= crate::RECENT_OVERALIGNED);
+            assert_eq!(PEAK_ALLOC.current_thread_id(), recent.last().unwrap().thread);
+        }
+        #[cfg(feature = "module-tags")]
+        {
+            static OVERALIGNED: crate::Tag = crate::Tag::new("overaligned");
+            let tagged = {
+                let _tag = OVERALIGNED.enter();
+                std::hint::black_box(Box::new(Padded([0; 8])))
+            };
+            let last = *PEAK_ALLOC.recent_overaligned().last().unwrap();
+            assert_eq!((128, Some("overaligned")), (last.size, last.tag));
+            drop(tagged);
+        }
+        PEAK_ALLOC.set_overalign_threshold(256);
+        drop(std::hint::black_box(Box::new(Padded([0; 8]))));
+        assert_eq!(256, PEAK_ALLOC.overalign_threshold());
+        PEAK_ALLOC.set_overalign_threshold(16);
+        assert!(PEAK_ALLOC.overaligned_count() <= count + 3);
+        drop((small, padded, many));
+    }
+
+    #[test]
+    fn tracking_alloc_maintains_the_same_counters() {
+        use crate::TrackingAlloc;
+        use std::alloc::{GlobalAlloc, Layout, System};
+        let _guard = serial();
+        let tracking = TrackingAlloc::new(System);
+        let base = PEAK_ALLOC.current_usage();
+        let layout = Layout::from_size_align(256, 16).unwrap();
+        unsafe {
+            let ptr = tracking.alloc(layout);
+            assert!(!ptr.is_null());
+            assert_eq!(base + 256, PEAK_ALLOC.current_usage());
+            let ptr = tracking.realloc(ptr, layout, 512);
+            assert_eq!(base + 512, PEAK_ALLOC.current_usage());
+            tracking.dealloc(ptr, Layout::from_size_align(512, 16).unwrap());
+        }
+        assert_eq!(base, PEAK_ALLOC.current_usage());
+    }
+
+    #[test]
+    fn tracking_alloc_works_through_a_trait_object() {
+        use crate::{DynAlloc, TrackingAlloc};
+        use std::alloc::{GlobalAlloc, Layout, System};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        let _guard = serial();
+
+        /// A backend chosen at runtime, which counts the calls it gets
+        struct Counting(AtomicUsize);
+        unsafe impl GlobalAlloc for Counting {
+            unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+                self.0.fetch_add(1, Ordering::Relaxed);
+                System.alloc(layout)
+            }
+            unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+                self.0.fetch_add(1, Ordering::Relaxed);
+                System.dealloc(ptr, layout)
+            }
+        }
+        let counting: &'static Counting = Box::leak(Box::new(Counting(AtomicUsize::new(0))));
+        let tracking = TrackingAlloc::new(DynAlloc::new(counting));
+
+        let base = PEAK_ALLOC.current_usage();
+        let layout = Layout::from_size_align(128, 8).unwrap();
+        unsafe {
+            let ptr = tracking.alloc(layout);
+            assert!(!ptr.is_null());
+            assert_eq!(base + 128, PEAK_ALLOC.current_usage());
+            // the default realloc of the backend allocates and deallocates
+            let ptr = tracking.realloc(ptr, layout, 1024);
+            assert_eq!(base + 1024, PEAK_ALLOC.current_usage());
+            tracking.dealloc(ptr, Layout::from_size_align(1024, 8).unwrap());
+        }
+        assert_eq!(base, PEAK_ALLOC.current_usage());
+        assert_eq!(4, counting.0.load(Ordering::Relaxed));
+    }
+
+    /// A backend counting the blocks it hands out
+    struct CountingBackend(std::sync::atomic::AtomicUsize);
+
+    unsafe impl std::alloc::GlobalAlloc for CountingBackend {
+        unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+            self.0.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            std::alloc::System.alloc(layout)
+        }
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+            std::alloc::System.dealloc(ptr, layout)
+        }
+    }
+
+    #[test]
+    fn dyn_tracking_alloc_delegates_to_the_inner_allocator_set_at_startup() {
+        use crate::DynTrackingAlloc;
+        use std::alloc::{GlobalAlloc, Layout};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static ALLOC: DynTrackingAlloc = DynTrackingAlloc::new();
+        static BACKEND: CountingBackend = CountingBackend(AtomicUsize::new(0));
+        let _guard = serial();
+
+        assert!(!ALLOC.is_configured() && !ALLOC.has_allocated());
+        assert!(ALLOC.set_inner_before_first_alloc(&BACKEND));
+        assert!(ALLOC.is_configured());
+        let base = PEAK_ALLOC.current_usage();
+        let layout = Layout::from_size_align(256, 8).unwrap();
+        unsafe {
+            let ptr = ALLOC.alloc(layout);
+            assert!(!ptr.is_null());
+            assert_eq!(base + 256, PEAK_ALLOC.current_usage());
+            let ptr = ALLOC.realloc(ptr, layout, 512);
+            assert_eq!(base + 512, PEAK_ALLOC.current_usage());
+            ALLOC.dealloc(ptr, Layout::from_size_align(512, 8).unwrap());
+        }
+        assert_eq!(base, PEAK_ALLOC.current_usage());
+        // the default realloc of the backend allocates too
+        assert_eq!(2, BACKEND.0.load(Ordering::Relaxed));
+        assert!(ALLOC.has_allocated());
+
+        // an unconfigured one delegates to the system allocator
+        let system = DynTrackingAlloc::new();
+        unsafe { system.dealloc(system.alloc(layout), layout) };
+        assert!(!system.is_configured());
+        assert_eq!(2, BACKEND.0.load(Ordering::Relaxed));
+        // and a reference to PeakAlloc is an allocator too
+        fn roundtrip<A: GlobalAlloc>(alloc: A, layout: Layout) -> usize {
+            unsafe {
+                let ptr = alloc.alloc(layout);
+                let usage = PEAK_ALLOC.current_usage();
+                alloc.dealloc(ptr, layout);
+                usage
+            }
+        }
+        assert_eq!(base + 256, roundtrip::<&crate::PeakAlloc>(&PEAK_ALLOC, layout));
+        assert_eq!(base, PEAK_ALLOC.current_usage());
+    }
+
+    #[test]
+    fn a_switch_racing_the_first_allocation_never_mixes_the_allocators() {
+        use std::alloc::{GlobalAlloc, Layout};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Barrier;
+        static BACKEND: CountingBackend = CountingBackend(AtomicUsize::new(0));
+        let _guard = serial();
+        let layout = Layout::new::<u64>();
+        for _ in 0..200 {
+            let alloc = crate::DynTrackingAlloc::new();
+            let barrier = Barrier::new(2);
+            let before = BACKEND.0.load(Ordering::SeqCst);
+            let (set, ptr) = std::thread::scope(|scope| {
+                let setter = scope.spawn(|| {
+                    barrier.wait();
+                    alloc.try_set_inner(&BACKEND)
+                });
+                barrier.wait();
+                let ptr = unsafe { alloc.alloc(layout) } as usize;
+                (setter.join().unwrap(), ptr)
+            });
+            // the block comes from the backend iff the switch succeeded
+            assert_eq!(set, BACKEND.0.load(Ordering::SeqCst) == before + 1);
+            assert_eq!(set, alloc.is_configured());
+            unsafe { alloc.dealloc(ptr as *mut u8, layout) };
+        }
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    fn switching_the_inner_allocator_late_aborts() {
+        let _guard = serial();
+        let output = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "tests::late_switch_child", "--include-ignored", "--nocapture"])
+            .env("PEAK_ALLOC_LATE_SWITCH_CHILD", "1")
+            .output()
+            .unwrap();
+        assert!(!output.status.success());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("the inner allocator was set after the first allocation"), "{}", stderr);
+        assert!(!stderr.contains("not reached"));
+    }
+    /// Run as a subprocess by `switching_the_inner_allocator_late_aborts`
+    #[test]
+    #[ignore]
+    fn late_switch_child() {
+        use std::alloc::{GlobalAlloc, Layout};
+        use std::sync::atomic::AtomicUsize;
+        static BACKEND: CountingBackend = CountingBackend(AtomicUsize::new(0));
+        if std::env::var_os("PEAK_ALLOC_LATE_SWITCH_CHILD").is_none() {
+            return;
+        }
+        let alloc = crate::DynTrackingAlloc::new();
+        let layout = Layout::new::<u64>();
+        unsafe { alloc.dealloc(alloc.alloc(layout), layout) };
+        alloc.set_inner_before_first_alloc(&BACKEND);
+        eprintln!("not reached");
+    }
+
+    #[test]
+    fn construction_and_drop_sizes_are_measured_exactly() {
+        use crate::{measure_construction, measure_drop};
+        let _guard = serial();
+        // 100 boxed u64 in a vector of capacity 100: 800 + 800 bytes, the
+        // temporary (which is released) set aside
+        let (boxes, built) = measure_construction(|| {
+            drop(vec![0_u8; 4096]);
+            let mut boxes = Vec::with_capacity(100);
+            boxes.extend((0..100_u64).map(Box::new));
+            boxes
+        });
+        assert_eq!(1600, built);
+        // what the other threads allocate meanwhile does not count
+        let (_, other) = measure_construction(|| std::thread::spawn(|| vec![0_u8; 1000]).join().unwrap());
+        assert!(other < 1000, "{}", other);
+
+        // nested measurements only see their own part
+        let ((inner, inner_bytes), outer_bytes) =
+            measure_construction(|| measure_construction(|| vec![Box::new(1_u64); 3]));
+        assert_eq!((24 + 24, 24 + 24), (inner_bytes, outer_bytes));
+        drop(inner);
+
+        /// A value whose destructor allocates temporarily
+        #[allow(clippy::vec_box)]
+        struct Noisy(Vec<Box<u64>>);
+        impl Drop for Noisy {
+            fn drop(&mut self) {
+                let log = format!("dropping {} boxes", self.0.len());
+                std::hint::black_box(log);
+            }
+        }
+        assert_eq!(1600, measure_drop(Noisy(boxes)));
+        assert_eq!(0, measure_drop(42_u64));
+    }
+
+    #[test]
+    fn allocations_are_attributed_to_the_main_thread_or_the_others() {
+        let _guard = serial();
+        PEAK_ALLOC.mark_main_thread();
+
+        let main = PEAK_ALLOC.main_thread_allocations();
+        let other = PEAK_ALLOC.other_thread_allocations();
+        let boxes = (0..10).map(|i| std::hint::black_box(Box::new(i))).collect::<Vec<_>>();
+        // 10 boxes and the vector holding them
+        assert_eq!(main + 11, PEAK_ALLOC.main_thread_allocations());
+        drop(boxes);
+
+        let main = PEAK_ALLOC.main_thread_allocations();
+        let other = other.max(PEAK_ALLOC.other_thread_allocations());
+        std::thread::spawn(|| {
+            for i in 0..10 {
+                drop(std::hint::black_box(Box::new(i)));
+            }
+        })
+        .join()
+        .unwrap();
+        assert!(PEAK_ALLOC.other_thread_allocations() >= other + 10);
+        // spawning the thread allocates on this one, but far less
+        assert!(PEAK_ALLOC.main_thread_allocations() - main < 10);
+    }
+
+    #[test]
+    fn thread_allocation_count_only_counts_the_calling_thread() {
+        use std::sync::{Arc, Barrier};
+        let _guard = serial();
+        let barrier = Arc::new(Barrier::new(2));
+        let spawned = {
+            let barrier = Arc::clone(&barrier);
+            std::thread::spawn(move || {
+                let before = PEAK_ALLOC.thread_allocation_count();
+                barrier.wait();
+                // the main thread allocates meanwhile: this one does not
+                barrier.wait();
+                let idle = PEAK_ALLOC.thread_allocation_count() - before;
+                let boxes = (0..5).map(|i| std::hint::black_box(Box::new(i))).collect::<Vec<_>>();
+                let busy = PEAK_ALLOC.thread_allocation_count() - before;
+                drop(boxes);
+                (idle, busy)
+            })
+        };
+        barrier.wait();
+        let before = PEAK_ALLOC.thread_allocation_count();
+        let boxes = (0..10).map(|i| std::hint::black_box(Box::new(i))).collect::<Vec<_>>();
+        // 10 boxes and the vector holding them
+        assert_eq!(before + 11, PEAK_ALLOC.thread_allocation_count());
+        drop(boxes);
+        barrier.wait();
+
+        let (idle, busy) = spawned.join().unwrap();
+        assert_eq!(0, idle);
+        assert_eq!(6, busy);
+    }
+
+    #[cfg(feature = "tracing-attribution")]
+    #[test]
+    fn memory_is_attributed_to_nested_and_reentered_spans() {
+        use crate::SpanMemory;
+        let _guard = serial();
+        let mut outer = SpanMemory::new();
+        let mut inner = SpanMemory::new();
+
+        outer.enter();
+        let kept = Vec::<u8>::with_capacity(1000);
+        inner.enter();
+        drop(Vec::<u8>::with_capacity(5000));
+        // re-entering (recursively) is only accounted for once
+        inner.enter();
+        let held = Vec::<u8>::with_capacity(300);
+        inner.exit();
+        assert!(inner.is_entered());
+        inner.exit();
+        assert!(!inner.is_entered());
+        outer.exit();
+
+        assert_eq!(300, inner.net_bytes());
+        assert_eq!(5000, inner.peak_delta());
+        assert_eq!(2, inner.allocations());
+        assert_eq!(1300, outer.net_bytes());
+        assert_eq!(6000, outer.peak_delta());
+        assert_eq!(3, outer.allocations());
+
+        // entering the span once more accumulates
+        inner.enter();
+        drop(held);
+        drop(Vec::<u8>::with_capacity(100));
+        inner.exit();
+        assert_eq!(0, inner.net_bytes());
+        assert_eq!(5000, inner.peak_delta());
+        assert_eq!(3, inner.allocations());
+        // what is allocated outside of the spans is not attributed to them
+        drop(kept);
+        drop(Vec::<u8>::with_capacity(10_000));
+        assert_eq!(1300, outer.net_bytes());
+        // exiting a span which is not entered does nothing
+        outer.exit();
+        assert_eq!(3, outer.allocations());
+    }
+
+    #[cfg(feature = "tracing-attribution")]
+    #[test]
+    fn spans_may_be_exited_out_of_order() {
+        use crate::SpanMemory;
+        let _guard = serial();
+        let (mut outer, mut a, mut b) = (SpanMemory::new(), SpanMemory::new(), SpanMemory::new());
+        outer.enter();
+        drop(Vec::<u8>::with_capacity(8000));
+        a.enter();
+        b.enter();
+        let kept = Vec::<u8>::with_capacity(1000);
+        // `a` is exited before `b`, which was entered after it
+        a.exit();
+        drop(Vec::<u8>::with_capacity(500));
+        b.exit();
+        outer.exit();
+        assert_eq!((1000, 1000), (a.net_bytes(), a.peak_delta()));
+        // the peak of the enclosing span does not leak into `b`
+        assert_eq!((1000, 1500), (b.net_bytes(), b.peak_delta()));
+        assert_eq!((1000, 8000), (outer.net_bytes(), outer.peak_delta()));
+
+        // and a later span starts afresh
+        let mut later = SpanMemory::new();
+        later.enter();
+        drop(Vec::<u8>::with_capacity(300));
+        later.exit();
+        assert_eq!((0, 300), (later.net_bytes(), later.peak_delta()));
+        drop(kept);
+    }
+
+    #[cfg(feature = "module-tags")]
+    #[crate::instrument_module]
+    mod parser {
+        pub fn parse(len: usize) -> Vec<u8> {
+            helper(len)
+        }
+        pub fn parse_generic<T: Clone>(value: T, len: usize) -> Vec<T> {
+            vec![value; len]
+        }
+        pub async fn parse_async(len: usize) -> Vec<u8> {
+            let head = helper(len);
+            super::YieldOnce(false).await;
+            let tail = helper(len);
+            [head, tail].concat()
+        }
+        // not instrumented, hence attributed to its caller's tag
+        fn helper(len: usize) -> Vec<u8> {
+            vec![0; len]
+        }
+        pub struct Parser;
+        impl Parser {
+            pub fn parse(&self, len: usize) -> Vec<u8> {
+                helper(len)
+            }
+        }
+        impl Clone for Parser {
+            fn clone(&self) -> Self {
+                drop(helper(100));
+                Parser
+            }
+        }
+    }
+
+    #[cfg(feature = "module-tags")]
+    #[crate::instrument_module("lexer")]
+    mod lexer {
+        pub fn lex(len: usize) -> Vec<u8> {
+            vec![1; len]
+        }
+    }
+
+    /// A future which is pending the first time it is polled
+    #[cfg(feature = "module-tags")]
+    struct YieldOnce(bool);
+
+    #[cfg(feature = "module-tags")]
+    impl std::future::Future for YieldOnce {
+        type Output = ();
+        fn poll(mut self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<()> {
+            if std::mem::replace(&mut self.0, true) {
+                std::task::Poll::Ready(())
+            } else {
+                cx.waker().wake_by_ref();
+                std::task::Poll::Pending
+            }
+        }
+    }
+
+    #[cfg(feature = "module-tags")]
+    #[test]
+    fn memory_is_attributed_to_the_instrumented_modules() {
+        use std::future::Future;
+        use std::task::{Context, Poll, Wake, Waker};
+        struct Noop;
+        impl Wake for Noop {
+            fn wake(self: std::sync::Arc<Self>) {}
+        }
+        let usage_of = |tag: &str| PEAK_ALLOC.tag_usage().into_iter().find(|usage| usage.tag == tag);
+        let _guard = serial();
+
+        let data = parser::parse(1000);
+        let generic = parser::parse_generic(7_u32, 100);
+        let parsed = parser::Parser.parse(500);
+        let _ = crate::tests::parser::Parser.clone();
+        let parser = usage_of("peak_alloc::tests::parser").expect("the parser should be tagged");
+        assert_eq!(4, parser.allocations);
+        assert_eq!(1000 + 400 + 500 + 100, parser.allocated_bytes);
+        assert_eq!(1900, parser.net_bytes);
+        // what is allocated outside of the module is not attributed to it
+        let outside = vec![0_u8; 10_000];
+        drop((data, generic, parsed));
+        assert_eq!(Some(parser), usage_of("peak_alloc::tests::parser"));
+
+        // the async functions are attributed across their await points, but
+        // what is allocated between two polls is not
+        let lexed = lexer::lex(10);
+        let waker = Waker::from(std::sync::Arc::new(Noop));
+        let mut future = Box::pin(parser::parse_async(64));
+        let mut polls = 0;
+        let parsed = loop {
+            polls += 1;
+            if let Poll::Ready(parsed) = future.as_mut().poll(&mut Context::from_waker(&waker)) {
+                break parsed;
+            }
+            drop(vec![0_u8; 5000]);
+        };
+        assert_eq!(2, polls);
+        let after = usage_of("peak_alloc::tests::parser").unwrap();
+        assert_eq!(parser.allocations + 3, after.allocations);
+        assert_eq!(parser.net_bytes + 128, after.net_bytes);
+        assert_eq!(parser.allocated_bytes + 4 * 64, after.allocated_bytes);
+        assert_eq!(
+            "lexer: 1 allocations, 10 B allocated, 10 B net",
+            usage_of("lexer").unwrap().to_string()
+        );
+        // the report is sorted by tag
+        let tags: Vec<&str> = PEAK_ALLOC.tag_usage().iter().map(|usage| usage.tag).collect();
+        assert!(tags.windows(2).all(|w| w[0] < w[1]), "{:?}", tags);
+        drop((parsed, lexed, outside));
+    }
+
+    #[test]
+    fn query_and_reporting_paths_never_allocate() {
+        use crate::ByteSize;
+        use std::fmt::Write;
+        use std::time::Duration;
+        let _guard = serial();
+        let history = PEAK_ALLOC.start_history(8, Duration::from_millis(1));
+        std::thread::sleep(Duration::from_millis(10));
+        let mut out = crate::fmt::StackString::<4096>::new();
+
+        let forbidden = PEAK_ALLOC.forbid_alloc();
+        std::hint::black_box(PEAK_ALLOC.current_usage());
+        std::hint::black_box(PEAK_ALLOC.peak_usage());
+        std::hint::black_box(PEAK_ALLOC.stats());
+        std::hint::black_box(PEAK_ALLOC.read_all());
+        let mut metrics = crate::MetricsBuffer::new();
+        PEAK_ALLOC.read_metrics(&mut metrics);
+        std::hint::black_box(metrics);
+        PEAK_ALLOC.write_report(&mut out).unwrap();
+        assert!(!out.is_truncated());
+        out.clear();
+        PEAK_ALLOC.write_json(&mut out).unwrap();
+        out.clear();
+        PEAK_ALLOC.write_influx(&mut out, "heap", &[("env", "ci")]).unwrap();
+        out.clear();
+        write!(out, "{} {} {:?}", ByteSize(123), ByteSize::mib(3), PEAK_ALLOC).unwrap();
+        crate::fmt::write_usize(&mut out, usize::MAX).unwrap();
+        crate::fmt::write_bytes_human(&mut out, usize::MAX).unwrap();
+        crate::fmt::write_duration(&mut out, Duration::MAX).unwrap();
+        write!(out, "{} {:#} {:.1}", PEAK_ALLOC, PEAK_ALLOC, PEAK_ALLOC.display_in(crate::units::Unit::Gb)).unwrap();
+        assert!(!out.is_truncated());
+        history.for_each_sample(|time, usage| {
+            std::hint::black_box((time, usage));
+        });
+        assert_eq!(0, forbidden.violations());
+        drop(forbidden);
+    }
+    #[test]
+    #[should_panic(expected = "1 allocation(s) performed while allocating was forbidden")]
+    fn forbid_alloc_detects_allocations() {
+        let guard = PEAK_ALLOC.forbid_alloc();
+        {
+            let nested = PEAK_ALLOC.forbid_alloc();
+            assert_eq!(0, nested.violations());
+        }
+        drop(std::hint::black_box(Box::new(42)));
+        assert_eq!(1, guard.violations());
+    }
+
+    #[test]
+    fn freeze_aborts_upon_allocation() {
+        let _guard = serial();
+        let output = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "tests::frozen_child", "--include-ignored", "--nocapture"])
+            .env("PEAK_ALLOC_FROZEN_CHILD", "1")
+            .output()
+            .unwrap();
+        assert!(!output.status.success());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let message = "peak_alloc: allocation while the allocator is frozen, aborting";
+        assert!(stderr.contains(message), "{}", stderr);
+        assert!(!stderr.contains("not reached"));
+    }
+    /// Run as a subprocess by `freeze_aborts_upon_allocation`
+    #[test]
+    #[ignore]
+    fn frozen_child() {
+        if std::env::var_os("PEAK_ALLOC_FROZEN_CHILD").is_none() {
+            return;
+        }
+        PEAK_ALLOC.freeze();
+        drop(std::hint::black_box(Box::new(42)));
+        PEAK_ALLOC.unfreeze();
+        eprintln!("not reached");
+    }
+
+    #[cfg(feature = "oom-report")]
+    #[test]
+    fn out_of_memory_is_reported_before_aborting() {
+        let _guard = serial();
+        let output = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "tests::oom_child", "--include-ignored", "--nocapture"])
+            .env("PEAK_ALLOC_OOM_CHILD", "1")
+            .output()
+            .unwrap();
+        assert!(!output.status.success());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let message = "peak_alloc: out of memory, failed to allocate 16777216 bytes (align 1)";
+        assert!(stderr.contains(message), "{}", stderr);
+        assert!(stderr.contains("  memory limit       : "), "{}", stderr);
+        assert!(!stderr.contains("memory limit       : none"), "{}", stderr);
+        assert!(!stderr.contains("not reached"));
+    }
+    /// Run as a subprocess by `out_of_memory_is_reported_before_aborting`
+    #[cfg(feature = "oom-report")]
+    #[test]
+    #[ignore]
+    fn oom_child() {
+        if std::env::var_os("PEAK_ALLOC_OOM_CHILD").is_none() {
+            return;
+        }
+        PEAK_ALLOC.install_oom_reporter();
+        PEAK_ALLOC.set_memory_limit(crate::TRACKER.raw_current() + 1024 * 1024);
+        let mut data: Vec<u8> = Vec::new();
+        crate::reserve_or_report(&mut data, 16 * 1024 * 1024);
+        eprintln!("not reached");
+    }
+
+    #[cfg(all(feature = "socket", unix))]
+    #[test]
+    fn stats_are_served_over_a_unix_socket() {
+        use std::io::Read;
+        use std::os::unix::net::UnixStream;
+        let _guard = serial();
+        let path = std::env::temp_dir().join(format!("peak_alloc_stats_{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let server = PEAK_ALLOC.serve_stats(&path).unwrap();
+        assert!(PEAK_ALLOC.serve_stats(&path).is_err());
+        for _ in 0..2 {
+            let mut response = String::new();
+            UnixStream::connect(&path).unwrap().read_to_string(&mut response).unwrap();
+            let json = response.trim_end();
+            assert!(json.starts_with("{\"schema\":") && json.ends_with('}'), "{}", json);
+            let peak = json.split("\"peak\":").nth(1).and_then(|v| v.split(',').next());
+            assert!(peak.unwrap().parse::<usize>().is_ok(), "{}", json);
+        }
+        assert_eq!(2, server.served());
+        assert_eq!(path, server.path());
+        drop(server);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn large_allocations_are_counted_apart() {
+        use std::alloc::{GlobalAlloc, Layout};
+        const MB: usize = 1024 * 1024;
+        let _guard = serial();
+        PEAK_ALLOC.set_large_threshold(4 * MB);
+        PEAK_ALLOC.reset_peak_usage();
+        let current = PEAK_ALLOC.large_current_usage();
+        let count = PEAK_ALLOC.large_allocation_count();
+        let layout = |size| Layout::from_size_align(size, 8).unwrap();
+        let moved = crate::system::REALLOC_BY_COPY as usize;
+        unsafe {
+            // small: untouched
+            let small = PEAK_ALLOC.alloc(layout(MB));
+            assert_eq!(current, PEAK_ALLOC.large_current_usage());
+            assert_eq!(count, PEAK_ALLOC.large_allocation_count());
+            // small -> large: moves to the large counters
+            let grown = PEAK_ALLOC.realloc(small, layout(MB), 5 * MB);
+            assert_eq!(current + 5 * MB, PEAK_ALLOC.large_current_usage());
+            assert_eq!(count + 1, PEAK_ALLOC.large_allocation_count());
+            // large -> larger: the difference
+            let larger = PEAK_ALLOC.realloc(grown, layout(5 * MB), 6 * MB);
+            assert_eq!(current + 6 * MB, PEAK_ALLOC.large_current_usage());
+            assert_eq!(count + 1 + moved, PEAK_ALLOC.large_allocation_count());
+            // large -> smaller large: the difference
+            let smaller = PEAK_ALLOC.realloc(larger, layout(6 * MB), 4 * MB);
+            assert_eq!(current + 4 * MB, PEAK_ALLOC.large_current_usage());
+            assert!(PEAK_ALLOC.large_peak_usage() >= current + 6 * MB);
+            // large -> small: leaves the large counters
+            let shrunk = PEAK_ALLOC.realloc(smaller, layout(4 * MB), 2 * MB);
+            assert_eq!(current, PEAK_ALLOC.large_current_usage());
+            PEAK_ALLOC.dealloc(shrunk, layout(2 * MB));
+
+            // a plain large allocation
+            let large = PEAK_ALLOC.alloc(layout(8 * MB));
+            assert_eq!(current + 8 * MB, PEAK_ALLOC.large_current_usage());
+            assert_eq!(count + 2 + 2 * moved, PEAK_ALLOC.large_allocation_count());
+            assert_eq!(current + 8 * MB, PEAK_ALLOC.stats().large_current_usage);
+            PEAK_ALLOC.dealloc(large, layout(8 * MB));
+        }
+        assert_eq!(current, PEAK_ALLOC.large_current_usage());
+        PEAK_ALLOC.set_large_threshold(0);
+    }
+
+    #[test]
+    fn current_usage_is_mirrored_into_a_user_atomic() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static MIRROR: AtomicUsize = AtomicUsize::new(0);
+        let _guard = serial();
+        PEAK_ALLOC.mirror_current_into(&MIRROR);
+
+        let block = std::hint::black_box(vec![0_u8; 4096]);
+        assert_eq!(PEAK_ALLOC.current_usage(), MIRROR.load(Ordering::Relaxed));
+        drop(block);
+        assert_eq!(PEAK_ALLOC.current_usage(), MIRROR.load(Ordering::Relaxed));
+
+        PEAK_ALLOC.stop_mirroring();
+        let stale = MIRROR.load(Ordering::Relaxed);
+        let block = std::hint::black_box(vec![0_u8; 4096]);
+        assert_eq!(stale, MIRROR.load(Ordering::Relaxed));
+        drop(block);
+    }
+
+    #[test]
+    fn usage_report_accepts_an_optional_label() {
+        let label = String::from("after load");
+        crate::usage_report!(PEAK_ALLOC);
+        crate::usage_report!(PEAK_ALLOC, "startup");
+        crate::usage_report!(PEAK_ALLOC, label,);
+        crate::usage_report!(&PEAK_ALLOC,);
+    }
+
+    #[test]
+    fn shrinking_reallocs_never_underflow_the_usage() {
+        use std::alloc::{GlobalAlloc, Layout};
+        let _guard = serial();
+        let layout = |size| Layout::from_size_align(size, 8).unwrap();
+        let moved = crate::system::REALLOC_BY_COPY;
+        let mut size = 1024 * 1024;
+        let base = PEAK_ALLOC.current_usage();
+        unsafe {
+            let mut ptr = PEAK_ALLOC.alloc(layout(size));
+            PEAK_ALLOC.reset_peak_usage();
+            let mut usage = PEAK_ALLOC.current_usage();
+            assert_eq!(base + size, usage);
+            while size > 1 {
+                ptr = PEAK_ALLOC.realloc(ptr, layout(size), size / 2);
+                assert!(!ptr.is_null());
+                size /= 2;
+                let shrunk = PEAK_ALLOC.current_usage();
+                assert!(shrunk < usage && shrunk >= base);
+                assert_eq!(base + size, shrunk);
+                usage = shrunk;
+            }
+            PEAK_ALLOC.dealloc(ptr, layout(size));
+        }
+        assert_eq!(base, PEAK_ALLOC.current_usage());
+        // a shrink is accounted for by its delta, whereas a moved block is
+        // briefly live along with its (smaller) copy
+        let transient = if moved { 512 * 1024 } else { 0 };
+        assert_eq!(base + 1024 * 1024 + transient, PEAK_ALLOC.peak_usage());
+    }
+}
@@ -0,0 +1,109 @@
+//! Statistics about how long the allocations live (their age when they are
+//! released). These are useful to decide whether some data would be better
+//! served by an arena than by the general purpose heap.
+//!
+//! # Note
+//! The ages are measured with the coarse clock of this crate. On Linux, its
+//! resolution is that of a scheduler tick (a few milliseconds): the blocks
+//! which are released within the tick they were allocated in may hence be
+//! reported as sub-microsecond.
+
+use std::fmt;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use crate::sampling::Ticker;
+use crate::{clock, ptrmap};
+
+/// Decides which blocks are recorded in the pointer map
+pub(crate) static TICKER: Ticker = Ticker::new();
+
+static UNDER_1US: AtomicUsize = AtomicUsize::new(0);
+static UNDER_1MS: AtomicUsize = AtomicUsize::new(0);
+static UNDER_1S: AtomicUsize = AtomicUsize::new(0);
+static LONGER: AtomicUsize = AtomicUsize::new(0);
+/// The sum of the ages (in nanoseconds) of all the released blocks
+static TOTAL_AGE: AtomicU64 = AtomicU64::new(0);
+
+/// Records the age (in nanoseconds) of a block being released
+#[inline]
+pub(crate) fn record(age: u64) {
+    let bucket = match age {
+        0..=999 => &UNDER_1US,
+        1_000..=999_999 => &UNDER_1MS,
+        1_000_000..=999_999_999 => &UNDER_1S,
+        _ => &LONGER,
+    };
+    bucket.fetch_add(1, Ordering::Relaxed);
+    TOTAL_AGE.fetch_add(age, Ordering::Relaxed);
+}
+
+/// Returns the histogram of the ages at which the blocks were released
+pub(crate) fn histogram() -> LifetimeHistogram {
+    LifetimeHistogram {
+        under_1us: UNDER_1US.load(Ordering::Relaxed),
+        under_1ms: UNDER_1MS.load(Ordering::Relaxed),
+        under_1s: UNDER_1S.load(Ordering::Relaxed),
+        longer: LONGER.load(Ordering::Relaxed),
+    }
+}
+
+/// Returns the mean age of the released blocks (None if none was released)
+pub(crate) fn mean() -> Option<Duration> {
+    let count = histogram().count() as u64;
+    TOTAL_AGE
+        .load(Ordering::Relaxed)
+        .checked_div(count)
+        .map(Duration::from_nanos)
+}
+
+/// Returns the age of the oldest block which is still alive (None if the
+/// pointer map holds no block). This scans the whole pointer map.
+pub(crate) fn oldest_live() -> Option<Duration> {
+    let mut oldest = None;
+    ptrmap::for_each(|e| oldest = Some(oldest.map_or(e.born, |o: u64| o.min(e.born))));
+    oldest.map(|born| Duration::from_nanos(clock::now().saturating_sub(born)))
+}
+
+/// Returns the number of bytes of the live blocks which were allocated more
+/// than `older_than` ago. This scans the whole pointer map.
+pub(crate) fn long_lived(older_than: Duration) -> usize {
+    let born_before = clock::now().saturating_sub(older_than.as_nanos() as u64);
+    let mut bytes = 0_usize;
+    ptrmap::for_each(|e| {
+        if e.born < born_before {
+            bytes += e.size;
+        }
+    });
+    bytes
+}
+
+/// The number of released blocks, grouped by the age they were released at
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LifetimeHistogram {
+    /// The number of blocks released after less than a microsecond
+    pub under_1us: usize,
+    /// The number of blocks released after one microsecond to one millisecond
+    pub under_1ms: usize,
+    /// The number of blocks released after one millisecond to one second
+    pub under_1s: usize,
+    /// The number of blocks released after one second or more
+    pub longer: usize,
+}
+
+impl LifetimeHistogram {
+    /// Returns the total number of released blocks
+    pub fn count(&self) -> usize {
+        self.under_1us + self.under_1ms + self.under_1s + self.longer
+    }
+}
+
+impl fmt::Display for LifetimeHistogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "allocation lifetimes ({} blocks released)", self.count())?;
+        writeln!(f, "  < 1us : {}", self.under_1us)?;
+        writeln!(f, "  < 1ms : {}", self.under_1ms)?;
+        writeln!(f, "  < 1s  : {}", self.under_1s)?;
+        writeln!(f, "  >= 1s : {}", self.longer)
+    }
+}
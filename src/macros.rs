@@ -0,0 +1,37 @@
+//! The macros of the crate.
+
+/// Prints the current and peak usage of the given `PeakAlloc` to stdout, in
+/// human readable units, optionally labelled:
+///
+/// ```
+/// use peak_alloc::{usage_report, PeakAlloc};
+///
+/// #[global_allocator]
+/// static PEAK_ALLOC: PeakAlloc = PeakAlloc;
+///
+/// let data = vec![0_u8; 4096];
+/// // peak_alloc: current 4.00 KiB, peak 4.00 KiB
+/// usage_report!(PEAK_ALLOC);
+/// // peak_alloc [after load]: current 4.00 KiB, peak 4.00 KiB
+/// usage_report!(PEAK_ALLOC, "after load");
+/// # drop(data);
+/// ```
+#[cfg(feature = "std")]
+#[macro_export]
+macro_rules! usage_report {
+    ($alloc:expr $(,)?) => {
+        println!(
+            "peak_alloc: current {}, peak {}",
+            $crate::ByteSize($alloc.current_usage()),
+            $crate::ByteSize($alloc.peak_usage())
+        )
+    };
+    ($alloc:expr, $label:expr $(,)?) => {
+        println!(
+            "peak_alloc [{}]: current {}, peak {}",
+            $label,
+            $crate::ByteSize($alloc.current_usage()),
+            $crate::ByteSize($alloc.peak_usage())
+        )
+    };
+}
@@ -0,0 +1,109 @@
+//! Measuring the size of a data structure: the net number of bytes the
+//! calling thread allocates while building it, or releases while dropping it
+//! (see `measure_construction` and `measure_drop`).
+//!
+//! The allocation path only maintains the thread local net while a
+//! measurement is in progress, or while the thread is within a span (see
+//! `spans`, which shares it). The helpers themselves do not allocate, hence
+//! they never account for themselves.
+
+use std::cell::Cell;
+
+/// What the allocation path maintains for a thread while it is measured
+pub(crate) struct Net {
+    /// The number of measurements and spans in progress on this thread
+    pub(crate) depth: Cell<usize>,
+    /// The net number of bytes this thread allocated meanwhile
+    pub(crate) bytes: Cell<isize>,
+    /// The highest `bytes` reached since the innermost span was entered
+    pub(crate) high: Cell<isize>,
+}
+
+thread_local! {
+    /// The net of this thread
+    pub(crate) static NET: Net = const {
+        Net { depth: Cell::new(0), bytes: Cell::new(0), high: Cell::new(0) }
+    };
+}
+
+/// Accounts for `delta` more bytes being used by this thread (if it is
+/// measured)
+#[inline]
+pub(crate) fn on_grow(delta: usize) {
+    let _ = NET.try_with(|net| {
+        if net.depth.get() > 0 {
+            let bytes = net.bytes.get().wrapping_add(delta as isize);
+            net.bytes.set(bytes);
+            net.high.set(net.high.get().max(bytes));
+        }
+    });
+}
+
+/// Accounts for `delta` less bytes being used by this thread (if it is
+/// measured). A thread may well release what another one allocated: the net
+/// can go down past where a measurement started.
+#[inline]
+pub(crate) fn on_shrink(delta: usize) {
+    let _ = NET.try_with(|net| {
+        if net.depth.get() > 0 {
+            net.bytes.set(net.bytes.get().wrapping_sub(delta as isize));
+        }
+    });
+}
+
+/// Runs `f` while maintaining the thread local net, and returns its result
+/// along with the net number of bytes allocated meanwhile
+fn measured<T, F: FnOnce() -> T>(f: F) -> (T, isize) {
+    let start = NET.with(|net| {
+        net.depth.set(net.depth.get() + 1);
+        net.bytes.get()
+    });
+    let value = f();
+    let bytes = NET.with(|net| {
+        net.depth.set(net.depth.get() - 1);
+        net.bytes.get()
+    });
+    (value, bytes.wrapping_sub(start))
+}
+
+/// Builds a value with `build` and returns it along with the number of bytes
+/// it retains: what the calling thread allocated during the construction,
+/// minus what it released (the temporaries do not count).
+///
+/// ```
+/// use peak_alloc::{measure_construction, PeakAlloc};
+///
+/// #[global_allocator]
+/// static PEAK_ALLOC: PeakAlloc = PeakAlloc;
+///
+/// let (boxes, bytes) = measure_construction(|| (0..10_u64).map(Box::new).collect::<Vec<_>>());
+/// assert_eq!(10 * 8 + 10 * 8, bytes);
+/// # drop(boxes);
+/// ```
+///
+/// # Note
+/// Only the calling thread is measured: what `build` has other threads
+/// allocate is missed, and so are the blocks below the minimum tracked size
+/// (see `PeakAlloc::set_min_tracked_size`).
+pub fn measure_construction<T, F: FnOnce() -> T>(build: F) -> (T, usize) {
+    let (value, net) = measured(build);
+    (value, net.max(0) as usize)
+}
+
+/// Drops `value` and returns the number of bytes the calling thread released
+/// meanwhile. What the destructors allocate temporarily (and release) does
+/// not count.
+///
+/// ```
+/// use peak_alloc::{measure_drop, PeakAlloc};
+///
+/// #[global_allocator]
+/// static PEAK_ALLOC: PeakAlloc = PeakAlloc;
+///
+/// let text = String::from("hello world");
+/// assert_eq!(11, measure_drop(text));
+/// ```
+pub fn measure_drop<T>(value: T) -> usize {
+    let ((), net) = measured(|| drop(value));
+    net.min(0).unsigned_abs()
+}
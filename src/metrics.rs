@@ -0,0 +1,223 @@
+//! The counters of the allocator in a single `#[repr(C)]` buffer (see
+//! `PeakAlloc::read_metrics`), for the pollers which read them often, and
+//! across the FFI.
+//!
+//! Every entry is a `u64` at a fixed index (the constants of
+//! `MetricsBuffer`). The entries of the features which are not compiled in
+//! are left to 0, with their bit of `valid` cleared: the mask is a constant
+//! of the build, hence filling the buffer branches on none of them. The
+//! entries are only ever appended, along with a bump of
+//! `STATS_SCHEMA_VERSION` (the size of the buffer changes).
+
+use crate::{PeakAlloc, STATS_SCHEMA_VERSION};
+
+/// The number of entries of a `MetricsBuffer`
+pub const METRICS: usize = 23;
+
+/// The entries filled in by this build
+const VALID: u64 = {
+    // the entries up to the growth slack are always there
+    let always = (1 << (MetricsBuffer::GROWTH_SLACK_BYTES + 1)) - 1;
+    always
+        | (cfg!(feature = "std") as u64) << MetricsBuffer::SMOOTHED_PEAK_USAGE
+        | (cfg!(feature = "zeroize-on-free") as u64) << MetricsBuffer::WIPED_BYTES
+        | (cfg!(feature = "checked-layout") as u64) << MetricsBuffer::INVALID_REQUEST_COUNT
+        | (cfg!(feature = "quarantine") as u64) << MetricsBuffer::QUARANTINED_BYTES
+        | (cfg!(feature = "redzones") as u64) << MetricsBuffer::REDZONE_VIOLATIONS
+        | (cfg!(feature = "pointer-map") as u64) << MetricsBuffer::POINTER_MAP_OVERFLOWS
+};
+
+// every entry has a bit of the mask, and the last one ends the buffer
+const _: () = assert!(VALID < 1 << METRICS && MetricsBuffer::POINTER_MAP_OVERFLOWS + 1 == METRICS);
+
+/// The counters of the allocator, as filled in by `PeakAlloc::read_metrics`.
+/// The entry `i` is the value of `values[i]`, valid iff the bit `1 << i` of
+/// `valid` is set; each entry is the counter of the `PeakAlloc` method of
+/// the same name.
+///
+/// Like `stats`, the counters are loaded one after the other: this is not an
+/// atomic snapshot.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MetricsBuffer {
+    /// The schema of the entries (`STATS_SCHEMA_VERSION`)
+    pub schema: u32,
+    /// The number of entries (`METRICS`)
+    pub len: u32,
+    /// The entries which were filled in (`1 << index`)
+    pub valid: u64,
+    /// The entries
+    pub values: [u64; METRICS],
+}
+
+impl MetricsBuffer {
+    /// The index of `current_usage`
+    pub const CURRENT_USAGE: usize = 0;
+    /// The index of `peak_usage`
+    pub const PEAK_USAGE: usize = 1;
+    /// The index of `all_time_peak_usage`
+    pub const ALL_TIME_PEAK_USAGE: usize = 2;
+    /// The index of `total_allocated`
+    pub const TOTAL_ALLOCATED: usize = 3;
+    /// The index of `allocation_count`
+    pub const ALLOCATION_COUNT: usize = 4;
+    /// The index of `deallocation_count`
+    pub const DEALLOCATION_COUNT: usize = 5;
+    /// The index of `reallocation_count`
+    pub const REALLOCATION_COUNT: usize = 6;
+    /// The index of `realloc_overhead_bytes`
+    pub const REALLOC_OVERHEAD_BYTES: usize = 7;
+    /// The index of `largest_allocation`
+    pub const LARGEST_ALLOCATION: usize = 8;
+    /// The index of `failed_allocation_count`
+    pub const FAILED_ALLOCATION_COUNT: usize = 9;
+    /// The index of `large_current_usage`
+    pub const LARGE_CURRENT_USAGE: usize = 10;
+    /// The index of `large_peak_usage`
+    pub const LARGE_PEAK_USAGE: usize = 11;
+    /// The index of `large_allocation_count`
+    pub const LARGE_ALLOCATION_COUNT: usize = 12;
+    /// The index of `overaligned_count`
+    pub const OVERALIGNED_COUNT: usize = 13;
+    /// The index of `overaligned_bytes`
+    pub const OVERALIGNED_BYTES: usize = 14;
+    /// The index of `external_usage`
+    pub const EXTERNAL_USAGE: usize = 15;
+    /// The index of `growth_slack_bytes`
+    pub const GROWTH_SLACK_BYTES: usize = 16;
+    /// The index of `smoothed_peak_usage` (feature `std`)
+    pub const SMOOTHED_PEAK_USAGE: usize = 17;
+    /// The index of `wiped_bytes` (feature `zeroize-on-free`)
+    pub const WIPED_BYTES: usize = 18;
+    /// The index of `invalid_request_count` (feature `checked-layout`)
+    pub const INVALID_REQUEST_COUNT: usize = 19;
+    /// The index of `quarantined_bytes` (feature `quarantine`)
+    pub const QUARANTINED_BYTES: usize = 20;
+    /// The index of `redzone_violations` (feature `redzones`)
+    pub const REDZONE_VIOLATIONS: usize = 21;
+    /// The index of `pointer_map_overflows` (feature `pointer-map`)
+    pub const POINTER_MAP_OVERFLOWS: usize = 22;
+
+    /// Returns an empty buffer: none of its entries is valid
+    pub const fn new() -> Self {
+        MetricsBuffer { schema: STATS_SCHEMA_VERSION, len: METRICS as u32, valid: 0, values: [0; METRICS] }
+    }
+    /// Returns the entry at `index`, if it is valid
+    pub fn get(&self, index: usize) -> Option<u64> {
+        let valid = index < METRICS && self.valid & (1 << index) != 0;
+        valid.then(|| self.values[index])
+    }
+    /// Returns the change of the entries since `prev`, for the rates: each
+    /// entry is this one minus that of `prev`, valid iff it is valid in both.
+    /// The subtraction wraps around, hence a gauge which went down (e.g. the
+    /// current usage) reads as a negative `i64` once cast.
+    pub fn diff(&self, prev: &MetricsBuffer) -> MetricsBuffer {
+        let mut diff = *self;
+        diff.valid &= prev.valid;
+        for (value, prev) in diff.values.iter_mut().zip(prev.values.iter()) {
+            *value = value.wrapping_sub(*prev);
+        }
+        diff
+    }
+    /// Loads the counters of `alloc` into the buffer
+    pub(crate) fn fill(&mut self, alloc: &PeakAlloc) {
+        let v = &mut self.values;
+        v[Self::CURRENT_USAGE] = alloc.current_usage() as u64;
+        v[Self::PEAK_USAGE] = alloc.peak_usage() as u64;
+        v[Self::ALL_TIME_PEAK_USAGE] = alloc.all_time_peak_usage() as u64;
+        v[Self::TOTAL_ALLOCATED] = alloc.total_allocated() as u64;
+        v[Self::ALLOCATION_COUNT] = alloc.allocation_count() as u64;
+        v[Self::DEALLOCATION_COUNT] = alloc.deallocation_count() as u64;
+        v[Self::REALLOCATION_COUNT] = alloc.reallocation_count() as u64;
+        v[Self::REALLOC_OVERHEAD_BYTES] = alloc.realloc_overhead_bytes() as u64;
+        v[Self::LARGEST_ALLOCATION] = alloc.largest_allocation() as u64;
+        v[Self::FAILED_ALLOCATION_COUNT] = alloc.failed_allocation_count() as u64;
+        v[Self::LARGE_CURRENT_USAGE] = alloc.large_current_usage() as u64;
+        v[Self::LARGE_PEAK_USAGE] = alloc.large_peak_usage() as u64;
+        v[Self::LARGE_ALLOCATION_COUNT] = alloc.large_allocation_count() as u64;
+        v[Self::OVERALIGNED_COUNT] = alloc.overaligned_count() as u64;
+        v[Self::OVERALIGNED_BYTES] = alloc.overaligned_bytes() as u64;
+        v[Self::EXTERNAL_USAGE] = alloc.external_usage() as u64;
+        v[Self::GROWTH_SLACK_BYTES] = alloc.growth_slack_bytes() as u64;
+        #[cfg(feature = "std")]
+        {
+            v[Self::SMOOTHED_PEAK_USAGE] = alloc.smoothed_peak_usage() as u64;
+        }
+        #[cfg(feature = "zeroize-on-free")]
+        {
+            v[Self::WIPED_BYTES] = alloc.wiped_bytes() as u64;
+        }
+        #[cfg(feature = "checked-layout")]
+        {
+            v[Self::INVALID_REQUEST_COUNT] = alloc.invalid_request_count() as u64;
+        }
+        #[cfg(feature = "quarantine")]
+        {
+            v[Self::QUARANTINED_BYTES] = alloc.quarantined_bytes() as u64;
+        }
+        #[cfg(feature = "redzones")]
+        {
+            v[Self::REDZONE_VIOLATIONS] = alloc.redzone_violations() as u64;
+        }
+        #[cfg(feature = "pointer-map")]
+        {
+            v[Self::POINTER_MAP_OVERFLOWS] = alloc.pointer_map_overflows() as u64;
+        }
+        self.schema = STATS_SCHEMA_VERSION;
+        self.len = METRICS as u32;
+        self.valid = VALID;
+    }
+}
+
+impl Default for MetricsBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MetricsBuffer, METRICS, VALID};
+
+    #[test]
+    fn the_mask_tracks_the_compiled_features() {
+        let features = [
+            (MetricsBuffer::SMOOTHED_PEAK_USAGE, cfg!(feature = "std")),
+            (MetricsBuffer::WIPED_BYTES, cfg!(feature = "zeroize-on-free")),
+            (MetricsBuffer::INVALID_REQUEST_COUNT, cfg!(feature = "checked-layout")),
+            (MetricsBuffer::QUARANTINED_BYTES, cfg!(feature = "quarantine")),
+            (MetricsBuffer::REDZONE_VIOLATIONS, cfg!(feature = "redzones")),
+            (MetricsBuffer::POINTER_MAP_OVERFLOWS, cfg!(feature = "pointer-map")),
+        ];
+        for (index, compiled) in features {
+            assert_eq!(compiled, VALID & (1 << index) != 0, "{}", index);
+        }
+        for index in MetricsBuffer::CURRENT_USAGE..=MetricsBuffer::GROWTH_SLACK_BYTES {
+            assert!(VALID & (1 << index) != 0, "{}", index);
+        }
+    }
+
+    #[test]
+    fn the_diff_subtracts_the_entries_valid_in_both() {
+        let mut prev = MetricsBuffer::new();
+        prev.valid = 0b111;
+        prev.values[0] = 1_000;
+        prev.values[1] = 50;
+        prev.values[2] = u64::MAX;
+        let mut now = prev;
+        now.valid = 0b1011;
+        now.values[0] = 1_500;
+        now.values[1] = 20;
+        now.values[2] = 9;
+        now.values[3] = 7;
+        let diff = now.diff(&prev);
+        assert_eq!(0b11, diff.valid);
+        assert_eq!(Some(500), diff.get(0));
+        assert_eq!(-30, diff.values[1] as i64);
+        // the entry 2 wrapped around, but is no longer valid
+        assert_eq!((10, None), (diff.values[2], diff.get(2)));
+        assert_eq!(None, diff.get(3));
+        assert_eq!(None, diff.get(METRICS));
+        assert_eq!((now.schema, now.len), (diff.schema, diff.len));
+    }
+}
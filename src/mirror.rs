@@ -0,0 +1,27 @@
+//! The user supplied atomic which mirrors the current usage (see
+//! `PeakAlloc::mirror_current_into`). It is kept in an `AtomicPtr` so that it
+//! can be swapped at any time: turning it back into a reference is the only
+//! `unsafe` this needs.
+
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+/// The mirror (null if none)
+static TARGET: AtomicPtr<AtomicUsize> = AtomicPtr::new(ptr::null_mut());
+
+/// Sets the atomic the usage is mirrored into
+pub(crate) fn set(target: &'static AtomicUsize) {
+    TARGET.store(target as *const AtomicUsize as *mut AtomicUsize, Ordering::Relaxed);
+}
+
+/// Stops mirroring the usage
+pub(crate) fn clear() {
+    TARGET.store(ptr::null_mut(), Ordering::Relaxed);
+}
+
+/// Returns the atomic the usage is mirrored into, if any
+#[inline]
+pub(crate) fn target() -> Option<&'static AtomicUsize> {
+    // only ever set from a &'static AtomicUsize
+    unsafe { TARGET.load(Ordering::Relaxed).as_ref() }
+}
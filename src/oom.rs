@@ -0,0 +1,186 @@
+//! A last-ditch report upon an allocation failure (see the `oom-report`
+//! feature): the usage, the peak, the memory limit, the failing layout and
+//! the busiest size classes, written to stderr before the process aborts.
+//!
+//! The report is formatted into a stack buffer and written with a single
+//! call: it never allocates. `PeakAlloc::install_oom_reporter` moreover sets
+//! an emergency reserve aside, which is released right before the report is
+//! written, so that the rest of the process (e.g. the other threads, or the
+//! default handler which follows) finds some memory to finish with.
+//!
+//! # Limitation
+//! On stable Rust, there is no way to run code when an infallible allocation
+//! (e.g. `Vec::push`) fails: the process prints a terse message and aborts.
+//! The report is hence only written by the allocations made through the
+//! helpers of this module (`alloc_or_report`, `reserve_or_report`), or by
+//! `report_out_of_memory` if the program calls it from its own handler.
+//! Built with `--cfg peak_alloc_nightly` on a nightly toolchain, the reporter
+//! is installed as the alloc error hook (`std::alloc::set_alloc_error_hook`)
+//! and covers all the allocations.
+
+use core::alloc::Layout;
+use core::fmt;
+use core::ptr::NonNull;
+use std::io::Write as _;
+use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+
+use crate::fmt::StackString;
+use crate::PeakAlloc;
+
+/// The size of the emergency reserve set aside by `install_oom_reporter`
+pub const EMERGENCY_RESERVE: usize = 64 * 1024;
+
+/// The layout of the emergency reserve
+const RESERVE_LAYOUT: Layout = match Layout::from_size_align(EMERGENCY_RESERVE, 16) {
+    Ok(layout) => layout,
+    Err(_) => panic!("invalid emergency reserve layout"),
+};
+
+/// The emergency reserve (null when it is not set aside)
+static RESERVE: AtomicPtr<u8> = AtomicPtr::new(core::ptr::null_mut());
+/// Whether the report has been written already: it is only written once
+static REPORTED: AtomicBool = AtomicBool::new(false);
+
+/// Sets the emergency reserve aside (and installs the alloc error hook when
+/// it is available)
+pub(crate) fn install() {
+    if RESERVE.load(Ordering::Acquire).is_null() {
+        // SAFETY: the layout is not zero-sized
+        let reserve = unsafe { std::alloc::alloc(RESERVE_LAYOUT) };
+        let installed =
+            RESERVE.compare_exchange(core::ptr::null_mut(), reserve, Ordering::AcqRel, Ordering::Acquire);
+        if installed.is_err() && !reserve.is_null() {
+            // SAFETY: the reserve was just allocated with this layout
+            unsafe { std::alloc::dealloc(reserve, RESERVE_LAYOUT) };
+        }
+    }
+    #[cfg(peak_alloc_nightly)]
+    std::alloc::set_alloc_error_hook(report_out_of_memory);
+}
+
+/// Writes the out of memory report (for the allocation of the given layout)
+/// to stderr, after releasing the emergency reserve. Only the first call
+/// writes the report: a process is only out of memory once.
+///
+/// This is what the helpers of this module call before aborting. It is
+/// public for the programs which handle the allocation failures by
+/// themselves.
+pub fn report_out_of_memory(layout: Layout) {
+    if REPORTED.swap(true, Ordering::AcqRel) {
+        return;
+    }
+    let reserve = RESERVE.swap(core::ptr::null_mut(), Ordering::AcqRel);
+    if !reserve.is_null() {
+        // SAFETY: the reserve was allocated with this layout
+        unsafe { std::alloc::dealloc(reserve, RESERVE_LAYOUT) };
+    }
+    let mut buffer = StackString::<1024>::new();
+    let _ = write_report(&mut buffer, layout);
+    let _ = std::io::stderr().write_all(buffer.as_bytes());
+}
+
+/// Allocates a block (like `std::alloc::alloc`), writing the out of memory
+/// report then aborting through `std::alloc::handle_alloc_error` upon
+/// failure.
+///
+/// # Safety
+/// The layout must not be zero-sized (as for `std::alloc::alloc`).
+pub unsafe fn alloc_or_report(layout: Layout) -> NonNull<u8> {
+    match NonNull::new(std::alloc::alloc(layout)) {
+        Some(ptr) => ptr,
+        None => {
+            report_out_of_memory(layout);
+            std::alloc::handle_alloc_error(layout)
+        }
+    }
+}
+
+/// Reserves room for at least `additional` more elements in `vec` (like
+/// `Vec::reserve`), writing the out of memory report then aborting through
+/// `std::alloc::handle_alloc_error` upon failure.
+pub fn reserve_or_report<T>(vec: &mut Vec<T>, additional: usize) {
+    if vec.try_reserve(additional).is_err() {
+        let layout = vec
+            .len()
+            .checked_add(additional)
+            .and_then(|len| Layout::array::<T>(len).ok())
+            .unwrap_or_else(Layout::new::<T>);
+        report_out_of_memory(layout);
+        std::alloc::handle_alloc_error(layout)
+    }
+}
+
+/// Writes the out of memory report to `out`
+fn write_report(out: &mut impl fmt::Write, layout: Layout) -> fmt::Result {
+    let alloc = PeakAlloc;
+    writeln!(
+        out,
+        "peak_alloc: out of memory, failed to allocate {} bytes (align {})",
+        layout.size(),
+        layout.align()
+    )?;
+    writeln!(out, "  current usage      : {} B", alloc.current_usage())?;
+    writeln!(out, "  peak usage         : {} B", alloc.peak_usage())?;
+    match alloc.memory_limit() {
+        Some(limit) => writeln!(out, "  memory limit       : {} B", limit)?,
+        None => writeln!(out, "  memory limit       : none")?,
+    }
+    writeln!(out, "  failed allocations : {}", alloc.failed_allocation_count())?;
+    #[cfg(feature = "histogram")]
+    {
+        let histogram = alloc.size_histogram();
+        write!(out, "  top size classes   :")?;
+        for (i, (bound, count)) in top_classes(&histogram.counts).iter().flatten().enumerate() {
+            let separator = if i == 0 { "" } else { "," };
+            write!(out, "{} <= {} B: {}", separator, bound, count)?;
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+/// The number of size classes listed in the report
+#[cfg(feature = "histogram")]
+const TOP_CLASSES: usize = 3;
+
+/// Returns the (upper bound, count) of the busiest size classes, the busiest
+/// first
+#[cfg(feature = "histogram")]
+fn top_classes(counts: &[usize]) -> [Option<(usize, usize)>; TOP_CLASSES] {
+    let mut top = [None; TOP_CLASSES];
+    for (class, &count) in counts.iter().enumerate().filter(|(_, count)| **count > 0) {
+        let entry = (crate::SizeHistogram::class_upper_bound(class), count);
+        if let Some(pos) = top.iter().position(|e| e.is_none_or(|(_, other)| count > other)) {
+            top[pos..].rotate_right(1);
+            top[pos] = Some(entry);
+        }
+    }
+    top
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fmt::StackString;
+    use core::fmt::Write;
+
+    #[test]
+    fn buffer_truncates_what_overflows() {
+        let mut buffer = StackString::<1024>::new();
+        write!(buffer, "{}", "x".repeat(1000)).unwrap();
+        write!(buffer, "{}", "y".repeat(100)).unwrap();
+        assert_eq!(1024, buffer.as_bytes().len());
+        assert_eq!(b'y', buffer.as_bytes()[1023]);
+    }
+
+    #[cfg(feature = "histogram")]
+    #[test]
+    fn top_classes_are_the_busiest_first() {
+        let mut counts = [0; 8];
+        counts[2] = 5;
+        counts[3] = 1;
+        counts[5] = 9;
+        counts[7] = 3;
+        assert_eq!([Some((32, 9)), Some((4, 5)), Some((128, 3))], super::top_classes(&counts));
+        assert_eq!([Some((2, 1)), None, None], super::top_classes(&[0, 1]));
+    }
+}
@@ -0,0 +1,126 @@
+//! Counting the over-aligned allocations: those whose alignment is above a
+//! configurable threshold (16 by default). A block aligned on 64 bytes or
+//! more costs the system allocator far more than its size when it is small,
+//! and a dependency which over-aligns everything goes unnoticed otherwise.
+//!
+//! The allocation path only compares the alignment with the threshold. The
+//! over-aligned allocations are then counted and (with `std`) recorded in a
+//! small ring of the most recent ones, along with the allocating thread and
+//! the tag they are attributed to (with `module-tags`).
+
+use core::alloc::Layout;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// The number of over-aligned allocations remembered by `recent_overaligned`
+pub const RECENT_OVERALIGNED: usize = 16;
+
+/// The alignment above which an allocation is over-aligned
+static THRESHOLD: AtomicUsize = AtomicUsize::new(16);
+/// The number of over-aligned allocations
+static COUNT: AtomicUsize = AtomicUsize::new(0);
+/// The number of bytes requested by the over-aligned allocations
+static BYTES: AtomicUsize = AtomicUsize::new(0);
+
+/// The most recent over-aligned allocations, and the number of them ever
+/// recorded (the next one goes to `total % RECENT_OVERALIGNED`)
+#[cfg(feature = "std")]
+static RECENT: crate::sync::SpinLock<([Entry; RECENT_OVERALIGNED], usize)> =
+    crate::sync::SpinLock::new(([Entry { size: 0, align: 0, thread: 0, tag: 0 }; RECENT_OVERALIGNED], 0));
+
+/// A recorded allocation
+#[cfg(feature = "std")]
+#[derive(Clone, Copy)]
+struct Entry {
+    size: usize,
+    align: usize,
+    thread: u32,
+    /// 1 + the slot of the tag (0 if none), see `tags::current_slot`
+    #[cfg_attr(not(feature = "module-tags"), allow(dead_code))]
+    tag: usize,
+}
+
+/// An over-aligned allocation (see `PeakAlloc::recent_overaligned`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OveralignedRecord {
+    /// The size of the block, in bytes
+    pub size: usize,
+    /// The alignment of the block, in bytes
+    pub align: usize,
+    /// The id of the allocating thread (see `PeakAlloc::current_thread_id`)
+    pub thread: u32,
+    /// The tag which was entered by the allocating thread (always `None`
+    /// without the `module-tags` feature)
+    pub tag: Option<&'static str>,
+}
+
+/// Sets the alignment above which an allocation is over-aligned
+pub(crate) fn set_threshold(align: usize) {
+    THRESHOLD.store(align, Ordering::Relaxed);
+}
+
+/// Returns the alignment above which an allocation is over-aligned
+pub(crate) fn threshold() -> usize {
+    THRESHOLD.load(Ordering::Relaxed)
+}
+
+/// Accounts for an allocation, if it is over-aligned
+#[inline]
+pub(crate) fn on_alloc(layout: Layout) {
+    if layout.align() > THRESHOLD.load(Ordering::Relaxed) {
+        record(layout);
+    }
+}
+
+#[cold]
+fn record(layout: Layout) {
+    COUNT.fetch_add(1, Ordering::Relaxed);
+    BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+    #[cfg(feature = "std")]
+    {
+        #[cfg(feature = "module-tags")]
+        let tag = crate::tags::current_slot();
+        #[cfg(not(feature = "module-tags"))]
+        let tag = 0;
+        let (size, align, thread) = (layout.size(), layout.align(), crate::threads::fast_thread_id());
+        let mut recent = RECENT.lock();
+        let (entries, total) = &mut *recent;
+        entries[*total % RECENT_OVERALIGNED] = Entry { size, align, thread, tag };
+        *total += 1;
+    }
+}
+
+/// Returns the number of over-aligned allocations
+pub(crate) fn count() -> usize {
+    COUNT.load(Ordering::Relaxed)
+}
+
+/// Returns the number of bytes requested by the over-aligned allocations
+pub(crate) fn bytes() -> usize {
+    BYTES.load(Ordering::Relaxed)
+}
+
+/// Sets the number of over-aligned allocations, and of bytes they requested
+pub(crate) fn set_counters(count: usize, bytes: usize) {
+    COUNT.store(count, Ordering::Relaxed);
+    BYTES.store(bytes, Ordering::Relaxed);
+}
+
+/// Returns the most recent over-aligned allocations, the oldest first
+#[cfg(feature = "std")]
+pub(crate) fn recent() -> Vec<OveralignedRecord> {
+    // copied first: collecting allocates, possibly an over-aligned block
+    let (entries, total) = *RECENT.lock();
+    let kept = total.min(RECENT_OVERALIGNED);
+    (total - kept..total)
+        .map(|i| entries[i % RECENT_OVERALIGNED])
+        .map(|entry| OveralignedRecord {
+            size: entry.size,
+            align: entry.align,
+            thread: entry.thread,
+            #[cfg(feature = "module-tags")]
+            tag: crate::tags::name_of(entry.tag),
+            #[cfg(not(feature = "module-tags"))]
+            tag: None,
+        })
+        .collect()
+}
@@ -0,0 +1,265 @@
+//! The breakdown of the peak per thread: which threads held the memory when
+//! the peak was reached (see `PeakAlloc::peak_thread_breakdown`).
+//!
+//! Each thread owns a slot of a fixed table (claimed upon its first tracked
+//! allocation and released when it exits) where it maintains its net number
+//! of bytes: what it allocated minus what it released. Whenever the peak
+//! rises by more than a configurable step, the allocating thread copies the
+//! `PEAK_BREAKDOWN_THREADS` largest nets into a preallocated snapshot.
+//!
+//! This is an approximation, in several ways:
+//! * the snapshot is taken when the peak rises by at least the step, not at
+//!   the exact instant of the peak: the last rise below the step is missed;
+//! * the nets are read one by one while the other threads keep allocating;
+//! * a thread releasing what another one allocated sees its net decrease
+//!   (possibly below zero) whereas the other thread's does not;
+//! * the threads beyond the `SLOTS` first ones alive at once are not
+//!   accounted for, and a snapshot is skipped when another thread is taking
+//!   one already.
+
+use std::cell::Cell;
+use std::sync::atomic::{AtomicIsize, AtomicU32, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread::ThreadId;
+
+use crate::threads;
+
+/// The number of threads whose net bytes can be maintained at once
+const SLOTS: usize = 64;
+/// The number of threads in a peak breakdown
+pub const PEAK_BREAKDOWN_THREADS: usize = 8;
+
+/// The per thread counters, readable by all the threads
+struct Slot {
+    /// The fast id (see `threads::fast_thread_id`) of the owner thread (0 if
+    /// free)
+    owner: AtomicU32,
+    /// The net number of bytes allocated by its owner
+    net: AtomicIsize,
+    /// The highest net of its owner
+    peak: AtomicUsize,
+    /// The number of allocations performed by its owner
+    allocations: AtomicUsize,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const FREE: Slot = Slot {
+    owner: AtomicU32::new(0),
+    net: AtomicIsize::new(0),
+    peak: AtomicUsize::new(0),
+    allocations: AtomicUsize::new(0),
+};
+
+static TABLE: [Slot; SLOTS] = [FREE; SLOTS];
+
+/// The part which cannot be maintained with atomics: the `ThreadId` and the
+/// name of the owner of each slot, and the last breakdown. It is only locked
+/// when a thread claims or releases a slot, and to take or read a snapshot.
+struct Registry {
+    ids: [Option<ThreadId>; SLOTS],
+    names: [Option<String>; SLOTS],
+    breakdown: [Option<(ThreadId, isize)>; PEAK_BREAKDOWN_THREADS],
+}
+
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry {
+    ids: [None; SLOTS],
+    names: [const { None }; SLOTS],
+    breakdown: [None; PEAK_BREAKDOWN_THREADS],
+});
+
+/// The minimum rise of the peak between two snapshots
+static STEP: AtomicUsize = AtomicUsize::new(1024 * 1024);
+/// The (raw) usage upon the last snapshot
+static CAPTURED: AtomicUsize = AtomicUsize::new(0);
+
+/// No slot has been claimed (yet)
+const UNCLAIMED: usize = 0;
+/// No slot could be claimed: the table is full
+const NO_SLOT: usize = usize::MAX;
+
+/// The slot of a thread, released when the thread exits
+struct Owned {
+    /// 1 + the index of the slot, or UNCLAIMED, or NO_SLOT
+    slot: Cell<usize>,
+    /// Set while the thread claims its slot, which may allocate
+    claiming: Cell<bool>,
+}
+
+impl Drop for Owned {
+    fn drop(&mut self) {
+        let slot = self.slot.get();
+        if slot != UNCLAIMED && slot != NO_SLOT {
+            let mut registry = REGISTRY.lock().unwrap_or_else(|e| e.into_inner());
+            registry.ids[slot - 1] = None;
+            let name = registry.names[slot - 1].take();
+            drop(registry);
+            // released once the registry is unlocked (see `claim`)
+            let _internal = threads::internal();
+            drop(name);
+            TABLE[slot - 1].net.store(0, Ordering::Relaxed);
+            TABLE[slot - 1].peak.store(0, Ordering::Relaxed);
+            TABLE[slot - 1].allocations.store(0, Ordering::Relaxed);
+            TABLE[slot - 1].owner.store(0, Ordering::Release);
+        }
+    }
+}
+
+thread_local! {
+    static OWNED: Owned = const { Owned { slot: Cell::new(UNCLAIMED), claiming: Cell::new(false) } };
+}
+
+/// Returns the slot of the calling thread (claiming one if needed)
+#[inline]
+fn slot() -> Option<&'static Slot> {
+    OWNED
+        .try_with(|owned| match owned.slot.get() {
+            NO_SLOT => None,
+            UNCLAIMED => claim(owned),
+            slot => Some(&TABLE[slot - 1]),
+        })
+        .ok()
+        .flatten()
+}
+
+/// Claims a slot for the calling thread. Getting its `ThreadId` may
+/// allocate: these allocations are not attributed to the thread. Its name is
+/// an internal allocation, released along with the slot.
+#[cold]
+fn claim(owned: &Owned) -> Option<&'static Slot> {
+    if owned.claiming.replace(true) {
+        return None;
+    }
+    let thread = std::thread::current();
+    let me = threads::fast_thread_id();
+    let claimed = TABLE.iter().position(|slot| {
+        slot.owner.compare_exchange(0, me, Ordering::Acquire, Ordering::Relaxed).is_ok()
+    });
+    match claimed {
+        Some(index) => {
+            let _internal = threads::internal();
+            let name = thread.name().map(String::from);
+            let mut registry = REGISTRY.lock().unwrap_or_else(|e| e.into_inner());
+            registry.ids[index] = Some(thread.id());
+            registry.names[index] = name;
+            owned.slot.set(index + 1);
+        }
+        None => owned.slot.set(NO_SLOT),
+    }
+    owned.claiming.set(false);
+    claimed.map(|index| &TABLE[index])
+}
+
+/// Accounts for `delta` more bytes being used by the calling thread, and
+/// takes a snapshot if the peak has risen by more than the step
+#[inline]
+pub(crate) fn on_grow(delta: usize, usage: usize) {
+    if let Some(slot) = slot() {
+        let net = slot.net.fetch_add(delta as isize, Ordering::Relaxed).wrapping_add(delta as isize);
+        slot.peak.fetch_max(net.max(0) as usize, Ordering::Relaxed);
+    }
+    let captured = CAPTURED.load(Ordering::Relaxed);
+    if usage >= captured.saturating_add(STEP.load(Ordering::Relaxed))
+        && usage >= crate::TRACKER.peak_usage()
+        && CAPTURED.compare_exchange(captured, usage, Ordering::Relaxed, Ordering::Relaxed).is_ok()
+    {
+        snapshot();
+    }
+}
+
+/// Counts an allocation for the calling thread
+#[inline]
+pub(crate) fn on_alloc() {
+    if let Some(slot) = slot() {
+        slot.allocations.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Accounts for `delta` less bytes being used by the calling thread
+#[inline]
+pub(crate) fn on_shrink(delta: usize) {
+    if let Some(slot) = slot() {
+        slot.net.fetch_sub(delta as isize, Ordering::Relaxed);
+    }
+}
+
+/// Copies the largest nets into the breakdown (unless a snapshot is already
+/// being taken or read)
+#[cold]
+fn snapshot() {
+    let mut registry = match REGISTRY.try_lock() {
+        Ok(registry) => registry,
+        Err(_) => return,
+    };
+    let registry = &mut *registry;
+    registry.breakdown = [None; PEAK_BREAKDOWN_THREADS];
+    for (index, slot) in TABLE.iter().enumerate() {
+        let id = match registry.ids[index] {
+            Some(id) => id,
+            None => continue,
+        };
+        let net = slot.net.load(Ordering::Relaxed);
+        // insertion into the (sorted) top threads
+        let top = &mut registry.breakdown;
+        if let Some(pos) = top.iter().position(|entry| entry.is_none_or(|(_, other)| net > other)) {
+            top[pos..].rotate_right(1);
+            top[pos] = Some((id, net));
+        }
+    }
+}
+
+/// Returns the last breakdown, the largest nets first
+pub(crate) fn breakdown() -> Vec<(ThreadId, isize)> {
+    // collecting allocates: this may claim a slot, which locks the registry
+    let breakdown = REGISTRY.lock().unwrap_or_else(|e| e.into_inner()).breakdown;
+    breakdown.iter().flatten().copied().collect()
+}
+
+/// The counters of a live thread (see `usage`)
+pub(crate) struct ThreadUsage {
+    pub(crate) id: ThreadId,
+    pub(crate) net: isize,
+    pub(crate) peak: usize,
+    pub(crate) allocations: usize,
+}
+
+/// Returns the counters of all the live threads which own a slot
+pub(crate) fn usage() -> Vec<ThreadUsage> {
+    // copied first: collecting allocates, which may claim a slot
+    let ids = REGISTRY.lock().unwrap_or_else(|e| e.into_inner()).ids;
+    ids.iter()
+        .zip(TABLE.iter())
+        .filter_map(|(id, slot)| {
+            id.map(|id| ThreadUsage {
+                id,
+                net: slot.net.load(Ordering::Relaxed),
+                peak: slot.peak.load(Ordering::Relaxed),
+                allocations: slot.allocations.load(Ordering::Relaxed),
+            })
+        })
+        .collect()
+}
+
+/// Returns the name of the live thread whose fast id is `fast_id`, if it owns
+/// a slot and has a name
+pub(crate) fn thread_name(fast_id: u32) -> Option<String> {
+    if fast_id == 0 {
+        return None;
+    }
+    // claimed first: cloning allocates, which would claim a slot (and lock
+    // the registry) otherwise
+    let _ = slot();
+    let registry = REGISTRY.lock().unwrap_or_else(|e| e.into_inner());
+    let index = TABLE.iter().position(|slot| slot.owner.load(Ordering::Acquire) == fast_id)?;
+    registry.names[index].clone()
+}
+
+/// Sets the minimum rise of the peak between two snapshots
+pub(crate) fn set_step(bytes: usize) {
+    STEP.store(bytes, Ordering::Relaxed);
+}
+
+/// Lets the next rise of the peak by more than the step be snapshot, when the
+/// peak is reset
+pub(crate) fn reset() {
+    CAPTURED.store(crate::TRACKER.current_usage(), Ordering::Relaxed);
+}
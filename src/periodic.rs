@@ -0,0 +1,96 @@
+//! The plumbing shared by all the background threads of this crate (the
+//! watchdog, the history sampler, ...): a thread which runs a task
+//! periodically until it is asked to stop.
+//!
+//! The task runs as internal to the crate (see `threads::internal`): what it
+//! allocates is not accounted for, and neither are the callbacks of the
+//! application it calls, unless they run within `threads::accounted`. A
+//! buffer the task keeps from one run to the next, and grows, is a `Scratch`:
+//! it is allocated and released as internal as well. What the task captures
+//! is allocated by the caller, and released (along with the task) as such.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::threads;
+
+/// A background thread running a task periodically. The thread is stopped
+/// (and joined) when this value is dropped.
+pub(crate) struct Periodic {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Periodic {
+    /// Spawns a thread with the given name which calls `task` once every
+    /// `interval` until it is stopped.
+    pub(crate) fn spawn<F>(name: &str, interval: Duration, mut task: F) -> Self
+    where
+        F: FnMut() + Send + 'static,
+    {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = Arc::clone(&stop);
+        let thread = std::thread::Builder::new()
+            .name(name.to_string())
+            .spawn(move || {
+                while !stopped.load(Ordering::Relaxed) {
+                    let internal = threads::internal();
+                    task();
+                    drop(internal);
+                    std::thread::park_timeout(interval);
+                }
+            })
+            .unwrap_or_else(|e| panic!("failed to spawn the {} thread: {}", name, e));
+        Periodic {
+            stop,
+            thread: Some(thread),
+        }
+    }
+    /// Returns true iff the thread has not been stopped yet
+    pub(crate) fn is_running(&self) -> bool {
+        self.thread.is_some()
+    }
+    /// Stops the thread and waits for it to terminate. This returns promptly
+    /// as the thread is woken up if it is waiting for its next period.
+    pub(crate) fn stop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Periodic {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// A value which the task of a `Periodic` creates upon its first run and
+/// keeps afterwards (e.g. a buffer rewritten in place), as an internal
+/// allocation: it is released as such, wherever it is dropped.
+#[cfg(any(test, all(feature = "socket", unix), feature = "statsd", feature = "influx-http"))]
+pub(crate) struct Scratch<T>(Option<T>);
+
+#[cfg(any(test, all(feature = "socket", unix), feature = "statsd", feature = "influx-http"))]
+impl<T> Scratch<T> {
+    /// Creates an empty scratch value
+    pub(crate) const fn new() -> Self {
+        Scratch(None)
+    }
+    /// Returns the value, created by `init` if need be
+    pub(crate) fn get_or_init(&mut self, init: impl FnOnce() -> T) -> &mut T {
+        self.0.get_or_insert_with(init)
+    }
+}
+
+#[cfg(any(test, all(feature = "socket", unix), feature = "statsd", feature = "influx-http"))]
+impl<T> Drop for Scratch<T> {
+    fn drop(&mut self) {
+        let _internal = threads::internal();
+        self.0 = None;
+    }
+}
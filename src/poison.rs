@@ -0,0 +1,44 @@
+//! Memory poisoning is a debugging aid which fills every freshly allocated
+//! (non-zeroed) block with a recognizable pattern, and every freed block with
+//! another one before it is handed back to the system. This way, programs
+//! which only work "by accident" because the system happened to return
+//! zeroed pages, or which read through dangling pointers, fail loudly and
+//! reproducibly.
+//!
+//! # Performance
+//! Poisoning writes every allocated byte once upon allocation and once upon
+//! deallocation. This roughly doubles the cost of allocating a block which
+//! is not fully initialized anyway and is bound to be noticeable for large
+//! blocks. Blocks obtained through `alloc_zeroed` are never poisoned.
+
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// The default pattern written in freshly allocated blocks
+pub(crate) const DEFAULT_ALLOC_BYTE: u8 = 0xAA;
+/// The default pattern written in freed blocks
+pub(crate) const DEFAULT_FREE_BYTE: u8 = 0xDD;
+
+static ALLOC_BYTE: AtomicU8 = AtomicU8::new(DEFAULT_ALLOC_BYTE);
+static FREE_BYTE: AtomicU8 = AtomicU8::new(DEFAULT_FREE_BYTE);
+
+/// Changes the patterns used to poison the allocated and freed blocks
+pub(crate) fn set_patterns(alloc_byte: u8, free_byte: u8) {
+    ALLOC_BYTE.store(alloc_byte, Ordering::Relaxed);
+    FREE_BYTE.store(free_byte, Ordering::Relaxed);
+}
+
+/// Poisons a freshly allocated block of `size` bytes
+#[inline]
+pub(crate) unsafe fn on_alloc(ptr: *mut u8, size: usize) {
+    if size > 0 {
+        ptr.write_bytes(ALLOC_BYTE.load(Ordering::Relaxed), size);
+    }
+}
+
+/// Poisons a block of `size` bytes which is about to be freed
+#[inline]
+pub(crate) unsafe fn on_free(ptr: *mut u8, size: usize) {
+    if size > 0 {
+        ptr.write_bytes(FREE_BYTE.load(Ordering::Relaxed), size);
+    }
+}
@@ -0,0 +1,238 @@
+//! Watching the memory pressure of the system through the Linux pressure
+//! stall information (PSI, `/proc/pressure/memory`). Combined with the usage
+//! tracked by `PeakAlloc`, this lets an application shed its caches when
+//! the system struggles (see `PeakAlloc::watch_memory_pressure`).
+//!
+//! The PSI file is polled once per window: the watcher compares the stall
+//! time accumulated during the window with the configured threshold, just
+//! like the kernel trigger interface would. It reads the file into a stack
+//! buffer, so that it does not allocate (only the callback does, if it
+//! wants to).
+
+use std::fs::File;
+use std::io::Read;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::periodic::Periodic;
+use crate::{threads, PeakAlloc};
+
+/// The PSI averages of one line (`some` or `full`) of the pressure file
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct PsiAverages {
+    /// The share of the time (in percent) stalled over the last 10 seconds
+    pub avg10: f32,
+    /// The share of the time (in percent) stalled over the last 60 seconds
+    pub avg60: f32,
+    /// The share of the time (in percent) stalled over the last 300 seconds
+    pub avg300: f32,
+    /// The total stall time (in microseconds) since boot
+    pub total: u64,
+}
+
+/// The content of a pressure file
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Pressure {
+    /// Some tasks were stalled waiting for memory
+    pub some: PsiAverages,
+    /// All the (non idle) tasks were stalled waiting for memory at once
+    pub full: PsiAverages,
+}
+
+/// Which line of the pressure file the stall threshold applies to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PressureKind {
+    /// At least one task was stalled
+    Some,
+    /// All the tasks were stalled at once
+    Full,
+}
+
+/// What the callback of `PeakAlloc::watch_memory_pressure` is told
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PressureEvent {
+    /// The averages read from the pressure file
+    pub pressure: Pressure,
+    /// The stall time accumulated during the last window
+    pub stalled: Duration,
+    /// The memory usage tracked by `PeakAlloc` when the event was raised
+    pub tracked_usage: usize,
+}
+
+/// The configuration of a memory pressure watcher
+#[derive(Debug, Clone)]
+pub struct PressureConfig {
+    /// The pressure file (`/proc/pressure/memory` by default)
+    pub path: PathBuf,
+    /// The line the threshold applies to (`Some` by default)
+    pub kind: PressureKind,
+    /// The stall time within a window above which the callback is called
+    /// (150ms by default)
+    pub stall: Duration,
+    /// The window over which the stall time is measured, which is also the
+    /// polling interval (1s by default)
+    pub window: Duration,
+}
+
+impl Default for PressureConfig {
+    fn default() -> Self {
+        PressureConfig {
+            path: PathBuf::from("/proc/pressure/memory"),
+            kind: PressureKind::Some,
+            stall: Duration::from_millis(150),
+            window: Duration::from_secs(1),
+        }
+    }
+}
+
+impl PressureConfig {
+    /// Sets the pressure file (this is mostly useful for tests, or to watch
+    /// a cgroup's `memory.pressure`)
+    pub fn path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.path = path.into();
+        self
+    }
+    /// Sets the line the threshold applies to
+    pub fn kind(mut self, kind: PressureKind) -> Self {
+        self.kind = kind;
+        self
+    }
+    /// Sets the stall time within a window above which the callback is called
+    pub fn stall(mut self, stall: Duration) -> Self {
+        self.stall = stall;
+        self
+    }
+    /// Sets the window over which the stall time is measured
+    pub fn window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+}
+
+/// The handle to a memory pressure watcher. The watcher thread is stopped
+/// when the handle is dropped, or explicitly with `stop`.
+pub struct PressureHandle {
+    watcher: Periodic,
+}
+
+impl PressureHandle {
+    /// Stops the watcher thread (and waits for it). This is idempotent.
+    pub fn stop(&mut self) {
+        self.watcher.stop();
+    }
+    /// Returns true iff the watcher has not been stopped yet
+    pub fn is_running(&self) -> bool {
+        self.watcher.is_running()
+    }
+}
+
+/// Spawns the pressure watcher thread
+pub(crate) fn start<F>(alloc: PeakAlloc, config: PressureConfig, mut callback: F) -> PressureHandle
+where
+    F: FnMut(PressureEvent) + Send + 'static,
+{
+    let threshold = config.stall.as_micros() as u64;
+    let mut last_total: Option<u64> = None;
+    let watcher = Periodic::spawn("peak_alloc-psi", config.window, move || {
+        let pressure = match read(&config.path) {
+            Some(pressure) => pressure,
+            None => return,
+        };
+        let total = match config.kind {
+            PressureKind::Some => pressure.some.total,
+            PressureKind::Full => pressure.full.total,
+        };
+        let stalled = last_total.map_or(0, |last| total.saturating_sub(last));
+        let first = last_total.is_none();
+        last_total = Some(total);
+        if !first && stalled >= threshold {
+            let event = PressureEvent {
+                pressure,
+                stalled: Duration::from_micros(stalled),
+                tracked_usage: alloc.current_usage(),
+            };
+            threads::accounted(|| callback(event));
+        }
+    });
+    PressureHandle { watcher }
+}
+
+/// Reads and parses the given pressure file
+fn read(path: &std::path::Path) -> Option<Pressure> {
+    // the pressure files are tiny: a stack buffer spares us an allocation
+    let mut buffer = [0_u8; 256];
+    let mut file = File::open(path).ok()?;
+    let len = file.read(&mut buffer).ok()?;
+    parse(&buffer[..len])
+}
+
+/// Parses the content of a pressure file. The `full` line is optional (it
+/// is absent from `/proc/pressure/cpu` on older kernels), but `some` is not.
+fn parse(content: &[u8]) -> Option<Pressure> {
+    let content = std::str::from_utf8(content).ok()?;
+    let mut pressure = Pressure::default();
+    let mut seen_some = false;
+    for line in content.lines() {
+        let mut fields = line.split_ascii_whitespace();
+        let averages = match fields.next() {
+            Some("some") => {
+                seen_some = true;
+                &mut pressure.some
+            }
+            Some("full") => &mut pressure.full,
+            _ => continue,
+        };
+        *averages = parse_averages(fields)?;
+    }
+    if seen_some {
+        Some(pressure)
+    } else {
+        None
+    }
+}
+
+/// Parses the `key=value` fields of a line of a pressure file
+fn parse_averages<'a>(fields: impl Iterator<Item = &'a str>) -> Option<PsiAverages> {
+    let mut averages = PsiAverages::default();
+    for field in fields {
+        let (key, value) = field.split_once('=')?;
+        match key {
+            "avg10" => averages.avg10 = value.parse().ok()?,
+            "avg60" => averages.avg60 = value.parse().ok()?,
+            "avg300" => averages.avg300 = value.parse().ok()?,
+            "total" => averages.total = value.parse().ok()?,
+            _ => {}
+        }
+    }
+    Some(averages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, PsiAverages};
+
+    #[test]
+    fn pressure_file_is_parsed() {
+        let content = b"some avg10=1.25 avg60=0.50 avg300=0.00 total=123456\n\
+                        full avg10=0.10 avg60=0.05 avg300=0.01 total=789\n";
+        let pressure = parse(content).unwrap();
+        assert_eq!(PsiAverages { avg10: 1.25, avg60: 0.5, avg300: 0.0, total: 123456 }, pressure.some);
+        assert_eq!(PsiAverages { avg10: 0.1, avg60: 0.05, avg300: 0.01, total: 789 }, pressure.full);
+    }
+
+    #[test]
+    fn full_line_is_optional() {
+        let pressure = parse(b"some avg10=0.00 avg60=0.00 avg300=0.00 total=42\n").unwrap();
+        assert_eq!(42, pressure.some.total);
+        assert_eq!(PsiAverages::default(), pressure.full);
+    }
+
+    #[test]
+    fn malformed_pressure_files_are_rejected() {
+        assert_eq!(None, parse(b""));
+        assert_eq!(None, parse(b"full avg10=0.00 avg60=0.00 avg300=0.00 total=1\n"));
+        assert_eq!(None, parse(b"some avg10=abc avg60=0.00 avg300=0.00 total=1\n"));
+        assert_eq!(None, parse(b"some avg10 avg60=0.00\n"));
+        assert_eq!(None, parse(&[0xff, 0xfe]));
+    }
+}
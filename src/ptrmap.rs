@@ -0,0 +1,131 @@
+//! The pointer map remembers the size and allocation time of every live block. It is the foundation of the features which need to know about the
+//! individual allocations rather than the mere totals.
+//!
+//! The map is a fixed capacity hash table (linear probing) split in a number
+//! of independently locked shards. It lives in a static and never allocates,
+//! so it can safely be used from within the allocator. Allocations which
+//! cannot be recorded because their shard is full are simply not tracked
+//! (see `PeakAlloc::pointer_map_overflows`).
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::sync::SpinLock;
+
+/// The number of shards (must be a power of two)
+const SHARDS: usize = 16;
+/// The number of slots in each shard (must be a power of two)
+const SLOTS: usize = 8192;
+
+/// The information remembered about one live block
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Entry {
+    /// The address of the block (0 denotes an empty slot)
+    pub(crate) ptr: usize,
+    /// The (requested) size of the block
+    pub(crate) size: usize,
+    /// The (coarse clock) time when the block was allocated
+    pub(crate) born: u64,
+}
+
+impl Entry {
+    const EMPTY: Entry = Entry { ptr: 0, size: 0, born: 0 };
+}
+
+struct Shard {
+    slots: [Entry; SLOTS],
+}
+
+impl Shard {
+    const fn new() -> Self {
+        Shard { slots: [Entry::EMPTY; SLOTS] }
+    }
+    fn insert(&mut self, entry: Entry, hash: usize) -> bool {
+        let mut i = hash & (SLOTS - 1);
+        for _ in 0..SLOTS {
+            let slot = &mut self.slots[i];
+            if slot.ptr == 0 || slot.ptr == entry.ptr {
+                *slot = entry;
+                return true;
+            }
+            i = (i + 1) & (SLOTS - 1);
+        }
+        false
+    }
+    fn remove(&mut self, ptr: usize, hash: usize) -> Option<Entry> {
+        let mut i = hash & (SLOTS - 1);
+        for _ in 0..SLOTS {
+            let slot = self.slots[i];
+            if slot.ptr == 0 {
+                return None;
+            }
+            if slot.ptr == ptr {
+                self.slots[i] = Entry::EMPTY;
+                self.backward_shift(i);
+                return Some(slot);
+            }
+            i = (i + 1) & (SLOTS - 1);
+        }
+        None
+    }
+    /// Closes the gap left at `hole` by moving the entries which follow it
+    /// (so that no lookup ever stops too early on an empty slot).
+    fn backward_shift(&mut self, mut hole: usize) {
+        let mut i = (hole + 1) & (SLOTS - 1);
+        while self.slots[i].ptr != 0 {
+            let home = hash(self.slots[i].ptr) & (SLOTS - 1);
+            // distance from home to i vs from home to hole (modulo SLOTS)
+            let dist_i = i.wrapping_sub(home) & (SLOTS - 1);
+            let dist_hole = hole.wrapping_sub(home) & (SLOTS - 1);
+            if dist_hole < dist_i {
+                self.slots[hole] = self.slots[i];
+                self.slots[i] = Entry::EMPTY;
+                hole = i;
+            }
+            i = (i + 1) & (SLOTS - 1);
+        }
+    }
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const SHARD: SpinLock<Shard> = SpinLock::new(Shard::new());
+static MAP: [SpinLock<Shard>; SHARDS] = [SHARD; SHARDS];
+/// The number of allocations that could not be recorded in the map
+static OVERFLOWS: AtomicUsize = AtomicUsize::new(0);
+
+/// Scrambles the address of a block (fibonacci hashing)
+fn hash(ptr: usize) -> usize {
+    (ptr >> 4).wrapping_mul(0x9E37_79B9_7F4A_7C15_u64 as usize)
+}
+fn shard(hash: usize) -> &'static SpinLock<Shard> {
+    &MAP[(hash >> (usize::BITS as usize - 4)) & (SHARDS - 1)]
+}
+
+/// Records a freshly allocated block of `size` bytes
+pub(crate) fn insert(ptr: *mut u8, size: usize, born: u64) {
+    let ptr = ptr as usize;
+    let hash = hash(ptr);
+    if !shard(hash).lock().insert(Entry { ptr, size, born }, hash) {
+        OVERFLOWS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Forgets about a block which is being released and returns what was
+/// known about it (if anything).
+pub(crate) fn remove(ptr: *mut u8) -> Option<Entry> {
+    let ptr = ptr as usize;
+    let hash = hash(ptr);
+    shard(hash).lock().remove(ptr, hash)
+}
+
+/// Calls `f` on every live block recorded in the map. Each shard is locked
+/// in turn, so `f` must not allocate.
+pub(crate) fn for_each(mut f: impl FnMut(&Entry)) {
+    for shard in MAP.iter() {
+        let shard = shard.lock();
+        shard.slots.iter().filter(|e| e.ptr != 0).for_each(&mut f);
+    }
+}
+
+pub(crate) fn overflows() -> usize {
+    OVERFLOWS.load(Ordering::Relaxed)
+}
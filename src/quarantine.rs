@@ -0,0 +1,155 @@
+//! The quarantine is a debugging aid which delays the release of freed blocks
+//! so as to detect use-after-free bugs. Whenever a block is deallocated, it is
+//! filled with a poison pattern and parked in a bounded FIFO instead of being
+//! handed back to the system allocator. The block is only truly released when
+//! it gets evicted from the quarantine; at which point, the poison pattern is
+//! verified. Any alteration of the pattern means that someone wrote through a
+//! dangling pointer.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::sync::SpinLock;
+
+/// The byte pattern used to poison the quarantined blocks
+pub(crate) const POISON: u8 = 0xDE;
+/// The maximum number of blocks that can be parked in quarantine at once
+const SLOTS: usize = 1024;
+/// The default bound on the total number of bytes held in quarantine (1 MB)
+pub(crate) const DEFAULT_CAPACITY: usize = 1024 * 1024;
+
+/// The maximum number of bytes that can be held in quarantine
+static CAPACITY: AtomicUsize = AtomicUsize::new(DEFAULT_CAPACITY);
+/// The number of bytes that are currently held in quarantine
+static QUARANTINED: AtomicUsize = AtomicUsize::new(0);
+/// The number of quarantined blocks whose poison pattern was found altered
+static VIOLATIONS: AtomicUsize = AtomicUsize::new(0);
+/// The size of the last block whose poison pattern was found altered
+static LAST_VIOLATION_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+/// The FIFO of quarantined blocks
+static QUEUE: SpinLock<Queue> = SpinLock::new(Queue::new());
+
+/// A block which is parked in quarantine
+#[derive(Clone, Copy)]
+struct Block {
+    ptr: *mut u8,
+    layout: Layout,
+}
+
+/// A fixed capacity ring buffer of quarantined blocks. It never allocates.
+struct Queue {
+    blocks: [Option<Block>; SLOTS],
+    head: usize,
+    len: usize,
+}
+// The raw pointers stored in the queue are owned by the queue.
+unsafe impl Send for Queue {}
+
+impl Queue {
+    const fn new() -> Self {
+        Queue {
+            blocks: [None; SLOTS],
+            head: 0,
+            len: 0,
+        }
+    }
+    fn push(&mut self, block: Block) {
+        let tail = (self.head + self.len) % SLOTS;
+        self.blocks[tail] = Some(block);
+        self.len += 1;
+    }
+    fn pop(&mut self) -> Option<Block> {
+        if self.len == 0 {
+            return None;
+        }
+        let block = self.blocks[self.head].take();
+        self.head = (self.head + 1) % SLOTS;
+        self.len -= 1;
+        block
+    }
+    fn iter(&self) -> impl Iterator<Item = &Block> {
+        (0..self.len).filter_map(move |i| self.blocks[(self.head + i) % SLOTS].as_ref())
+    }
+}
+
+/// Checks that the poison pattern of the given block is intact. If it is not,
+/// the violation is recorded and the pattern is restored (so that the same
+/// violation is not reported twice).
+unsafe fn check(block: &Block) {
+    let bytes = std::slice::from_raw_parts_mut(block.ptr, block.layout.size());
+    if bytes.iter().any(|b| *b != POISON) {
+        VIOLATIONS.fetch_add(1, Ordering::Relaxed);
+        LAST_VIOLATION_SIZE.store(block.layout.size(), Ordering::Relaxed);
+        bytes.fill(POISON);
+    }
+}
+
+/// Verifies and truly releases the oldest blocks until the quarantine holds
+/// no more than `capacity` bytes.
+unsafe fn evict(queue: &mut Queue, capacity: usize) {
+    while QUARANTINED.load(Ordering::Relaxed) > capacity || queue.len == SLOTS {
+        match queue.pop() {
+            None => break,
+            Some(block) => {
+                check(&block);
+                QUARANTINED.fetch_sub(block.layout.size(), Ordering::Relaxed);
+                #[cfg(feature = "zeroize-on-free")]
+                crate::zeroize::wipe(block.ptr, block.layout.size());
+                System.dealloc(block.ptr, block.layout);
+            }
+        }
+    }
+}
+
+/// Poisons the given block and parks it in quarantine (possibly evicting the
+/// oldest blocks). Blocks which are larger than the whole quarantine are
+/// immediately released.
+pub(crate) unsafe fn park(ptr: *mut u8, layout: Layout) {
+    let capacity = CAPACITY.load(Ordering::Relaxed);
+    if layout.size() == 0 || layout.size() > capacity {
+        #[cfg(feature = "zeroize-on-free")]
+        crate::zeroize::wipe(ptr, layout.size());
+        System.dealloc(ptr, layout);
+        return;
+    }
+    ptr.write_bytes(POISON, layout.size());
+
+    let mut queue = QUEUE.lock();
+    evict(&mut queue, capacity - layout.size());
+    QUARANTINED.fetch_add(layout.size(), Ordering::Relaxed);
+    queue.push(Block { ptr, layout });
+}
+
+/// Changes the bound on the total number of bytes held in quarantine. The
+/// blocks which no longer fit are evicted right away.
+pub(crate) fn set_capacity(bytes: usize) {
+    CAPACITY.store(bytes, Ordering::Relaxed);
+    let mut queue = QUEUE.lock();
+    unsafe { evict(&mut queue, bytes) };
+}
+
+/// Returns the bound on the total number of bytes held in quarantine
+pub(crate) fn capacity() -> usize {
+    CAPACITY.load(Ordering::Relaxed)
+}
+
+/// Checks the poison pattern of all the blocks currently held in quarantine
+/// and returns the total number of violations detected so far.
+pub(crate) fn verify() -> usize {
+    let queue = QUEUE.lock();
+    for block in queue.iter() {
+        unsafe { check(block) };
+    }
+    VIOLATIONS.load(Ordering::Relaxed)
+}
+
+pub(crate) fn quarantined_bytes() -> usize {
+    QUARANTINED.load(Ordering::Relaxed)
+}
+pub(crate) fn violations() -> usize {
+    VIOLATIONS.load(Ordering::Relaxed)
+}
+pub(crate) fn last_violation_size() -> usize {
+    LAST_VIOLATION_SIZE.load(Ordering::Relaxed)
+}
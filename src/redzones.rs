@@ -0,0 +1,78 @@
+//! Redzones are a debugging aid which detect buffer overflows (and underflows).
+//! Every allocation is surrounded by two small areas filled with a canary
+//! pattern. These canaries are verified when the block is released: any
+//! alteration means that the program wrote out of the bounds of its block.
+//!
+//! # Note
+//! Since `PeakAlloc` relies on the default `GlobalAlloc::realloc`, which is
+//! implemented in terms of `alloc` and `dealloc`, a reallocated block gets
+//! its canaries verified and a fresh pair of redzones around the new block.
+
+use std::alloc::Layout;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// The byte pattern written in the redzones
+const CANARY: u8 = 0xFD;
+/// The number of canary bytes on each side of an allocation
+const REDZONE: usize = 16;
+
+/// The number of blocks whose canaries were found altered
+static VIOLATIONS: AtomicUsize = AtomicUsize::new(0);
+/// The (user requested) size of the last block whose canaries were altered
+static LAST_VIOLATION_SIZE: AtomicUsize = AtomicUsize::new(0);
+/// The number of bytes currently spent on redzones (and their padding)
+static OVERHEAD: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the offset of the user block within the outer block. It is a
+/// multiple of the alignment so that the user block is properly aligned.
+fn front(layout: Layout) -> usize {
+    let align = layout.align();
+    REDZONE.div_ceil(align) * align
+}
+
+/// Returns the layout of the outer block (the user block and its redzones)
+/// or None if that layout would overflow.
+pub(crate) fn outer_layout(layout: Layout) -> Option<Layout> {
+    let size = front(layout)
+        .checked_add(layout.size())?
+        .checked_add(REDZONE)?;
+    Layout::from_size_align(size, layout.align()).ok()
+}
+
+/// Writes the canaries in the freshly allocated outer block and returns a
+/// pointer to the user block.
+pub(crate) unsafe fn wrap(outer: *mut u8, layout: Layout) -> *mut u8 {
+    let ptr = outer.add(front(layout));
+    ptr.sub(REDZONE).write_bytes(CANARY, REDZONE);
+    ptr.add(layout.size()).write_bytes(CANARY, REDZONE);
+    OVERHEAD.fetch_add(front(layout) + REDZONE, Ordering::Relaxed);
+    ptr
+}
+
+/// Verifies the canaries around the given user block and returns the outer
+/// block (along with its layout) which must be handed back to the system.
+pub(crate) unsafe fn unwrap(ptr: *mut u8, layout: Layout) -> (*mut u8, Layout) {
+    let before = std::slice::from_raw_parts(ptr.sub(REDZONE), REDZONE);
+    let after = std::slice::from_raw_parts(ptr.add(layout.size()), REDZONE);
+    if before.iter().chain(after).any(|b| *b != CANARY) {
+        VIOLATIONS.fetch_add(1, Ordering::Relaxed);
+        LAST_VIOLATION_SIZE.store(layout.size(), Ordering::Relaxed);
+    }
+    OVERHEAD.fetch_sub(front(layout) + REDZONE, Ordering::Relaxed);
+    // the outer layout was valid when the block was allocated
+    let outer = Layout::from_size_align_unchecked(
+        front(layout) + layout.size() + REDZONE,
+        layout.align(),
+    );
+    (ptr.sub(front(layout)), outer)
+}
+
+pub(crate) fn violations() -> usize {
+    VIOLATIONS.load(Ordering::Relaxed)
+}
+pub(crate) fn last_violation_size() -> usize {
+    LAST_VIOLATION_SIZE.load(Ordering::Relaxed)
+}
+pub(crate) fn overhead_bytes() -> usize {
+    OVERHEAD.load(Ordering::Relaxed)
+}
@@ -0,0 +1,55 @@
+//! The end-of-run report which bundles all the statistics gathered by
+//! `PeakAlloc` in one single place.
+
+use core::fmt;
+
+#[cfg(feature = "histogram")]
+use crate::SizeHistogram;
+
+/// All the statistics gathered by the allocator, as returned by
+/// `PeakAlloc::final_report`. Its `Display` implementation produces a
+/// multi-line human-readable dump.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Report {
+    /// The number of bytes currently allocated
+    pub current_usage: usize,
+    /// The maximum number of bytes that have been allocated at once
+    pub peak_usage: usize,
+    /// The cumulative number of bytes that have been allocated
+    pub total_allocated: usize,
+    /// The number of allocations performed
+    pub allocation_count: usize,
+    /// The number of deallocations performed
+    pub deallocation_count: usize,
+    /// The size (in bytes) of the largest allocation
+    pub largest_allocation: usize,
+    /// The usage when the startup was marked as complete (if it was)
+    pub startup_usage: Option<usize>,
+    /// How far the peak rose above the usage of the startup
+    pub post_startup_peak: usize,
+    /// The histogram of the allocation sizes
+    #[cfg(feature = "histogram")]
+    pub size_histogram: SizeHistogram,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const MB: f64 = crate::units::MIB as f64;
+        writeln!(f, "memory usage")?;
+        writeln!(f, "  current            : {} B ({:.2} MB)", self.current_usage, self.current_usage as f64 / MB)?;
+        writeln!(f, "  peak               : {} B ({:.2} MB)", self.peak_usage, self.peak_usage as f64 / MB)?;
+        writeln!(f, "  total allocated    : {} B ({:.2} MB)", self.total_allocated, self.total_allocated as f64 / MB)?;
+        if let Some(startup) = self.startup_usage {
+            writeln!(f, "  startup            : {} B ({:.2} MB)", startup, startup as f64 / MB)?;
+            let post = self.post_startup_peak;
+            writeln!(f, "  post-startup peak  : {} B ({:.2} MB)", post, post as f64 / MB)?;
+        }
+        writeln!(f, "allocations")?;
+        writeln!(f, "  allocations        : {}", self.allocation_count)?;
+        writeln!(f, "  deallocations      : {}", self.deallocation_count)?;
+        writeln!(f, "  largest allocation : {} B", self.largest_allocation)?;
+        #[cfg(feature = "histogram")]
+        write!(f, "{}", self.size_histogram)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,97 @@
+//! The rounding of the converted usages (see `PeakAlloc::current_usage_as_mb_with`).
+//!
+//! This does not rely on `f64::floor` and friends, which are not available
+//! without `std`.
+
+/// The number of decimals the converted usages are rounded to
+pub const ROUND_DECIMALS: u32 = 2;
+/// `10^ROUND_DECIMALS`
+const SCALE: f64 = 100.0;
+
+/// How a converted usage is rounded to `ROUND_DECIMALS` decimals.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RoundMode {
+    /// Towards negative infinity (conservative)
+    Floor,
+    /// Towards positive infinity
+    Ceil,
+    /// To the nearest value, the ties being rounded up (for display)
+    Nearest,
+    /// Towards zero (the decimals in excess are dropped)
+    Truncate,
+}
+
+impl RoundMode {
+    /// Rounds the given value to `ROUND_DECIMALS` decimals
+    pub fn round(self, value: f64) -> f64 {
+        self.round_scaled(value * SCALE)
+    }
+    /// Rounds the number of bytes expressed in units of `unit_bytes` bytes.
+    /// Going through the bytes spares the error of a first rounding (as long
+    /// as the unit is a power of two).
+    pub(crate) fn round_bytes(self, bytes: usize, unit_bytes: usize) -> f64 {
+        if unit_bytes == 0 {
+            f64::NAN
+        } else {
+            self.round_scaled(bytes as f64 * SCALE / unit_bytes as f64)
+        }
+    }
+    /// Rounds a value which has already been multiplied by `SCALE` to an
+    /// integer, and scales it back
+    fn round_scaled(self, scaled: f64) -> f64 {
+        if !scaled.is_finite() {
+            return scaled / SCALE;
+        }
+        let rounded = match self {
+            RoundMode::Floor => floor(scaled),
+            RoundMode::Ceil => -floor(-scaled),
+            RoundMode::Nearest => floor(scaled + 0.5),
+            RoundMode::Truncate => trunc(scaled),
+        };
+        rounded / SCALE
+    }
+}
+
+/// The integral part of a value (exact as long as it fits in an i64, which
+/// is always the case for a number of bytes scaled to two decimals)
+fn trunc(x: f64) -> f64 {
+    x as i64 as f64
+}
+
+fn floor(x: f64) -> f64 {
+    let t = trunc(x);
+    if t > x {
+        t - 1.0
+    } else {
+        t
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RoundMode;
+
+    #[test]
+    fn each_mode_rounds_to_two_decimals() {
+        assert_eq!(1.23, RoundMode::Floor.round(1.239));
+        assert_eq!(1.24, RoundMode::Ceil.round(1.231));
+        assert_eq!(1.24, RoundMode::Nearest.round(1.235));
+        assert_eq!(1.23, RoundMode::Nearest.round(1.234));
+        assert_eq!(1.23, RoundMode::Truncate.round(1.239));
+        assert_eq!(1.5, RoundMode::Ceil.round(1.5));
+    }
+
+    #[test]
+    fn floor_and_truncate_differ_on_negative_values() {
+        assert_eq!(-1.24, RoundMode::Floor.round(-1.231));
+        assert_eq!(-1.23, RoundMode::Truncate.round(-1.231));
+        assert_eq!(-1.23, RoundMode::Ceil.round(-1.239));
+    }
+
+    #[test]
+    fn non_finite_values_are_left_untouched() {
+        assert!(RoundMode::Nearest.round(f64::NAN).is_nan());
+        assert_eq!(f64::INFINITY, RoundMode::Floor.round(f64::INFINITY));
+        assert!(RoundMode::Floor.round_bytes(1, 0).is_nan());
+    }
+}
@@ -0,0 +1,215 @@
+//! Querying the resident set size (RSS) of the process from the operating
+//! system. This is what tools like `top` report and it typically differs from
+//! what `PeakAlloc` tracks (see `FootprintReport`).
+//!
+//! On wasm32, there is no operating system to ask: the size of the linear
+//! memory is reported instead, which is what the heap has been carved from.
+//!
+//! None of these functions is ever called on the allocation path.
+
+use std::fmt;
+
+/// Returns the resident set size (in bytes) of the current process, or None
+/// if it cannot be determined on this platform. On wasm32, this is the size
+/// of the linear memory.
+pub fn process_rss() -> Option<usize> {
+    imp::process_rss()
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use std::fs::File;
+    use std::io::Read;
+    use std::os::raw::{c_int, c_long};
+
+    extern "C" {
+        fn sysconf(name: c_int) -> c_long;
+    }
+    const SC_PAGESIZE: c_int = 30;
+
+    pub(super) fn process_rss() -> Option<usize> {
+        // statm is tiny: a stack buffer spares us an allocation
+        let mut buffer = [0_u8; 256];
+        let mut file = File::open("/proc/self/statm").ok()?;
+        let len = file.read(&mut buffer).ok()?;
+        let pages = super::parse_statm(&buffer[..len])?;
+        let page_size = unsafe { sysconf(SC_PAGESIZE) };
+        if page_size <= 0 {
+            return None;
+        }
+        pages.checked_mul(page_size as usize)
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod imp {
+    use std::os::raw::{c_int, c_uint};
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct TimeValue {
+        seconds: c_int,
+        microseconds: c_int,
+    }
+    #[repr(C)]
+    #[derive(Default)]
+    struct MachTaskBasicInfo {
+        virtual_size: u64,
+        resident_size: u64,
+        resident_size_max: u64,
+        user_time: TimeValue,
+        system_time: TimeValue,
+        policy: c_int,
+        suspend_count: c_int,
+    }
+    const MACH_TASK_BASIC_INFO: c_uint = 20;
+    const KERN_SUCCESS: c_int = 0;
+
+    extern "C" {
+        static mach_task_self_: c_uint;
+        fn task_info(task: c_uint, flavor: c_uint, info: *mut c_int, count: *mut c_uint) -> c_int;
+    }
+
+    pub(super) fn process_rss() -> Option<usize> {
+        let mut info = MachTaskBasicInfo::default();
+        let mut count = (std::mem::size_of::<MachTaskBasicInfo>() / std::mem::size_of::<c_int>()) as c_uint;
+        let ret = unsafe {
+            task_info(
+                mach_task_self_,
+                MACH_TASK_BASIC_INFO,
+                &mut info as *mut MachTaskBasicInfo as *mut c_int,
+                &mut count,
+            )
+        };
+        if ret == KERN_SUCCESS {
+            Some(info.resident_size as usize)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(windows)]
+mod imp {
+    use std::ffi::c_void;
+
+    #[repr(C)]
+    #[allow(non_snake_case)]
+    struct ProcessMemoryCounters {
+        cb: u32,
+        PageFaultCount: u32,
+        PeakWorkingSetSize: usize,
+        WorkingSetSize: usize,
+        QuotaPeakPagedPoolUsage: usize,
+        QuotaPagedPoolUsage: usize,
+        QuotaPeakNonPagedPoolUsage: usize,
+        QuotaNonPagedPoolUsage: usize,
+        PagefileUsage: usize,
+        PeakPagefileUsage: usize,
+    }
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetCurrentProcess() -> *mut c_void;
+        fn K32GetProcessMemoryInfo(process: *mut c_void, counters: *mut ProcessMemoryCounters, cb: u32) -> i32;
+    }
+
+    pub(super) fn process_rss() -> Option<usize> {
+        let cb = std::mem::size_of::<ProcessMemoryCounters>() as u32;
+        let mut counters = ProcessMemoryCounters {
+            cb,
+            PageFaultCount: 0,
+            PeakWorkingSetSize: 0,
+            WorkingSetSize: 0,
+            QuotaPeakPagedPoolUsage: 0,
+            QuotaPagedPoolUsage: 0,
+            QuotaPeakNonPagedPoolUsage: 0,
+            QuotaNonPagedPoolUsage: 0,
+            PagefileUsage: 0,
+            PeakPagefileUsage: 0,
+        };
+        let ok = unsafe { K32GetProcessMemoryInfo(GetCurrentProcess(), &mut counters, cb) };
+        if ok != 0 {
+            Some(counters.WorkingSetSize)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+mod imp {
+    /// The size of a wasm page
+    const WASM_PAGE: usize = 64 * 1024;
+
+    pub(super) fn process_rss() -> Option<usize> {
+        Some(core::arch::wasm32::memory_size::<0>() * WASM_PAGE)
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows, target_arch = "wasm32")))]
+mod imp {
+    pub(super) fn process_rss() -> Option<usize> {
+        None
+    }
+}
+
+/// Parses the content of `/proc/self/statm` and returns the resident set
+/// size it holds (in pages). That is, the second whitespace separated field.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_statm(content: &[u8]) -> Option<usize> {
+    let content = std::str::from_utf8(content).ok()?;
+    content.split_ascii_whitespace().nth(1)?.parse().ok()
+}
+
+/// A comparison between the heap usage tracked by `PeakAlloc` and the
+/// resident set size of the process, as returned by `PeakAlloc::footprint`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FootprintReport {
+    /// The number of bytes currently allocated (as tracked by `PeakAlloc`)
+    pub tracked_heap: usize,
+    /// The resident set size of the process (None if it is not available)
+    pub rss: Option<usize>,
+    /// The difference between the rss and the tracked heap
+    pub difference: Option<isize>,
+}
+
+impl FootprintReport {
+    pub(crate) fn new(tracked_heap: usize, rss: Option<usize>) -> Self {
+        let difference = rss.map(|rss| rss as isize - tracked_heap as isize);
+        FootprintReport { tracked_heap, rss, difference }
+    }
+}
+
+impl fmt::Display for FootprintReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "tracked heap : {} B", self.tracked_heap)?;
+        match (self.rss, self.difference) {
+            (Some(rss), Some(difference)) => {
+                writeln!(f, "process rss  : {} B", rss)?;
+                writeln!(f, "difference   : {} B", difference)?;
+                writeln!(
+                    f,
+                    "The rss also accounts for the code and stacks of the process, the memory \
+                     mapped files, and the memory the system allocator keeps cached for reuse \
+                     (or loses to fragmentation). Conversely, heap pages which were never \
+                     touched do not count towards the rss."
+                )
+            }
+            _ => writeln!(f, "process rss  : unavailable on this platform"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_statm;
+
+    #[test]
+    fn statm_resident_field_is_parsed() {
+        assert_eq!(Some(1234), parse_statm(b"56789 1234 321 12 0 4567 0\n"));
+        assert_eq!(None, parse_statm(b"56789"));
+        assert_eq!(None, parse_statm(b"56789 abc 321"));
+        assert_eq!(None, parse_statm(&[0xff, 0xfe]));
+    }
+}
@@ -0,0 +1,57 @@
+//! The sample rate of the costly per-allocation diagnostics. With a rate of
+//! `n`, only one allocation out of `n` is measured by the timed accounting
+//! and recorded in the pointer map (hence in the lifetime statistics). The
+//! plain counters of the allocator are never sampled: they remain exact.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// One out of `RATE` allocations is sampled
+static RATE: AtomicUsize = AtomicUsize::new(1);
+
+/// Returns the current sample rate
+pub(crate) fn rate() -> usize {
+    RATE.load(Ordering::Relaxed)
+}
+
+/// Sets the sample rate (a rate of 0 is treated as 1) and returns the
+/// previous one
+pub(crate) fn set_rate(n: usize) -> usize {
+    RATE.swap(n.max(1), Ordering::Relaxed)
+}
+
+/// Decides which allocations are sampled on behalf of one diagnostic. Each
+/// diagnostic has its own ticker so that they do not skew one another.
+#[cfg(any(feature = "timed-accounting", feature = "pointer-map"))]
+pub(crate) struct Ticker(AtomicUsize);
+
+#[cfg(any(feature = "timed-accounting", feature = "pointer-map"))]
+impl Ticker {
+    pub(crate) const fn new() -> Self {
+        Ticker(AtomicUsize::new(0))
+    }
+    /// Returns true iff the current allocation should be sampled
+    #[inline]
+    pub(crate) fn sampled(&self) -> bool {
+        let rate = rate();
+        rate <= 1 || self.0.fetch_add(1, Ordering::Relaxed).is_multiple_of(rate)
+    }
+}
+
+/// Temporarily changes the sample rate (see `PeakAlloc::with_sample_rate`).
+/// The previous rate is restored when the guard is dropped.
+#[must_use = "the previous sample rate is restored as soon as the guard is dropped"]
+pub struct SampleRateGuard {
+    previous: usize,
+}
+
+impl SampleRateGuard {
+    pub(crate) fn new(n: usize) -> Self {
+        SampleRateGuard { previous: set_rate(n) }
+    }
+}
+
+impl Drop for SampleRateGuard {
+    fn drop(&mut self) {
+        set_rate(self.previous);
+    }
+}
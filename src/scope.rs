@@ -0,0 +1,98 @@
+//! Isolating the tests which measure the memory (see `PeakAlloc::test_scope`).
+//!
+//! The counters are process-wide: within a test binary, whatever a test
+//! allocates shows up in the counters of the tests running along (or after)
+//! it. A `TestScope` serializes the scopes of the different threads, makes
+//! the counters it reports relative to its creation, and puts them back as
+//! they were when it is dropped.
+
+use core::marker::PhantomData;
+use core::sync::atomic::Ordering;
+use std::cell::Cell;
+use std::sync::{Mutex, MutexGuard};
+
+use crate::tracker::Saved;
+use crate::{FAILED_ALLOCS, TRACKER};
+
+/// Serializes the (outermost) scopes of the different threads
+static SERIAL: Mutex<()> = Mutex::new(());
+
+thread_local! {
+    /// The number of scopes the calling thread is within
+    static DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+/// The scope of a test (see `PeakAlloc::test_scope`). Its counters are the
+/// ones of the whole process since it was created, and they are put back as
+/// they were when it is dropped. It must be dropped on the thread which
+/// created it.
+#[must_use = "the counters are restored as soon as the scope is dropped"]
+pub struct TestScope {
+    saved: Saved,
+    failed: usize,
+    /// Only held by the outermost scope of a thread
+    _serial: Option<MutexGuard<'static, ()>>,
+    /// The scope is bound to the thread which created it
+    _not_send: PhantomData<*const ()>,
+}
+
+impl TestScope {
+    pub(crate) fn new() -> Self {
+        let depth = DEPTH.with(|depth| depth.replace(depth.get() + 1));
+        let serial = if depth == 0 {
+            Some(SERIAL.lock().unwrap_or_else(|e| e.into_inner()))
+        } else {
+            None
+        };
+        let saved = TRACKER.save();
+        TRACKER.reset_peak_usage();
+        TestScope {
+            saved,
+            failed: FAILED_ALLOCS.load(Ordering::SeqCst),
+            _serial: serial,
+            _not_send: PhantomData,
+        }
+    }
+    /// Returns the number of bytes allocated minus the number of bytes
+    /// released since the scope was created
+    pub fn net_bytes(&self) -> isize {
+        TRACKER.raw_current().wrapping_sub(self.saved.current) as isize
+    }
+    /// Returns the highest number of bytes in use at once since the scope
+    /// was created, above the usage when it was created
+    pub fn peak_bytes(&self) -> usize {
+        TRACKER.peak_usage().saturating_sub(self.saved.current)
+    }
+    /// Returns the number of bytes allocated since the scope was created
+    pub fn allocated_bytes(&self) -> usize {
+        TRACKER.total_allocated() - self.saved.total_allocated
+    }
+    /// Returns the number of allocations performed since the scope was
+    /// created
+    pub fn allocation_count(&self) -> usize {
+        TRACKER.allocation_count() - self.saved.allocations
+    }
+    /// Returns the number of deallocations performed since the scope was
+    /// created
+    pub fn deallocation_count(&self) -> usize {
+        TRACKER.deallocation_count() - self.saved.deallocations
+    }
+    /// Returns the number of reallocations performed since the scope was
+    /// created
+    pub fn reallocation_count(&self) -> usize {
+        TRACKER.reallocation_count() - self.saved.reallocations
+    }
+    /// Returns the number of allocations which failed since the scope was
+    /// created
+    pub fn failed_allocation_count(&self) -> usize {
+        FAILED_ALLOCS.load(Ordering::Relaxed) - self.failed
+    }
+}
+
+impl Drop for TestScope {
+    fn drop(&mut self) {
+        TRACKER.restore(&self.saved);
+        FAILED_ALLOCS.store(self.failed, Ordering::SeqCst);
+        DEPTH.with(|depth| depth.set(depth.get() - 1));
+    }
+}
@@ -0,0 +1,86 @@
+//! The growth slack (see `PeakAlloc::growth_slack_bytes`): an estimate of
+//! how much of the usage comes from the geometric growth of the collections.
+//!
+//! The allocator cannot see the logical length of a `Vec`, only its capacity.
+//! The bytes a reallocation adds to a block are taken for slack, until they
+//! are given back: a block shrunk by a reallocation (say, `shrink_to_fit`)
+//! gives back its delta, and a block released gives back its share of the
+//! slack, in proportion of its size to the usage. This is a heuristic: the
+//! slack is not tracked per block, and the grown capacity may well be used.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// The number of bytes added by the growing reallocations, net of those
+/// given back
+static SLACK: AtomicUsize = AtomicUsize::new(0);
+
+/// Accounts for a block resized from `old_size` to `new_size` bytes
+#[inline]
+pub(crate) fn on_realloc(old_size: usize, new_size: usize) {
+    if new_size > old_size {
+        SLACK.fetch_add(new_size - old_size, Ordering::Relaxed);
+    } else if old_size > new_size {
+        give_back(|slack| shrunk(slack, old_size - new_size));
+    }
+}
+
+/// Accounts for the release of a block of `size` bytes, when `usage` (raw)
+/// bytes were in use
+#[inline]
+pub(crate) fn on_dealloc(size: usize, usage: usize) {
+    if SLACK.load(Ordering::Relaxed) != 0 {
+        give_back(|slack| released(slack, size, usage));
+    }
+}
+
+fn give_back(update: impl Fn(usize) -> usize) {
+    let _ = SLACK.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |slack| Some(update(slack)));
+}
+
+/// Returns the slack once a block has been shrunk by `delta` bytes
+fn shrunk(slack: usize, delta: usize) -> usize {
+    slack.saturating_sub(delta)
+}
+
+/// Returns the slack once a block of `size` bytes out of the `usage` ones
+/// has been released
+fn released(slack: usize, size: usize, usage: usize) -> usize {
+    if size >= usage {
+        return 0;
+    }
+    let share = slack as u128 * size as u128 / usage as u128;
+    slack - share as usize
+}
+
+/// Returns the growth slack, in bytes
+pub(crate) fn bytes() -> usize {
+    SLACK.load(Ordering::Relaxed)
+}
+
+/// Sets the growth slack (see `PeakAlloc::import_state`)
+pub(crate) fn set_bytes(bytes: usize) {
+    SLACK.store(bytes, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{released, shrunk};
+
+    #[test]
+    fn a_shrink_gives_its_delta_back() {
+        // 1000 -> 5000 -> 2000 bytes
+        let slack = 4_000;
+        assert_eq!(1_000, shrunk(slack, 3_000));
+        assert_eq!(0, shrunk(slack, 5_000));
+    }
+
+    #[test]
+    fn a_release_gives_its_share_back() {
+        // a block of 2000 bytes, out of 10000 in use, carries a fifth of it
+        assert_eq!(800, released(1_000, 2_000, 10_000));
+        assert_eq!(1_000, released(1_000, 0, 10_000));
+        assert_eq!(0, released(1_000, 10_000, 10_000));
+        assert_eq!(0, released(1_000, 20_000, 10_000));
+        assert_eq!(usize::MAX / 2 + 1, released(usize::MAX, usize::MAX / 2, usize::MAX));
+    }
+}
@@ -0,0 +1,87 @@
+//! The smoothed peak: a peak which ignores the transient spikes, those which
+//! are freed before they could be observed by a sampler thread.
+//!
+//! While smoothing is enabled, the allocation path records the highest usage
+//! reached since the last tick of the sampler (the candidate peak). Upon each
+//! tick, the sampler confirms the part of the candidate which is still in
+//! use: `min(candidate, current usage)` becomes the smoothed peak if it is
+//! higher. A level of usage hence only counts if it is still held when the
+//! next tick comes.
+//!
+//! This trades accuracy for stability: a spike which is freed before the
+//! next tick is never reported, whereas a spike which happens to be alive
+//! on a tick is reported in full even if it was short lived. The smoothed
+//! peak also lags the usage by up to one sampling interval. The shorter the
+//! interval, the closer the smoothed peak gets to the raw one.
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use crate::periodic::Periodic;
+use crate::tracker::raise;
+use crate::TRACKER;
+
+/// Whether the allocation path records the candidate peak
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// The highest (raw) usage reached since the last tick
+static CANDIDATE: AtomicUsize = AtomicUsize::new(0);
+/// The highest (raw) usage which persisted until a tick
+static SMOOTHED: AtomicUsize = AtomicUsize::new(0);
+
+/// Records the usage as a candidate peak (if smoothing is enabled)
+#[inline]
+pub(crate) fn on_grow(usage: usize) {
+    if ENABLED.load(Ordering::Relaxed) {
+        raise(&CANDIDATE, usage);
+    }
+}
+
+/// Confirms the part of the candidate peak which is still in use, and
+/// starts a new window
+fn tick() {
+    let current = TRACKER.current_usage();
+    let candidate = CANDIDATE.swap(current, Ordering::Relaxed);
+    raise(&SMOOTHED, candidate.min(current));
+}
+
+/// Returns the smoothed peak (raw, the baseline is not subtracted)
+pub(crate) fn peak() -> usize {
+    SMOOTHED.load(Ordering::Relaxed)
+}
+
+/// Resets the smoothed peak to the current usage
+pub(crate) fn reset() {
+    SMOOTHED.store(TRACKER.current_usage(), Ordering::Relaxed);
+}
+
+/// The handle to the peak smoothing sampler (see
+/// `PeakAlloc::start_peak_smoothing`). Smoothing stops (but the smoothed
+/// peak remains available) when the handle is dropped.
+pub struct SmoothingHandle {
+    sampler: Periodic,
+}
+
+impl SmoothingHandle {
+    /// Stops smoothing. This is idempotent.
+    pub fn stop(&mut self) {
+        if self.sampler.is_running() {
+            ENABLED.store(false, Ordering::Relaxed);
+            self.sampler.stop();
+        }
+    }
+}
+
+impl Drop for SmoothingHandle {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Enables smoothing and spawns the sampler thread
+pub(crate) fn start(interval: Duration) -> SmoothingHandle {
+    CANDIDATE.store(TRACKER.current_usage(), Ordering::Relaxed);
+    reset();
+    ENABLED.store(true, Ordering::Relaxed);
+    let sampler = Periodic::spawn("peak_alloc-smoothing", interval, tick);
+    SmoothingHandle { sampler }
+}
@@ -0,0 +1,77 @@
+//! Serving the statistics over a Unix domain socket, for the live
+//! introspection of a running daemon: each connection is answered with the
+//! JSON object of `PeakAlloc::stats_json` (and a newline), then closed.
+//!
+//! ```sh
+//! socat - UNIX-CONNECT:/run/my-daemon/heap.sock
+//! ```
+//!
+//! The server thread polls a non-blocking listener. Its footprint is a
+//! buffer allocated upfront, along with whatever the system needs for each
+//! connection: answering does not allocate.
+
+use std::io::{self, Write as _};
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::periodic::{Periodic, Scratch};
+use crate::PeakAlloc;
+
+/// The delay between two polls of the listener
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+/// For how long a client may stall the server while it is being answered
+const WRITE_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// The handle to a stats server (see `PeakAlloc::serve_stats`). The server
+/// is stopped, and its socket file removed, when the handle is dropped.
+pub struct StatsServerHandle {
+    served: Arc<AtomicUsize>,
+    path: PathBuf,
+    _thread: Periodic,
+}
+
+impl StatsServerHandle {
+    /// Returns the number of connections which have been answered so far
+    pub fn served(&self) -> usize {
+        self.served.load(Ordering::Relaxed)
+    }
+    /// Returns the path of the socket
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for StatsServerHandle {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Binds the socket and spawns the server thread
+pub(crate) fn serve(alloc: PeakAlloc, path: &Path) -> io::Result<StatsServerHandle> {
+    let listener = UnixListener::bind(path)?;
+    listener.set_nonblocking(true)?;
+    let served = Arc::new(AtomicUsize::new(0));
+    let count = Arc::clone(&served);
+    // allocated once: the JSON object is rewritten in place afterwards
+    let mut json = Scratch::new();
+    let thread = Periodic::spawn("peak_alloc-socket", POLL_INTERVAL, move || {
+        while let Ok((mut stream, _)) = listener.accept() {
+            let json = json.get_or_init(|| String::with_capacity(512));
+            json.clear();
+            let _ = alloc.write_json(&mut *json);
+            json.push('\n');
+            let answered = stream
+                .set_nonblocking(false)
+                .and_then(|_| stream.set_write_timeout(Some(WRITE_TIMEOUT)))
+                .and_then(|_| stream.write_all(json.as_bytes()));
+            if answered.is_ok() {
+                count.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    });
+    Ok(StatsServerHandle { served, path: path.to_path_buf(), _thread: thread })
+}
@@ -0,0 +1,247 @@
+//! Attributing the memory to spans: the sections of the execution (e.g. the
+//! spans of the `tracing` crate) which a thread enters and exits, possibly
+//! several times and possibly nested.
+//!
+//! While a thread is within a span, the allocation path maintains the thread
+//! local net of `measure`: the net number of bytes this thread allocated,
+//! and the highest value this net reached since the innermost span was
+//! entered. Entering a span saves the high-water mark of the enclosing one
+//! and restarts it from the current net; exiting merges it back. Each span
+//! hence sees the peak reached while it was entered without disturbing the
+//! one of the spans around it.
+//!
+//! The saved marks are kept per thread, in the order the spans were entered,
+//! rather than in the spans: `tracing` lets a span be exited before the ones
+//! entered after it. Such a span takes the marks saved above it into its peak
+//! and leaves its own in place, for the span below to merge back once the
+//! ones above are exited. Beyond `LEVELS` spans, the innermost ones share the
+//! mark of the last: their peak is an upper bound.
+//!
+//! Peak Alloc does not depend on `tracing`, and does not provide a `Layer`:
+//! one owns a `SpanMemory` per span (in the span extensions) and calls
+//! `enter`/`exit` from its `on_enter`/`on_exit`. Neither allocates, hence the
+//! bookkeeping never accounts for itself.
+
+use std::cell::Cell;
+
+use crate::measure::NET;
+use crate::threads;
+
+/// The number of spans a thread can be within with exact peaks
+const LEVELS: usize = 32;
+/// The level of a span entered beyond `LEVELS`
+const NO_LEVEL: usize = usize::MAX;
+
+/// The spans a thread is within, in the order they were entered
+struct Levels {
+    /// The number of levels in use
+    len: Cell<usize>,
+    /// The highest net of each level until the next one was entered
+    highs: [Cell<isize>; LEVELS],
+    /// Whether the span of each level was exited (out of order) already
+    exited: [Cell<bool>; LEVELS],
+}
+
+thread_local! {
+    /// The levels of the spans this thread is within
+    static OPEN: Levels = const {
+        Levels {
+            len: Cell::new(0),
+            highs: [const { Cell::new(0) }; LEVELS],
+            exited: [const { Cell::new(false) }; LEVELS],
+        }
+    };
+}
+
+/// The memory attributed to a span, accumulated over all the times it was
+/// entered. The span must be exited on the thread which entered it, though
+/// not necessarily before the spans entered after it. Entering a span which
+/// is already entered (recursively) is fine: only the outermost enter/exit
+/// pair is accounted for.
+///
+/// ```
+/// use peak_alloc::{PeakAlloc, SpanMemory};
+///
+/// #[global_allocator]
+/// static PEAK_ALLOC: PeakAlloc = PeakAlloc;
+///
+/// let mut span = SpanMemory::new();
+/// span.enter();
+/// let data = vec![0_u8; 4096];
+/// span.exit();
+/// assert!(span.net_bytes() >= 4096);
+/// assert!(span.peak_delta() >= 4096);
+/// assert!(span.allocations() >= 1);
+/// # drop(data);
+/// ```
+///
+/// In a `tracing_subscriber::Layer`, the `SpanMemory` goes into the span
+/// extensions (`on_new_span`), is entered and exited in `on_enter` and
+/// `on_exit`, and its figures are recorded as the `mem.net_bytes`,
+/// `mem.allocations` and `mem.peak_delta` fields in `on_close`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SpanMemory {
+    /// The number of times the span is currently entered
+    entered: usize,
+    /// The thread local net when the span was entered
+    start_net: isize,
+    /// The number of allocations of the thread when the span was entered
+    start_allocations: usize,
+    /// The level of the span on its thread while it is entered (see `Levels`)
+    level: usize,
+    /// The accumulated net number of bytes
+    net_bytes: isize,
+    /// The accumulated number of allocations
+    allocations: usize,
+    /// The highest peak reached over the times the span was entered
+    peak_delta: usize,
+}
+
+impl SpanMemory {
+    /// Creates the (empty) accounting of a span which has not been entered
+    pub const fn new() -> Self {
+        SpanMemory {
+            entered: 0,
+            start_net: 0,
+            start_allocations: 0,
+            level: NO_LEVEL,
+            net_bytes: 0,
+            allocations: 0,
+            peak_delta: 0,
+        }
+    }
+    /// Starts attributing the memory allocated by the calling thread to this
+    /// span
+    pub fn enter(&mut self) {
+        self.entered += 1;
+        if self.entered > 1 {
+            return;
+        }
+        self.start_allocations = threads::thread_allocations();
+        (self.start_net, self.level) = NET.with(|net| {
+            net.depth.set(net.depth.get() + 1);
+            let start = net.bytes.get();
+            (start, OPEN.with(|open| push(open, &net.high, start)))
+        });
+    }
+    /// Stops attributing the memory allocated by the calling thread to this
+    /// span. Exiting a span which is not entered does nothing.
+    pub fn exit(&mut self) {
+        match self.entered {
+            0 => return,
+            1 => {}
+            _ => {
+                self.entered -= 1;
+                return;
+            }
+        }
+        self.entered = 0;
+        let (net, high) = NET.with(|net| {
+            net.depth.set(net.depth.get() - 1);
+            (net.bytes.get(), OPEN.with(|open| pop(open, &net.high, self.level)))
+        });
+        self.net_bytes = self.net_bytes.wrapping_add(net.wrapping_sub(self.start_net));
+        self.allocations += threads::thread_allocations().wrapping_sub(self.start_allocations);
+        self.peak_delta = self.peak_delta.max(high.wrapping_sub(self.start_net).max(0) as usize);
+    }
+    /// Returns true iff the span is currently entered
+    pub fn is_entered(&self) -> bool {
+        self.entered > 0
+    }
+    /// Returns the net number of bytes allocated (negative if more was
+    /// released than allocated) while the span was entered
+    pub fn net_bytes(&self) -> isize {
+        self.net_bytes
+    }
+    /// Returns the number of allocations performed while the span was entered
+    pub fn allocations(&self) -> usize {
+        self.allocations
+    }
+    /// Returns the highest amount of memory the thread held in excess of what
+    /// it held when entering the span, over all the times it was entered
+    pub fn peak_delta(&self) -> usize {
+        self.peak_delta
+    }
+}
+
+/// Enters a level (if any is left), which restarts the high-water mark from
+/// `net`, and returns it
+fn push(open: &Levels, high: &Cell<isize>, net: isize) -> usize {
+    let len = open.len.get();
+    if len == LEVELS {
+        return NO_LEVEL;
+    }
+    if len > 0 {
+        open.highs[len - 1].set(high.get());
+    }
+    open.exited[len].set(false);
+    open.len.set(len + 1);
+    high.set(net);
+    len
+}
+
+/// Exits a level, and returns the highest net reached since it was entered
+fn pop(open: &Levels, high: &Cell<isize>, level: usize) -> isize {
+    let len = open.len.get();
+    if level >= len {
+        // beyond the levels: the last one is shared
+        return high.get();
+    }
+    if level + 1 < len {
+        // out of order: the level stays until the ones above are exited
+        open.exited[level].set(true);
+        return open.highs[level..len - 1].iter().map(Cell::get).fold(high.get(), isize::max);
+    }
+    let peak = high.get();
+    // merges back the marks of this level and of the ones already exited
+    // below it (the enclosing span has seen all of them)
+    let mut len = level;
+    while len > 0 {
+        high.set(high.get().max(open.highs[len - 1].get()));
+        if !open.exited[len - 1].get() {
+            break;
+        }
+        len -= 1;
+    }
+    open.len.set(len);
+    peak
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{pop, push, Levels, LEVELS, NO_LEVEL};
+    use std::cell::Cell;
+
+    fn levels() -> Levels {
+        Levels { len: Cell::new(0), highs: Default::default(), exited: Default::default() }
+    }
+
+    #[test]
+    fn the_levels_exited_out_of_order_are_merged_back_in_turn() {
+        let (open, high) = (levels(), Cell::new(0));
+        assert_eq!(0, push(&open, &high, 0));
+        high.set(8000);
+        assert_eq!(1, push(&open, &high, 0));
+        assert_eq!(2, push(&open, &high, 100));
+        high.set(1000);
+        assert_eq!(1000, pop(&open, &high, 1));
+        high.set(1500);
+        assert_eq!(1500, pop(&open, &high, 2));
+        // both levels are gone, and the mark of the first one is back
+        assert_eq!((1, 8000), (open.len.get(), high.get()));
+        assert_eq!(8000, pop(&open, &high, 0));
+        assert_eq!(0, open.len.get());
+    }
+
+    #[test]
+    fn the_spans_beyond_the_levels_share_the_last_one() {
+        let (open, high) = (levels(), Cell::new(0));
+        for level in 0..LEVELS {
+            assert_eq!(level, push(&open, &high, 0));
+        }
+        assert_eq!(NO_LEVEL, push(&open, &high, 0));
+        high.set(42);
+        assert_eq!(42, pop(&open, &high, NO_LEVEL));
+        assert_eq!(LEVELS, open.len.get());
+    }
+}
@@ -0,0 +1,283 @@
+//! Detecting the spikes of the memory usage: the rises well above what the
+//! usage has recently been, as opposed to a fixed threshold which is either
+//! too low for the busy hours or too high for the quiet ones.
+//!
+//! A sampler thread compares the usage with a trailing reference: an
+//! exponential moving average whose time constant sets how fast it adapts.
+//! A spike opens when the usage exceeds the reference by more than the rise
+//! percentage, and closes when it falls back within the settle percentage of
+//! the reference (which is frozen meanwhile): the gap between both makes for
+//! a hysteresis, so that a usage hovering around a boundary is reported once.
+//! A further rise while a spike is open extends it rather than opening an
+//! overlapping one.
+//!
+//! The detector state is a handful of scalars: sampling does not allocate,
+//! only the callback does (if it wants to).
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::periodic::Periodic;
+use crate::{threads, PeakAlloc};
+
+/// What the callback of `PeakAlloc::on_spike` is told about a spike
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpikeReport {
+    /// The usage upon the last sample before the spike
+    pub start_usage: usize,
+    /// The highest usage sampled during the spike
+    pub peak_usage: usize,
+    /// The time from the last sample before the spike to the sample which
+    /// closed it
+    pub duration: Duration,
+    /// The number of bytes allocated (regardless of what was released)
+    /// during the spike
+    pub bytes_allocated_during: usize,
+    /// False iff the spike was still open when the detector was stopped
+    pub complete: bool,
+}
+
+/// The configuration of a spike detector (see `PeakAlloc::on_spike`)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpikeConfig {
+    /// By how much (in percent of the reference) the usage must rise to open
+    /// a spike (50% by default)
+    pub rise_percent: f64,
+    /// Within how much (in percent of the reference) the usage must fall back
+    /// to close a spike (10% by default)
+    pub settle_percent: f64,
+    /// The time constant of the reference: the longer, the slower it adapts
+    /// to a new usage level (10s by default)
+    pub reference_window: Duration,
+    /// The delay between two samples of the memory usage (100ms by default)
+    pub interval: Duration,
+}
+
+impl Default for SpikeConfig {
+    fn default() -> Self {
+        SpikeConfig {
+            rise_percent: 50.0,
+            settle_percent: 10.0,
+            reference_window: Duration::from_secs(10),
+            interval: Duration::from_millis(100),
+        }
+    }
+}
+
+impl SpikeConfig {
+    /// Sets by how much (in percent of the reference) the usage must rise to
+    /// open a spike
+    pub fn rise_percent(mut self, percent: f64) -> Self {
+        self.rise_percent = percent;
+        self
+    }
+    /// Sets within how much (in percent of the reference) the usage must fall
+    /// back to close a spike
+    pub fn settle_percent(mut self, percent: f64) -> Self {
+        self.settle_percent = percent;
+        self
+    }
+    /// Sets the time constant of the reference
+    pub fn reference_window(mut self, window: Duration) -> Self {
+        self.reference_window = window;
+        self
+    }
+    /// Sets the delay between two samples of the memory usage
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+}
+
+/// A sample: the time it was taken, the usage and the total allocated
+type Sample = (Instant, usize, usize);
+
+/// The spike detection proper, fed with the samples
+pub(crate) struct Detector {
+    config: SpikeConfig,
+    /// The trailing reference (None until the first sample)
+    reference: Option<f64>,
+    /// The previous sample
+    last: Option<Sample>,
+    /// The sample before the open spike, and the peak of that spike
+    open: Option<(Sample, usize)>,
+}
+
+impl Detector {
+    pub(crate) fn new(config: SpikeConfig) -> Self {
+        Detector { config, reference: None, last: None, open: None }
+    }
+    /// Accounts for a sample of the `usage`, taken at `now` when `allocated`
+    /// bytes had been allocated in total. Returns the report of the spike it
+    /// closes, if any.
+    pub(crate) fn sample(&mut self, now: Instant, usage: usize, allocated: usize) -> Option<SpikeReport> {
+        let last = self.last.replace((now, usage, allocated));
+        let reference = match self.reference {
+            Some(reference) => reference,
+            None => {
+                self.reference = Some(usage as f64);
+                return None;
+            }
+        };
+        if let Some((_, peak)) = self.open.as_mut() {
+            *peak = (*peak).max(usage);
+            if usage as f64 <= reference * (1.0 + self.config.settle_percent / 100.0) {
+                return self.close(now, allocated, true);
+            }
+            return None;
+        }
+        if usage as f64 > reference * (1.0 + self.config.rise_percent / 100.0) {
+            // `last` is there since the reference is
+            self.open = last.map(|start| (start, usage));
+            return None;
+        }
+        let elapsed = last.map_or(Duration::ZERO, |(time, _, _)| now.saturating_duration_since(time));
+        let window = self.config.reference_window.as_secs_f64();
+        let alpha = if window > 0.0 { 1.0 - (-elapsed.as_secs_f64() / window).exp() } else { 1.0 };
+        self.reference = Some(reference + alpha * (usage as f64 - reference));
+        None
+    }
+    /// Reports the spike which is still open (if any) as incomplete, as of the
+    /// last sample
+    pub(crate) fn finish(&mut self) -> Option<SpikeReport> {
+        let (now, _, allocated) = self.last?;
+        self.close(now, allocated, false)
+    }
+    /// Closes the open spike (if any)
+    fn close(&mut self, now: Instant, allocated: usize, complete: bool) -> Option<SpikeReport> {
+        let ((time, usage, total), peak) = self.open.take()?;
+        Some(SpikeReport {
+            start_usage: usage,
+            peak_usage: peak,
+            duration: now.saturating_duration_since(time),
+            bytes_allocated_during: allocated.wrapping_sub(total),
+            complete,
+        })
+    }
+}
+
+/// The detector along with its callback, shared by the sampler thread and the
+/// handle (which reports the spike left open)
+struct Watch {
+    detector: Detector,
+    callback: Box<dyn FnMut(SpikeReport) + Send>,
+}
+
+/// The handle to a spike detector. The detector is stopped when the handle is
+/// dropped, or explicitly with `stop`: a spike which is still open then is
+/// reported as incomplete.
+pub struct SpikeWatchHandle {
+    watch: Arc<Mutex<Watch>>,
+    sampler: Periodic,
+}
+
+impl SpikeWatchHandle {
+    /// Stops the sampler thread (and waits for it), then reports the spike
+    /// which is still open, if any. This is idempotent.
+    pub fn stop(&mut self) {
+        self.sampler.stop();
+        let mut watch = self.watch.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(report) = watch.detector.finish() {
+            (watch.callback)(report);
+        }
+    }
+    /// Returns true iff the detector has not been stopped yet
+    pub fn is_running(&self) -> bool {
+        self.sampler.is_running()
+    }
+}
+
+impl Drop for SpikeWatchHandle {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Spawns the spike detector thread
+pub(crate) fn start<F>(alloc: PeakAlloc, config: SpikeConfig, callback: F) -> SpikeWatchHandle
+where
+    F: FnMut(SpikeReport) + Send + 'static,
+{
+    let watch = Arc::new(Mutex::new(Watch { detector: Detector::new(config), callback: Box::new(callback) }));
+    let sampled = Arc::clone(&watch);
+    let sampler = Periodic::spawn("peak_alloc-spikes", config.interval, move || {
+        let mut watch = sampled.lock().unwrap_or_else(|e| e.into_inner());
+        let Watch { detector, callback } = &mut *watch;
+        if let Some(report) = detector.sample(Instant::now(), alloc.current_usage(), alloc.total_allocated()) {
+            threads::accounted(|| callback(report));
+        }
+    });
+    SpikeWatchHandle { watch, sampler }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Detector, SpikeConfig, SpikeReport};
+    use std::time::{Duration, Instant};
+
+    /// Feeds the detector with one sample per second, each allocating 10
+    /// bytes more than the previous one, and returns the reports
+    fn feed(detector: &mut Detector, usages: &[usize]) -> Vec<SpikeReport> {
+        let start = Instant::now();
+        usages
+            .iter()
+            .enumerate()
+            .filter_map(|(i, &usage)| {
+                detector.sample(start + Duration::from_secs(i as u64), usage, 10 * i + usage)
+            })
+            .collect()
+    }
+
+    fn detector() -> Detector {
+        // a reference which does not move, so that the boundaries are exact
+        Detector::new(SpikeConfig::default().reference_window(Duration::from_secs(1 << 30)))
+    }
+
+    #[test]
+    fn rises_beyond_the_rise_percentage_are_spikes() {
+        // the boundaries are 150 (opening) and 110 (closing)
+        assert!(feed(&mut detector(), &[100, 150, 100]).is_empty());
+        let reports = feed(&mut detector(), &[100, 151, 200, 111, 110, 100]);
+        assert_eq!(
+            vec![SpikeReport {
+                start_usage: 100,
+                peak_usage: 200,
+                duration: Duration::from_secs(4),
+                bytes_allocated_during: 40 + 110 - 100,
+                complete: true,
+            }],
+            reports
+        );
+    }
+
+    #[test]
+    fn rises_during_a_spike_extend_it() {
+        let reports = feed(&mut detector(), &[100, 200, 120, 300, 120, 105, 400, 100]);
+        let spikes: Vec<_> = reports.iter().map(|r| (r.start_usage, r.peak_usage, r.duration)).collect();
+        assert_eq!(vec![(100, 300, Duration::from_secs(5)), (105, 400, Duration::from_secs(2))], spikes);
+    }
+
+    #[test]
+    fn open_spikes_are_reported_as_incomplete() {
+        let mut detector = detector();
+        assert!(feed(&mut detector, &[100, 200, 300]).is_empty());
+        let report = detector.finish().unwrap();
+        assert_eq!((100, 300, false), (report.start_usage, report.peak_usage, report.complete));
+        assert_eq!(None, detector.finish());
+        assert_eq!(None, Detector::new(SpikeConfig::default()).finish());
+    }
+
+    #[test]
+    fn reference_adapts_to_slow_rises() {
+        // +20% per second is no spike for a reference adapting within a second
+        let ramp: Vec<usize> = (0..20).map(|i| (100.0 * 1.2_f64.powi(i)) as usize).collect();
+        let mut fast = Detector::new(SpikeConfig::default().reference_window(Duration::from_millis(100)));
+        assert!(feed(&mut fast, &ramp).is_empty());
+        assert_eq!(None, fast.finish());
+        // ... whereas it is one (still open, from 144 bytes on) for a reference
+        // which does not move
+        let mut slow = detector();
+        assert!(feed(&mut slow, &ramp).is_empty());
+        assert_eq!(Some(144), slow.finish().map(|report| report.start_usage));
+    }
+}
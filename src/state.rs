@@ -0,0 +1,248 @@
+//! Capturing and writing back the counters of the allocator (see
+//! `PeakAlloc::export_state`), e.g. to replay a test from a known state.
+//!
+//! Unlike `stats`, which only reads the counters, `import_state` overwrites
+//! them. The blob of `AllocatorState::to_bytes` is a version number followed
+//! by the counters, each of them as a little endian `u64`.
+
+use core::convert::TryFrom;
+use core::sync::atomic::Ordering;
+
+use crate::top::TOP_ALLOCATIONS;
+use crate::tracker::{clamp, Saved};
+use crate::{budget, large, overalign, slack, top, FAILED_ALLOCS, STARTUP, STARTUP_MARKED, TRACKER, WINDOW_START};
+
+/// The version of the layout of `AllocatorState::to_bytes`
+const VERSION: u64 = 2;
+/// The number of counters before the largest sizes
+const FIXED: usize = 24;
+/// The number of counters in a state
+const COUNTERS: usize = FIXED + TOP_ALLOCATIONS;
+
+/// All the counters of the allocator at some point: the usage, the peaks,
+/// the totals and the counts, those of the large and of the over-aligned
+/// allocations, the largest sizes, the external usage, the growth slack,
+/// the startup milestone and the start of the reporting window (see
+/// `PeakAlloc::export_state`). The statistics of the optional features (the
+/// histogram, the tags, the threads...) are not part of it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocatorState {
+    tracker: Saved,
+    failed: usize,
+    large: [usize; 3],
+    overaligned: (usize, usize),
+    external: usize,
+    slack: usize,
+    /// Whether the startup is marked, with its usage and allocation count
+    startup: (bool, [usize; 2]),
+    window: [usize; 3],
+    top: [usize; TOP_ALLOCATIONS],
+}
+
+impl AllocatorState {
+    /// The number of bytes of `to_bytes`
+    pub const SIZE: usize = 8 * (1 + COUNTERS);
+
+    /// Reads the counters, one after the other (this is not an atomic
+    /// snapshot)
+    pub(crate) fn capture() -> Self {
+        AllocatorState {
+            tracker: TRACKER.save(),
+            failed: FAILED_ALLOCS.load(Ordering::SeqCst),
+            large: large::counters(),
+            overaligned: (overalign::count(), overalign::bytes()),
+            external: budget::external_usage(),
+            slack: slack::bytes(),
+            startup: (
+                STARTUP_MARKED.load(Ordering::SeqCst),
+                [STARTUP[0].load(Ordering::SeqCst), STARTUP[1].load(Ordering::SeqCst)],
+            ),
+            window: [
+                WINDOW_START[0].load(Ordering::SeqCst),
+                WINDOW_START[1].load(Ordering::SeqCst),
+                WINDOW_START[2].load(Ordering::SeqCst),
+            ],
+            top: top::sizes(),
+        }
+    }
+    /// Writes the counters back, one after the other
+    pub(crate) fn apply(&self) {
+        TRACKER.overwrite(&self.tracker);
+        FAILED_ALLOCS.store(self.failed, Ordering::SeqCst);
+        large::set_counters(self.large);
+        overalign::set_counters(self.overaligned.0, self.overaligned.1);
+        budget::set_external(self.external);
+        slack::set_bytes(self.slack);
+        for (counter, &value) in STARTUP.iter().zip(self.startup.1.iter()) {
+            counter.store(value, Ordering::SeqCst);
+        }
+        STARTUP_MARKED.store(self.startup.0, Ordering::SeqCst);
+        for (counter, &value) in WINDOW_START.iter().zip(self.window.iter()) {
+            counter.store(value, Ordering::SeqCst);
+        }
+        top::set_sizes(&self.top);
+    }
+    /// Returns the number of bytes in use, as per this state
+    pub fn current_usage(&self) -> usize {
+        self.tracker.current
+    }
+    /// Returns the peak usage, as per this state
+    pub fn peak_usage(&self) -> usize {
+        self.tracker.peak
+    }
+    /// Returns the number of allocations, as per this state
+    pub fn allocation_count(&self) -> usize {
+        self.tracker.allocations
+    }
+    /// Serializes the state into a blob of `SIZE` bytes (see `from_bytes`)
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        let counters = self.counters();
+        let values = core::iter::once(VERSION).chain(counters.iter().map(|&counter| counter as u64));
+        for (chunk, value) in bytes.chunks_exact_mut(8).zip(values) {
+            chunk.copy_from_slice(&value.to_le_bytes());
+        }
+        bytes
+    }
+    /// Deserializes a state written by `to_bytes`. Returns None if the blob
+    /// was written by another version of the crate, or is not a state at all
+    /// (a counter beyond `usize`, a peak below the usage, the largest sizes
+    /// out of order...).
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != Self::SIZE {
+            return None;
+        }
+        let mut values = bytes.chunks_exact(8).map(|chunk| {
+            let mut value = [0; 8];
+            value.copy_from_slice(chunk);
+            u64::from_le_bytes(value)
+        });
+        if values.next() != Some(VERSION) {
+            return None;
+        }
+        let mut counters = [0; COUNTERS];
+        for (counter, value) in counters.iter_mut().zip(values) {
+            *counter = usize::try_from(value).ok()?;
+        }
+        Self::from_counters(&counters)
+    }
+    /// Returns the counters, in the order of `to_bytes`
+    fn counters(&self) -> [usize; COUNTERS] {
+        let t = &self.tracker;
+        let mut counters = [0; COUNTERS];
+        let (fixed, top) = counters.split_at_mut(FIXED);
+        fixed.copy_from_slice(&[
+            t.current,
+            t.peak,
+            t.all_time_peak,
+            t.total_allocated,
+            t.total_overflowed as usize,
+            t.allocations,
+            t.deallocations,
+            t.reallocations,
+            t.realloc_copied,
+            t.largest,
+            self.failed,
+            self.large[0],
+            self.large[1],
+            self.large[2],
+            self.overaligned.0,
+            self.overaligned.1,
+            self.external,
+            self.slack,
+            self.startup.0 as usize,
+            self.startup.1[0],
+            self.startup.1[1],
+            self.window[0],
+            self.window[1],
+            self.window[2],
+        ]);
+        top.copy_from_slice(&self.top);
+        counters
+    }
+    /// Returns the state holding the counters of `counters`, if it is valid
+    fn from_counters(counters: &[usize; COUNTERS]) -> Option<Self> {
+        let c = counters;
+        let mut top = [0; TOP_ALLOCATIONS];
+        top.copy_from_slice(&c[FIXED..]);
+        // the flags are booleans, and the peaks are above the usage
+        let (current, peak, all_time_peak) = (clamp(c[0]), c[1], c[2]);
+        if c[4] > 1 || c[18] > 1 || peak < current || all_time_peak < peak {
+            return None;
+        }
+        if top.windows(2).any(|pair| pair[0] < pair[1]) {
+            return None;
+        }
+        Some(AllocatorState {
+            tracker: Saved {
+                current: c[0],
+                peak: c[1],
+                all_time_peak: c[2],
+                total_allocated: c[3],
+                total_overflowed: c[4] == 1,
+                allocations: c[5],
+                deallocations: c[6],
+                reallocations: c[7],
+                realloc_copied: c[8],
+                largest: c[9],
+            },
+            failed: c[10],
+            large: [c[11], c[12], c[13]],
+            overaligned: (c[14], c[15]),
+            external: c[16],
+            slack: c[17],
+            startup: (c[18] == 1, [c[19], c[20]]),
+            window: [c[21], c[22], c[23]],
+            top,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AllocatorState, COUNTERS};
+
+    #[test]
+    fn the_blob_holds_all_the_counters() {
+        let mut counters = [0; COUNTERS];
+        for (i, counter) in counters.iter_mut().enumerate() {
+            *counter = 1000 - i;
+        }
+        // the peaks are above the usage, and the flags are booleans
+        (counters[0], counters[2]) = (990, 1000);
+        (counters[4], counters[18]) = (1, 1);
+        let state = AllocatorState::from_counters(&counters).unwrap();
+        assert_eq!(counters, state.counters());
+        let bytes = state.to_bytes();
+        assert_eq!(AllocatorState::SIZE, bytes.len());
+        assert_eq!(Some(state), AllocatorState::from_bytes(&bytes));
+        assert_eq!((990, 999, 995), (state.current_usage(), state.peak_usage(), state.allocation_count()));
+    }
+
+    #[test]
+    fn invalid_blobs_are_refused() {
+        let state = AllocatorState::from_counters(&[0; COUNTERS]).unwrap();
+        let bytes = state.to_bytes();
+        assert_eq!(None, AllocatorState::from_bytes(&bytes[1..]));
+        let mut version = bytes;
+        version[0] = 1;
+        assert_eq!(None, AllocatorState::from_bytes(&version));
+        let mut overflowed = bytes;
+        overflowed[8 * 5] = 2;
+        assert_eq!(None, AllocatorState::from_bytes(&overflowed));
+        let mut unordered = bytes;
+        unordered[AllocatorState::SIZE - 8] = 1;
+        assert_eq!(None, AllocatorState::from_bytes(&unordered));
+        let mut marked = bytes;
+        marked[8 * 19] = 2;
+        assert_eq!(None, AllocatorState::from_bytes(&marked));
+        // the peak (and the all time one) are never below the usage
+        let mut over_peak = bytes;
+        over_peak[8] = 1;
+        assert_eq!(None, AllocatorState::from_bytes(&over_peak));
+        over_peak[8 * 2] = 1;
+        assert_eq!(None, AllocatorState::from_bytes(&over_peak));
+        over_peak[8 * 3] = 1;
+        assert!(AllocatorState::from_bytes(&over_peak).is_some());
+    }
+}
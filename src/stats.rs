@@ -0,0 +1,50 @@
+//! A plain copy of the counters of the allocator, cheap to take and which
+//! never allocates.
+
+/// The version of the schema of the exported statistics: the fields of
+/// `stats_json` (its `"schema"` field) and of the influx lines (their
+/// `schema` field), and the entries of `MetricsBuffer`. It is bumped whenever
+/// a field is renamed, removed or reordered, or changes meaning (or an entry
+/// is appended to the buffer), so that the tools ingesting the exports
+/// notice.
+///
+/// * 1: the counters, up to `post_startup_peak`
+/// * 2: the `schema` field, and the `size_classes` of the JSON object (with
+///   the `histogram` feature)
+///
+/// The C struct has a version of its own, `PEAK_ALLOC_STATS_VERSION`: its
+/// fields are only ever appended.
+pub const STATS_SCHEMA_VERSION: u32 = 2;
+
+/// The counters of the allocator, as returned by `PeakAlloc::stats`. These
+/// are loaded one after the other (see `PeakAlloc::read_all`): this is not an
+/// atomic snapshot.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    /// Same as `PeakAlloc::current_usage`
+    pub current_usage: usize,
+    /// Same as `PeakAlloc::peak_usage`
+    pub peak_usage: usize,
+    /// Same as `PeakAlloc::all_time_peak_usage`
+    pub all_time_peak_usage: usize,
+    /// Same as `PeakAlloc::total_allocated`
+    pub total_allocated: usize,
+    /// Same as `PeakAlloc::allocation_count`
+    pub allocation_count: usize,
+    /// Same as `PeakAlloc::deallocation_count`
+    pub deallocation_count: usize,
+    /// Same as `PeakAlloc::largest_allocation`
+    pub largest_allocation: usize,
+    /// Same as `PeakAlloc::large_current_usage`
+    pub large_current_usage: usize,
+    /// Same as `PeakAlloc::large_peak_usage`
+    pub large_peak_usage: usize,
+    /// Same as `PeakAlloc::large_allocation_count`
+    pub large_allocation_count: usize,
+    /// Same as `PeakAlloc::external_usage`
+    pub external_usage: usize,
+    /// Same as `PeakAlloc::startup_usage`
+    pub startup_usage: Option<usize>,
+    /// Same as `PeakAlloc::post_startup_peak`
+    pub post_startup_peak: usize,
+}
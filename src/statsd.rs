@@ -0,0 +1,103 @@
+//! A statsd (DogStatsD flavored) exporter which periodically sends the
+//! statistics of the allocator over UDP.
+
+use std::io::Write;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::Duration;
+
+use crate::exporter::{self, ExporterHandle};
+use crate::periodic::Scratch;
+use crate::PeakAlloc;
+
+/// Renders the DogStatsD tag suffix (e.g. `|#env:prod,region:eu`)
+fn tag_suffix(tags: &[(&str, &str)]) -> String {
+    let mut suffix = String::new();
+    for (i, (key, value)) in tags.iter().enumerate() {
+        suffix.push_str(if i == 0 { "|#" } else { "," });
+        suffix.push_str(key);
+        suffix.push(':');
+        suffix.push_str(value);
+    }
+    suffix
+}
+
+/// Formats one datagram holding all the metrics in the given buffer
+pub(crate) fn format_datagram(
+    buffer: &mut Vec<u8>,
+    prefix: &str,
+    tags: &str,
+    (current, peak, allocations, deallocations): (usize, usize, usize, usize),
+) {
+    buffer.clear();
+    // writing to a vec cannot fail
+    let _ = writeln!(buffer, "{}.current:{}|g{}", prefix, current, tags);
+    let _ = writeln!(buffer, "{}.peak:{}|g{}", prefix, peak, tags);
+    let _ = writeln!(buffer, "{}.allocations:{}|c{}", prefix, allocations, tags);
+    let _ = write!(
+        buffer,
+        "{}.deallocations:{}|c{}",
+        prefix, deallocations, tags
+    );
+}
+
+/// Binds a non-blocking UDP socket and spawns the exporter thread
+pub(crate) fn start(
+    alloc: PeakAlloc,
+    addr: SocketAddr,
+    prefix: &str,
+    interval: Duration,
+    tags: &[(&str, &str)],
+) -> std::io::Result<ExporterHandle> {
+    let local: SocketAddr = if addr.is_ipv4() {
+        ([0, 0, 0, 0], 0).into()
+    } else {
+        ([0_u16; 8], 0).into()
+    };
+    let socket = UdpSocket::bind(local)?;
+    socket.set_nonblocking(true)?;
+
+    let prefix = prefix.to_string();
+    let tags = tag_suffix(tags);
+    // allocated once: formatting the datagrams does not allocate afterwards
+    let capacity = 4 * (prefix.len() + tags.len() + 48);
+    let mut buffer = Scratch::new();
+    let mut last = (alloc.allocation_count(), alloc.deallocation_count());
+
+    Ok(exporter::spawn("peak_alloc-statsd", interval, move || {
+        let counts = (alloc.allocation_count(), alloc.deallocation_count());
+        let metrics = (
+            alloc.current_usage(),
+            alloc.peak_usage(),
+            counts.0.wrapping_sub(last.0),
+            counts.1.wrapping_sub(last.1),
+        );
+        last = counts;
+        let buffer = buffer.get_or_init(|| Vec::with_capacity(capacity));
+        format_datagram(buffer, &prefix, &tags, metrics);
+        socket.send_to(buffer, addr).is_ok()
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_datagram, tag_suffix};
+
+    #[test]
+    fn datagrams_follow_the_dogstatsd_format() {
+        let mut buffer = Vec::new();
+        let tags = tag_suffix(&[("env", "test"), ("host", "ci")]);
+        format_datagram(&mut buffer, "app.heap", &tags, (10, 20, 3, 2));
+        let expected = "\
+app.heap.current:10|g|#env:test,host:ci
+app.heap.peak:20|g|#env:test,host:ci
+app.heap.allocations:3|c|#env:test,host:ci
+app.heap.deallocations:2|c|#env:test,host:ci";
+        assert_eq!(expected, std::str::from_utf8(&buffer).unwrap());
+
+        format_datagram(&mut buffer, "heap", &tag_suffix(&[]), (1, 2, 3, 4));
+        assert_eq!(
+            "heap.current:1|g\nheap.peak:2|g\nheap.allocations:3|c\nheap.deallocations:4|c",
+            std::str::from_utf8(&buffer).unwrap()
+        );
+    }
+}
@@ -0,0 +1,112 @@
+//! Detecting the allocation storms: bursts of allocations far above the
+//! usual pace, which often betray a bug (e.g. a loop reallocating a buffer
+//! on each iteration). See `PeakAlloc::on_allocation_storm`.
+//!
+//! The number of allocations within the sliding window is estimated from two
+//! consecutive fixed windows: the count of the current one, plus the count of
+//! the previous one weighted by the part of it the sliding window still
+//! covers. This only takes a handful of atomics, and the (coarse) clock is
+//! only read once every `STRIDE` allocations of a thread: a storm is noticed
+//! within `STRIDE` allocations per thread. A storm is reported once, when the
+//! estimate exceeds the threshold; the next one can only be reported after
+//! the estimate has fallen back below the threshold.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use core::time::Duration;
+
+use crate::events::{self, Event};
+use crate::{clock, threads, TRACKER};
+
+/// A thread checks for a storm once every this many allocations
+pub(crate) const STRIDE: usize = 64;
+
+/// The number of allocations within the window above which it is a storm (0
+/// disables the detection)
+static THRESHOLD: AtomicUsize = AtomicUsize::new(0);
+/// The length of the window (ns)
+static WINDOW: AtomicU64 = AtomicU64::new(0);
+/// The start of the current fixed window (ns, see `clock::now`)
+static START: AtomicU64 = AtomicU64::new(0);
+/// The allocation count when the current fixed window started
+static BASE: AtomicUsize = AtomicUsize::new(0);
+/// The number of allocations within the previous fixed window
+static PREVIOUS: AtomicUsize = AtomicUsize::new(0);
+/// Taken by the thread which rotates the fixed windows
+static ROTATING: AtomicBool = AtomicBool::new(false);
+/// Whether a storm is in progress (it has been reported already)
+static STORMING: AtomicBool = AtomicBool::new(false);
+
+/// Sets the threshold and the window of the detection (a threshold of 0
+/// disables it)
+pub(crate) fn configure(threshold: usize, window: Duration) {
+    THRESHOLD.store(0, Ordering::Relaxed);
+    WINDOW.store(window.as_nanos().clamp(1, u64::MAX as u128) as u64, Ordering::Relaxed);
+    START.store(clock::now(), Ordering::Relaxed);
+    BASE.store(TRACKER.allocation_count(), Ordering::Relaxed);
+    PREVIOUS.store(0, Ordering::Relaxed);
+    STORMING.store(false, Ordering::Relaxed);
+    THRESHOLD.store(threshold, Ordering::Relaxed);
+}
+
+/// Checks for a storm, once every `STRIDE` allocations of the calling thread
+#[inline]
+pub(crate) fn on_alloc() {
+    if THRESHOLD.load(Ordering::Relaxed) != 0 && threads::thread_allocations().is_multiple_of(STRIDE) {
+        check();
+    }
+}
+
+#[cold]
+fn check() {
+    let threshold = THRESHOLD.load(Ordering::Relaxed);
+    let window = WINDOW.load(Ordering::Relaxed);
+    let (now, count) = (clock::now(), TRACKER.allocation_count());
+    let mut start = START.load(Ordering::Relaxed);
+    if now.saturating_sub(start) >= window && !ROTATING.swap(true, Ordering::Acquire) {
+        start = START.load(Ordering::Relaxed);
+        let elapsed = now.saturating_sub(start);
+        if elapsed >= window {
+            let base = BASE.swap(count, Ordering::Relaxed);
+            // the previous window is only relevant if it just ended
+            let previous = if elapsed < 2 * window { count.wrapping_sub(base) } else { 0 };
+            PREVIOUS.store(previous, Ordering::Relaxed);
+            start = now - elapsed % window;
+            START.store(start, Ordering::Relaxed);
+        }
+        ROTATING.store(false, Ordering::Release);
+    }
+    let estimate = estimate(
+        PREVIOUS.load(Ordering::Relaxed),
+        count.wrapping_sub(BASE.load(Ordering::Relaxed)),
+        now.saturating_sub(start),
+        window,
+    );
+    if estimate < threshold {
+        STORMING.store(false, Ordering::Relaxed);
+    } else if !STORMING.swap(true, Ordering::Relaxed) {
+        events::emit(Event::AllocationStorm { count: estimate, window: Duration::from_nanos(window) });
+    }
+}
+
+/// Estimates the number of allocations within a sliding window which ends
+/// `elapsed` ns into the current fixed window, given the counts of the
+/// previous and of the current fixed windows
+pub(crate) fn estimate(previous: usize, current: usize, elapsed: u64, window: u64) -> usize {
+    let remaining = window.saturating_sub(elapsed) as u128;
+    let weighted = previous as u128 * remaining / window.max(1) as u128;
+    current.saturating_add(weighted as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::estimate;
+
+    #[test]
+    fn the_previous_window_fades_out() {
+        assert_eq!(1100, estimate(1000, 100, 0, 1000));
+        assert_eq!(600, estimate(1000, 100, 500, 1000));
+        assert_eq!(100, estimate(1000, 100, 1000, 1000));
+        assert_eq!(100, estimate(1000, 100, 5000, 1000));
+        assert_eq!(usize::MAX, estimate(usize::MAX, usize::MAX, 0, 1));
+    }
+}
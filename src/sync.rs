@@ -0,0 +1,111 @@
+//! Minimal synchronization primitives which are safe to use from within the
+//! allocator itself (they never allocate and never re-enter the allocator).
+//!
+//! # Lock ordering
+//! The spin locks (the shards of the pointer map, the quarantine queue, the
+//! ring of the over-aligned allocations) are leaves: no other lock is ever
+//! acquired while one of them is held. What runs under them never allocates
+//! through the tracking path (the blocks they release go straight to
+//! `System`) and never runs user code: the callbacks (event handlers, reset
+//! hooks, the out of memory report) are only invoked once every internal
+//! lock has been released. The memory limit is a plain atomic, checked
+//! before any of these locks is taken.
+//!
+//! In debug builds, a thread which tries to acquire a spin lock it already
+//! holds aborts with a message instead of spinning forever.
+
+use std::cell::UnsafeCell;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// A dead simple spin lock. It is only meant to protect the small amount of
+/// bookkeeping some of the optional features need to perform on the
+/// allocation path, where regular locks might not be an option.
+pub(crate) struct SpinLock<T> {
+    locked: AtomicBool,
+    /// The id (see `threads::current_id`) of the thread holding the lock
+    #[cfg(debug_assertions)]
+    owner: std::sync::atomic::AtomicUsize,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+
+impl<T> SpinLock<T> {
+    pub(crate) const fn new(value: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            #[cfg(debug_assertions)]
+            owner: std::sync::atomic::AtomicUsize::new(0),
+            value: UnsafeCell::new(value),
+        }
+    }
+    /// Spins until the lock is acquired
+    pub(crate) fn lock(&self) -> SpinGuard<'_, T> {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            #[cfg(debug_assertions)]
+            self.check_reentrancy();
+            while self.locked.load(Ordering::Relaxed) {
+                std::hint::spin_loop();
+            }
+        }
+        #[cfg(debug_assertions)]
+        self.owner.store(crate::threads::current_id(), Ordering::Relaxed);
+        SpinGuard { lock: self }
+    }
+    /// Aborts if the calling thread already holds the lock: it would spin
+    /// forever otherwise. Threads whose id is gone (they are exiting) are not
+    /// told apart, hence not checked.
+    #[cfg(debug_assertions)]
+    #[cold]
+    fn check_reentrancy(&self) {
+        let me = crate::threads::current_id();
+        if me != usize::MAX && self.owner.load(Ordering::Relaxed) == me {
+            use std::io::Write as _;
+            let _ = std::io::stderr().write_all(b"peak_alloc: re-entrant spin lock acquisition, aborting\n");
+            std::process::abort();
+        }
+    }
+}
+
+/// The guard releasing a `SpinLock` when it goes out of scope.
+pub(crate) struct SpinGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+}
+
+impl<T> Deref for SpinGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+impl<T> DerefMut for SpinGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+impl<T> Drop for SpinGuard<'_, T> {
+    fn drop(&mut self) {
+        #[cfg(debug_assertions)]
+        self.lock.owner.store(0, Ordering::Relaxed);
+        self.lock.locked.store(false, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SpinLock;
+
+    #[test]
+    fn a_released_lock_can_be_acquired_again_by_the_same_thread() {
+        let lock = SpinLock::new(0);
+        for _ in 0..3 {
+            *lock.lock() += 1;
+        }
+        assert_eq!(3, *lock.lock());
+    }
+}
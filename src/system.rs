@@ -0,0 +1,243 @@
+//! The implementation of `GlobalAlloc` for `PeakAlloc`, on top of the system
+//! allocator. Along with `TrackingAlloc` (and the debugging features which
+//! need to write into the blocks), this is where the `unsafe` code of the
+//! crate lives: the rest of it, among which the whole statistics API, is
+//! compiled under `#![deny(unsafe_code)]`.
+
+use core::alloc::{GlobalAlloc, Layout};
+#[cfg(feature = "checked-layout")]
+use core::sync::atomic::{AtomicUsize, Ordering};
+use std::alloc::System;
+
+#[cfg(feature = "poison")]
+use crate::poison;
+#[cfg(feature = "quarantine")]
+use crate::quarantine;
+#[cfg(feature = "redzones")]
+use crate::redzones;
+#[cfg(feature = "actual-size")]
+use crate::usable;
+use crate::config;
+use crate::entry_points::{self, EntryPoint};
+use crate::{
+    check_frozen, failed, growth, is_tracked, track_alloc, track_dealloc, track_realloc, within_limit, PeakAlloc,
+};
+
+/// True iff the blocks are reallocated by copy (see `PeakAlloc::realloc`)
+pub(crate) const REALLOC_BY_COPY: bool =
+    cfg!(any(feature = "redzones", feature = "quarantine", feature = "poison", feature = "zeroize-on-free"));
+
+/// PeakAlloc only implements the minimum required set of methods to make it
+/// useable as a global allocator (with `#[global_allocator]` attribute), plus
+/// `alloc_zeroed` so that zeroed blocks are obtained from the system as such
+/// and `realloc` so that the blocks can be resized in place.
+///
+/// No funky stuff is done below.
+unsafe impl GlobalAlloc for PeakAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        #[cfg(feature = "checked-layout")]
+        if layout.size() == 0 {
+            return reject();
+        }
+        allocate(layout, false)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        #[cfg(feature = "checked-layout")]
+        if layout.size() == 0 {
+            return reject();
+        }
+        allocate(layout, true)
+    }
+
+    /// Every outcome is accounted for in a single place: a request which is
+    /// rejected, refused by the limit or failed by the system allocator leaves
+    /// the block (and all the counters but the failures) untouched, whereas a
+    /// successful one is accounted for once the block has been resized.
+    ///
+    /// With the debugging features which see the blocks one by one
+    /// (`redzones`, `quarantine`, `poison` and `zeroize-on-free`), the block
+    /// is never resized in place: a new one is allocated, the contents are
+    /// copied and the old one is released. Such a reallocation by copy is
+    /// accounted for as an allocation and a deallocation.
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        #[cfg(feature = "checked-layout")]
+        if new_size == 0 || new_size > isize::MAX as usize - (layout.align() - 1) {
+            return reject();
+        }
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        if REALLOC_BY_COPY {
+            return realloc_by_copy(self, ptr, layout, new_layout);
+        }
+        check_frozen();
+        let limits = config::limits();
+        let growth = growth(&limits, layout.size(), new_size);
+        if growth > 0 && !within_limit(&limits, growth) {
+            return failed();
+        }
+        // queried first: the system may release the block
+        #[cfg(feature = "actual-size")]
+        let usable = if is_tracked(&limits, layout.size()) { Some(usable::peek(ptr, layout)) } else { None };
+        let new_ptr = System.realloc(ptr, layout, new_size);
+        if new_ptr.is_null() {
+            // the original block is left untouched
+            return failed();
+        }
+        #[allow(unused_mut)]
+        let (mut charged, mut new_charged) = (layout, new_layout);
+        #[cfg(feature = "actual-size")]
+        {
+            if let Some(usable) = usable {
+                charged = usable::on_released(layout, layout, usable);
+            }
+            if is_tracked(&limits, new_size) {
+                new_charged = usable::on_alloc(new_ptr, new_layout, new_layout);
+            }
+        }
+        if entry_points::is_tracked(EntryPoint::Realloc) {
+            track_realloc(ptr, charged, new_ptr, new_charged, &limits);
+        }
+        new_ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let (size, tracked_ptr) = (layout.size(), ptr);
+        #[allow(unused_mut)]
+        let mut charged = layout;
+        let limits = config::limits();
+        #[cfg(feature = "poison")]
+        poison::on_free(ptr, size);
+        #[cfg(feature = "redzones")]
+        let (ptr, layout) = redzones::unwrap(ptr, layout);
+        #[cfg(feature = "actual-size")]
+        if is_tracked(&limits, size) {
+            charged = usable::on_dealloc(ptr, layout, charged);
+        }
+        // accounted for once the usable size of the (unwrapped) block is known
+        if is_tracked(&limits, size) && entry_points::is_tracked(EntryPoint::Dealloc) {
+            track_dealloc(tracked_ptr, charged, &limits);
+        }
+        #[cfg(feature = "quarantine")]
+        quarantine::park(ptr, layout);
+        #[cfg(all(feature = "zeroize-on-free", not(feature = "quarantine")))]
+        crate::zeroize::wipe(ptr, layout.size());
+        #[cfg(not(feature = "quarantine"))]
+        System.dealloc(ptr, layout);
+    }
+}
+
+/// A reference to the allocator is an allocator as well, e.g. for the APIs
+/// taking an allocator by value
+unsafe impl GlobalAlloc for &PeakAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        PeakAlloc.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        PeakAlloc.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        PeakAlloc.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        PeakAlloc.dealloc(ptr, layout)
+    }
+}
+
+/// Reallocates a block by allocating a new one, copying and releasing the old
+/// one (which the debugging features need to see the blocks one by one): the
+/// accounting is that of the allocation and of the deallocation. Upon failure,
+/// the old block is left untouched (the allocation counted the failure).
+#[inline]
+unsafe fn realloc_by_copy(alloc: &PeakAlloc, ptr: *mut u8, layout: Layout, new_layout: Layout) -> *mut u8 {
+    let new_ptr = alloc.alloc(new_layout);
+    if !new_ptr.is_null() {
+        let copied = layout.size().min(new_layout.size());
+        core::ptr::copy_nonoverlapping(ptr, new_ptr, copied);
+        alloc.dealloc(ptr, layout);
+        if is_tracked(&config::limits(), new_layout.size()) && entry_points::is_tracked(EntryPoint::Realloc) {
+            crate::TRACKER.count_realloc_copy(copied);
+        }
+    }
+    new_ptr
+}
+
+/// The number of requests rejected because they did not describe a valid
+/// (non zero-sized) layout
+#[cfg(feature = "checked-layout")]
+static INVALID_REQUESTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Counts a rejected request (as an invalid one, and as a failed one) and
+/// fails it
+#[cfg(feature = "checked-layout")]
+#[cold]
+fn reject() -> *mut u8 {
+    INVALID_REQUESTS.fetch_add(1, Ordering::Relaxed);
+    failed()
+}
+
+/// Returns the number of requests rejected so far
+#[cfg(feature = "checked-layout")]
+pub(crate) fn invalid_requests() -> usize {
+    INVALID_REQUESTS.load(Ordering::Relaxed)
+}
+
+/// Obtains a block that fits the given layout from the system allocator
+/// (zeroed if so requested) and accounts for it.
+#[inline]
+unsafe fn allocate(layout: Layout, zeroed: bool) -> *mut u8 {
+    check_frozen();
+    let limits = config::limits();
+    if is_tracked(&limits, layout.size()) && !within_limit(&limits, layout.size()) {
+        return failed();
+    }
+    #[allow(unused_mut)]
+    let mut charged = layout;
+    #[cfg(feature = "redzones")]
+    let ret = match redzones::outer_layout(layout) {
+        None => std::ptr::null_mut(),
+        Some(outer) => {
+            let ptr = system_alloc(outer, zeroed);
+            if ptr.is_null() {
+                ptr
+            } else {
+                #[cfg(feature = "actual-size")]
+                if is_tracked(&limits, layout.size()) {
+                    charged = usable::on_alloc(ptr, outer, layout);
+                }
+                redzones::wrap(ptr, layout)
+            }
+        }
+    };
+    #[cfg(not(feature = "redzones"))]
+    let ret = system_alloc(layout, zeroed);
+    #[cfg(all(feature = "actual-size", not(feature = "redzones")))]
+    if !ret.is_null() && is_tracked(&limits, layout.size()) {
+        charged = usable::on_alloc(ret, layout, layout);
+    }
+    if !ret.is_null() {
+        #[cfg(feature = "poison")]
+        if !zeroed {
+            poison::on_alloc(ret, layout.size());
+        }
+        let entry = if zeroed { EntryPoint::AllocZeroed } else { EntryPoint::Alloc };
+        if is_tracked(&limits, layout.size()) && entry_points::is_tracked(entry) {
+            track_alloc(ret, charged, &limits);
+        }
+    } else {
+        failed();
+    }
+    ret
+}
+
+/// Obtains a (zeroed if so requested) block from the system allocator
+#[inline]
+unsafe fn system_alloc(layout: Layout, zeroed: bool) -> *mut u8 {
+    if zeroed {
+        System.alloc_zeroed(layout)
+    } else {
+        System.alloc(layout)
+    }
+}
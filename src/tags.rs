@@ -0,0 +1,260 @@
+//! Attributing the memory to tags: static labels (e.g. the path of a module)
+//! which a thread enters for the duration of a call. While a thread is within
+//! a tag, what it allocates and releases is counted for that tag.
+//!
+//! Unlike the spans, the tags need no handle of their own: each call site owns
+//! a `static Tag`, which resolves (once) its name to one of the `TAG_SLOTS`
+//! slots of a table. Entering a tag is then a thread local write, and the
+//! allocation path only updates the counters of the current slot. The
+//! `#[instrument_module]` attribute declares these statics and enters the
+//! tags in all the public functions of a module, the futures of the async
+//! ones included (see `Tag::instrument`).
+
+use core::fmt;
+use core::future::Future;
+use core::marker::PhantomData;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use std::cell::Cell;
+use std::sync::atomic::{AtomicIsize, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// The maximum number of distinct tags
+pub const TAG_SLOTS: usize = 64;
+
+/// The counters of a tag
+struct Counters {
+    allocations: AtomicUsize,
+    allocated: AtomicUsize,
+    net: AtomicIsize,
+    peak: AtomicUsize,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: Counters = Counters {
+    allocations: AtomicUsize::new(0),
+    allocated: AtomicUsize::new(0),
+    net: AtomicIsize::new(0),
+    peak: AtomicUsize::new(0),
+};
+
+static COUNTERS: [Counters; TAG_SLOTS] = [ZERO; TAG_SLOTS];
+/// The names of the tags, by slot. It is only locked when a tag resolves its
+/// slot, and to read the usage.
+static NAMES: Mutex<[Option<&'static str>; TAG_SLOTS]> = Mutex::new([None; TAG_SLOTS]);
+
+/// The slot of a tag has not been resolved (yet)
+const UNRESOLVED: usize = 0;
+/// The tag did not get a slot: the table is full
+const NO_SLOT: usize = usize::MAX;
+
+thread_local! {
+    /// 1 + the slot of the tag this thread is within (0 if none)
+    static CURRENT: Cell<usize> = const { Cell::new(0) };
+}
+
+/// A tag the memory can be attributed to. It is meant to be a `static`, so
+/// that its slot is only resolved once:
+///
+/// ```
+/// use peak_alloc::{PeakAlloc, Tag};
+///
+/// #[global_allocator]
+/// static PEAK_ALLOC: PeakAlloc = PeakAlloc;
+///
+/// static PARSER: Tag = Tag::new("parser");
+///
+/// let data = {
+///     let _guard = PARSER.enter();
+///     vec![0_u8; 4096]
+/// };
+/// let usage = PEAK_ALLOC.tag_usage();
+/// let parser = usage.iter().find(|usage| usage.tag == "parser").unwrap();
+/// assert!(parser.net_bytes >= 4096);
+/// # drop(data);
+/// ```
+///
+/// Distinct statics with the same name share the same counters. Once
+/// `TAG_SLOTS` names have been seen, entering a new one attributes nothing.
+#[derive(Debug)]
+pub struct Tag {
+    name: &'static str,
+    /// 1 + the slot, or UNRESOLVED, or NO_SLOT
+    slot: AtomicUsize,
+}
+
+impl Tag {
+    /// Creates a tag with the given name
+    pub const fn new(name: &'static str) -> Self {
+        Tag { name, slot: AtomicUsize::new(UNRESOLVED) }
+    }
+    /// Returns the name of this tag
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+    /// Attributes the memory the calling thread allocates and releases to
+    /// this tag, until the guard is dropped (the enclosing tag, if any, is
+    /// then restored)
+    pub fn enter(&self) -> TagGuard {
+        let slot = match self.slot.load(Ordering::Relaxed) {
+            UNRESOLVED => self.resolve(),
+            slot => slot,
+        };
+        let current = if slot == NO_SLOT { 0 } else { slot };
+        let previous = CURRENT.try_with(|tag| tag.replace(current)).unwrap_or(0);
+        TagGuard { previous, _not_send: PhantomData }
+    }
+    /// Wraps a future so that this tag is entered whenever it is polled: what
+    /// it allocates is attributed to the tag, whichever thread polls it, and
+    /// what the other tasks allocate in between is not.
+    pub fn instrument<F: Future>(&self, future: F) -> Tagged<'_, F> {
+        Tagged { tag: self, future }
+    }
+    /// Looks the slot of this tag up (claiming a free one if needed)
+    #[cold]
+    fn resolve(&self) -> usize {
+        let mut names = NAMES.lock().unwrap_or_else(|e| e.into_inner());
+        let slot = match names.iter().position(|name| *name == Some(self.name)) {
+            Some(index) => index + 1,
+            None => match names.iter().position(Option::is_none) {
+                Some(index) => {
+                    names[index] = Some(self.name);
+                    index + 1
+                }
+                None => NO_SLOT,
+            },
+        };
+        self.slot.store(slot, Ordering::Relaxed);
+        slot
+    }
+}
+
+/// The guard restoring the enclosing tag when it goes out of scope. It must
+/// be dropped on the thread which entered the tag.
+#[derive(Debug)]
+#[must_use = "the tag is exited as soon as the guard is dropped"]
+pub struct TagGuard {
+    previous: usize,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Drop for TagGuard {
+    fn drop(&mut self) {
+        let _ = CURRENT.try_with(|tag| tag.set(self.previous));
+    }
+}
+
+/// A future which enters a tag whenever it is polled (see `Tag::instrument`)
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct Tagged<'a, F> {
+    tag: &'a Tag,
+    future: F,
+}
+
+impl<F: Future> Future for Tagged<'_, F> {
+    type Output = F::Output;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        // SAFETY: the future is pinned along with `self`: it is never moved
+        // out of it (and `Tagged` is only `Unpin` if the future is)
+        let this = unsafe { self.get_unchecked_mut() };
+        let _guard = this.tag.enter();
+        unsafe { Pin::new_unchecked(&mut this.future) }.poll(cx)
+    }
+}
+
+/// Returns the counters of the tag the calling thread is within, if any
+#[inline]
+fn current() -> Option<&'static Counters> {
+    match current_slot() {
+        0 => None,
+        slot => Some(&COUNTERS[slot - 1]),
+    }
+}
+
+/// Returns 1 + the slot of the tag the calling thread is within (0 if none)
+#[inline]
+pub(crate) fn current_slot() -> usize {
+    CURRENT.try_with(Cell::get).unwrap_or(0)
+}
+
+/// Returns the name of the tag of the given slot (as per `current_slot`)
+pub(crate) fn name_of(slot: usize) -> Option<&'static str> {
+    let index = slot.checked_sub(1)?;
+    NAMES.lock().unwrap_or_else(|e| e.into_inner())[index]
+}
+
+/// Counts an allocation for the current tag (if any)
+#[inline]
+pub(crate) fn on_alloc() {
+    if let Some(counters) = current() {
+        counters.allocations.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Accounts for `delta` more bytes being used in the current tag (if any)
+#[inline]
+pub(crate) fn on_grow(delta: usize) {
+    if let Some(counters) = current() {
+        counters.allocated.fetch_add(delta, Ordering::Relaxed);
+        let net = counters.net.fetch_add(delta as isize, Ordering::Relaxed).wrapping_add(delta as isize);
+        counters.peak.fetch_max(net.max(0) as usize, Ordering::Relaxed);
+    }
+}
+
+/// Accounts for `delta` less bytes being used in the current tag (if any).
+/// What a tag releases may have been allocated within another one: the net
+/// of a tag can be negative.
+#[inline]
+pub(crate) fn on_shrink(delta: usize) {
+    if let Some(counters) = current() {
+        counters.net.fetch_sub(delta as isize, Ordering::Relaxed);
+    }
+}
+
+/// The memory attributed to a tag (see `PeakAlloc::tag_usage`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TagUsage {
+    /// The name of the tag
+    pub tag: &'static str,
+    /// The number of allocations performed within the tag
+    pub allocations: usize,
+    /// The number of bytes allocated within the tag (the growth of the
+    /// reallocated blocks included)
+    pub allocated_bytes: usize,
+    /// The bytes allocated minus the bytes released within the tag
+    pub net_bytes: isize,
+    /// The highest net number of bytes of the tag so far
+    pub peak_bytes: usize,
+}
+
+impl fmt::Display for TagUsage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} allocations, {} B allocated, {} B net",
+            self.tag, self.allocations, self.allocated_bytes, self.net_bytes
+        )
+    }
+}
+
+/// Returns the usage of all the tags seen so far, sorted by name
+pub(crate) fn usage() -> Vec<TagUsage> {
+    // copied first: collecting allocates, possibly within a tag
+    let names = *NAMES.lock().unwrap_or_else(|e| e.into_inner());
+    let mut usage: Vec<TagUsage> = names
+        .iter()
+        .zip(COUNTERS.iter())
+        .filter_map(|(name, counters)| {
+            name.map(|tag| TagUsage {
+                tag,
+                allocations: counters.allocations.load(Ordering::Relaxed),
+                allocated_bytes: counters.allocated.load(Ordering::Relaxed),
+                net_bytes: counters.net.load(Ordering::Relaxed),
+                peak_bytes: counters.peak.load(Ordering::Relaxed),
+            })
+        })
+        .collect();
+    usage.sort_by(|a, b| a.tag.cmp(b.tag));
+    usage
+}
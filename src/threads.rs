@@ -0,0 +1,201 @@
+//! Identifying the thread which performs an allocation. `std::thread::current`
+//! cannot be used from within the allocator (it may allocate itself): the
+//! address of a thread local variable is used instead. It is unique among
+//! the live threads, but it may be reused once a thread has exited.
+//!
+//! The ids handed out to the users (in the records and the events) are the
+//! fast ids instead: small numbers assigned to the threads in turn, which
+//! are never reused.
+//!
+//! The allocations the crate makes for itself (see `internal`) are told
+//! apart per thread as well.
+
+use std::cell::Cell;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+thread_local! {
+    /// A one byte thread local: its address identifies the thread. It has
+    /// a const initializer and no destructor, hence accessing it never
+    /// allocates and never fails (not even while the thread is exiting).
+    static MARKER: u8 = const { 0 };
+    /// The number of `ForbidAllocGuard` alive on this thread
+    static FORBIDDEN: Cell<usize> = const { Cell::new(0) };
+    /// The number of allocations this thread performed while forbidden
+    static FORBIDDEN_ALLOCS: Cell<usize> = const { Cell::new(0) };
+    /// The number of allocations this thread performed
+    static THREAD_ALLOCS: Cell<usize> = const { Cell::new(0) };
+    /// The fast id of this thread (0 until it is assigned)
+    static FAST_ID: Cell<u32> = const { Cell::new(0) };
+    /// The number of `InternalGuard` alive on this thread
+    static INTERNAL: Cell<usize> = const { Cell::new(0) };
+}
+
+/// The fast id of the next thread
+static NEXT_FAST_ID: AtomicU32 = AtomicU32::new(1);
+
+/// The id of the thread marked as the main one (0 means none)
+static MAIN_THREAD: AtomicUsize = AtomicUsize::new(0);
+/// The number of allocations performed by the main thread
+static MAIN_THREAD_ALLOCS: AtomicUsize = AtomicUsize::new(0);
+/// The number of allocations performed by all the other threads
+static OTHER_ALLOCS: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns an id of the calling thread (non zero)
+#[inline]
+pub(crate) fn current_id() -> usize {
+    MARKER.try_with(|marker| marker as *const u8 as usize).unwrap_or(usize::MAX)
+}
+
+/// Returns the fast id of the calling thread: a small number (from 1 on),
+/// stable within the thread and never reused by another one. It is assigned
+/// upon the first call of each thread, at the cost of a single `fetch_add`
+/// on a global counter: this is the only per thread cost, and it does not
+/// allocate. Returns 0 once the thread local storage of the thread has been
+/// torn down.
+#[inline]
+pub(crate) fn fast_thread_id() -> u32 {
+    FAST_ID
+        .try_with(|id| match id.get() {
+            0 => assign_fast_id(id),
+            id => id,
+        })
+        .unwrap_or(0)
+}
+
+#[cold]
+fn assign_fast_id(id: &Cell<u32>) -> u32 {
+    let fast = NEXT_FAST_ID.fetch_add(1, Ordering::Relaxed);
+    id.set(fast);
+    fast
+}
+
+/// Marks the calling thread as the main one
+pub(crate) fn mark_main_thread() {
+    MAIN_THREAD.store(current_id(), Ordering::Relaxed);
+}
+
+/// Counts one allocation of the calling thread, attributes it to the main
+/// thread or to the others (nothing is counted until a main thread has been
+/// marked), and counts it as a violation if allocating is currently
+/// forbidden on this thread.
+#[inline]
+pub(crate) fn on_alloc() {
+    let _ = THREAD_ALLOCS.try_with(|count| count.set(count.get() + 1));
+    check_forbidden();
+    let main = MAIN_THREAD.load(Ordering::Relaxed);
+    if main == 0 {
+        return;
+    }
+    let counter = if current_id() == main { &MAIN_THREAD_ALLOCS } else { &OTHER_ALLOCS };
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts a violation if allocating (or reallocating) is currently forbidden
+/// on this thread
+#[inline]
+pub(crate) fn check_forbidden() {
+    let _ = FORBIDDEN.try_with(|forbidden| {
+        if forbidden.get() > 0 {
+            FORBIDDEN_ALLOCS.with(|count| count.set(count.get() + 1));
+        }
+    });
+}
+
+/// Returns the number of allocations performed by the calling thread
+pub(crate) fn thread_allocations() -> usize {
+    THREAD_ALLOCS.try_with(Cell::get).unwrap_or(0)
+}
+
+/// Returns the number of allocations performed by the main thread
+pub(crate) fn main_thread_allocations() -> usize {
+    MAIN_THREAD_ALLOCS.load(Ordering::Relaxed)
+}
+
+/// Returns the number of allocations performed by the other threads
+pub(crate) fn other_thread_allocations() -> usize {
+    OTHER_ALLOCS.load(Ordering::Relaxed)
+}
+
+/// Returns true iff the calling thread is allocating for the crate itself
+/// (see `internal`)
+#[inline]
+pub(crate) fn is_internal() -> bool {
+    INTERNAL.try_with(|internal| internal.get() > 0).unwrap_or(false)
+}
+
+/// Marks the allocations of the calling thread as internal until the guard
+/// is dropped: they are accounted for nowhere (not even in the memory
+/// limit), so that the machinery of the crate (the registered handlers, the
+/// names of the threads...) does not pollute the counters it maintains. The
+/// guards can be nested.
+///
+/// A block allocated while internal must be released while internal as
+/// well (or never), lest its release is accounted for.
+pub(crate) fn internal() -> InternalGuard {
+    INTERNAL.with(|internal| internal.set(internal.get() + 1));
+    InternalGuard { _not_send: std::marker::PhantomData }
+}
+
+/// Runs `f` with the allocations of the calling thread accounted for, even
+/// if it is internal: the callbacks of the application, which the threads of
+/// the crate call, allocate on its behalf.
+pub(crate) fn accounted<T>(f: impl FnOnce() -> T) -> T {
+    /// Puts the depth back, even if `f` panics
+    struct Restore(usize);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            let _ = INTERNAL.try_with(|internal| internal.set(self.0));
+        }
+    }
+    let _restore = Restore(INTERNAL.with(|internal| internal.replace(0)));
+    f()
+}
+
+/// Marks the allocations of a thread as internal (see `internal`)
+pub(crate) struct InternalGuard {
+    /// The guard is bound to the thread which created it
+    _not_send: std::marker::PhantomData<*const ()>,
+}
+
+impl Drop for InternalGuard {
+    fn drop(&mut self) {
+        let _ = INTERNAL.try_with(|internal| internal.set(internal.get() - 1));
+    }
+}
+
+/// Forbids the current thread to allocate until it is dropped (see
+/// `PeakAlloc::forbid_alloc`). The guards can be nested.
+#[must_use = "allocating is only forbidden for as long as the guard is alive"]
+pub struct ForbidAllocGuard {
+    /// The number of violations when the guard was created
+    before: usize,
+    /// The guard is bound to the thread which created it
+    _not_send: std::marker::PhantomData<*const ()>,
+}
+
+impl ForbidAllocGuard {
+    pub(crate) fn new() -> Self {
+        FORBIDDEN.with(|forbidden| forbidden.set(forbidden.get() + 1));
+        ForbidAllocGuard {
+            before: FORBIDDEN_ALLOCS.with(Cell::get),
+            _not_send: std::marker::PhantomData,
+        }
+    }
+    /// Returns the number of allocations performed by this thread since the
+    /// guard was created
+    pub fn violations(&self) -> usize {
+        FORBIDDEN_ALLOCS.with(Cell::get) - self.before
+    }
+}
+
+impl Drop for ForbidAllocGuard {
+    /// Panics if the thread has allocated while the guard was alive (unless
+    /// it is already panicking)
+    fn drop(&mut self) {
+        FORBIDDEN.with(|forbidden| forbidden.set(forbidden.get() - 1));
+        let violations = self.violations();
+        if violations > 0 && !std::thread::panicking() {
+            panic!("{} allocation(s) performed while allocating was forbidden", violations);
+        }
+    }
+}
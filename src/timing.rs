@@ -0,0 +1,106 @@
+//! This module measures how long the accounting itself takes. It is a
+//! maintainer-facing diagnostic meant to validate the "low overhead" claim of
+//! the crate on various platforms; it should never be enabled in production.
+//!
+//! # Note
+//! Reading the clock typically costs more than the accounting it measures.
+//! The absolute figures are hence an upper bound; they are mostly useful to
+//! compare platforms or implementations against one another.
+
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use crate::sampling::Ticker;
+
+/// The number of buckets of the latency histogram. Bucket 0 counts the
+/// measurements of 0ns, and bucket `i > 0` counts the measurements in the
+/// range [2^(i-1), 2^i) ns. The last bucket also holds all longer ones.
+pub const LATENCY_BUCKETS: usize = 32;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicUsize = AtomicUsize::new(0);
+/// The latency histogram of the accounting operations
+static HISTOGRAM: [AtomicUsize; LATENCY_BUCKETS] = [ZERO; LATENCY_BUCKETS];
+
+/// Decides which accounting operations are measured
+pub(crate) static TICKER: Ticker = Ticker::new();
+
+/// Records one measurement in the histogram
+#[inline]
+pub(crate) fn record(elapsed: Duration) {
+    let nanos = elapsed.as_nanos().min(u64::MAX as u128) as u64;
+    let bucket = (64 - nanos.leading_zeros() as usize).min(LATENCY_BUCKETS - 1);
+    HISTOGRAM[bucket].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns a copy of the current latency histogram
+pub(crate) fn snapshot() -> AccountingLatency {
+    let mut buckets = [0; LATENCY_BUCKETS];
+    for (count, bucket) in buckets.iter_mut().zip(HISTOGRAM.iter()) {
+        *count = bucket.load(Ordering::Relaxed);
+    }
+    AccountingLatency { buckets }
+}
+
+/// Clears the latency histogram
+pub(crate) fn reset() {
+    for bucket in HISTOGRAM.iter() {
+        bucket.store(0, Ordering::Relaxed);
+    }
+}
+
+/// A copy of the latency histogram of the accounting operations, as returned
+/// by `PeakAlloc::timed_accounting`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AccountingLatency {
+    /// The number of measurements which fell in each bucket
+    pub buckets: [usize; LATENCY_BUCKETS],
+}
+
+impl AccountingLatency {
+    /// Returns the total number of measurements
+    pub fn count(&self) -> usize {
+        self.buckets.iter().sum()
+    }
+    /// Returns the (exclusive) upper bound of the given bucket
+    pub fn upper_bound(bucket: usize) -> Duration {
+        Duration::from_nanos(1_u64 << bucket.min(63))
+    }
+    /// Returns an upper bound on the given quantile (between 0 and 1) of the
+    /// measured latencies, or None if nothing was measured.
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        let target = ((count as f64) * q.clamp(0.0, 1.0)).ceil().max(1.0) as usize;
+        let mut seen = 0;
+        for (bucket, n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= target {
+                return Some(Self::upper_bound(bucket));
+            }
+        }
+        Some(Self::upper_bound(LATENCY_BUCKETS - 1))
+    }
+}
+
+impl fmt::Display for AccountingLatency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let count = self.count();
+        writeln!(f, "accounting latency ({} measurements)", count)?;
+        for (bucket, n) in self.buckets.iter().enumerate().filter(|(_, n)| **n > 0) {
+            let low = if bucket == 0 { 0 } else { 1_u64 << (bucket - 1) };
+            writeln!(
+                f,
+                "  {:>10} .. {:>10} ns: {:>10} ({:5.1}%)",
+                low,
+                Self::upper_bound(bucket).as_nanos(),
+                n,
+                100.0 * *n as f64 / count as f64
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,111 @@
+//! The largest allocation sizes seen so far, beyond the single largest one:
+//! a handful of bloated buffers stands out among them.
+//!
+//! The sizes are kept in a small array sorted in decreasing order. An
+//! allocation which does not rank (the common case) costs a single load. One
+//! which ranks is inserted by compare-and-swap, pushing the smaller sizes
+//! down the array. Each swap only ever raises a slot, hence an insertion
+//! always completes, and the sizes it carries down are never lost: while
+//! insertions are underway, a reader may see a size twice or miss one, but
+//! the array holds the largest sizes, in order, as soon as they complete.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// The number of sizes returned by `PeakAlloc::top_allocations`
+pub const TOP_ALLOCATIONS: usize = 8;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicUsize = AtomicUsize::new(0);
+
+/// The largest allocation sizes, in decreasing order (0 in the free slots)
+static TOP: [AtomicUsize; TOP_ALLOCATIONS] = [ZERO; TOP_ALLOCATIONS];
+
+/// Accounts for an allocation of `size` bytes
+#[inline]
+pub(crate) fn on_alloc(size: usize) {
+    insert(&TOP, size)
+}
+
+/// Inserts `size` in `top`, if it ranks
+#[inline]
+fn insert<const N: usize>(top: &[AtomicUsize; N], size: usize) {
+    if size > top[N - 1].load(Ordering::Relaxed) {
+        rank(top, size);
+    }
+}
+
+#[cold]
+fn rank<const N: usize>(top: &[AtomicUsize; N], mut size: usize) {
+    for slot in top {
+        let mut current = slot.load(Ordering::Relaxed);
+        while size > current {
+            match slot.compare_exchange_weak(current, size, Ordering::Relaxed, Ordering::Relaxed) {
+                // the displaced size goes on down
+                Ok(_) => size = current,
+                Err(actual) => current = actual,
+            }
+        }
+        if size == 0 {
+            return;
+        }
+    }
+}
+
+/// Returns the largest allocation sizes, in decreasing order
+pub(crate) fn sizes() -> [usize; TOP_ALLOCATIONS] {
+    let mut sizes = [0; TOP_ALLOCATIONS];
+    for (size, slot) in sizes.iter_mut().zip(TOP.iter()) {
+        *size = slot.load(Ordering::Relaxed);
+    }
+    sizes
+}
+
+/// Sets the largest allocation sizes (which must be in decreasing order)
+pub(crate) fn set_sizes(sizes: &[usize; TOP_ALLOCATIONS]) {
+    for (slot, size) in TOP.iter().zip(sizes.iter()) {
+        slot.store(*size, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::insert;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    fn sizes(top: &[AtomicUsize; 4]) -> [usize; 4] {
+        [0, 1, 2, 3].map(|i| top[i].load(Ordering::Relaxed))
+    }
+
+    #[test]
+    fn the_largest_sizes_are_kept_in_order() {
+        let top: [AtomicUsize; 4] = Default::default();
+        insert(&top, 30);
+        insert(&top, 10);
+        assert_eq!([30, 10, 0, 0], sizes(&top));
+        for size in [50, 20, 40, 10, 5, 60] {
+            insert(&top, size);
+        }
+        assert_eq!([60, 50, 40, 30], sizes(&top));
+        insert(&top, 30);
+        insert(&top, 0);
+        assert_eq!([60, 50, 40, 30], sizes(&top));
+        insert(&top, 60);
+        assert_eq!([60, 60, 50, 40], sizes(&top));
+    }
+
+    #[test]
+    fn concurrent_insertions_lose_none_of_the_largest() {
+        let top: [AtomicUsize; 4] = Default::default();
+        std::thread::scope(|scope| {
+            for thread in 0..4 {
+                let top = &top;
+                scope.spawn(move || {
+                    for i in 0..1000 {
+                        insert(top, i * 4 + thread);
+                    }
+                });
+            }
+        });
+        assert_eq!([3999, 3998, 3997, 3996], sizes(&top));
+    }
+}
@@ -0,0 +1,472 @@
+//! The accounting itself: a set of counters which are told about the
+//! allocations, deallocations and reallocations and which maintain the
+//! current usage, the peaks and the totals. `PeakAlloc` is a thin wrapper
+//! around a static `AllocationTracker`, but nothing prevents an allocator of
+//! your own to embed one (or several) of them.
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// Maintains the memory usage statistics of an allocator. All the methods
+/// take `&self` and are lock-free: a tracker is meant to be stored in a
+/// static and updated from a `GlobalAlloc` implementation.
+///
+/// ```
+/// use peak_alloc::AllocationTracker;
+///
+/// static TRACKER: AllocationTracker = AllocationTracker::new();
+///
+/// TRACKER.on_alloc(100);
+/// TRACKER.on_realloc(100, 300);
+/// TRACKER.on_dealloc(300);
+/// assert_eq!(0, TRACKER.current_usage());
+/// assert_eq!(300, TRACKER.peak_usage());
+/// ```
+#[derive(Debug, Default)]
+pub struct AllocationTracker {
+    /// The number of bytes currently allocated
+    current: AtomicUsize,
+    /// The maximum number of bytes allocated at once (since the last reset)
+    peak: AtomicUsize,
+    /// The maximum number of bytes allocated at once (never reset)
+    all_time_peak: AtomicUsize,
+    /// The cumulative number of bytes allocated (saturated upon overflow)
+    total_allocated: AtomicUsize,
+    /// Whether the cumulative number of bytes allocated has overflowed
+    total_overflowed: AtomicBool,
+    /// The number of allocations
+    allocations: AtomicUsize,
+    /// The number of deallocations
+    deallocations: AtomicUsize,
+    /// The number of reallocations
+    reallocations: AtomicUsize,
+    /// The number of bytes copied by the reallocations which moved a block
+    realloc_copied: AtomicUsize,
+    /// The size of the largest allocation
+    largest: AtomicUsize,
+}
+
+impl AllocationTracker {
+    /// Creates a tracker whose counters are all zero
+    pub const fn new() -> Self {
+        AllocationTracker {
+            current: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+            all_time_peak: AtomicUsize::new(0),
+            total_allocated: AtomicUsize::new(0),
+            total_overflowed: AtomicBool::new(false),
+            allocations: AtomicUsize::new(0),
+            deallocations: AtomicUsize::new(0),
+            reallocations: AtomicUsize::new(0),
+            realloc_copied: AtomicUsize::new(0),
+            largest: AtomicUsize::new(0),
+        }
+    }
+    /// Accounts for the allocation of a block of `size` bytes
+    #[inline]
+    pub fn on_alloc(&self, size: usize) {
+        self.grow(size);
+        self.count_allocation(size);
+    }
+    /// Accounts for the release of a block of `size` bytes
+    #[inline]
+    pub fn on_dealloc(&self, size: usize) {
+        self.shrink(size);
+        self.count_deallocation();
+    }
+    /// Accounts for a block being resized from `old_size` to `new_size`
+    /// bytes. This is counted as a reallocation (neither as an allocation nor
+    /// as a deallocation), and a block which grows may become the largest one.
+    #[inline]
+    pub fn on_realloc(&self, old_size: usize, new_size: usize) {
+        self.count_reallocation();
+        if new_size >= old_size {
+            self.grow(new_size - old_size);
+            self.raise_largest(new_size);
+        } else {
+            self.shrink(old_size - new_size);
+        }
+    }
+
+    /// Accounts for `delta` more bytes being in use and returns the previous
+    /// (raw) usage. The peaks are raised with the clamped usage: a counter
+    /// which has wrapped around never raises them.
+    #[inline]
+    pub(crate) fn grow(&self, delta: usize) -> usize {
+        // as pointed out by @luxalpa, fetch_add returns the PREVIOUS value.
+        let prev = self.current.fetch_add(delta, Ordering::Relaxed);
+        let usage = clamp(prev.wrapping_add(delta));
+        raise(&self.peak, usage);
+        raise(&self.all_time_peak, usage);
+        self.add_to_total(delta);
+        prev
+    }
+    /// Adds `delta` to the cumulative number of bytes allocated, which
+    /// saturates (and remembers it) rather than wrapping around. The total is
+    /// never seen wrapped: the sum saturates in the same step.
+    #[inline]
+    fn add_to_total(&self, delta: usize) {
+        if self.total_overflowed.load(Ordering::Relaxed) {
+            return;
+        }
+        let added = self.total_allocated.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |total| {
+            Some(total.saturating_add(delta))
+        });
+        if let Ok(total) = added {
+            if total.checked_add(delta).is_none() {
+                self.overflow_total();
+            }
+        }
+    }
+    #[cold]
+    fn overflow_total(&self) {
+        self.total_overflowed.store(true, Ordering::Relaxed);
+    }
+    /// Accounts for `delta` less bytes being in use and returns the previous
+    /// (raw) usage. Releasing more than what is in use wraps the counter
+    /// around (see `current_usage`).
+    #[inline]
+    pub(crate) fn shrink(&self, delta: usize) -> usize {
+        self.current.fetch_sub(delta, Ordering::Relaxed)
+    }
+    /// Counts one allocation of `size` bytes
+    #[inline]
+    pub(crate) fn count_allocation(&self, size: usize) {
+        self.allocations.fetch_add(1, Ordering::Relaxed);
+        self.raise_largest(size);
+    }
+    /// Counts one deallocation
+    #[inline]
+    pub(crate) fn count_deallocation(&self) {
+        self.deallocations.fetch_add(1, Ordering::Relaxed);
+    }
+    /// Counts one reallocation
+    #[inline]
+    pub(crate) fn count_reallocation(&self) {
+        self.reallocations.fetch_add(1, Ordering::Relaxed);
+    }
+    /// Counts the `bytes` copied by a reallocation which moved its block
+    #[inline]
+    pub(crate) fn count_realloc_copy(&self, bytes: usize) {
+        self.realloc_copied.fetch_add(bytes, Ordering::Relaxed);
+    }
+    /// Remembers that a block of `size` bytes has been in use
+    #[inline]
+    pub(crate) fn raise_largest(&self, size: usize) {
+        raise(&self.largest, size);
+    }
+    /// Returns the raw value of the usage counter (not clamped)
+    #[inline]
+    pub(crate) fn raw_current(&self) -> usize {
+        self.current.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of bytes currently allocated. If more bytes were
+    /// released than allocated (which means that some `Layout` was
+    /// inconsistent), the usage is clamped to zero.
+    pub fn current_usage(&self) -> usize {
+        clamp(self.raw_current())
+    }
+    /// Returns the maximum number of bytes allocated at once since the last
+    /// call to `reset_peak_usage`
+    pub fn peak_usage(&self) -> usize {
+        self.peak.load(Ordering::Relaxed)
+    }
+    /// Returns the maximum number of bytes allocated at once. Unlike
+    /// `peak_usage`, this value is never reset.
+    pub fn all_time_peak_usage(&self) -> usize {
+        self.all_time_peak.load(Ordering::Relaxed)
+    }
+    /// Returns the cumulative number of bytes allocated (the growth of the
+    /// reallocated blocks included). It saturates at `usize::MAX` (see
+    /// `total_allocated_overflowed`).
+    pub fn total_allocated(&self) -> usize {
+        self.total_allocated.load(Ordering::Relaxed)
+    }
+    /// Returns true iff the cumulative number of bytes allocated has
+    /// overflowed: `total_allocated` is stuck at `usize::MAX` since.
+    pub fn total_allocated_overflowed(&self) -> bool {
+        self.total_overflowed.load(Ordering::Relaxed)
+    }
+    /// Returns the number of allocations
+    pub fn allocation_count(&self) -> usize {
+        self.allocations.load(Ordering::Relaxed)
+    }
+    /// Returns the number of deallocations
+    pub fn deallocation_count(&self) -> usize {
+        self.deallocations.load(Ordering::Relaxed)
+    }
+    /// Returns the number of reallocations
+    pub fn reallocation_count(&self) -> usize {
+        self.reallocations.load(Ordering::Relaxed)
+    }
+    /// Returns the number of bytes copied by the reallocations which moved
+    /// their block
+    pub fn realloc_copied_bytes(&self) -> usize {
+        self.realloc_copied.load(Ordering::Relaxed)
+    }
+    /// Returns the size of the largest block ever allocated (or grown to)
+    pub fn largest_allocation(&self) -> usize {
+        self.largest.load(Ordering::Relaxed)
+    }
+    /// Resets the peak usage to the current usage (the all time peak usage
+    /// is left untouched). A concurrent allocation can never leave the peak
+    /// below the usage.
+    pub fn reset_peak_usage(&self) {
+        reset_mark(&self.peak, &self.current);
+    }
+    /// Resets the peak usage to zero: the peak is then the highest usage
+    /// reached by the next allocations
+    pub(crate) fn clear_peak_usage(&self) {
+        self.peak.store(0, Ordering::SeqCst);
+    }
+    /// Returns the current values of the counters (see `restore`)
+    pub(crate) fn save(&self) -> Saved {
+        Saved {
+            current: self.current.load(Ordering::SeqCst),
+            peak: self.peak.load(Ordering::SeqCst),
+            all_time_peak: self.all_time_peak.load(Ordering::SeqCst),
+            total_allocated: self.total_allocated.load(Ordering::SeqCst),
+            total_overflowed: self.total_overflowed.load(Ordering::SeqCst),
+            allocations: self.allocations.load(Ordering::SeqCst),
+            deallocations: self.deallocations.load(Ordering::SeqCst),
+            reallocations: self.reallocations.load(Ordering::SeqCst),
+            realloc_copied: self.realloc_copied.load(Ordering::SeqCst),
+            largest: self.largest.load(Ordering::SeqCst),
+        }
+    }
+    /// Puts the counters back to the values they had when they were saved.
+    /// The current usage is left alone (the blocks allocated in between are
+    /// still live, and will be released), and the peak never falls below it.
+    #[cfg(feature = "std")]
+    pub(crate) fn restore(&self, saved: &Saved) {
+        self.total_allocated.store(saved.total_allocated, Ordering::SeqCst);
+        self.total_overflowed.store(saved.total_overflowed, Ordering::SeqCst);
+        self.allocations.store(saved.allocations, Ordering::SeqCst);
+        self.deallocations.store(saved.deallocations, Ordering::SeqCst);
+        self.reallocations.store(saved.reallocations, Ordering::SeqCst);
+        self.realloc_copied.store(saved.realloc_copied, Ordering::SeqCst);
+        self.largest.store(saved.largest, Ordering::SeqCst);
+        reset_mark(&self.peak, &self.current);
+        raise(&self.peak, saved.peak);
+    }
+    /// Sets all the counters, the current usage and the all time peak
+    /// included, to the saved values
+    pub(crate) fn overwrite(&self, saved: &Saved) {
+        self.current.store(saved.current, Ordering::SeqCst);
+        self.peak.store(saved.peak, Ordering::SeqCst);
+        self.all_time_peak.store(saved.all_time_peak, Ordering::SeqCst);
+        self.total_allocated.store(saved.total_allocated, Ordering::SeqCst);
+        self.total_overflowed.store(saved.total_overflowed, Ordering::SeqCst);
+        self.allocations.store(saved.allocations, Ordering::SeqCst);
+        self.deallocations.store(saved.deallocations, Ordering::SeqCst);
+        self.reallocations.store(saved.reallocations, Ordering::SeqCst);
+        self.realloc_copied.store(saved.realloc_copied, Ordering::SeqCst);
+        self.largest.store(saved.largest, Ordering::SeqCst);
+    }
+}
+
+/// The counters of a tracker at some point (see `AllocationTracker::save`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Saved {
+    pub(crate) current: usize,
+    pub(crate) peak: usize,
+    pub(crate) all_time_peak: usize,
+    pub(crate) total_allocated: usize,
+    pub(crate) total_overflowed: bool,
+    pub(crate) allocations: usize,
+    pub(crate) deallocations: usize,
+    pub(crate) reallocations: usize,
+    pub(crate) realloc_copied: usize,
+    pub(crate) largest: usize,
+}
+
+/// What `PeakAlloc::reset_peak_usage` resets the peak usage to
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ResetPolicy {
+    /// The current usage, hence the peak never falls below the usage (this
+    /// is the default)
+    #[default]
+    ToCurrent,
+    /// Zero: until the next allocation, the peak is below the usage. This
+    /// suits the programs which treat the usage as a delta from a baseline,
+    /// and want the peak of a window to only count what the window allocated.
+    ToZero,
+}
+
+/// Clamps a usage counter which has wrapped around to zero. No live usage
+/// can ever exceed `isize::MAX` bytes (the size of a `Layout` cannot).
+#[inline]
+pub(crate) fn clamp(usage: usize) -> usize {
+    if usage > isize::MAX as usize {
+        0
+    } else {
+        usage
+    }
+}
+
+/// Raises the given high-water mark to `value` (if it is higher). In the
+/// common case where the usage is below the mark, a mere load is performed
+/// instead of the (contended) read-modify-write.
+#[inline]
+pub(crate) fn raise(mark: &AtomicUsize, value: usize) {
+    if value > mark.load(Ordering::Relaxed) {
+        mark.fetch_max(value, Ordering::Relaxed);
+    }
+}
+
+/// Lowers the given high-water mark to the value of `usage`, without ever
+/// leaving it below the usage. An allocation landing between the load of
+/// the usage and the store can have its raise overwritten: the mark is then
+/// raised again until it covers the usage. The allocations which land
+/// afterwards raise it by themselves. The usage is clamped (see `clamp`).
+pub(crate) fn reset_mark(mark: &AtomicUsize, usage: &AtomicUsize) {
+    mark.store(clamp(usage.load(Ordering::SeqCst)), Ordering::SeqCst);
+    loop {
+        let current = clamp(usage.load(Ordering::SeqCst));
+        if mark.fetch_max(current, Ordering::SeqCst) >= current {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AllocationTracker;
+
+    #[test]
+    fn allocations_and_deallocations_are_counted() {
+        let tracker = AllocationTracker::new();
+        tracker.on_alloc(100);
+        tracker.on_alloc(50);
+        assert_eq!(150, tracker.current_usage());
+        tracker.on_dealloc(100);
+        assert_eq!(50, tracker.current_usage());
+        assert_eq!(150, tracker.peak_usage());
+        assert_eq!(150, tracker.total_allocated());
+        assert_eq!(2, tracker.allocation_count());
+        assert_eq!(1, tracker.deallocation_count());
+        assert_eq!(100, tracker.largest_allocation());
+    }
+
+    #[test]
+    fn realloc_accounts_for_the_delta_only() {
+        let tracker = AllocationTracker::new();
+        tracker.on_alloc(100);
+        tracker.on_realloc(100, 250);
+        assert_eq!(250, tracker.current_usage());
+        assert_eq!(250, tracker.total_allocated());
+        assert_eq!(250, tracker.largest_allocation());
+        tracker.on_realloc(250, 10);
+        assert_eq!(10, tracker.current_usage());
+        assert_eq!(250, tracker.peak_usage());
+        // same size: nothing changes
+        tracker.on_realloc(10, 10);
+        assert_eq!(10, tracker.current_usage());
+        assert_eq!(1, tracker.allocation_count());
+        assert_eq!(0, tracker.deallocation_count());
+        assert_eq!(3, tracker.reallocation_count());
+    }
+
+    #[test]
+    fn peak_is_reset_to_the_current_usage() {
+        let tracker = AllocationTracker::new();
+        tracker.on_alloc(1000);
+        tracker.on_dealloc(1000);
+        tracker.on_alloc(10);
+        tracker.reset_peak_usage();
+        assert_eq!(10, tracker.peak_usage());
+        assert_eq!(1000, tracker.all_time_peak_usage());
+        tracker.on_alloc(20);
+        assert_eq!(30, tracker.peak_usage());
+    }
+
+    #[test]
+    fn underflow_is_clamped_to_zero() {
+        let tracker = AllocationTracker::new();
+        tracker.on_alloc(10);
+        tracker.on_dealloc(30);
+        assert_eq!(0, tracker.current_usage());
+        // a wrapped counter raises none of the peaks
+        tracker.on_alloc(5);
+        assert_eq!(0, tracker.current_usage());
+        assert_eq!((10, 10), (tracker.peak_usage(), tracker.all_time_peak_usage()));
+        tracker.reset_peak_usage();
+        assert_eq!(0, tracker.peak_usage());
+        // the counter wrapped around: what was missing is still owed
+        tracker.on_alloc(20);
+        assert_eq!(5, tracker.current_usage());
+        assert_eq!((5, 10), (tracker.peak_usage(), tracker.all_time_peak_usage()));
+        assert_eq!(35, tracker.total_allocated());
+    }
+
+    #[test]
+    fn total_saturates_upon_overflow() {
+        let tracker = AllocationTracker::new();
+        let near_max = usize::MAX - 10;
+        tracker.on_alloc(near_max);
+        tracker.on_dealloc(near_max);
+        assert_eq!(near_max, tracker.total_allocated());
+        assert!(!tracker.total_allocated_overflowed());
+        tracker.on_alloc(10);
+        assert_eq!(usize::MAX, tracker.total_allocated());
+        assert!(!tracker.total_allocated_overflowed());
+        tracker.on_alloc(1);
+        assert!(tracker.total_allocated_overflowed());
+        assert_eq!(usize::MAX, tracker.total_allocated());
+        // the other counters are not affected
+        tracker.on_realloc(1, 100);
+        assert_eq!(usize::MAX, tracker.total_allocated());
+        assert_eq!(110, tracker.current_usage());
+    }
+
+    #[test]
+    fn a_saturating_total_is_never_seen_wrapped() {
+        let tracker = AllocationTracker::new();
+        let near_max = usize::MAX - 1000 * (1 << 20);
+        tracker.on_alloc(near_max);
+        tracker.on_dealloc(near_max);
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..1000 {
+                        tracker.on_alloc(1 << 20);
+                        tracker.on_dealloc(1 << 20);
+                    }
+                });
+            }
+            while !tracker.total_allocated_overflowed() {
+                assert!(tracker.total_allocated() >= near_max);
+            }
+        });
+        assert_eq!(usize::MAX, tracker.total_allocated());
+    }
+
+    #[test]
+    fn trackers_are_independent() {
+        let first = AllocationTracker::new();
+        let second = AllocationTracker::default();
+        first.on_alloc(64);
+        assert_eq!(64, first.current_usage());
+        assert_eq!(0, second.current_usage());
+        assert_eq!(0, second.allocation_count());
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn restored_counters_keep_the_live_usage() {
+        let tracker = AllocationTracker::new();
+        tracker.on_alloc(100);
+        let saved = tracker.save();
+        tracker.on_alloc(1000);
+        tracker.on_realloc(1000, 50);
+        tracker.on_alloc(20);
+        tracker.restore(&saved);
+        assert_eq!(1, tracker.allocation_count());
+        assert_eq!(0, tracker.reallocation_count());
+        assert_eq!(100, tracker.total_allocated());
+        assert_eq!(100, tracker.largest_allocation());
+        assert_eq!(170, tracker.current_usage());
+        assert_eq!(170, tracker.peak_usage());
+        assert_eq!(1100, tracker.all_time_peak_usage());
+    }
+}
@@ -0,0 +1,336 @@
+//! The accounting of `PeakAlloc` wrapped around an allocator of your choice,
+//! which is how the counters are maintained without `std` (where there is no
+//! system allocator to delegate to).
+
+use core::alloc::{GlobalAlloc, Layout};
+
+#[cfg(feature = "std")]
+use crate::check_frozen;
+use crate::config;
+use crate::entry_points::{self, EntryPoint};
+use crate::{failed, growth, is_tracked, track_alloc, track_dealloc, track_realloc, within_limit};
+
+/// An allocator which delegates all its work to `inner` and maintains the
+/// same (global) counters as `PeakAlloc`. These counters are still queried
+/// through `PeakAlloc`:
+///
+/// ```
+/// use peak_alloc::{PeakAlloc, TrackingAlloc};
+/// use std::alloc::System;
+///
+/// #[global_allocator]
+/// static ALLOC: TrackingAlloc<System> = TrackingAlloc::new(System);
+///
+/// fn main() {
+///     let peak = PeakAlloc.peak_usage();
+/// }
+/// ```
+///
+/// # Note
+/// The features which act on the blocks or on the system allocator
+/// (`quarantine`, `redzones`, `poison`, `zeroize-on-free`, `actual-size` and
+/// `checked-layout`) are only supported by `PeakAlloc` itself: a
+/// `TrackingAlloc` does not wipe the blocks it frees, for one.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TrackingAlloc<A> {
+    inner: A,
+}
+
+impl<A> TrackingAlloc<A> {
+    /// Wraps the given allocator
+    pub const fn new(inner: A) -> Self {
+        TrackingAlloc { inner }
+    }
+    /// Returns the wrapped allocator
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for TrackingAlloc<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        #[cfg(feature = "std")]
+        check_frozen();
+        let limits = config::limits();
+        if is_tracked(&limits, layout.size()) && !within_limit(&limits, layout.size()) {
+            return failed();
+        }
+        let ptr = self.inner.alloc(layout);
+        if ptr.is_null() {
+            return failed();
+        }
+        if is_tracked(&limits, layout.size()) && entry_points::is_tracked(EntryPoint::Alloc) {
+            track_alloc(ptr, layout, &limits);
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        #[cfg(feature = "std")]
+        check_frozen();
+        let limits = config::limits();
+        if is_tracked(&limits, layout.size()) && !within_limit(&limits, layout.size()) {
+            return failed();
+        }
+        let ptr = self.inner.alloc_zeroed(layout);
+        if ptr.is_null() {
+            return failed();
+        }
+        if is_tracked(&limits, layout.size()) && entry_points::is_tracked(EntryPoint::AllocZeroed) {
+            track_alloc(ptr, layout, &limits);
+        }
+        ptr
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        #[cfg(feature = "std")]
+        check_frozen();
+        let limits = config::limits();
+        let growth = growth(&limits, layout.size(), new_size);
+        if growth > 0 && !within_limit(&limits, growth) {
+            return failed();
+        }
+        let new_ptr = self.inner.realloc(ptr, layout, new_size);
+        if new_ptr.is_null() {
+            // the original block is left untouched
+            return failed();
+        }
+        if entry_points::is_tracked(EntryPoint::Realloc) {
+            let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+            track_realloc(ptr, layout, new_ptr, new_layout, &limits);
+        }
+        new_ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let limits = config::limits();
+        if is_tracked(&limits, layout.size()) && entry_points::is_tracked(EntryPoint::Dealloc) {
+            track_dealloc(ptr, layout, &limits);
+        }
+        self.inner.dealloc(ptr, layout)
+    }
+}
+
+/// An allocator chosen at runtime, behind a trait object: wrapped in a
+/// `TrackingAlloc`, it lets the backend be selected at startup, at the cost
+/// of a dynamic dispatch per call.
+///
+/// ```
+/// use peak_alloc::{DynAlloc, PeakAlloc, TrackingAlloc};
+/// use std::alloc::{GlobalAlloc, Layout, System};
+///
+/// let backend: Box<dyn GlobalAlloc + Sync> = Box::new(System);
+/// let alloc = TrackingAlloc::new(DynAlloc::new(Box::leak(backend)));
+/// let layout = Layout::new::<u64>();
+/// unsafe { alloc.dealloc(alloc.alloc(layout), layout) };
+/// ```
+///
+/// # Note
+/// The global allocator is a static, hence it must be built in a const
+/// context: `DynAlloc::new` is a `const fn`, but the backend of the global
+/// allocator then has to be a static as well.
+#[derive(Clone, Copy)]
+pub struct DynAlloc {
+    backend: &'static (dyn GlobalAlloc + Sync),
+}
+
+impl DynAlloc {
+    /// Forwards all the calls to `backend`
+    pub const fn new(backend: &'static (dyn GlobalAlloc + Sync)) -> Self {
+        DynAlloc { backend }
+    }
+}
+
+impl core::fmt::Debug for DynAlloc {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("DynAlloc").finish_non_exhaustive()
+    }
+}
+
+unsafe impl GlobalAlloc for DynAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.backend.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        self.backend.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        self.backend.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.backend.dealloc(ptr, layout)
+    }
+}
+
+/// The backend of a `DynTrackingAlloc` has not been set (`System` is used)
+#[cfg(feature = "std")]
+const UNSET: u8 = 0;
+/// The backend of a `DynTrackingAlloc` is being set
+#[cfg(feature = "std")]
+const SETTING: u8 = 1;
+/// The backend of a `DynTrackingAlloc` is set
+#[cfg(feature = "std")]
+const SET: u8 = 2;
+
+/// A `TrackingAlloc` whose inner allocator is selected at runtime, e.g. from
+/// an environment variable at startup. Until it is configured, it delegates
+/// to `System`.
+///
+/// ```
+/// use peak_alloc::{DynTrackingAlloc, PeakAlloc};
+/// use std::alloc::{GlobalAlloc, Layout, System};
+///
+/// static ALLOC: DynTrackingAlloc = DynTrackingAlloc::new();
+/// static BACKEND: System = System;
+///
+/// assert!(ALLOC.set_inner_before_first_alloc(&BACKEND));
+/// let layout = Layout::new::<u64>();
+/// unsafe { ALLOC.dealloc(ALLOC.alloc(layout), layout) };
+/// assert!(ALLOC.has_allocated());
+/// ```
+///
+/// # Note
+/// A block must be released by the allocator which created it: the inner
+/// allocator can only be set once, and only before the first allocation.
+/// A later attempt is ignored (`set_inner_before_first_alloc` returns false)
+/// and, in debug builds, aborts the process so that the mistake does not go
+/// unnoticed. Beware that the runtime may allocate before `main` starts: the
+/// inner allocator of a global allocator is best set from a constructor,
+/// when `has_allocated` is still false.
+#[cfg(feature = "std")]
+pub struct DynTrackingAlloc {
+    /// UNSET, SETTING or SET
+    state: core::sync::atomic::AtomicU8,
+    /// The inner allocator, written once while SETTING
+    backend: core::cell::UnsafeCell<Option<&'static (dyn GlobalAlloc + Sync)>>,
+    /// Whether a block has been allocated already
+    allocated: core::sync::atomic::AtomicBool,
+}
+
+// SAFETY: the backend is only written once, before the state becomes SET,
+// and only read after the state was seen SET
+#[cfg(feature = "std")]
+unsafe impl Sync for DynTrackingAlloc {}
+
+#[cfg(feature = "std")]
+impl DynTrackingAlloc {
+    /// Creates an allocator delegating to `System` until it is configured
+    pub const fn new() -> Self {
+        DynTrackingAlloc {
+            state: core::sync::atomic::AtomicU8::new(UNSET),
+            backend: core::cell::UnsafeCell::new(None),
+            allocated: core::sync::atomic::AtomicBool::new(false),
+        }
+    }
+    /// Sets the inner allocator, provided that nothing has been allocated yet
+    /// and that it has not been set already. Returns true iff it was set.
+    ///
+    /// In debug builds, a late switch aborts the process instead.
+    pub fn set_inner_before_first_alloc(&self, inner: &'static (dyn GlobalAlloc + Sync)) -> bool {
+        self.try_set_inner(inner) || late_switch()
+    }
+    /// Sets the inner allocator, or returns false if it is too late
+    pub(crate) fn try_set_inner(&self, inner: &'static (dyn GlobalAlloc + Sync)) -> bool {
+        use core::sync::atomic::Ordering;
+        if self.state.compare_exchange(UNSET, SETTING, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+            return false;
+        }
+        if self.allocated.load(Ordering::SeqCst) {
+            self.state.store(UNSET, Ordering::SeqCst);
+            return false;
+        }
+        // SAFETY: the state is SETTING, nobody reads the backend meanwhile
+        unsafe { *self.backend.get() = Some(inner) };
+        self.state.store(SET, Ordering::SeqCst);
+        true
+    }
+    /// Returns true iff a block has been allocated already: the inner
+    /// allocator can no longer be changed.
+    pub fn has_allocated(&self) -> bool {
+        self.allocated.load(core::sync::atomic::Ordering::SeqCst)
+    }
+    /// Returns true iff the inner allocator has been set (it is `System`
+    /// otherwise)
+    pub fn is_configured(&self) -> bool {
+        self.state.load(core::sync::atomic::Ordering::SeqCst) == SET
+    }
+    /// Returns the allocator the calls are delegated to
+    #[inline]
+    fn tracking(&self) -> TrackingAlloc<DynAlloc> {
+        static SYSTEM: std::alloc::System = std::alloc::System;
+        let mut state = self.state.load(core::sync::atomic::Ordering::SeqCst);
+        // a switch underway may have missed the allocation: wait for its
+        // outcome, or the block would be released by the other allocator
+        while state == SETTING {
+            core::hint::spin_loop();
+            state = self.state.load(core::sync::atomic::Ordering::SeqCst);
+        }
+        if state == SET {
+            // SAFETY: the backend is never written again once SET
+            if let Some(backend) = unsafe { *self.backend.get() } {
+                return TrackingAlloc::new(DynAlloc::new(backend));
+            }
+        }
+        TrackingAlloc::new(DynAlloc::new(&SYSTEM))
+    }
+    /// Remembers that a block is about to be allocated (before the backend
+    /// is chosen, so that a concurrent switch sees it)
+    #[inline]
+    fn allocating(&self) {
+        self.allocated.store(true, core::sync::atomic::Ordering::SeqCst);
+    }
+}
+
+#[cfg(feature = "std")]
+impl Default for DynTrackingAlloc {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "std")]
+impl core::fmt::Debug for DynTrackingAlloc {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("DynTrackingAlloc")
+            .field("configured", &self.is_configured())
+            .field("allocated", &self.has_allocated())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Rejects a late switch of the inner allocator (aborting in debug builds)
+#[cfg(feature = "std")]
+#[cold]
+fn late_switch() -> bool {
+    if cfg!(debug_assertions) {
+        use std::io::Write as _;
+        let _ = std::io::stderr()
+            .write_all(b"peak_alloc: the inner allocator was set after the first allocation, aborting\n");
+        std::process::abort();
+    }
+    false
+}
+
+#[cfg(feature = "std")]
+unsafe impl GlobalAlloc for DynTrackingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.allocating();
+        self.tracking().alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        self.allocating();
+        self.tracking().alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        self.tracking().realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.tracking().dealloc(ptr, layout)
+    }
+}
@@ -0,0 +1,181 @@
+//! The size units, as numbers of bytes. The binary ones (`KIB`, `MIB`, ...)
+//! are the units of the `*_as_kb`, `*_as_mb` and `*_as_gb` conversions of
+//! `PeakAlloc` (which predate the IEC names), the decimal ones (`KB`, `MB`,
+//! `GB`) are those of the disk vendors and of most dashboards. Any of them
+//! can be given to `PeakAlloc::current_usage_in_units`, and the `Unit` they
+//! are named after to `PeakAlloc::display_in`.
+
+use core::fmt;
+
+use crate::PeakAlloc;
+
+/// A kibibyte: 1024 bytes
+pub const KIB: usize = 1 << 10;
+/// A mebibyte: 1024 kibibytes
+pub const MIB: usize = 1 << 20;
+/// A gibibyte: 1024 mebibytes
+pub const GIB: usize = 1 << 30;
+/// A tebibyte: 1024 gibibytes (only representable on 64 bit targets)
+#[cfg(target_pointer_width = "64")]
+pub const TIB: usize = 1 << 40;
+/// A kilobyte: 1000 bytes
+pub const KB: usize = 1_000;
+/// A megabyte: 1000 kilobytes
+pub const MB: usize = 1_000_000;
+/// A gigabyte: 1000 megabytes
+pub const GB: usize = 1_000_000_000;
+
+/// The unit in which `PeakAlloc::display_in` writes the usage
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Unit {
+    /// Plain bytes, as integers (`1572864 B`)
+    Bytes,
+    /// Kibibytes (`1536.00 KiB`)
+    Kib,
+    /// Mebibytes (`1.50 MiB`)
+    Mib,
+    /// Gibibytes (`0.00 GiB`)
+    Gib,
+    /// Kilobytes (`1572.86 KB`)
+    Kb,
+    /// Megabytes (`1.57 MB`)
+    Mb,
+    /// Gigabytes (`0.00 GB`)
+    Gb,
+    /// The largest binary unit in which the value is at least one, as per
+    /// `ByteSize` (`1.50 MiB`)
+    Auto,
+}
+
+impl Unit {
+    /// Returns the number of bytes in this unit, and its symbol (none for
+    /// `Bytes` and `Auto`)
+    fn scale(self) -> Option<(usize, &'static str)> {
+        match self {
+            Unit::Bytes | Unit::Auto => None,
+            Unit::Kib => Some((KIB, "KiB")),
+            Unit::Mib => Some((MIB, "MiB")),
+            Unit::Gib => Some((GIB, "GiB")),
+            Unit::Kb => Some((KB, "KB")),
+            Unit::Mb => Some((MB, "MB")),
+            Unit::Gb => Some((GB, "GB")),
+        }
+    }
+    /// Writes a number of bytes in this unit, with the given number of
+    /// decimals (ignored for the plain bytes)
+    fn write(self, f: &mut fmt::Formatter<'_>, bytes: usize, decimals: usize) -> fmt::Result {
+        match (self, self.scale()) {
+            (Unit::Auto, _) => crate::fmt::write_bytes_with_precision(f, bytes, decimals),
+            (_, Some((unit, symbol))) => write!(f, "{:.*} {}", decimals, bytes as f64 / unit as f64, symbol),
+            (_, None) => write!(f, "{} B", bytes),
+        }
+    }
+}
+
+/// Writes the current and the peak usage in a fixed unit (see
+/// `PeakAlloc::display_in`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DisplayIn {
+    pub(crate) unit: Unit,
+}
+
+impl fmt::Display for DisplayIn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let decimals = f.precision().unwrap_or(2);
+        f.write_str("current: ")?;
+        self.unit.write(f, PeakAlloc.current_usage(), decimals)?;
+        f.write_str(", peak: ")?;
+        self.unit.write(f, PeakAlloc.peak_usage(), decimals)
+    }
+}
+
+/// Returns a number of bytes in the largest binary unit in which it is at
+/// least one, along with the symbol of that unit: `(1.5, "KiB")` for 1536
+/// bytes, `(1023.0, "B")` for 1023. This is the unit `ByteSize` is displayed
+/// in, for the formatters of one's own.
+pub fn human(bytes: usize) -> (f64, &'static str) {
+    const UNITS: [&str; 7] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    (value, UNITS[unit])
+}
+
+/// Returns the number of bytes in `n` kibibytes
+pub const fn kib(n: usize) -> usize {
+    n * KIB
+}
+/// Returns the number of bytes in `n` mebibytes
+pub const fn mib(n: usize) -> usize {
+    n * MIB
+}
+/// Returns the number of bytes in `n` gibibytes
+pub const fn gib(n: usize) -> usize {
+    n * GIB
+}
+/// Returns the number of bytes in `n` tebibytes
+#[cfg(target_pointer_width = "64")]
+pub const fn tib(n: usize) -> usize {
+    n * TIB
+}
+/// Returns the number of bytes in `n` kilobytes
+pub const fn kb(n: usize) -> usize {
+    n * KB
+}
+/// Returns the number of bytes in `n` megabytes
+pub const fn mb(n: usize) -> usize {
+    n * MB
+}
+/// Returns the number of bytes in `n` gigabytes
+pub const fn gb(n: usize) -> usize {
+    n * GB
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn helpers_multiply_by_their_unit() {
+        let table = [
+            (kib as fn(usize) -> usize, KIB, 1024),
+            (mib, MIB, 1024 * 1024),
+            (gib, GIB, 1024 * 1024 * 1024),
+            (kb, KB, 1000),
+            (mb, MB, 1000 * 1000),
+            (gb, GB, 1000 * 1000 * 1000),
+        ];
+        for (helper, unit, bytes) in table.iter() {
+            assert_eq!(*bytes, *unit);
+            assert_eq!(0, helper(0));
+            assert_eq!(*unit, helper(1));
+            assert_eq!(3 * *unit, helper(3));
+        }
+        #[cfg(target_pointer_width = "64")]
+        assert_eq!((TIB, 3 * TIB), (1024 * GIB, tib(3)));
+    }
+
+    #[test]
+    fn human_picks_the_largest_unit_at_least_one() {
+        assert_eq!((0.0, "B"), human(0));
+        assert_eq!((1023.0, "B"), human(KIB - 1));
+        assert_eq!((1.0, "KiB"), human(KIB));
+        assert_eq!((1.5, "KiB"), human(KIB + KIB / 2));
+        assert_eq!("KiB", human(MIB - 1).1);
+        assert_eq!((1.0, "MiB"), human(MIB));
+        assert_eq!("MiB", human(GIB - 1).1);
+        assert_eq!((1.0, "GiB"), human(GIB));
+        // the decimal units are not picked
+        assert_eq!("KiB", human(MB).1);
+        #[cfg(target_pointer_width = "64")]
+        {
+            assert_eq!("GiB", human(TIB - 1).1);
+            assert_eq!((1.0, "TiB"), human(TIB));
+            assert_eq!((1.0, "PiB"), human(1024 * TIB));
+            assert_eq!((16.0, "EiB"), human(usize::MAX));
+        }
+    }
+}
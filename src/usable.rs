@@ -0,0 +1,292 @@
+//! Accounting for the *usable* size of the blocks: the system allocator
+//! rounds the requests up to its size classes, so that the blocks it hands
+//! out are often a bit larger than what was asked for. The sum of these
+//! usable sizes, compared to the sum of the requested sizes, tells how much
+//! memory is lost to the rounding (see `FragmentationReport`).
+//!
+//! The usable size can only be asked to the C allocator (or to the process
+//! heap on Windows), hence this is only maintained by `PeakAlloc` (never by
+//! `TrackingAlloc` whose inner allocator may be anything) and only on Linux,
+//! macOS and Windows.
+//!
+//! Optionally (see `PeakAlloc::set_usable_size_accounting`), the usage itself
+//! (hence the peak, the limit...) is charged with the usable sizes: each block
+//! counts for its requested size plus the slack the allocator granted it. The
+//! slack is queried again from the pointer right before the block is released,
+//! so that both charges match. Where the usable size cannot be queried, a
+//! block counts for its requested size.
+
+use core::alloc::Layout;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// The sum of the usable sizes of the live (tracked) blocks
+static USABLE: AtomicUsize = AtomicUsize::new(0);
+/// The sum of the slacks the usage is charged with
+static SLACK: AtomicUsize = AtomicUsize::new(0);
+/// Whether the usage is charged with the usable sizes
+static CHARGE_USABLE: AtomicBool = AtomicBool::new(false);
+
+/// Returns true iff the usable sizes can be queried on this platform
+pub(crate) const SUPPORTED: bool = cfg!(any(target_os = "linux", target_os = "macos", windows));
+
+#[cfg(target_os = "linux")]
+unsafe fn usable_size(ptr: *mut u8, _layout: Layout) -> usize {
+    extern "C" {
+        fn malloc_usable_size(ptr: *mut std::ffi::c_void) -> usize;
+    }
+    malloc_usable_size(ptr.cast())
+}
+
+#[cfg(target_os = "macos")]
+unsafe fn usable_size(ptr: *mut u8, _layout: Layout) -> usize {
+    extern "C" {
+        fn malloc_size(ptr: *const std::ffi::c_void) -> usize;
+    }
+    malloc_size(ptr.cast())
+}
+
+#[cfg(windows)]
+use self::windows::usable_size;
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+unsafe fn usable_size(_ptr: *mut u8, _layout: Layout) -> usize {
+    0
+}
+
+/// On Windows, `System` hands out the blocks of the process heap as they are
+/// as long as their alignment is at most `MIN_ALIGN`. The more aligned ones
+/// are carved out of larger blocks, whose pointer `HeapSize` must never be
+/// given: their usable size is taken to be their requested size, and so is
+/// the one of the blocks `HeapSize` fails on. The difference between the
+/// usable and the requested sizes is the overhead of the heap.
+#[cfg(windows)]
+mod windows {
+    use core::alloc::Layout;
+    use core::ffi::c_void;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetProcessHeap() -> *mut c_void;
+        fn HeapSize(heap: *mut c_void, flags: u32, ptr: *const c_void) -> usize;
+    }
+
+    /// The alignment guaranteed by `HeapAlloc` (the one of `System`)
+    #[cfg(target_pointer_width = "64")]
+    const MIN_ALIGN: usize = 16;
+    #[cfg(not(target_pointer_width = "64"))]
+    const MIN_ALIGN: usize = 8;
+
+    /// The sum of (usable - requested) over the live blocks
+    static OVERHEAD: AtomicUsize = AtomicUsize::new(0);
+    /// The number of times the requested size stood for the usable one
+    static FALLBACKS: AtomicUsize = AtomicUsize::new(0);
+
+    /// Returns the usable size of a block, without accounting for its
+    /// overhead
+    pub(super) unsafe fn query(ptr: *mut u8, layout: Layout) -> usize {
+        let size = if layout.align() <= MIN_ALIGN {
+            HeapSize(GetProcessHeap(), 0, ptr as *const c_void)
+        } else {
+            usize::MAX
+        };
+        if size == usize::MAX || size < layout.size() {
+            FALLBACKS.fetch_add(1, Ordering::Relaxed);
+            return layout.size();
+        }
+        size
+    }
+
+    /// Returns the usable size of a block obtained from `System`, and
+    /// accounts for its overhead
+    pub(super) unsafe fn usable_size(ptr: *mut u8, layout: Layout) -> usize {
+        let size = query(ptr, layout);
+        OVERHEAD.fetch_add(size - layout.size(), Ordering::Relaxed);
+        size
+    }
+
+    /// Accounts for the release of the overhead of a block of the given
+    /// usable size (as returned by `query`)
+    pub(super) fn release(layout: Layout, size: usize) {
+        OVERHEAD.fetch_sub(size - layout.size(), Ordering::Relaxed);
+    }
+
+    /// Returns the overhead of the process heap over the live blocks
+    pub(crate) fn overhead() -> usize {
+        OVERHEAD.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of times the usable size could not be queried
+    pub(crate) fn fallbacks() -> usize {
+        FALLBACKS.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(windows)]
+pub(crate) use self::windows::{fallbacks, overhead};
+
+#[cfg(windows)]
+use self::windows::{query, release};
+
+#[cfg(not(windows))]
+unsafe fn query(ptr: *mut u8, layout: Layout) -> usize {
+    usable_size(ptr, layout)
+}
+
+#[cfg(not(windows))]
+fn release(_layout: Layout, _size: usize) {}
+
+/// Accounts for a block which was just obtained from the system allocator
+/// as `outer` to hold `layout` (both only differ with the redzones), and
+/// returns the layout the usage is to be charged with
+#[inline]
+pub(crate) unsafe fn on_alloc(ptr: *mut u8, outer: Layout, layout: Layout) -> Layout {
+    let usable = usable_size(ptr, outer);
+    USABLE.fetch_add(usable, Ordering::Relaxed);
+    charged(layout, outer, usable, false)
+}
+
+/// Accounts for a block (obtained as `outer` to hold `layout`) which is about
+/// to be given back to the system, and returns the layout the usage was
+/// charged with
+#[inline]
+pub(crate) unsafe fn on_dealloc(ptr: *mut u8, outer: Layout, layout: Layout) -> Layout {
+    on_released(outer, layout, query(ptr, outer))
+}
+
+/// Returns the usable size of a block (obtained as `outer`) without
+/// accounting for anything: `realloc` queries it before the system may
+/// release the block, and only accounts for it (with `on_released`) once
+/// the block has actually been resized.
+#[inline]
+pub(crate) unsafe fn peek(ptr: *mut u8, outer: Layout) -> usize {
+    query(ptr, outer)
+}
+
+/// Accounts for a block (obtained as `outer` to hold `layout`) of the given
+/// usable size having been given back to the system, and returns the layout
+/// the usage was charged with
+#[inline]
+pub(crate) fn on_released(outer: Layout, layout: Layout, usable: usize) -> Layout {
+    release(outer, usable);
+    USABLE.fetch_sub(usable, Ordering::Relaxed);
+    charged(layout, outer, usable, true)
+}
+
+/// Returns `layout` grown by the slack of its block when the usage is
+/// charged with the usable sizes, and `layout` itself otherwise
+#[inline]
+fn charged(layout: Layout, outer: Layout, usable: usize, freed: bool) -> Layout {
+    if !CHARGE_USABLE.load(Ordering::Relaxed) {
+        return layout;
+    }
+    let slack = usable.saturating_sub(outer.size());
+    match Layout::from_size_align(layout.size() + slack, layout.align()) {
+        Ok(charged) => {
+            if freed {
+                SLACK.fetch_sub(slack, Ordering::Relaxed);
+            } else {
+                SLACK.fetch_add(slack, Ordering::Relaxed);
+            }
+            charged
+        }
+        Err(_) => layout,
+    }
+}
+
+/// Returns the sum of the usable sizes of the live blocks
+pub(crate) fn current() -> usize {
+    USABLE.load(Ordering::Relaxed)
+}
+
+/// Returns the part of the usage which is slack rather than requested bytes
+pub(crate) fn slack() -> usize {
+    SLACK.load(Ordering::Relaxed)
+}
+
+/// Sets whether the usage is charged with the usable sizes
+pub(crate) fn set_charge_usable(enabled: bool) {
+    CHARGE_USABLE.store(enabled, Ordering::Relaxed);
+}
+
+/// Returns true iff the usage is charged with the usable sizes
+pub(crate) fn charge_usable() -> bool {
+    CHARGE_USABLE.load(Ordering::Relaxed)
+}
+
+/// An estimate of how much memory is lost between what the program asked
+/// for and what the process actually holds (see `PeakAlloc::fragmentation`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FragmentationReport {
+    /// The number of bytes the live blocks were requested with
+    pub requested_bytes: usize,
+    /// The number of bytes the system allocator actually reserved for the
+    /// live blocks (the sum of their usable sizes)
+    pub usable_bytes: usize,
+    /// The resident set size of the process
+    pub rss_bytes: usize,
+}
+
+impl FragmentationReport {
+    /// Returns the fraction of the usable bytes which were not requested:
+    /// the slack the size classes of the allocator introduce.
+    pub fn slack_ratio(&self) -> f64 {
+        ratio(self.usable_bytes.saturating_sub(self.requested_bytes), self.usable_bytes)
+    }
+    /// Returns the fraction of the rss which is not held by live blocks: the
+    /// memory kept cached (or lost to fragmentation) by the allocator, plus
+    /// the code, stacks and mappings of the process.
+    pub fn overhead_ratio(&self) -> f64 {
+        ratio(self.rss_bytes.saturating_sub(self.usable_bytes), self.rss_bytes)
+    }
+    /// Returns the ratio between the rss and the requested bytes (how many
+    /// bytes the process holds per byte that was asked for)
+    pub fn amplification(&self) -> f64 {
+        if self.requested_bytes == 0 {
+            0.0
+        } else {
+            self.rss_bytes as f64 / self.requested_bytes as f64
+        }
+    }
+}
+
+fn ratio(part: usize, whole: usize) -> f64 {
+    if whole == 0 {
+        0.0
+    } else {
+        part as f64 / whole as f64
+    }
+}
+
+impl fmt::Display for FragmentationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "requested : {} B", self.requested_bytes)?;
+        writeln!(f, "usable    : {} B ({:.1}% slack)", self.usable_bytes, 100.0 * self.slack_ratio())?;
+        writeln!(f, "rss       : {} B ({:.1}% overhead)", self.rss_bytes, 100.0 * self.overhead_ratio())?;
+        writeln!(f, "amplification : {:.2}x", self.amplification())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FragmentationReport;
+
+    #[test]
+    fn display_renders_percentages() {
+        let report = FragmentationReport { requested_bytes: 750, usable_bytes: 1000, rss_bytes: 4000 };
+        assert_eq!(0.25, report.slack_ratio());
+        assert_eq!(0.75, report.overhead_ratio());
+        let text = report.to_string();
+        assert!(text.contains("25.0% slack"), "{}", text);
+        assert!(text.contains("75.0% overhead"), "{}", text);
+    }
+
+    #[test]
+    fn ratios_are_zero_when_nothing_is_held() {
+        let report = FragmentationReport { requested_bytes: 0, usable_bytes: 0, rss_bytes: 0 };
+        assert_eq!(0.0, report.slack_ratio());
+        assert_eq!(0.0, report.overhead_ratio());
+        assert_eq!(0.0, report.amplification());
+    }
+}
@@ -0,0 +1,161 @@
+//! USDT (user statically defined tracing) probes, which tools such as
+//! bpftrace, perf or SystemTap can attach to in a live process:
+//!
+//! ```text
+//! bpftrace -p PID -e 'usdt::peak_alloc:new_peak { printf("%d\n", arg0); }'
+//! ```
+//!
+//! The probes follow the SystemTap SDT convention (an ELF note per probe
+//! site, pointing at a `nop`). Each probe has a semaphore which the tracer
+//! increments when it attaches: as long as nobody is attached, the cost of a
+//! probe is the load and test of its semaphore.
+//!
+//! The probes are only emitted on Linux (x86_64 and aarch64). Elsewhere, and
+//! notably on macOS whose dtrace probes need the support of the linker, they
+//! compile to nothing but the semaphore test.
+
+use core::sync::atomic::{AtomicU16, AtomicUsize, Ordering};
+
+/// A probe semaphore. It lives in the `.probes` section, where the tracer
+/// expects to find it.
+macro_rules! semaphore {
+    ($(#[$doc:meta])* $name:ident) => {
+        $(#[$doc])*
+        #[cfg_attr(target_os = "linux", link_section = ".probes")]
+        pub(crate) static $name: AtomicU16 = AtomicU16::new(0);
+    };
+}
+
+semaphore!(
+    /// The semaphore of the `peak_alloc:new_peak(bytes)` probe
+    NEW_PEAK
+);
+semaphore!(
+    /// The semaphore of the `peak_alloc:threshold_crossed(bytes, threshold)`
+    /// probe
+    THRESHOLD_CROSSED
+);
+
+/// The number of times the `new_peak` probe fired
+static NEW_PEAK_FIRED: AtomicUsize = AtomicUsize::new(0);
+/// The number of times the `threshold_crossed` probe fired
+static THRESHOLD_CROSSED_FIRED: AtomicUsize = AtomicUsize::new(0);
+
+/// Emits a probe site: a `nop` along with the SDT note describing it. The
+/// arguments are given as 8 bytes values in registers (with the AT&T
+/// syntax, which the SDT argument specifications use on x86_64).
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+macro_rules! probe {
+    ($semaphore:ident, $name:literal, $args:literal, $($arg:expr),*) => {
+        // the note is only read by the tracers, the nop has no effect
+        unsafe { core::arch::asm!(
+            "990: nop",
+            ".pushsection .note.stapsdt, \"\", \"note\"",
+            ".balign 4",
+            ".4byte 992f-991f, 994f-993f, 3",
+            "991: .asciz \"stapsdt\"",
+            "992: .balign 4",
+            "993: .8byte 990b",
+            ".8byte _.stapsdt.base",
+            ".8byte {semaphore}",
+            ".asciz \"peak_alloc\"",
+            concat!(".asciz \"", $name, "\""),
+            concat!(".asciz \"", $args, "\""),
+            "994: .balign 4",
+            ".popsection",
+            ".ifndef _.stapsdt.base",
+            ".pushsection .stapsdt.base, \"aG\", \"progbits\", .stapsdt.base, comdat",
+            ".weak _.stapsdt.base",
+            ".hidden _.stapsdt.base",
+            "_.stapsdt.base: .space 1",
+            ".size _.stapsdt.base, 1",
+            ".popsection",
+            ".endif",
+            $(in(reg) $arg as u64,)*
+            semaphore = sym $semaphore,
+            options(att_syntax, nostack, preserves_flags)
+        ) }
+    };
+}
+/// The same, in the (default) syntax of aarch64
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+macro_rules! probe {
+    ($semaphore:ident, $name:literal, $args:literal, $($arg:expr),*) => {
+        // the note is only read by the tracers, the nop has no effect
+        unsafe { core::arch::asm!(
+            "990: nop",
+            ".pushsection .note.stapsdt, \"\", \"note\"",
+            ".balign 4",
+            ".4byte 992f-991f, 994f-993f, 3",
+            "991: .asciz \"stapsdt\"",
+            "992: .balign 4",
+            "993: .8byte 990b",
+            ".8byte _.stapsdt.base",
+            ".8byte {semaphore}",
+            ".asciz \"peak_alloc\"",
+            concat!(".asciz \"", $name, "\""),
+            concat!(".asciz \"", $args, "\""),
+            "994: .balign 4",
+            ".popsection",
+            ".ifndef _.stapsdt.base",
+            ".pushsection .stapsdt.base, \"aG\", \"progbits\", .stapsdt.base, comdat",
+            ".weak _.stapsdt.base",
+            ".hidden _.stapsdt.base",
+            "_.stapsdt.base: .space 1",
+            ".size _.stapsdt.base, 1",
+            ".popsection",
+            ".endif",
+            $(in(reg) $arg as u64,)*
+            semaphore = sym $semaphore,
+            options(nostack, preserves_flags)
+        ) }
+    };
+}
+#[cfg(not(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64"))))]
+macro_rules! probe {
+    ($semaphore:ident, $name:literal, $args:literal, $($arg:expr),*) => {{
+        $(let _ = $arg;)*
+    }};
+}
+
+/// Returns true iff a tracer is attached to the probe of this semaphore
+#[inline]
+fn enabled(semaphore: &AtomicU16) -> bool {
+    semaphore.load(Ordering::Relaxed) != 0
+}
+
+/// Fires the `new_peak` probe if the usage has just reached the peak (and
+/// somebody is listening)
+#[inline]
+pub(crate) fn on_grow(usage: usize) {
+    if enabled(&NEW_PEAK) {
+        new_peak(usage);
+    }
+}
+#[cold]
+fn new_peak(usage: usize) {
+    if usage >= crate::TRACKER.peak_usage() {
+        NEW_PEAK_FIRED.fetch_add(1, Ordering::Relaxed);
+        probe!(NEW_PEAK, "new_peak", "8@{0}", usage);
+    }
+}
+
+/// Fires the `threshold_crossed` probe (if somebody is listening)
+#[inline]
+pub(crate) fn on_threshold_crossed(usage: usize, threshold: usize) {
+    if enabled(&THRESHOLD_CROSSED) {
+        threshold_crossed(usage, threshold);
+    }
+}
+#[cold]
+fn threshold_crossed(usage: usize, threshold: usize) {
+    THRESHOLD_CROSSED_FIRED.fetch_add(1, Ordering::Relaxed);
+    probe!(THRESHOLD_CROSSED, "threshold_crossed", "8@{0} 8@{1}", usage, threshold);
+}
+
+/// Returns the number of times the probes fired: (new_peak,
+/// threshold_crossed)
+#[cfg(test)]
+pub(crate) fn fired() -> (usize, usize) {
+    (NEW_PEAK_FIRED.load(Ordering::Relaxed), THRESHOLD_CROSSED_FIRED.load(Ordering::Relaxed))
+}
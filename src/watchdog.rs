@@ -0,0 +1,111 @@
+//! The watchdog is a background thread which keeps an eye on the memory
+//! usage and calls back whenever it has remained above a threshold for too
+//! long. Short spikes are fine, sustained high usage is a bug.
+
+use std::time::{Duration, Instant};
+
+use crate::periodic::Periodic;
+use crate::{threads, ByteSize, PeakAlloc};
+
+/// What the watchdog tells its callback when it fires
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchdogAlert {
+    /// The memory usage when the watchdog fired
+    pub usage: ByteSize,
+    /// The threshold which was exceeded
+    pub threshold: ByteSize,
+    /// For how long the usage has been above the threshold
+    pub above_for: Duration,
+}
+
+/// The configuration of a watchdog (see `PeakAlloc::start_watchdog`)
+pub struct WatchdogConfig {
+    /// The usage above which the watchdog starts counting
+    pub threshold: ByteSize,
+    /// The delay between two samples of the memory usage (100ms by default)
+    pub interval: Duration,
+    /// For how long the usage may remain above the threshold before the
+    /// watchdog fires (1s by default)
+    pub grace: Duration,
+    /// The minimum delay between two firings of the watchdog (60s by default)
+    pub cooldown: Duration,
+    /// The function called when the watchdog fires. When it is None, the
+    /// final report is written to stderr.
+    pub callback: Option<Box<dyn FnMut(WatchdogAlert) + Send>>,
+}
+
+impl WatchdogConfig {
+    /// Creates a watchdog configuration with the given threshold and the
+    /// default interval, grace period, cooldown and callback.
+    pub fn new(threshold: impl Into<ByteSize>) -> Self {
+        WatchdogConfig {
+            threshold: threshold.into(),
+            interval: Duration::from_millis(100),
+            grace: Duration::from_secs(1),
+            cooldown: Duration::from_secs(60),
+            callback: None,
+        }
+    }
+    /// Sets the delay between two samples of the memory usage
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+    /// Sets for how long the usage may remain above the threshold
+    pub fn grace(mut self, grace: Duration) -> Self {
+        self.grace = grace;
+        self
+    }
+    /// Sets the minimum delay between two firings of the watchdog
+    pub fn cooldown(mut self, cooldown: Duration) -> Self {
+        self.cooldown = cooldown;
+        self
+    }
+    /// Sets the function called when the watchdog fires
+    pub fn callback<F: FnMut(WatchdogAlert) + Send + 'static>(mut self, callback: F) -> Self {
+        self.callback = Some(Box::new(callback));
+        self
+    }
+}
+
+/// The handle to a running watchdog. The watchdog is stopped (and its
+/// thread joined) when the handle is dropped.
+pub struct WatchdogHandle {
+    _thread: Periodic,
+}
+
+/// Spawns the watchdog thread
+pub(crate) fn start(alloc: PeakAlloc, mut config: WatchdogConfig) -> WatchdogHandle {
+    let threshold = config.threshold.bytes();
+    let mut above_since: Option<Instant> = None;
+    let mut last_fired: Option<Instant> = None;
+    let thread = Periodic::spawn("peak_alloc-watchdog", config.interval, move || {
+        let usage = alloc.current_usage();
+        let now = Instant::now();
+        if usage <= threshold {
+            above_since = None;
+            return;
+        }
+        let since = *above_since.get_or_insert(now);
+        let cooled = last_fired.is_none_or(|t| now - t >= config.cooldown);
+        if now - since >= config.grace && cooled {
+            last_fired = Some(now);
+            let alert = WatchdogAlert {
+                usage: ByteSize(usage),
+                threshold: config.threshold,
+                above_for: now - since,
+            };
+            match config.callback.as_mut() {
+                Some(callback) => threads::accounted(|| callback(alert)),
+                None => eprintln!(
+                    "peak_alloc: usage ({}) above {} for {:?}\n{}",
+                    alert.usage,
+                    alert.threshold,
+                    alert.above_for,
+                    alloc.final_report()
+                ),
+            }
+        }
+    });
+    WatchdogHandle { _thread: thread }
+}
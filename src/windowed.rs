@@ -0,0 +1,139 @@
+//! The peak usage within a sliding time window (see
+//! `PeakAlloc::windowed_peak`): unlike the peak usage, it decays on its own.
+//!
+//! The time is cut into one second intervals, and the maximum usage seen
+//! during each of the last `SLOTS` intervals is kept in a fixed array (the
+//! interval `s` goes to the slot `s % SLOTS`). The usage is recorded with
+//! each allocation, and the usage before each deallocation (it was held
+//! until then), with a single `fetch_max` once the (coarse) clock has been
+//! read. A slot which still holds an older interval is taken over by the
+//! first thread to record into it; the expired slots are otherwise ignored
+//! by the queries. A usage which is recorded while a slot is being taken
+//! over may be lost; the next one makes up for it.
+
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use core::time::Duration;
+
+use crate::clock;
+
+/// The number of intervals kept: the longest window, in seconds
+pub(crate) const SLOTS: usize = 60;
+
+/// The maximum usage during an interval
+struct Slot {
+    /// 1 + the second of the interval (0 if the slot is unused)
+    epoch: AtomicU64,
+    max: AtomicUsize,
+}
+
+/// The maxima of the last `N` intervals
+pub(crate) struct Window<const N: usize> {
+    slots: [Slot; N],
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const UNUSED: Slot = Slot { epoch: AtomicU64::new(0), max: AtomicUsize::new(0) };
+
+static WINDOW: Window<SLOTS> = Window::new();
+
+impl<const N: usize> Window<N> {
+    pub(crate) const fn new() -> Self {
+        Window { slots: [UNUSED; N] }
+    }
+    /// Records that `usage` bytes were in use during the second `second`
+    #[inline]
+    pub(crate) fn record(&self, second: u64, usage: usize) {
+        let epoch = second + 1;
+        let slot = &self.slots[(second % N as u64) as usize];
+        let current = slot.epoch.load(Ordering::Relaxed);
+        if current != epoch {
+            take_over(slot, current, epoch);
+        }
+        slot.max.fetch_max(usage, Ordering::Relaxed);
+    }
+    /// Returns the maximum usage during the last `seconds` intervals, up to
+    /// the second `now` included
+    pub(crate) fn peak(&self, now: u64, seconds: u64) -> usize {
+        let covered = seconds.clamp(1, N as u64);
+        let oldest = (now + 1).saturating_sub(covered) + 1;
+        self.slots
+            .iter()
+            .filter(|slot| (oldest..=now + 1).contains(&slot.epoch.load(Ordering::Relaxed)))
+            .map(|slot| slot.max.load(Ordering::Relaxed))
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+#[cold]
+fn take_over(slot: &Slot, current: u64, epoch: u64) {
+    // only a newer interval takes the slot over (a thread may lag behind)
+    if current < epoch
+        && slot.epoch.compare_exchange(current, epoch, Ordering::Relaxed, Ordering::Relaxed).is_ok()
+    {
+        slot.max.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Returns the current second of the coarse clock
+#[inline]
+fn second() -> u64 {
+    clock::now() / 1_000_000_000
+}
+
+/// Accounts for `usage` bytes being in use
+#[inline]
+pub(crate) fn on_usage(usage: usize) {
+    WINDOW.record(second(), usage)
+}
+
+/// Returns the maximum usage within the last `window` (rounded up to whole
+/// seconds, and at most `SLOTS` seconds)
+pub(crate) fn peak(window: Duration) -> usize {
+    let seconds = window.as_secs() + (window.subsec_nanos() > 0) as u64;
+    WINDOW.peak(second(), seconds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Window;
+
+    #[test]
+    fn the_peak_covers_the_last_intervals_only() {
+        let window = Window::<60>::new();
+        window.record(1_000, 500);
+        window.record(1_000, 100);
+        window.record(1_030, 200);
+        assert_eq!(500, window.peak(1_030, 60));
+        assert_eq!(200, window.peak(1_030, 30));
+        assert_eq!(200, window.peak(1_030, 1));
+        assert_eq!(500, window.peak(1_059, 60));
+        // the interval 1_000 has expired
+        assert_eq!(200, window.peak(1_060, 60));
+        assert_eq!(0, window.peak(1_090, 60));
+        // longer windows are clamped
+        assert_eq!(0, window.peak(1_090, 600));
+    }
+
+    #[test]
+    fn a_spike_90_seconds_ago_is_excluded_from_a_60_seconds_window() {
+        let window = Window::<60>::new();
+        window.record(10, 1_000_000);
+        for second in 11..=100 {
+            window.record(second, 1_000);
+        }
+        assert_eq!(1_000, window.peak(100, 60));
+        assert_eq!(1_000, window.peak(100, 90));
+        // the slot of the spike is taken over by a newer interval
+        window.record(130, 2_000);
+        assert_eq!(2_000, window.peak(130, 60));
+    }
+
+    #[test]
+    fn a_late_record_does_not_take_a_newer_slot_over() {
+        let window = Window::<4>::new();
+        window.record(8, 300);
+        window.record(4, 100);
+        assert_eq!(300, window.peak(8, 4));
+    }
+}
@@ -0,0 +1,32 @@
+//! Wiping the freed blocks, so that secrets do not linger in the free lists
+//! of the system allocator (see the `zeroize-on-free` feature).
+//!
+//! Every block is overwritten with zeros right before it is handed back to
+//! the system, after the other debugging features are done with it: the
+//! zeros win over the poison patterns. Since a reallocation would discard
+//! the old block (or the tail of a shrunk one) without wiping it, blocks are
+//! reallocated by allocating a new block, copying and freeing the old one.
+//!
+//! # Performance
+//! The zeros are written one byte at a time, with volatile writes which the
+//! compiler cannot elide: freeing a block costs a pass over all its bytes,
+//! and every reallocation is a copy.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// The number of bytes wiped so far
+static WIPED: AtomicUsize = AtomicUsize::new(0);
+
+/// Overwrites a block of `size` bytes which is about to be released
+#[inline]
+pub(crate) unsafe fn wipe(ptr: *mut u8, size: usize) {
+    for i in 0..size {
+        ptr.add(i).write_volatile(0);
+    }
+    WIPED.fetch_add(size, Ordering::Relaxed);
+}
+
+/// Returns the number of bytes wiped so far
+pub(crate) fn wiped() -> usize {
+    WIPED.load(Ordering::Relaxed)
+}